@@ -1,18 +1,165 @@
-use rusoto_core::Region;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use email_shared::{
+    is_region, AssumeRole, CallTimeouts, Config, EmailId, EmailStatus, MessageGroup, QueueUrl,
+    WeightedQueue,
+};
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
+use tracing_subscriber::filter::LevelFilter;
 
 const LOCALSTACK_REGION: &str = "localstack";
+const LOCALSTACK_ENDPOINT: &str = "http://localhost:4566";
 
-/// Create a custom `Region` if the given name is "localstack" otherwise determine `Region` from
-/// the given string.
-fn parse_region(s: &str) -> Region {
-    if s == LOCALSTACK_REGION {
-        Region::Custom {
-            name: LOCALSTACK_REGION.into(),
-            endpoint: "http://localhost:4566".into(),
+/// AWS `Region` in which services reside along with an endpoint to use in place of the default
+/// endpoints for the region.
+#[derive(Clone, Debug)]
+pub struct AwsRegion {
+    /// Region used to sign requests and resolve default endpoints.
+    pub region: Region,
+    /// URL used for all service requests instead of the default endpoints.
+    pub endpoint_url: Option<String>,
+}
+
+impl AwsRegion {
+    /// Name of the `Region`.
+    pub fn name(&self) -> &str {
+        self.region.as_ref()
+    }
+
+    /// Load the shared AWS configuration for this region. Credentials are resolved from the
+    /// environment by the default provider chain. When `use_dual_stack` is set endpoints which
+    /// accept both IPv4 and IPv6 connections are resolved, otherwise the environment decides.
+    /// Every call is bounded by `timeouts`.
+    pub async fn load(&self, use_dual_stack: bool, timeouts: &CallTimeouts) -> SdkConfig {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest())
+            .region(self.region.clone())
+            .timeout_config(timeouts.timeout_config());
+        if use_dual_stack {
+            loader = loader.use_dual_stack(true);
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
         }
+        loader.load().await
+    }
+}
+
+/// Describe where credentials for `config` come from without exposing them.
+pub fn credentials_source(config: &SdkConfig, assume_role: Option<&AssumeRole>) -> &'static str {
+    if let Some(assume_role) = assume_role {
+        assume_role.source()
+    } else if config.credentials_provider().is_some() {
+        "default provider chain"
     } else {
-        s.parse().unwrap_or(Region::default())
+        "none"
+    }
+}
+
+/// Create an `AwsRegion` with a "localhost" endpoint if the given name is "localstack" otherwise
+/// use the given string as the name of the `Region`, refusing names not shaped like a region.
+fn parse_region(s: &str) -> Result<AwsRegion, String> {
+    if s == LOCALSTACK_REGION {
+        Ok(AwsRegion {
+            region: Region::from_static("us-east-1"),
+            endpoint_url: Some(LOCALSTACK_ENDPOINT.into()),
+        })
+    } else if is_region(s) {
+        Ok(AwsRegion {
+            region: Region::new(s.to_owned()),
+            endpoint_url: None,
+        })
+    } else {
+        Err(format!(
+            "\"{}\" is not \"{}\" or a region such as \"us-east-1\"",
+            s, LOCALSTACK_REGION
+        ))
+    }
+}
+
+/// Queue URL, AWS account id, or table name belonging to an environment, usually production,
+/// which the broker refuses to change unless that environment is named with
+/// `--i-know-what-im-doing`. An account id protects every queue of that account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Protected {
+    /// Name of the environment the resource belongs to.
+    pub environment: String,
+    /// Queue URL, AWS account id, or table name.
+    pub resource: String,
+}
+
+impl Protected {
+    /// Whether `queue_url` is the protected queue or belongs to the protected account.
+    fn covers_queue(&self, queue_url: &QueueUrl) -> bool {
+        self.resource == queue_url.as_str() || self.resource == queue_url.account_id()
+    }
+}
+
+/// Create a `Protected` from "<environment>=<queue url, account id, or table name>".
+fn parse_protected(s: &str) -> Result<Protected, String> {
+    match s.split_once('=') {
+        Some((environment, resource)) if !environment.is_empty() && !resource.is_empty() => {
+            Ok(Protected {
+                environment: environment.trim().to_owned(),
+                resource: resource.trim().to_owned(),
+            })
+        }
+        _ => Err(format!(
+            "\"{}\" is not \"<environment>=<queue url, account id, or table name>\"",
+            s
+        )),
+    }
+}
+
+/// Create an `EmailStatus` from its name, such as "Pending".
+fn parse_status(s: &str) -> Result<EmailStatus, String> {
+    match EmailStatus::from(s) {
+        EmailStatus::Unknown => Err(format!("\"{}\" is not an email status", s)),
+        status => Ok(status),
+    }
+}
+
+/// Create a `WeightedQueue` from "<weight>=<queue url>".
+fn parse_weighted_queue(s: &str) -> Result<WeightedQueue, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "\"{}\" is not \"<weight>=<queue url>\" with a weight greater than 0",
+            s
+        )
+    })
+}
+
+/// Format of the lines the broker logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines, for local runs.
+    Text,
+    /// One JSON object per line, for log aggregation in production.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("\"{}\" is not \"text\" or \"json\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
     }
 }
 
@@ -22,16 +169,726 @@ fn parse_region(s: &str) -> Region {
     about = "Transmit pending email ids in SQS with data stored in DynamoDB"
 )]
 pub struct Options {
+    /// Report what would happen to queued messages without processing them, refused when receives
+    /// could count towards a redrive policy or failure queue
+    #[structopt(long)]
+    pub audit_only: bool,
+    /// Send one email to this address through the full pipeline before reading the queue
+    #[structopt(long)]
+    pub canary: Option<String>,
+    /// Run a command instead of reading the queue
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+    /// TOML or YAML file of settings, replaced by the environment then by flags
+    #[structopt(long, env = "CONFIG_FILE", parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
     /// Do not transmit emails
     #[structopt(long)]
     pub dry_run: bool,
+    /// Environment whose protected queues and tables may be changed by this run
+    #[structopt(long = "i-know-what-im-doing")]
+    pub i_know_what_im_doing: Option<String>,
+    /// Format of logged lines, "text" or "json"
+    #[structopt(long, env = "LOG_FORMAT", default_value = "text")]
+    pub log_format: LogFormat,
+    /// Least severe level logged, "error", "warn", "info", "debug", "trace", or "off"
+    #[structopt(long, env = "LOG_LEVEL", default_value = "info")]
+    pub log_level: LevelFilter,
+    /// Longest wait, in seconds, between receives once the queue keeps returning no messages. The
+    /// wait starts at one second and doubles with each empty receive, 0 never waits
+    #[structopt(long, default_value = "0")]
+    pub max_idle_wait: u64,
+    /// Stop after this many batches, for runs from a scheduler such as cron
+    #[structopt(long)]
+    pub max_iterations: Option<NonZeroUsize>,
+    /// Most threads the runtime starts for blocking work
+    #[structopt(long)]
+    pub max_blocking_threads: Option<NonZeroUsize>,
+    /// Serve "/metrics" for Prometheus along with "/healthz" and "/readyz" on this address
+    #[structopt(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Stop once a receive returns no messages, draining the queue rather than waiting for more
+    #[structopt(long)]
+    pub until_empty: bool,
+    /// Use AWS endpoints which accept both IPv4 and IPv6 connections
+    #[structopt(long)]
+    pub use_dual_stack: bool,
+    /// Settings shared with the Lambda
+    #[structopt(flatten)]
+    pub overrides: ConfigOverrides,
+    /// Queue URL, account id, or table, as "<environment>=<url, account, or table>", only changed
+    /// when the environment is named with --i-know-what-im-doing, may be repeated
+    #[structopt(long = "protect", parse(try_from_str = parse_protected))]
+    pub protected: Vec<Protected>,
+    /// Queue polled along with the queue URL, as "<weight>=<queue url>", may be repeated. Queues
+    /// with a greater weight are drained first, receiving that many batches in a row, the queue
+    /// URL has a weight of 1 unless it is given here
+    #[structopt(long = "queue-weight", parse(try_from_str = parse_weighted_queue))]
+    pub queue_weights: Vec<WeightedQueue>,
+    /// Only read, auditing queued messages and their records with no claims, sends, deletes, or
+    /// visibility changes, and refusing commands which would write. Refused when receives could
+    /// count towards a redrive policy or failure queue
+    #[structopt(long, conflicts_with = "canary")]
+    pub read_only: bool,
+    /// AWS Region in which services reside
+    #[structopt(short = "r", long, parse(try_from_str = parse_region))]
+    pub region: AwsRegion,
+    /// Run every task on the main thread instead of a pool of worker threads
+    #[structopt(long, conflicts_with = "worker-threads")]
+    pub single_threaded: bool,
+    /// Number of worker threads, defaults to the number of CPU cores
+    #[structopt(long)]
+    pub worker_threads: Option<NonZeroUsize>,
+}
+
+/// Settings shared with the Lambda, each replacing the value read from the config file and the
+/// environment when given.
+#[derive(StructOpt, Debug, Serialize)]
+pub struct ConfigOverrides {
+    /// Share of the sends in a window which may fail before an alert is published, from 0 to 1
+    #[structopt(long)]
+    pub alert_failure_rate: Option<f64>,
+    /// ARN of an SNS topic alerted when an email permanently fails or too many sends fail
+    #[structopt(long)]
+    pub alert_topic_arn: Option<String>,
+    /// Seconds of sends the failure rate is alerted on over
+    #[structopt(long)]
+    pub alert_window: Option<u64>,
+    /// Only send to recipients on these domains, separated by commas
+    #[structopt(long, use_delimiter = true)]
+    pub allow_domains: Vec<String>,
+    /// Archival address blind copied on every message sent, as
+    /// "journal@example.com,billing=billing@example.com"
+    #[structopt(long)]
+    pub archive_bcc: Option<String>,
+    /// ARN of an IAM role assumed for every call to AWS, for queues and tables in another account
+    #[structopt(long)]
+    pub assume_role_arn: Option<String>,
+    /// External ID required by the trust policy of the assumed role
+    #[structopt(long)]
+    pub assume_role_external_id: Option<String>,
+    /// Name of the session the role is assumed with
+    #[structopt(long)]
+    pub assume_role_session_name: Option<String>,
+    /// Largest attachment, in bytes, fetched from a URL
+    #[structopt(long)]
+    pub attachment_max_bytes: Option<u64>,
+    /// Seconds before fetching an attachment from a URL is abandoned
+    #[structopt(long)]
+    pub attachment_timeout: Option<u64>,
+    /// Most templates, and most suppression lookups, kept in memory
+    #[structopt(long)]
+    pub cache_max_entries: Option<usize>,
+    /// Seconds sends stay stopped after the circuit breaker opens
+    #[structopt(long)]
+    pub circuit_breaker_cooldown: Option<u64>,
+    /// Consecutive failed sends which stop sending until the cooldown has passed
+    #[structopt(long)]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// Seconds allowed to connect to an AWS service
+    #[structopt(long)]
+    pub connect_timeout: Option<u64>,
+    /// Never send to recipients on these domains, separated by commas
+    #[structopt(long, use_delimiter = true)]
+    pub deny_domains: Vec<String>,
+    /// URL of DynamoDB used in place of the endpoint for the region, such as
+    /// "http://localhost:8000"
+    #[structopt(long)]
+    pub dynamo_endpoint: Option<String>,
+    /// Attempts of each call to DynamoDB, optionally with the wait after the first failure, as
+    /// "4,100ms"
+    #[structopt(long)]
+    pub dynamo_retry: Option<String>,
+    /// Name or ARN of an EventBridge bus EmailQueued, EmailSending, EmailSent, and EmailFailed
+    /// events are published to
+    #[structopt(long)]
+    pub event_bus: Option<String>,
+    /// URL of SQS Queue to which emails failing their last attempt are sent before being marked
+    /// Failed
+    #[structopt(long)]
+    pub failure_queue_url: Option<String>,
+    /// Times a message is received before its email is sent to the failure queue
+    #[structopt(long)]
+    pub max_attempts: Option<u32>,
+    /// Oldest a message may be before its email is failed instead of sent, as "24h,otp=15m"
+    #[structopt(long)]
+    pub max_message_age: Option<String>,
+    /// Seconds a message may take to be ready to transmit before its email is released and the
+    /// message retried, keep well inside the visibility timeout of the queue
+    #[structopt(long)]
+    pub message_budget: Option<u64>,
+    /// Publish counts of processed messages and send latency to CloudWatch under this namespace
+    #[structopt(long)]
+    pub metrics_namespace: Option<String>,
+    /// Store each message sent as "s3://<bucket>/<prefix>" so it can be resent unchanged
+    #[structopt(long)]
+    pub mime_store: Option<String>,
+    /// Server-side encryption of stored messages, as "AES256", "aws:kms", or "aws:kms:<key id>"
+    #[structopt(long)]
+    pub mime_store_encryption: Option<String>,
+    /// Seconds allowed for a call to an AWS service, calls to SQS also wait for messages to arrive
+    #[structopt(long)]
+    pub operation_timeout: Option<u64>,
+    /// Attempts of each transmission through the email provider, optionally with the wait after
+    /// the first failure, as "3,1s"
+    #[structopt(long)]
+    pub provider_retry: Option<String>,
+    /// Fields of quarantined messages stored without redaction, separated by commas
+    #[structopt(long, use_delimiter = true)]
+    pub quarantine_allow_fields: Vec<String>,
+    /// Store a redacted copy of each message which can never be processed as
+    /// "s3://<bucket>/<prefix>"
+    #[structopt(long)]
+    pub quarantine_store: Option<String>,
     /// URL of SQS Queue from which email message ids will be read
     #[structopt(short = "q", long)]
-    pub queue_url: String,
-    /// AWS Region in which services reside
-    #[structopt(short = "r", long, parse(from_str = parse_region))]
-    pub region: Region,
+    pub queue_url: Option<String>,
+    /// Most messages sent per second, as "20,ses=14" for a total and per provider limits
+    #[structopt(long)]
+    pub rate_limit: Option<String>,
+    /// Seconds a send waits for budget before its message is retried later
+    #[structopt(long)]
+    pub rate_limit_max_delay: Option<u64>,
+    /// DynamoDB table tracking the status of each recipient of personalized emails
+    #[structopt(long)]
+    pub recipient_table: Option<String>,
+    /// Send every email to this address instead of its recipients, for non-production use
+    #[structopt(long)]
+    pub redirect_to: Option<String>,
+    /// Seconds an email is kept once it is Sent, Failed, Skipped, Suppressed, or Cancelled before
+    /// DynamoDB TTL deletes it, forever when not given
+    #[structopt(long)]
+    pub retention: Option<u64>,
+    /// Strip scripts, event handlers, and dangerous URLs from every HTML body before it is sent,
+    /// for producers rendering untrusted data into their templates
+    #[structopt(long)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sanitize_html: bool,
+    /// Seconds the claim on an email being sent is held before another worker may take it over,
+    /// keep longer than a send takes
+    #[structopt(long)]
+    pub sending_lease: Option<u64>,
+    /// URL of SQS used in place of the endpoint for the region, such as "http://localhost:4566"
+    #[structopt(long)]
+    pub sqs_endpoint: Option<String>,
+    /// Attempts of each receive, delete, and visibility change made on SQS, optionally with the
+    /// wait after the first failure, as "4,100ms"
+    #[structopt(long)]
+    pub sqs_retry: Option<String>,
+    /// Global secondary index of the email table with EmailStatus as its partition key and
+    /// UpdatedAt as its sort key, used to list emails by status instead of scanning the table
+    #[structopt(long)]
+    pub status_index: Option<String>,
+    /// Seconds whether an address is suppressed is remembered, addresses are looked up for every
+    /// email when not given
+    #[structopt(long)]
+    pub suppression_cache_ttl: Option<u64>,
+    /// DynamoDB table of addresses which are never sent mail
+    #[structopt(long)]
+    pub suppression_table: Option<String>,
     /// DynamoDB table from which email data will be read.
     #[structopt(short = "t", long)]
-    pub table_name: String,
+    pub table_name: Option<String>,
+    /// Location of templates as "dynamodb:<table_name>" or "s3://<bucket>/<prefix>"
+    #[structopt(long)]
+    pub template_source: Option<String>,
+    /// Seconds a loaded template is used before it is loaded again
+    #[structopt(long)]
+    pub template_ttl: Option<u64>,
+    /// DynamoDB table keyed by TrackingId mapping each link rewritten for tracking to its
+    /// destination, required with --tracking-url
+    #[structopt(long)]
+    pub tracking_table: Option<String>,
+    /// Base URL of the endpoint recording opens and clicks, such as "https://t.example.com", links
+    /// in HTML bodies are rewritten to it and a tracking pixel is added when given
+    #[structopt(long)]
+    pub tracking_url: Option<String>,
+    /// File holding a web identity token the role is assumed with, such as the token mounted for
+    /// IAM roles for service accounts
+    #[structopt(long, parse(from_os_str))]
+    pub web_identity_token_file: Option<PathBuf>,
+}
+
+impl Options {
+    /// Name of the command this run would write with, which `--read-only` refuses.
+    pub fn writing_command(&self) -> Option<&'static str> {
+        match &self.command {
+            Some(Command::Cancel(_)) => Some("cancel"),
+            Some(Command::Feedback(_)) => Some("feedback"),
+            Some(Command::Import(_)) => Some("import"),
+            Some(Command::Ingest(_)) => Some("ingest"),
+            Some(Command::Reconcile(_)) => Some("reconcile"),
+            Some(Command::Relay(_)) => Some("relay"),
+            Some(Command::Requeue(_)) => Some("requeue"),
+            Some(Command::Resend(_)) => Some("resend"),
+            Some(Command::Send(_)) => Some("send"),
+            Some(Command::Sweep(_)) => Some("sweep"),
+            Some(Command::Preview(_))
+            | Some(Command::Status(_))
+            | Some(Command::SupportBundle(_))
+            | None => None,
+        }
+    }
+
+    /// Whether a loop stops after `iterations` batches, the last of which was `empty`.
+    pub fn stops_after(&self, iterations: usize, empty: bool) -> bool {
+        (self.until_empty && empty)
+            || self
+                .max_iterations
+                .is_some_and(|max_iterations| iterations >= max_iterations.get())
+    }
+
+    /// Whether this run only reads, auditing the queue rather than processing it.
+    pub fn audits(&self) -> bool {
+        self.audit_only || self.read_only
+    }
+
+    /// The first protected queue or table this run would change without its environment having
+    /// been named by `--i-know-what-im-doing`. Audits, read-only runs, previews, and support
+    /// bundles only read so they are never refused. A dry run still processes a batch, so it is
+    /// refused like any other run.
+    pub fn unacknowledged_protected(&self, config: &Config) -> Option<&Protected> {
+        let read_only = match &self.command {
+            Some(Command::Preview(_))
+            | Some(Command::Status(_))
+            | Some(Command::SupportBundle(_)) => true,
+            Some(_) => self.read_only,
+            None => self.audits(),
+        };
+        if read_only {
+            return None;
+        }
+        let queues = self.queues(config);
+        let tables = self.tables(config);
+        self.protected.iter().find(|protected| {
+            let used = tables.contains(&protected.resource.as_str())
+                || queues
+                    .iter()
+                    .any(|queue_url| protected.covers_queue(queue_url));
+            used && self.i_know_what_im_doing.as_deref() != Some(protected.environment.as_str())
+        })
+    }
+
+    /// Queues the broker polls for emails, the queue URL of `config` with a weight of 1 unless
+    /// given a weight by `--queue-weight`.
+    pub fn polled_queues(&self, config: &Config) -> Vec<WeightedQueue> {
+        let mut queues = self.queue_weights.clone();
+        if !queues
+            .iter()
+            .any(|queue| queue.queue_url == config.queue_url)
+        {
+            queues.insert(
+                0,
+                WeightedQueue {
+                    queue_url: config.queue_url.clone(),
+                    weight: 1,
+                },
+            );
+        }
+        queues
+    }
+
+    /// Every queue this run uses.
+    fn queues<'a>(&'a self, config: &'a Config) -> Vec<&'a QueueUrl> {
+        let mut queues = vec![&config.queue_url];
+        queues.extend(self.queue_weights.iter().map(|queue| &queue.queue_url));
+        queues.extend(config.failure_queue_url.as_ref());
+        match &self.command {
+            Some(Command::Feedback(options)) => queues.push(&options.feedback_queue_url),
+            Some(Command::Ingest(options)) => queues.push(&options.drop_queue_url),
+            _ => {}
+        }
+        queues
+    }
+
+    /// Every table this run uses, including the table of every tenant since any pointer may name
+    /// one.
+    fn tables<'a>(&'a self, config: &'a Config) -> Vec<&'a str> {
+        let mut tables = vec![config.table_name.as_str()];
+        tables.extend(config.recipient_table.as_deref());
+        tables.extend(config.suppression_table.as_deref());
+        tables.extend(
+            config
+                .tenants
+                .values()
+                .map(|tenant| tenant.table_name.as_str()),
+        );
+        if let Some(Command::Relay(options)) = &self.command {
+            tables.push(&options.outbox_table);
+        }
+        tables
+    }
+
+    /// Builder for the Tokio runtime sized by the runtime options.
+    pub fn runtime(&self) -> tokio::runtime::Builder {
+        let mut builder = if self.single_threaded {
+            tokio::runtime::Builder::new_current_thread()
+        } else {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(worker_threads) = self.worker_threads {
+                builder.worker_threads(worker_threads.get());
+            }
+            builder
+        };
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads.get());
+        }
+        builder.enable_all();
+        builder
+    }
+}
+
+/// Commands run in place of reading the queue.
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Retract a Pending email so it is never sent
+    Cancel(CancelOptions),
+    /// Record SES bounce and complaint notifications read from a feedback queue
+    Feedback(FeedbackOptions),
+    /// Enqueue emails read from .eml files, such as mail exported from another system
+    Import(ImportOptions),
+    /// Enqueue the email in each .eml file or JSON description dropped into an S3 bucket
+    Ingest(IngestOptions),
+    /// Write the message an email would be sent as to an .eml file without sending it
+    Preview(PreviewOptions),
+    /// Enqueue again emails left Pending without a pointer, such as when enqueueing failed
+    Reconcile(ReconcileOptions),
+    /// Send pointer messages for emails written with an outbox marker
+    Relay(RelayOptions),
+    /// Return a failed email to Pending and send a pointer for it so it is attempted again
+    Requeue(RequeueOptions),
+    /// Transmit an email which has already been sent again
+    Resend(ResendOptions),
+    /// Send one email immediately through the configured provider
+    Send(SendOptions),
+    /// List emails with a status through the status index, least recently updated first
+    Status(StatusOptions),
+    /// Collect what is known about an email into a JSON document for an incident ticket
+    SupportBundle(SupportBundleOptions),
+    /// Enqueue again emails left Sending past their lease by a worker which stopped
+    Sweep(SweepOptions),
+}
+
+/// Email retracted by the `cancel` command.
+#[derive(StructOpt, Debug)]
+pub struct CancelOptions {
+    /// Id of the email to cancel
+    #[structopt(long)]
+    pub email_id: EmailId,
+    /// Tenant whose table holds the email, the default table when not given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Queue read by the `feedback` command.
+#[derive(StructOpt, Debug)]
+pub struct FeedbackOptions {
+    /// URL of the SQS Queue subscribed to the SNS topic SES publishes notifications to
+    #[structopt(long)]
+    pub feedback_queue_url: QueueUrl,
+}
+
+/// Files read by the `import` command.
+#[derive(StructOpt, Debug)]
+pub struct ImportOptions {
+    /// .eml file to enqueue, may be repeated
+    #[structopt(long = "eml", parse(from_os_str), required = true)]
+    pub files: Vec<PathBuf>,
+    /// Attribute of each email whose value groups its pointer on a FIFO queue, "email_id",
+    /// "category", "recipient_domain", or "sender_domain"
+    #[structopt(long, default_value = "email_id")]
+    pub message_group: MessageGroup,
+}
+
+/// Queue read by the `ingest` command.
+#[derive(StructOpt, Debug)]
+pub struct IngestOptions {
+    /// URL of the SQS Queue receiving the object created notifications of the drop bucket
+    #[structopt(long)]
+    pub drop_queue_url: QueueUrl,
+    /// Attribute of each email whose value groups its pointer on a FIFO queue, "email_id",
+    /// "category", "recipient_domain", or "sender_domain"
+    #[structopt(long, default_value = "email_id")]
+    pub message_group: MessageGroup,
+}
+
+/// Email rendered by the `preview` command.
+#[derive(StructOpt, Debug)]
+pub struct PreviewOptions {
+    /// Id of the email to preview
+    #[structopt(long)]
+    pub email_id: EmailId,
+    /// File the message is written to, such as "preview.eml"
+    #[structopt(long, parse(from_os_str))]
+    pub out: PathBuf,
+    /// Tenant whose table holds the email, the default table when not given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Schedule of the `reconcile` command, which only reconciles the default table, never the table
+/// of a tenant.
+#[derive(StructOpt, Debug)]
+pub struct ReconcileOptions {
+    /// Minutes an email is Pending before its pointer is taken to be lost, keep longer than a
+    /// pointer may wait on the queue
+    #[structopt(long, default_value = "15")]
+    pub min_age: u64,
+    /// Seconds between queries of the status index
+    #[structopt(long, default_value = "300")]
+    pub reconcile_interval: u64,
+}
+
+/// Outbox read by the `relay` command.
+#[derive(StructOpt, Debug)]
+pub struct RelayOptions {
+    /// DynamoDB table holding outbox markers written along with each email
+    #[structopt(long)]
+    pub outbox_table: String,
+}
+
+/// Email attempted again by the `requeue` command.
+#[derive(StructOpt, Debug)]
+pub struct RequeueOptions {
+    /// Id of the email to requeue
+    #[structopt(long)]
+    pub email_id: EmailId,
+    /// Attribute of the email whose value groups its pointer on a FIFO queue, "email_id",
+    /// "category", "recipient_domain", or "sender_domain"
+    #[structopt(long, default_value = "email_id")]
+    pub message_group: MessageGroup,
+    /// Tenant whose table holds the email, named by the pointer sent, the default table when not
+    /// given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Email transmitted again by the `resend` command.
+#[derive(StructOpt, Debug)]
+pub struct ResendOptions {
+    /// Id of the email to resend
+    #[structopt(long)]
+    pub email_id: EmailId,
+    /// Transmit the message stored when the email was sent instead of rendering it again
+    #[structopt(long)]
+    pub exact: bool,
+    /// Tenant whose table holds the email, the default table when not given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Content of an email sent with the `send` command.
+#[derive(StructOpt, Debug)]
+pub struct SendOptions {
+    /// File to attach, may be repeated
+    #[structopt(long = "attach", parse(from_os_str))]
+    pub attachments: Vec<PathBuf>,
+    /// File containing the body, sent as HTML when the extension is "html" or "htm" and rendered
+    /// from Markdown when it is "md" or "markdown"
+    #[structopt(long, parse(from_os_str))]
+    pub body_file: PathBuf,
+    /// Address from which the email is sent
+    #[structopt(long)]
+    pub from: String,
+    /// Write the email record and track its status as a queued email would be
+    #[structopt(long)]
+    pub persist: bool,
+    /// Subject line of the email
+    #[structopt(long)]
+    pub subject: String,
+    /// Tenant the email is sent for, written to its table and sent from its sender through its
+    /// provider
+    #[structopt(long)]
+    pub tenant: Option<String>,
+    /// Recipient to send to directly, may be repeated
+    #[structopt(long, required = true)]
+    pub to: Vec<String>,
+}
+
+/// Emails listed by the `status` command.
+#[derive(StructOpt, Debug)]
+pub struct StatusOptions {
+    /// Status of the emails listed, such as "Pending" or "Sending"
+    #[structopt(long, parse(try_from_str = parse_status))]
+    pub status: EmailStatus,
+    /// Most emails listed in one page
+    #[structopt(long, default_value = "25")]
+    pub limit: i32,
+    /// Cursor printed with the previous page, the first page is listed when not given
+    #[structopt(long)]
+    pub cursor: Option<String>,
+    /// Tenant whose table is listed, the default table when not given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Email collected by the `support-bundle` command.
+#[derive(StructOpt, Debug)]
+pub struct SupportBundleOptions {
+    /// Id of the email to collect
+    #[structopt(long)]
+    pub email_id: EmailId,
+    /// File the bundle is written to instead of standard output
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    /// Tenant whose table holds the email, the default table when not given
+    #[structopt(long)]
+    pub tenant: Option<String>,
+}
+
+/// Schedule of the `sweep` command, which only sweeps the default table, never the table of a
+/// tenant.
+#[derive(StructOpt, Debug)]
+pub struct SweepOptions {
+    /// Seconds between scans of the email table
+    #[structopt(long, default_value = "60")]
+    pub sweep_interval: u64,
+}
+
+#[cfg(test)]
+mod unacknowledged_protected {
+    use super::*;
+    use email_shared::Tenant;
+    use std::collections::HashMap;
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/emails";
+
+    fn config() -> Config {
+        let mut overrides = HashMap::new();
+        overrides.insert("queue_url", QUEUE_URL);
+        overrides.insert("table_name", "emails");
+        Config::load_with_overrides(None, &overrides).unwrap()
+    }
+
+    fn options(args: &[&str]) -> Options {
+        let protect = format!("production={}", QUEUE_URL);
+        let mut all = vec![
+            "email_broker",
+            "--region",
+            "us-east-1",
+            "--protect",
+            &protect,
+        ];
+        all.extend_from_slice(args);
+        Options::from_iter_safe(all).unwrap()
+    }
+
+    #[test]
+    fn refuses_unacknowledged_runs() {
+        let config = config();
+        for args in [&[][..], &["--dry-run"][..]] {
+            let protected = options(args).unacknowledged_protected(&config).cloned();
+            assert_eq!(protected.unwrap().environment, "production");
+        }
+    }
+
+    #[test]
+    fn allows_acknowledged_runs() {
+        let config = config();
+        let options = options(&["--i-know-what-im-doing", "production"]);
+        assert_eq!(options.unacknowledged_protected(&config), None);
+    }
+
+    #[test]
+    fn refuses_protected_tenant_tables() {
+        let mut config = config();
+        config.tenants.insert(
+            "acme".into(),
+            Tenant {
+                table_name: "acme_emails".into(),
+                provider: None,
+                sender: None,
+            },
+        );
+        let options = Options::from_iter_safe([
+            "email_broker",
+            "--region",
+            "us-east-1",
+            "--protect",
+            "production=acme_emails",
+        ])
+        .unwrap();
+        let protected = options.unacknowledged_protected(&config).cloned();
+        assert_eq!(protected.unwrap().resource, "acme_emails");
+    }
+
+    #[test]
+    fn refuses_other_environments() {
+        let config = config();
+        let options = options(&["--i-know-what-im-doing", "staging"]);
+        assert!(options.unacknowledged_protected(&config).is_some());
+    }
+
+    #[test]
+    fn allows_read_only_runs() {
+        let config = config();
+        assert_eq!(
+            options(&["--read-only"]).unacknowledged_protected(&config),
+            None
+        );
+        assert_eq!(
+            options(&["--read-only", "requeue", "--email-id", "Test EmailId"])
+                .unacknowledged_protected(&config),
+            None
+        );
+    }
+}
+
+#[cfg(test)]
+mod audits {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let mut all = vec!["email_broker", "--region", "us-east-1"];
+        all.extend_from_slice(args);
+        Options::from_iter_safe(all).unwrap()
+    }
+
+    #[test]
+    fn audits_when_only_reading() {
+        assert!(options(&["--audit-only"]).audits());
+        assert!(options(&["--read-only"]).audits());
+        assert!(!options(&[]).audits());
+        assert!(!options(&["--dry-run"]).audits());
+    }
+
+    #[test]
+    fn refuses_writing_commands_when_read_only() {
+        let requeue = options(&["--read-only", "requeue", "--email-id", "Test EmailId"]);
+        assert_eq!(requeue.writing_command(), Some("requeue"));
+        let status = options(&["--read-only", "status", "--status", "Pending"]);
+        assert_eq!(status.writing_command(), None);
+    }
+}
+
+#[cfg(test)]
+mod stops_after {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let mut all = vec!["email_broker", "--region", "us-east-1"];
+        all.extend_from_slice(args);
+        Options::from_iter_safe(all).unwrap()
+    }
+
+    #[test]
+    fn runs_until_stopped_by_default() {
+        let options = options(&[]);
+        assert!(!options.stops_after(1, true));
+        assert!(!options.stops_after(1_000, false));
+    }
+
+    #[test]
+    fn stops_once_empty() {
+        let options = options(&["--until-empty"]);
+        assert!(!options.stops_after(3, false));
+        assert!(options.stops_after(3, true));
+    }
+
+    #[test]
+    fn stops_after_max_iterations() {
+        let options = options(&["--max-iterations", "2"]);
+        assert!(!options.stops_after(1, false));
+        assert!(options.stops_after(2, false));
+    }
 }
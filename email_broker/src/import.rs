@@ -0,0 +1,67 @@
+use crate::config::ImportOptions;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
+use email_shared::{
+    enqueue_email, EmailEvent, EmailEventType, EmailId, EmailMessageDraft, EventBus, QueueUrl,
+};
+use std::error::Error;
+use std::fs;
+use tracing::{event, span, Instrument, Level};
+
+/// Enqueue the email read from each .eml file of `options`, carrying on past files which can not
+/// be imported so one bad file does not hold up the rest. Importing the same file again enqueues
+/// its email once, so a run with failures can be repeated.
+pub async fn run(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    event_bus: Option<&EventBus>,
+    options: &ImportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut failed = 0;
+    for path in &options.files {
+        let span = span!(Level::INFO, "import", path = ?path);
+        let imported = async {
+            let draft = EmailMessageDraft::from_eml(&fs::read(path)?)?;
+            let email_id = enqueue_email(
+                dynamodb,
+                table_name,
+                sqs,
+                queue_url,
+                options.message_group,
+                draft,
+            )
+            .await?;
+            announce(event_bus, &email_id).await;
+            Ok::<_, Box<dyn Error>>(email_id)
+        };
+        match imported.instrument(span.clone()).await {
+            Ok(email_id) => event!(parent: &span, Level::INFO, %email_id, "email imported"),
+            Err(error) => {
+                failed += 1;
+                event!(parent: &span, Level::ERROR, %error, "email not imported");
+            }
+        }
+    }
+    event!(
+        Level::INFO,
+        files = options.files.len(),
+        failed,
+        "import complete"
+    );
+    if failed > 0 {
+        return Err(format!("{} of {} files not imported", failed, options.files.len()).into());
+    }
+    Ok(())
+}
+
+/// Publish that the email identified by `email_id` was queued when there is an event bus.
+async fn announce(event_bus: Option<&EventBus>, email_id: &EmailId) {
+    if let Some(event_bus) = event_bus {
+        let queued = EmailEvent::for_email_id(EmailEventType::EmailQueued, email_id);
+        if let Err(error) = event_bus.publish(&queued).await {
+            event!(Level::WARN, %error, "email event not published");
+        }
+    }
+}
@@ -1,21 +1,137 @@
+// The support bundle lists every setting in a single `json!`
+#![recursion_limit = "256"]
+
 mod config;
+mod import;
+mod metrics_server;
+mod send;
+mod shutdown;
+mod support;
 
-use rusoto_dynamodb::DynamoDbClient;
-use rusoto_sqs::{DeleteMessageBatchRequest, Sqs, SqsClient};
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_eventbridge::Client as EventBridgeClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use aws_sdk_sns::Client as SnsClient;
+use aws_sdk_sqs::Client as SqsClient;
+use aws_sdk_ssm::Client as SsmClient;
 use structopt::StructOpt;
 use tracing::{event, span, Level};
+use tracing_subscriber::layer::SubscriberExt;
 
-use config::Options;
-use email_shared::Client;
+use config::{credentials_source, Command, LogFormat, Options};
+use email_shared::{
+    cancel_email, check_audit, dynamodb_config, normalize_address, query_by_status, redact_url,
+    requeue_email, requeue_tenant_email, sqs_config, Alerts, AssumeRole, AttachmentFetcher,
+    AuditSummary, CallTimeouts, CircuitBreaker, ClientServices, Config, ConfigError, ConfigSources,
+    DomainPolicy, DropFolder, EmailEvent, EmailEventType, EventBus, FailureQueue, FeedbackWorker,
+    HttpFetcher, IdleBackoff, Metrics, OutboxRelay, PendingReconciler, QuarantineRedaction,
+    RateLimiter, RunSummary, Runner, S3MimeStore, S3QuarantineStore, Secrets, SqsPoll,
+    StuckEmailSweeper, Suppressions, Telemetry, Templates, Tracking, WeightedPoll,
+};
+use metrics_server::LoopHealth;
+use shutdown::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use tracing_futures::Instrument;
-    // Setup Logger
-    let subscriber = tracing_subscriber::fmt()
-        .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc3339())
-        .finish();
+/// Time the `relay` command waits before reading an empty outbox again.
+const RELAY_IDLE_WAIT: Duration = Duration::from_secs(1);
+/// Longest time between loop iterations before `/healthz` reports the broker as stalled.
+const LOOP_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+/// Wait after the first empty receive when `--max-idle-wait` is given.
+const IDLE_INITIAL_WAIT: Duration = Duration::from_secs(1);
+/// Exit status of a run stopped by missing or invalid settings, `EX_CONFIG` of sysexits.h.
+const EXIT_CONFIG: i32 = 78;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Options::from_args();
+    // Setup Logger, spans are also exported when an OTLP endpoint is configured
+    let telemetry = Telemetry::from_env(env!("CARGO_PKG_NAME"))?;
+    let timer = tracing_subscriber::fmt::time::ChronoUtc::rfc_3339();
+    let (text, json) = match opt.log_format {
+        LogFormat::Text => (
+            Some(tracing_subscriber::fmt::layer().with_timer(timer)),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(tracing_subscriber::fmt::layer().json().with_timer(timer)),
+        ),
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(opt.log_level)
+        .with(text)
+        .with(json)
+        .with(telemetry.as_ref().map(|telemetry| telemetry.layer()));
     let _subscriber_guard = tracing::subscriber::set_global_default(subscriber);
+    // Settings shared with the Lambda are read from the config file, the environment, then flags
+    let sources = match ConfigSources::new(opt.config_file.as_deref())
+        .and_then(|sources| sources.with_overrides(&opt.overrides))
+    {
+        Ok(sources) => sources,
+        Err(error) => exit_on_config_error(&error),
+    };
+    // The runtime is sized from the options so it is built before anything else runs
+    let runtime = opt.runtime().build()?;
+    let result = runtime.block_on(async move {
+        let config = match load_config(&opt, sources).await {
+            Ok(config) => config,
+            Err(error) => exit_on_config_error(&error),
+        };
+        run(opt, config).await
+    });
+    // Spans still waiting for a batch are exported before exiting
+    if let Some(telemetry) = &telemetry {
+        if let Err(error) = telemetry.shutdown() {
+            event!(Level::WARN, %error, "telemetry shutdown failed");
+        }
+    }
+    result
+}
+
+/// Report the missing or invalid setting of `error` to the person running the broker and exit
+/// rather than panic or print the error's debug form.
+fn exit_on_config_error(error: &ConfigError) -> ! {
+    event!(Level::ERROR, %error, "config invalid");
+    eprintln!("error: {}", error.explain());
+    std::process::exit(EXIT_CONFIG);
+}
+
+/// Read settings which refer to SSM Parameter Store or Secrets Manager, only connecting to AWS
+/// when a setting does, then check the queue is in the region used.
+async fn load_config(opt: &Options, sources: ConfigSources) -> Result<Config, ConfigError> {
+    let config = if sources.secret_references()?.is_empty() {
+        sources.extract()?
+    } else {
+        let aws_config = opt
+            .region
+            .load(opt.use_dual_stack, &CallTimeouts::default())
+            .await;
+        let secrets = Secrets::new(
+            SsmClient::new(&aws_config),
+            SecretsManagerClient::new(&aws_config),
+        );
+        sources.resolve(&secrets).await?.extract()?
+    };
+    config.check_region(opt.region.name())?;
+    Ok(config)
+}
+
+/// Table holding the emails of the tenant named `name`, the default table when not given.
+fn tenant_table<'a>(config: &'a Config, name: Option<&str>) -> Result<&'a str, String> {
+    match name {
+        Some(name) => config
+            .tenants
+            .get(name)
+            .map(|tenant| tenant.table_name.as_str())
+            .ok_or_else(|| format!("--tenant {} is not configured", name)),
+        None => Ok(config.table_name.as_str()),
+    }
+}
+
+async fn run(opt: Options, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    use tracing_futures::Instrument;
     let main_span = span!(
         Level::INFO,
         env!("CARGO_PKG_NAME"),
@@ -23,56 +139,632 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
     let _main_guard = main_span.enter();
     // Start
-    let opt = Options::from_args();
+    let timeouts = CallTimeouts::new(
+        Duration::from_secs(config.connect_timeout),
+        Duration::from_secs(config.operation_timeout),
+    );
+    let aws_config = opt.region.load(opt.use_dual_stack, &timeouts).await;
+    // Every client acts as the assumed role, if any, so another account can be used
+    let assume_role = AssumeRole::from_config(&config);
+    let aws_config = match &assume_role {
+        Some(assume_role) => assume_role.apply(aws_config).await,
+        None => aws_config,
+    };
+    // Log the configuration as resolved from flags and environment, secrets are never included
     event!(
         Level::INFO,
-        queue_url = %opt.queue_url,
-        region = %opt.region.name(),
-        table_name = %opt.table_name,
+        alert_failure_rate = config.alert_failure_rate,
+        alert_topic_arn = ?config.alert_topic_arn,
+        alert_window = config.alert_window,
+        allow_domains = ?config.allow_domains,
+        archive_bcc = ?config.archive_bcc,
+        assume_role_arn = ?config.assume_role_arn,
+        assume_role_session_name = ?assume_role.as_ref().map(|role| &role.session_name),
+        audit_only = opt.audit_only,
+        attachment_max_bytes = config.attachment_max_bytes,
+        attachment_timeout = config.attachment_timeout,
+        cache_max_entries = config.cache_max_entries,
+        canary = ?opt.canary,
+        circuit_breaker_cooldown = config.circuit_breaker_cooldown,
+        circuit_breaker_threshold = ?config.circuit_breaker_threshold,
+        config_file = ?opt.config_file,
+        connect_timeout = config.connect_timeout,
+        credentials = credentials_source(&aws_config, assume_role.as_ref()),
+        deny_domains = ?config.deny_domains,
+        dry_run = opt.dry_run,
+        dynamo_endpoint = ?config.dynamo_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+        dynamo_retry = ?config.dynamo_retry,
+        endpoint_url = %aws_config.endpoint_url().map(redact_url).unwrap_or_default(),
+        event_bus = ?config.event_bus,
+        failure_queue_url = ?config.failure_queue_url.as_ref().map(|url| redact_url(url.as_str())),
+        i_know_what_im_doing = ?opt.i_know_what_im_doing,
+        log_format = %opt.log_format,
+        log_level = %opt.log_level,
+        max_attempts = config.max_attempts,
+        max_blocking_threads = ?opt.max_blocking_threads,
+        max_idle_wait = opt.max_idle_wait,
+        max_iterations = ?opt.max_iterations,
+        max_message_age = ?config.max_message_age,
+        message_budget = ?config.message_budget,
+        metrics_addr = ?opt.metrics_addr,
+        metrics_namespace = ?config.metrics_namespace,
+        mime_store = ?config.mime_store,
+        mime_store_encryption = ?config.mime_store_encryption,
+        operation_timeout = config.operation_timeout,
+        provider_retry = ?config.provider_retry,
+        protected = ?opt.protected.iter().map(|p| &p.environment).collect::<Vec<_>>(),
+        read_only = opt.read_only,
+        quarantine_allow_fields = ?config.quarantine_allow_fields,
+        quarantine_store = ?config.quarantine_store,
+        queue_name = %config.queue_url.name(),
+        queue_url = %redact_url(config.queue_url.as_str()),
+        queue_weights = ?opt.queue_weights.iter().map(|queue| format!("{}={}", queue.weight, redact_url(queue.queue_url.as_str()))).collect::<Vec<_>>(),
+        rate_limit = ?config.rate_limit,
+        rate_limit_max_delay = config.rate_limit_max_delay,
+        recipient_table = ?config.recipient_table,
+        single_threaded = opt.single_threaded,
+        redirect_to = ?config.redirect_to,
+        region = %aws_config.region().map(|r| r.as_ref()).unwrap_or(opt.region.name()),
+        retention = ?config.retention,
+        sanitize_html = config.sanitize_html,
+        sending_lease = config.sending_lease,
+        sqs_endpoint = ?config.sqs_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+        sqs_retry = ?config.sqs_retry,
+        status_index = ?config.status_index,
+        suppression_cache_ttl = ?config.suppression_cache_ttl,
+        suppression_table = ?config.suppression_table,
+        table_name = %config.table_name,
+        template_source = ?config.template_source,
+        template_ttl = config.template_ttl,
+        tenants = ?config.tenants.iter().map(|(name, tenant)| format!("{}={}", name, tenant.table_name)).collect::<Vec<_>>(),
+        tracking_table = ?config.tracking_table,
+        tracking_url = ?config.tracking_url.as_ref().map(|url| redact_url(url.as_str())),
+        until_empty = opt.until_empty,
+        use_dual_stack = aws_config.use_dual_stack().unwrap_or(false),
+        web_identity_token_file = ?config.web_identity_token_file,
+        worker_threads = ?opt.worker_threads,
         "broker init",
     );
-    let sqs = SqsClient::new(opt.region.clone());
-    let dynamodb = DynamoDbClient::new(opt.region.clone());
-    let client = Client::new(&dynamodb, &opt.table_name);
-    let queue_url = &opt.queue_url;
-    let mut iteration = 0;
-    loop {
-        let loop_span = span!(Level::INFO, "loop", Iteration = &iteration);
-        let _loop_guard = loop_span.enter();
-        let message_list = email_shared::get_sqs_email_messages(queue_url, &sqs)
+    // Refuse before any client is created so a read-only run can not write by mistake
+    if let (true, Some(command)) = (opt.read_only, opt.writing_command()) {
+        event!(Level::ERROR, %command, "command refused in read-only mode");
+        return Err(format!("{} writes and can not run with --read-only", command).into());
+    }
+    // Refuse before any client is created so a run in the wrong terminal changes nothing
+    if let Some(protected) = opt.unacknowledged_protected(&config) {
+        event!(Level::ERROR, environment = %protected.environment, "protected resource refused");
+        return Err(format!(
+            "{} belongs to {}, pass --i-know-what-im-doing {} to use it",
+            redact_url(&protected.resource),
+            protected.environment,
+            protected.environment
+        )
+        .into());
+    }
+    if timeouts.exceeds_visibility_timeout() {
+        event!(
+            Level::WARN,
+            "--operation-timeout is not less than the visibility timeout, messages may be processed twice"
+        );
+    }
+    // Receives wait for messages to arrive so SQS calls are allowed longer
+    let sqs = SqsClient::from_conf(
+        sqs_config(&aws_config, config.sqs_endpoint.as_ref())
+            .timeout_config(timeouts.receive_timeout_config())
+            .build(),
+    );
+    let dynamodb = DynamoDbClient::from_conf(
+        dynamodb_config(&aws_config, config.dynamo_endpoint.as_ref()).build(),
+    );
+    let templates = config.template_source.clone().map(|source| {
+        let ttl = Duration::from_secs(config.template_ttl);
+        Templates::new(source, ttl, dynamodb.clone(), S3Client::new(&aws_config))
+            .with_cache_size(config.cache_max_entries)
+    });
+    let http = HttpFetcher::new(
+        config.attachment_max_bytes,
+        Duration::from_secs(config.attachment_timeout),
+    )?;
+    let domains = DomainPolicy::new(&config.allow_domains, &config.deny_domains);
+    let redirect_to = match &config.redirect_to {
+        Some(address) => Some(normalize_address(address).ok_or("--redirect-to is not valid")?),
+        None => None,
+    };
+    let rate_limiter = config
+        .rate_limit
+        .as_ref()
+        .map(|limits| RateLimiter::new(limits, Duration::from_secs(config.rate_limit_max_delay)));
+    let circuit_breaker = config.circuit_breaker_threshold.map(|threshold| {
+        CircuitBreaker::new(
+            threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown),
+        )
+    });
+    let failure_queue = config
+        .failure_queue_url
+        .clone()
+        .map(|queue_url| FailureQueue::new(queue_url, config.max_attempts, sqs.clone()));
+    let event_bus = config
+        .event_bus
+        .as_ref()
+        .map(|bus_name| EventBus::new(bus_name, EventBridgeClient::new(&aws_config)));
+    let alerts = config.alert_topic_arn.as_ref().map(|topic_arn| {
+        Alerts::new(
+            topic_arn,
+            config.alert_failure_rate,
+            Duration::from_secs(config.alert_window),
+            SnsClient::new(&aws_config),
+        )
+    });
+    let mime_store = config.mime_store.clone().map(|location| {
+        let mime_store = S3MimeStore::new(location, S3Client::new(&aws_config));
+        match config.mime_store_encryption.clone() {
+            Some(encryption) => mime_store.with_encryption(encryption),
+            None => mime_store,
+        }
+    });
+    let suppressions = config.suppression_table.as_ref().map(|table_name| {
+        let suppressions = Suppressions::new(dynamodb.clone(), table_name);
+        match config.suppression_cache_ttl {
+            Some(ttl) => {
+                suppressions.with_cache(Duration::from_secs(ttl), config.cache_max_entries)
+            }
+            None => suppressions,
+        }
+    });
+    let tracking = match (&config.tracking_url, &config.tracking_table) {
+        (Some(url), Some(table_name)) => {
+            Some(Tracking::new(dynamodb.clone(), table_name, url.clone()))
+        }
+        (Some(_), None) => return Err("--tracking-url requires --tracking-table".into()),
+        (None, _) => None,
+    };
+    // Metrics are kept for the metrics server even when they are not published to CloudWatch
+    let metrics = match (&config.metrics_namespace, &opt.metrics_addr) {
+        (Some(namespace), _) => Some(Arc::new(
+            Metrics::default().with_cloudwatch(namespace, CloudWatchClient::new(&aws_config)),
+        )),
+        (None, Some(_)) => Some(Arc::new(Metrics::default())),
+        (None, None) => None,
+    };
+    let client_services = ClientServices {
+        alerts,
+        archive_bcc: config.archive_bcc.clone(),
+        attachments: AttachmentFetcher::new(S3Client::new(&aws_config)).with_http(http),
+        circuit_breaker,
+        domains: if domains.is_empty() {
+            None
+        } else {
+            Some(domains)
+        },
+        dynamodb: dynamodb.clone(),
+        dynamo_retry: config.dynamo_retry,
+        event_bus,
+        failure_queue,
+        max_age: config.max_message_age.clone(),
+        message_budget: config.message_budget.map(Duration::from_secs),
+        metrics,
+        mime_store,
+        provider_retry: config.provider_retry,
+        rate_limiter,
+        recipient_table: config.recipient_table.clone(),
+        redirect_to,
+        retention: config.retention.map(Duration::from_secs),
+        sanitize_html: config.sanitize_html,
+        sending_lease: Duration::from_secs(config.sending_lease),
+        sqs_retry: config.sqs_retry,
+        suppressions,
+        table_name: config.table_name.clone(),
+        templates,
+        tenants: config.tenants.clone(),
+        tracking,
+    };
+    let client = client_services.client();
+    let metrics = &client_services.metrics;
+    let suppressions = &client_services.suppressions;
+    let event_bus = &client_services.event_bus;
+    // Commands run in place of reading the queue
+    match &opt.command {
+        Some(Command::Feedback(options)) => {
+            let suppressions = suppressions
+                .as_ref()
+                .ok_or("--suppression-table is required to record feedback")?;
+            let queue_url = &options.feedback_queue_url;
+            let worker =
+                FeedbackWorker::new(&dynamodb, &config.table_name, suppressions, queue_url, &sqs);
+            let mut source = SqsPoll::new(queue_url, &sqs);
+            let shutdown = Shutdown::listen();
+            let mut summary = RunSummary::default();
+            let mut iteration = 0;
+            while !shutdown.is_requested() {
+                let loop_span = span!(Level::INFO, "feedback", Iteration = &iteration);
+                let _loop_guard = loop_span.enter();
+                let report = worker.run_once(&mut source).in_current_span().await;
+                event!(Level::DEBUG, ?report, "batch complete");
+                summary.record(&report);
+                iteration += 1;
+                if opt.dry_run || opt.stops_after(iteration, report.is_empty()) {
+                    break;
+                }
+            }
+            event!(Level::INFO, ?summary, "feedback shutdown");
+            return Ok(());
+        }
+        Some(Command::Import(options)) => {
+            import::run(
+                &dynamodb,
+                &config.table_name,
+                &sqs,
+                &config.queue_url,
+                event_bus.as_ref(),
+                options,
+            )
             .in_current_span()
-            .await;
-        let processed_messages = match message_list {
-            Ok(messages) => client.process_messages(messages).in_current_span().await,
-            Err(error) => {
-                event!(Level::ERROR, %error, "ReceiveMessageError");
-                Vec::new()
+            .await?;
+            return Ok(());
+        }
+        Some(Command::Ingest(options)) => {
+            let s3 = S3Client::new(&aws_config);
+            let queue_url = &options.drop_queue_url;
+            let drop_folder = DropFolder::new(
+                &dynamodb,
+                &config.table_name,
+                &config.queue_url,
+                queue_url,
+                &sqs,
+                &s3,
+                options.message_group,
+            );
+            let drop_folder = match event_bus {
+                Some(event_bus) => drop_folder.with_event_bus(event_bus),
+                None => drop_folder,
+            };
+            let mut source = SqsPoll::new(queue_url, &sqs);
+            let shutdown = Shutdown::listen();
+            let mut summary = RunSummary::default();
+            let mut iteration = 0;
+            while !shutdown.is_requested() {
+                let loop_span = span!(Level::INFO, "ingest", Iteration = &iteration);
+                let _loop_guard = loop_span.enter();
+                let report = drop_folder.run_once(&mut source).in_current_span().await;
+                event!(Level::DEBUG, ?report, "batch complete");
+                summary.record(&report);
+                iteration += 1;
+                if opt.dry_run || opt.stops_after(iteration, report.is_empty()) {
+                    break;
+                }
             }
-        };
-        let delete_messages_request = DeleteMessageBatchRequest {
-            entries: processed_messages,
-            queue_url: queue_url.into(),
-        };
-        if delete_messages_request.entries.len() > 0 {
-            match sqs
-                .delete_message_batch(delete_messages_request)
+            event!(Level::INFO, ?summary, "ingest shutdown");
+            return Ok(());
+        }
+        Some(Command::Preview(options)) => {
+            let message = client
+                .preview(options.email_id.as_str(), options.tenant.as_deref())
+                .in_current_span()
+                .await?;
+            std::fs::write(&options.out, &message.raw)?;
+            event!(Level::INFO, email_id = %options.email_id, out = ?options.out, "preview complete");
+            return Ok(());
+        }
+        Some(Command::Reconcile(options)) => {
+            let index_name = config
+                .status_index
+                .as_deref()
+                .ok_or("--status-index is required to reconcile pending emails")?;
+            let reconciler = PendingReconciler::new(
+                &dynamodb,
+                &config.table_name,
+                index_name,
+                &config.queue_url,
+                &sqs,
+                Duration::from_secs(options.min_age * 60),
+            );
+            let reconciler = match metrics {
+                Some(metrics) => reconciler.with_metrics(metrics),
+                None => reconciler,
+            };
+            let shutdown = Shutdown::listen();
+            let mut enqueued = 0;
+            let mut iteration = 0;
+            while !shutdown.is_requested() {
+                let found = match reconciler.run_once().in_current_span().await {
+                    Ok(report) => {
+                        event!(Level::INFO, ?report, "reconcile complete");
+                        enqueued += report.enqueued;
+                        Some(report.found)
+                    }
+                    Err(error) => {
+                        event!(Level::ERROR, %error, "query status index failed");
+                        None
+                    }
+                };
+                if let Some(metrics) = metrics {
+                    if let Err(error) = metrics.publish().in_current_span().await {
+                        event!(Level::WARN, %error, "publish metrics failed");
+                    }
+                }
+                iteration += 1;
+                if opt.dry_run || opt.stops_after(iteration, found == Some(0)) {
+                    break;
+                }
+                // Emails are only taken to be lost after minutes so the index is read after a pause
+                shutdown
+                    .sleep(Duration::from_secs(options.reconcile_interval))
+                    .await;
+            }
+            event!(Level::INFO, enqueued, "reconcile shutdown");
+            return Ok(());
+        }
+        Some(Command::Relay(options)) => {
+            let relay = OutboxRelay::new(&dynamodb, &options.outbox_table, &config.queue_url, &sqs);
+            let shutdown = Shutdown::listen();
+            let mut relayed = 0;
+            let mut iteration = 0;
+            while !shutdown.is_requested() {
+                let found = match relay.run_once().in_current_span().await {
+                    Ok(report) => {
+                        event!(Level::DEBUG, ?report, "relay complete");
+                        relayed += report.relayed;
+                        Some(report.found)
+                    }
+                    Err(error) => {
+                        event!(Level::ERROR, %error, "read outbox failed");
+                        None
+                    }
+                };
+                iteration += 1;
+                if opt.dry_run || opt.stops_after(iteration, found == Some(0)) {
+                    break;
+                }
+                // Wait for more markers to be written when the outbox is empty or unreadable
+                if found.unwrap_or(0) == 0 {
+                    tokio::time::sleep(RELAY_IDLE_WAIT).await;
+                }
+            }
+            event!(Level::INFO, relayed, "relay shutdown");
+            return Ok(());
+        }
+        Some(Command::Cancel(options)) => {
+            cancel_email(
+                &dynamodb,
+                tenant_table(&config, options.tenant.as_deref())?,
+                &options.email_id,
+                config.retention.map(Duration::from_secs),
+            )
+            .in_current_span()
+            .await?;
+            event!(Level::INFO, email_id = %options.email_id, "cancel complete");
+            return Ok(());
+        }
+        Some(Command::Requeue(options)) => {
+            match &options.tenant {
+                Some(name) => {
+                    let tenant = config
+                        .tenants
+                        .get(name)
+                        .ok_or_else(|| format!("--tenant {} is not configured", name))?;
+                    requeue_tenant_email(
+                        &dynamodb,
+                        &sqs,
+                        &config.queue_url,
+                        options.message_group,
+                        name,
+                        tenant,
+                        &options.email_id,
+                    )
+                    .in_current_span()
+                    .await?
+                }
+                None => {
+                    requeue_email(
+                        &dynamodb,
+                        &config.table_name,
+                        &sqs,
+                        &config.queue_url,
+                        options.message_group,
+                        &options.email_id,
+                    )
+                    .in_current_span()
+                    .await?
+                }
+            }
+            if let Some(event_bus) = event_bus {
+                let queued =
+                    EmailEvent::for_email_id(EmailEventType::EmailQueued, &options.email_id);
+                if let Err(error) = event_bus.publish(&queued).in_current_span().await {
+                    event!(Level::WARN, %error, "email event not published");
+                }
+            }
+            event!(Level::INFO, email_id = %options.email_id, "requeue complete");
+            return Ok(());
+        }
+        Some(Command::Resend(options)) => {
+            client
+                .resend(
+                    options.email_id.as_str(),
+                    options.exact,
+                    options.tenant.as_deref(),
+                )
                 .in_current_span()
-                .await
+                .await?;
+            event!(Level::INFO, email_id = %options.email_id, exact = options.exact, "resend complete");
+            return Ok(());
+        }
+        Some(Command::Send(options)) => {
+            let email_id = send::run(&client, options).in_current_span().await?;
+            event!(Level::INFO, %email_id, "send complete");
+            return Ok(());
+        }
+        Some(Command::Status(options)) => {
+            let index_name = config
+                .status_index
+                .as_deref()
+                .ok_or("--status-index is required to list emails by status")?;
+            let page = query_by_status(
+                &dynamodb,
+                tenant_table(&config, options.tenant.as_deref())?,
+                index_name,
+                options.status,
+                options.limit,
+                options.cursor.as_deref(),
+            )
+            .in_current_span()
+            .await?;
+            println!("{}", serde_json::to_string_pretty(&page)?);
+            return Ok(());
+        }
+        Some(Command::SupportBundle(options)) => {
+            let region = aws_config.region().map(|r| r.as_ref().to_owned());
+            let bundle = support::collect(
+                &opt,
+                &config,
+                region,
+                &client,
+                &sqs,
+                options.email_id.as_str(),
+                options.tenant.as_deref(),
+            )
+            .in_current_span()
+            .await;
+            support::write(&bundle, options.output.as_deref())?;
+            return Ok(());
+        }
+        Some(Command::Sweep(options)) => {
+            let sweeper =
+                StuckEmailSweeper::new(&dynamodb, &config.table_name, &config.queue_url, &sqs);
+            let sweeper = match metrics {
+                Some(metrics) => sweeper.with_metrics(metrics),
+                None => sweeper,
+            };
+            let sweeper = match &config.status_index {
+                Some(index_name) => sweeper.with_status_index(index_name),
+                None => sweeper,
+            };
+            let shutdown = Shutdown::listen();
+            let mut recovered = 0;
+            let mut iteration = 0;
+            while !shutdown.is_requested() {
+                let found = match sweeper.run_once().in_current_span().await {
+                    Ok(report) => {
+                        event!(Level::INFO, ?report, "sweep complete");
+                        recovered += report.recovered;
+                        Some(report.found)
+                    }
+                    Err(error) => {
+                        event!(Level::ERROR, %error, "scan email table failed");
+                        None
+                    }
+                };
+                if let Some(metrics) = metrics {
+                    if let Err(error) = metrics.publish().in_current_span().await {
+                        event!(Level::WARN, %error, "publish metrics failed");
+                    }
+                }
+                iteration += 1;
+                if opt.dry_run || opt.stops_after(iteration, found == Some(0)) {
+                    break;
+                }
+                // Claims lapse over minutes so the table is scanned again after a pause
+                shutdown
+                    .sleep(Duration::from_secs(options.sweep_interval))
+                    .await;
+            }
+            event!(Level::INFO, recovered, "sweep shutdown");
+            return Ok(());
+        }
+        None => {}
+    }
+    // Verify a deployment can send before any real email is taken from the queue
+    if let Some(recipient) = &opt.canary {
+        if let Err(error) = client.send_canary(recipient).in_current_span().await {
+            event!(Level::ERROR, %error, "canary failed");
+            return Err(error.into());
+        }
+    }
+    let quarantine = config.quarantine_store.clone().map(|location| {
+        let redaction = QuarantineRedaction::new(&config.quarantine_allow_fields);
+        S3QuarantineStore::new(location, redaction, S3Client::new(&aws_config))
+    });
+    let runner = Runner::new(client, &config.queue_url, &sqs);
+    let runner = match &quarantine {
+        Some(store) => runner.with_quarantine_store(store),
+        None => runner,
+    };
+    // Every queue shares the client, deletes going to the queue each batch was received from
+    let source = WeightedPoll::new(opt.polled_queues(&config), &sqs);
+    // Audited messages are left visible by the receive itself so the queue is never written to
+    let mut source = if opt.audits() {
+        source.with_visibility_timeout(0)
+    } else {
+        source
+    };
+    // Stop between batches when asked so the last batch is always deleted before exiting
+    let shutdown = Shutdown::listen();
+    // Audit until a batch contains no message which has not been seen already
+    if opt.audits() {
+        let queues = opt.polled_queues(&config);
+        let queue_urls = queues.iter().map(|queue| &queue.queue_url);
+        check_audit(queue_urls, config.failure_queue_url.as_ref(), &sqs)
+            .in_current_span()
+            .await?;
+        let mut summary = AuditSummary::default();
+        let mut iteration = 0;
+        loop {
+            let entries = runner.audit_once(&mut source).in_current_span().await;
+            iteration += 1;
+            if summary.record(&entries) == 0
+                || opt.dry_run
+                || opt.stops_after(iteration, entries.is_empty())
+                || shutdown.is_requested()
             {
-                Ok(result) => event!(Level::TRACE, ?result, "deleted messages"),
-                Err(error) => event!(Level::ERROR, %error, "Delete messages Error"),
+                break;
+            }
+        }
+        event!(Level::INFO, total = summary.total(), counts = ?summary.counts, "audit complete");
+        return Ok(());
+    }
+    let health = Arc::new(LoopHealth::new(LOOP_STALL_TIMEOUT));
+    if let (Some(addr), Some(metrics)) = (opt.metrics_addr, metrics) {
+        let server = metrics_server::serve(addr, metrics.clone(), health.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(error) = server.await {
+                event!(Level::ERROR, %error, "metrics server stopped");
+            }
+        });
+    }
+    let mut summary = RunSummary::default();
+    let mut idle = IdleBackoff::new(IDLE_INITIAL_WAIT, Duration::from_secs(opt.max_idle_wait));
+    let mut iteration = 0;
+    while !shutdown.is_requested() {
+        let loop_span = span!(Level::INFO, "loop", Iteration = &iteration);
+        let _loop_guard = loop_span.enter();
+        let report = runner.run_once(&mut source).in_current_span().await;
+        event!(Level::DEBUG, ?report, "batch complete");
+        summary.record(&report);
+        health.record_iteration();
+        if let Some(metrics) = metrics {
+            if let Err(error) = metrics.publish().in_current_span().await {
+                event!(Level::WARN, %error, "publish metrics failed");
             }
-        } else {
-            event!(
-                Level::INFO,
-                count = delete_messages_request.entries.len(),
-                "no messages to delete"
-            );
         }
-        if opt.dry_run {
+        iteration += 1;
+        if opt.dry_run || opt.stops_after(iteration, report.is_empty()) {
             break;
         }
-        iteration = iteration + 1;
+        // Poll a quiet queue less often, a stop request ends the wait early
+        let received = Some(report.received).filter(|_| !report.receive_failed);
+        let wait = idle.record_receive(received);
+        if !wait.is_zero() {
+            event!(
+                Level::DEBUG,
+                empty_receives = idle.empty_receives(),
+                wait_ms = wait.as_millis() as u64,
+                "idle wait"
+            );
+            shutdown.sleep(wait).await;
+        }
     }
+    // Final totals so the work done by this process is recorded even when it is stopped
+    event!(Level::INFO, ?summary, "broker shutdown");
     Ok(())
 }
@@ -0,0 +1,146 @@
+use crate::shutdown::Shutdown;
+use email_shared::Metrics;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tracing::{event, Level};
+
+/// Content type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Whether the broker loop is making progress, reported by `/healthz` and `/readyz`.
+#[derive(Debug)]
+pub struct LoopHealth {
+    /// When the broker started, used until the first iteration completes.
+    started: Instant,
+    /// When the last loop iteration completed.
+    last_iteration: Mutex<Option<Instant>>,
+    /// Longest time between iterations before the loop is considered stalled.
+    stall_after: Duration,
+}
+
+impl LoopHealth {
+    pub fn new(stall_after: Duration) -> Self {
+        LoopHealth {
+            started: Instant::now(),
+            last_iteration: Mutex::new(None),
+            stall_after,
+        }
+    }
+
+    /// Record that a loop iteration completed.
+    pub fn record_iteration(&self) {
+        *self.lock() = Some(Instant::now());
+    }
+
+    /// Whether a loop iteration has completed yet.
+    fn has_iterated(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    /// Whether the loop completed an iteration within `stall_after` of `now`.
+    fn is_healthy(&self, now: Instant) -> bool {
+        let since = self.lock().unwrap_or(self.started);
+        now.saturating_duration_since(since) < self.stall_after
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.last_iteration
+            .lock()
+            .expect("LoopHealth lock poisoned")
+    }
+}
+
+/// State shared by every connection to the metrics server.
+#[derive(Clone)]
+struct ServerState {
+    health: Arc<LoopHealth>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+}
+
+impl ServerState {
+    /// Answer `request`.
+    ///
+    /// - `/metrics` renders every metric in the Prometheus text exposition format.
+    /// - `/healthz` succeeds unless the loop has stopped completing iterations.
+    /// - `/readyz` succeeds once an iteration has completed and until shutdown is requested.
+    fn respond(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => Response::builder()
+                .header(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)
+                .body(Full::from(self.metrics.render_prometheus()))
+                .unwrap_or_default(),
+            (&Method::GET, "/healthz") => {
+                status_response(self.health.is_healthy(Instant::now()), "stalled")
+            }
+            (&Method::GET, "/readyz") => status_response(
+                self.health.has_iterated() && !self.shutdown.is_requested(),
+                "not ready",
+            ),
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// `200 ok` when `ok`, otherwise `503` with `reason`.
+fn status_response(ok: bool, reason: &'static str) -> Response<Full<Bytes>> {
+    if ok {
+        text_response(StatusCode::OK, "ok")
+    } else {
+        text_response(StatusCode::SERVICE_UNAVAILABLE, reason)
+    }
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Full::from(body))
+        .unwrap_or_default()
+}
+
+/// Serve `/metrics`, `/healthz`, and `/readyz` on `addr` until the process exits.
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    health: Arc<LoopHealth>,
+    shutdown: Shutdown,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    event!(Level::INFO, %addr, "metrics server listening");
+    let state = ServerState {
+        health,
+        metrics,
+        shutdown,
+    };
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                event!(Level::WARN, %error, "metrics connection not accepted");
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = state.respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            if let Err(error) = connection.await {
+                event!(Level::DEBUG, %error, %remote, "metrics connection failed");
+            }
+        });
+    }
+}
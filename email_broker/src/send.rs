@@ -0,0 +1,71 @@
+use crate::config::SendOptions;
+use email_shared::{Client, DirectSendError, EmailId, EmailMessageAttachment, EmailMessageDraft};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Build an email from `options` and send it immediately with `client`.
+pub async fn run(client: &Client<'_>, options: &SendOptions) -> Result<EmailId, Box<dyn Error>> {
+    let body = fs::read_to_string(&options.body_file)?;
+    let attachments = options
+        .attachments
+        .iter()
+        .map(|path| read_attachment(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (body_html, body_markdown, body_text) = match extension(&options.body_file).as_deref() {
+        Some("html" | "htm") => (body, String::new(), String::new()),
+        Some("md" | "markdown") => (String::new(), body, String::new()),
+        _ => (String::new(), String::new(), body),
+    };
+    let draft = EmailMessageDraft {
+        attachments,
+        body_html,
+        body_markdown,
+        body_text,
+        recipients_to: options.to.clone(),
+        sender: options.from.clone(),
+        subject: options.subject.clone(),
+        ..EmailMessageDraft::default()
+    };
+    let email = draft.into_email().map_err(DirectSendError::Invalid)?;
+    Ok(client
+        .send_direct(email, options.persist, options.tenant.as_deref())
+        .await?)
+}
+
+/// Read the file at `path` as an inline attachment.
+fn read_attachment(path: &Path) -> Result<EmailMessageAttachment, std::io::Error> {
+    let body = fs::read(path)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok(EmailMessageAttachment::inline(
+        name,
+        content_type(path),
+        &body,
+    ))
+}
+
+/// Lower case extension of `path`.
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Guess the MIME type of the file at `path` from its extension.
+fn content_type(path: &Path) -> &'static str {
+    match extension(path).as_deref() {
+        Some("csv") => "text/csv",
+        Some("gif") => "image/gif",
+        Some("htm" | "html") => "text/html",
+        Some("ics") => "text/calendar",
+        Some("jpeg" | "jpg") => "image/jpeg",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("png") => "image/png",
+        Some("txt") => "text/plain",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
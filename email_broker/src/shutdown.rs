@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{event, Level};
+
+/// Records whether the broker was asked to stop by SIGINT or SIGTERM. The broker finishes the
+/// batch in progress, including deleting processed messages, before it stops so exiting never
+/// races with the last delete.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    /// Set once a signal to stop has been received.
+    requested: Arc<AtomicBool>,
+    /// Wakes tasks sleeping through `Shutdown::sleep` once a signal to stop has been received.
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Start listening for signals to stop.
+    pub fn listen() -> Self {
+        let shutdown = Shutdown::default();
+        let requested = shutdown.requested.clone();
+        let notify = shutdown.notify.clone();
+        tokio::spawn(async move {
+            let signal = wait_for_signal().await;
+            event!(Level::INFO, %signal, "shutdown requested, finishing current batch");
+            requested.store(true, Ordering::SeqCst);
+            notify.notify_waiters();
+        });
+        shutdown
+    }
+
+    /// Whether a signal to stop has been received.
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wait for `duration`, returning early once a signal to stop is received.
+    pub async fn sleep(&self, duration: Duration) {
+        // Created before checking so a signal arriving in between still wakes the sleep
+        let notified = self.notify.notified();
+        if duration.is_zero() || self.is_requested() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = notified => {}
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, returning the name of the signal received.
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(error) => {
+            event!(Level::WARN, %error, "SIGTERM handler not installed");
+            return wait_for_interrupt().await;
+        }
+    };
+    tokio::select! {
+        signal = wait_for_interrupt() => signal,
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Wait for SIGINT, returning the name of the signal received.
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    wait_for_interrupt().await
+}
+
+/// Wait for SIGINT. When the handler can not be installed this never completes.
+async fn wait_for_interrupt() -> &'static str {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => "SIGINT",
+        Err(error) => {
+            event!(Level::WARN, %error, "SIGINT handler not installed");
+            futures::future::pending().await
+        }
+    }
+}
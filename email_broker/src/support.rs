@@ -0,0 +1,163 @@
+use crate::config::Options;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::types::QueueAttributeName;
+use aws_sdk_sqs::Client as SqsClient;
+use email_shared::{redact_url, Client, Config};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Collect the record, recipient statuses, queue attributes, log query hints, and redacted
+/// configuration for `email_id`, read from the table of `tenant` when given. Anything which can not be read is recorded as an `error` in its
+/// place so a bundle is always produced.
+pub async fn collect(
+    opt: &Options,
+    config: &Config,
+    region: Option<String>,
+    client: &Client<'_>,
+    sqs: &SqsClient,
+    email_id: &str,
+    tenant: Option<&str>,
+) -> Value {
+    let record = match client.get_email(email_id, tenant).await {
+        Ok(email) => without_attachment_bodies(json!(email)),
+        Err(error) => json!({ "error": error.to_string() }),
+    };
+    let recipient_statuses = match client.get_recipient_statuses(email_id).await {
+        Ok(statuses) => json!(statuses),
+        Err(error) => json!({ "error": error.to_string() }),
+    };
+    let queue_attributes = match sqs
+        .get_queue_attributes()
+        .queue_url(config.queue_url.as_str())
+        .attribute_names(QueueAttributeName::All)
+        .send()
+        .await
+    {
+        Ok(output) => Value::Object(
+            output
+                .attributes
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, value)| (name.as_str().to_owned(), Value::String(value)))
+                .collect(),
+        ),
+        Err(error) => json!({ "error": format!("{}", DisplayErrorContext(&error)) }),
+    };
+    json!({
+        "config": {
+            "alert_failure_rate": config.alert_failure_rate,
+            "alert_topic_arn": config.alert_topic_arn,
+            "alert_window": config.alert_window,
+            "allow_domains": config.allow_domains,
+            "archive_bcc": config.archive_bcc.as_ref().map(|archive| format!("{:?}", archive)),
+            "assume_role_arn": config.assume_role_arn,
+            "assume_role_session_name": config.assume_role_session_name,
+            "audit_only": opt.audit_only,
+            "cache_max_entries": config.cache_max_entries,
+            "circuit_breaker_cooldown": config.circuit_breaker_cooldown,
+            "circuit_breaker_threshold": config.circuit_breaker_threshold,
+            "config_file": opt.config_file,
+            "connect_timeout": config.connect_timeout,
+            "deny_domains": config.deny_domains,
+            "dynamo_endpoint": config.dynamo_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+            "dynamo_retry": config.dynamo_retry.as_ref().map(|policy| format!("{:?}", policy)),
+            "event_bus": config.event_bus,
+            "failure_queue_url": config.failure_queue_url.as_ref().map(|url| redact_url(url.as_str())),
+            "log_format": opt.log_format.to_string(),
+            "log_level": opt.log_level.to_string(),
+            "max_attempts": config.max_attempts,
+            "max_blocking_threads": opt.max_blocking_threads,
+            "max_idle_wait": opt.max_idle_wait,
+            "max_iterations": opt.max_iterations,
+            "max_message_age": config.max_message_age.as_ref().map(|age| format!("{:?}", age)),
+            "message_budget": config.message_budget,
+            "metrics_addr": opt.metrics_addr,
+            "metrics_namespace": config.metrics_namespace,
+            "mime_store": config.mime_store.as_ref().map(|location| format!("{:?}", location)),
+            "mime_store_encryption": config.mime_store_encryption.as_ref().map(|encryption| encryption.to_string()),
+            "operation_timeout": config.operation_timeout,
+            "provider_retry": config.provider_retry.as_ref().map(|policy| format!("{:?}", policy)),
+            "protected": opt.protected.iter().map(|p| &p.environment).collect::<Vec<_>>(),
+            "read_only": opt.read_only,
+            "quarantine_allow_fields": config.quarantine_allow_fields,
+            "quarantine_store": config.quarantine_store.as_ref().map(|location| format!("{:?}", location)),
+            "queue_name": config.queue_url.name(),
+            "queue_url": redact_url(config.queue_url.as_str()),
+            "queue_weights": opt.queue_weights.iter().map(|queue| format!("{}={}", queue.weight, redact_url(queue.queue_url.as_str()))).collect::<Vec<_>>(),
+            "rate_limit": config.rate_limit.as_ref().map(|limits| format!("{:?}", limits)),
+            "rate_limit_max_delay": config.rate_limit_max_delay,
+            "recipient_table": config.recipient_table,
+            "redirect_to": config.redirect_to,
+            "region": region,
+            "retention": config.retention,
+            "sanitize_html": config.sanitize_html,
+            "sending_lease": config.sending_lease,
+            "sqs_endpoint": config.sqs_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+            "sqs_retry": config.sqs_retry.as_ref().map(|policy| format!("{:?}", policy)),
+            "status_index": config.status_index,
+            "single_threaded": opt.single_threaded,
+            "suppression_cache_ttl": config.suppression_cache_ttl,
+            "suppression_table": config.suppression_table,
+            "table_name": config.table_name,
+            "template_source": config.template_source.as_ref().map(|source| format!("{:?}", source)),
+            "template_ttl": config.template_ttl,
+            "tenants": config.tenants.iter().map(|(name, tenant)| format!("{}={}", name, tenant.table_name)).collect::<Vec<_>>(),
+            "tracking_table": config.tracking_table,
+            "tracking_url": config.tracking_url.as_ref().map(|url| redact_url(url.as_str())),
+            "until_empty": opt.until_empty,
+            "use_dual_stack": opt.use_dual_stack,
+            "web_identity_token_file": config.web_identity_token_file,
+            "worker_threads": opt.worker_threads,
+        },
+        "email_id": email_id,
+        "generated_at": chrono::Utc::now().to_rfc3339(),
+        "log_queries": log_queries(email_id),
+        "queue_attributes": queue_attributes,
+        "recipient_statuses": recipient_statuses,
+        "record": record,
+        "version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Write `bundle` as pretty printed JSON to `output`, or standard output.
+pub fn write(bundle: &Value, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    serde_json::to_writer_pretty(&mut writer, bundle)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// CloudWatch Logs Insights queries finding the log events of the broker and Lambda mentioning
+/// `email_id`.
+fn log_queries(email_id: &str) -> Vec<String> {
+    vec![
+        format!(
+            "fields @timestamp, @message | filter @message like \"{}\" | sort @timestamp asc",
+            email_id
+        ),
+        format!(
+            "fields @timestamp, level, fields.message | filter fields.email_id = \"{}\" or span.email_id = \"{}\" | sort @timestamp asc",
+            email_id, email_id
+        ),
+    ]
+}
+
+/// Replace the contents of inline attachments in `record` with their length, contents are rarely
+/// useful for support and can be large.
+fn without_attachment_bodies(mut record: Value) -> Value {
+    if let Some(attachments) = record.get_mut("Attachments").and_then(Value::as_array_mut) {
+        for attachment in attachments {
+            if let Some(body) = attachment.get_mut("body") {
+                let length = body.as_str().map(str::len).unwrap_or_default();
+                *body = json!(format!("<{} base64 characters>", length));
+            }
+        }
+    }
+    record
+}
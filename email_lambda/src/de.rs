@@ -1,34 +1,185 @@
-use rusoto_sqs::{Message, MessageAttributeValue};
+use aws_sdk_sqs::types::{Message, MessageAttributeValue, MessageSystemAttributeName};
 use serde::Deserialize;
+use std::collections::HashMap;
 
-/// Implementation copied from `rusoto_sqs` at 0.45.0. Originally intended for use with [`serde`
-/// remote (de)serialization](https://serde.rs/remote-derive.html) but ran into problems with the
-/// `MessageAttributeValue` deserialization.
+/// Shape of an SQS message as delivered in the `Records` of a Lambda SQS event. The
+/// `aws_sdk_sqs::types::Message` type does not implement `Deserialize` so event records are read
+/// into this structure and then converted.
 #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageDef {
-    pub attributes: Option<::std::collections::HashMap<String, String>>,
+    pub attributes: Option<HashMap<String, String>>,
     pub body: Option<String>,
     pub md5_of_body: Option<String>,
     pub md5_of_message_attributes: Option<String>,
-    pub message_attributes: Option<::std::collections::HashMap<String, MessageAttributeValue>>,
+    pub message_attributes: Option<HashMap<String, MessageAttributeValueDef>>,
     pub message_id: Option<String>,
     pub receipt_handle: Option<String>,
 }
 
-/// Create a `rusoto_sqs::Message` instance from a `MessageDef`. These structs should be equivalent
-/// so it should simply be a matter of reassigning values.
+/// Shape of an SQS message attribute as delivered in a Lambda SQS event. Only string values are
+/// carried over to the `aws_sdk_sqs::types::MessageAttributeValue`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAttributeValueDef {
+    pub data_type: String,
+    pub string_value: Option<String>,
+    pub string_list_values: Option<Vec<String>>,
+}
+
+impl MessageAttributeValueDef {
+    /// Build a `MessageAttributeValue`. The only required field is always present so this is only
+    /// `None` if the SDK adds new required fields.
+    fn into_attribute_value(self) -> Option<MessageAttributeValue> {
+        MessageAttributeValue::builder()
+            .data_type(self.data_type)
+            .set_string_value(self.string_value)
+            .set_string_list_values(self.string_list_values)
+            .build()
+            .ok()
+    }
+}
+
+/// Create an `aws_sdk_sqs::types::Message` instance from a `MessageDef`. These structs should be
+/// equivalent so it should simply be a matter of reassigning values.
 impl From<MessageDef> for Message {
     /// Assign values from `MessageDef` to the attributes of `Message`.
     fn from(message: MessageDef) -> Self {
-        Message {
-            attributes: message.attributes,
-            body: message.body,
-            md5_of_body: message.md5_of_body,
-            md5_of_message_attributes: message.md5_of_message_attributes,
-            message_attributes: message.message_attributes,
-            message_id: message.message_id,
-            receipt_handle: message.receipt_handle,
-        }
+        let attributes = message.attributes.map(|attributes| {
+            attributes
+                .into_iter()
+                .map(|(key, value)| (MessageSystemAttributeName::from(key.as_str()), value))
+                .collect()
+        });
+        let message_attributes = message.message_attributes.map(|attributes| {
+            attributes
+                .into_iter()
+                .filter_map(|(key, value)| value.into_attribute_value().map(|value| (key, value)))
+                .collect()
+        });
+        Message::builder()
+            .set_attributes(attributes)
+            .set_body(message.body)
+            .set_md5_of_body(message.md5_of_body)
+            .set_md5_of_message_attributes(message.md5_of_message_attributes)
+            .set_message_attributes(message_attributes)
+            .set_message_id(message.message_id)
+            .set_receipt_handle(message.receipt_handle)
+            .build()
+    }
+}
+
+/// Shape of a record of a Lambda SNS event, delivered when the function subscribes to a topic.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SnsRecordDef {
+    #[serde(rename = "Sns")]
+    pub sns: SnsMessageDef,
+}
+
+/// Shape of the notification carried by an SNS event record.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsMessageDef {
+    pub message: String,
+    pub message_id: String,
+    #[serde(default)]
+    pub message_attributes: HashMap<String, SnsMessageAttributeDef>,
+}
+
+/// Shape of an SNS message attribute. Only string values are carried over to the
+/// `aws_sdk_sqs::types::MessageAttributeValue`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsMessageAttributeDef {
+    #[serde(rename = "Type")]
+    pub data_type: String,
+    pub value: String,
+}
+
+/// Create an `aws_sdk_sqs::types::Message` from an SNS notification so it is processed as a
+/// message received from the queue. A notification has no receipt handle, nothing is deleted for
+/// it, so its id stands in for one.
+impl From<SnsRecordDef> for Message {
+    fn from(record: SnsRecordDef) -> Self {
+        let SnsMessageDef {
+            message,
+            message_id,
+            message_attributes,
+        } = record.sns;
+        let message_attributes = message_attributes
+            .into_iter()
+            .filter(|(_, value)| value.data_type == "String")
+            .filter_map(|(key, value)| {
+                MessageAttributeValue::builder()
+                    .data_type(value.data_type)
+                    .string_value(value.value)
+                    .build()
+                    .ok()
+                    .map(|value| (key, value))
+            })
+            .collect();
+        Message::builder()
+            .body(message)
+            .set_message_attributes(Some(message_attributes))
+            .message_id(message_id.clone())
+            .receipt_handle(message_id)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod from_sns_record_def {
+    use super::*;
+    use email_shared::{EmailPointerMessage, PointerError};
+    use std::convert::TryFrom;
+
+    fn record(json: &str) -> SnsRecordDef {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn unwraps_pointers() {
+        let message = Message::from(record(
+            r#"{"Sns":{"MessageId":"Test MessageId","Message":"{\"email_id\":\"Test EmailId\",\"tenant\":\"acme\"}"}}"#,
+        ));
+        assert_eq!(message.message_id(), Some("Test MessageId"));
+        // Nothing is deleted for a notification so its id stands in for a receipt handle
+        assert_eq!(message.receipt_handle(), Some("Test MessageId"));
+        let pointer = EmailPointerMessage::try_from(message).unwrap();
+        assert_eq!(pointer.email_id.as_str(), "Test EmailId");
+        assert_eq!(pointer.tenant.as_deref(), Some("acme"));
+        assert_eq!(pointer.receive_count, 1);
+    }
+
+    #[test]
+    fn keeps_malformed_bodies() {
+        let message = Message::from(record(
+            r#"{"Sns":{"MessageId":"Test MessageId","Message":"not a pointer"}}"#,
+        ));
+        assert_eq!(message.body(), Some("not a pointer"));
+        assert_eq!(
+            EmailPointerMessage::try_from(message).err(),
+            Some(PointerError::InvalidBody)
+        );
+    }
+
+    #[test]
+    fn keeps_string_attributes() {
+        let message = Message::from(record(
+            r#"{"Sns":{
+                "MessageId":"Test MessageId",
+                "Message":"{\"email_id\":\"Test EmailId\"}",
+                "MessageAttributes":{
+                    "traceparent":{"Type":"String","Value":"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"},
+                    "priority":{"Type":"Number","Value":"1"}
+                }
+            }}"#,
+        ));
+        let attributes = message.message_attributes().unwrap();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(
+            attributes["traceparent"].string_value(),
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        );
     }
 }
@@ -1,15 +1,11 @@
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub enum EmailHandlerError {
     InitializationFailure,
+    #[default]
     BatchFailure,
     PartialBatchFailure,
     SqsDeleteFailed,
-}
-
-impl Default for EmailHandlerError {
-    fn default() -> Self {
-        EmailHandlerError::BatchFailure
-    }
+    DirectSendFailed,
 }
 
 impl std::fmt::Display for EmailHandlerError {
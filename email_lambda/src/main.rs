@@ -1,32 +1,74 @@
 mod de;
 mod error;
 
-#[macro_use]
-extern crate lazy_static;
-
-use de::MessageDef;
-use email_shared::Client;
+use aws_config::BehaviorVersion;
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_eventbridge::Client as EventBridgeClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use aws_sdk_sns::Client as SnsClient;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::types::Message;
+use aws_sdk_sqs::Client as SqsClient;
+use aws_sdk_ssm::Client as SsmClient;
+use de::{MessageDef, SnsRecordDef};
+use email_shared::schema::env_var::{CONFIG_FILE, METRICS_FORMAT};
+use email_shared::{
+    dynamodb_config, forward_pointer, normalize_address, redact_url, sqs_config, Alerts,
+    AssumeRole, AttachmentFetcher, CallTimeouts, CircuitBreaker, Client, ClientServices, Config,
+    ConfigError, ConfigSources, DeleteOutcome, DomainPolicy, EmailId, EventBatch, EventBus,
+    FailureQueue, HttpFetcher, Metrics, QuarantineRedaction, QueueUrl, RateLimiter, Runner,
+    S3MimeStore, S3QuarantineStore, Secrets, StreamEnqueuer, StreamRecord, Suppressions, Telemetry,
+    Templates, Tracking,
+};
 use error::EmailHandlerError;
-use rusoto_core::Region;
-use rusoto_dynamodb::DynamoDbClient;
-use rusoto_sqs::{DeleteMessageBatchRequest, Sqs, SqsClient};
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{event, span, Level};
 use tracing_futures::Instrument;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
 
-const DYNAMO_TABLE: &str = "DYNAMO_TABLE";
-const QUEUE_URL: &str = "QUEUE_URL";
+/// What the function was invoked with, told apart by its shape.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum LambdaEvent {
+    /// Records delivered by an event source mapping.
+    Records {
+        #[serde(rename = "Records")]
+        records: Vec<EventRecord>,
+    },
+    /// An invocation naming one email to send now, such as
+    /// `aws lambda invoke --payload '{"email_id": "<email_id>"}'`.
+    Direct(DirectInvocation),
+}
 
-lazy_static! {
-    static ref DYNAMODB: DynamoDbClient = DynamoDbClient::new(Region::UsEast1);
-    static ref SQS: SqsClient = SqsClient::new(Region::UsEast1);
+/// Payload of a direct invocation.
+#[derive(Deserialize, Clone)]
+struct DirectInvocation {
+    /// Id of the email to send.
+    email_id: EmailId,
+    /// Transmit an email which has already been sent again rather than send a Pending email.
+    #[serde(default)]
+    resend: bool,
+    /// Tenant whose table holds the email, the default table when not given.
+    #[serde(default)]
+    tenant: Option<String>,
 }
 
+/// A record of the event, told apart by its shape: a change read from the DynamoDB Stream of the
+/// email table, an SNS notification, or an SQS message from the email queue. Every field of an
+/// SQS message is optional so it is tried last.
 #[derive(Deserialize, Clone)]
-struct SqsEvent {
-    #[serde(rename = "Records")]
-    records: Vec<MessageDef>,
+#[serde(untagged)]
+enum EventRecord {
+    DynamoDb(StreamRecord),
+    Sns(SnsRecordDef),
+    Sqs(MessageDef),
 }
 
 #[derive(Serialize, Clone)]
@@ -34,23 +76,420 @@ struct CustomOutput {
     message: String,
 }
 
+/// Clients created once per cold start and shared by every invocation.
+#[derive(Clone)]
+struct Services {
+    client_services: Arc<ClientServices>,
+    quarantine: Option<Arc<S3QuarantineStore>>,
+    queue_url: QueueUrl,
+    sqs: SqsClient,
+}
+
 type Error = Box<dyn std::error::Error + Sync + Send + 'static>;
 
-#[tokio::main]
+// A Lambda handles one event at a time so worker threads would only sit idle
+#[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let subscriber = tracing_subscriber::fmt()
-        .json()
-        .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc3339())
-        .finish();
+    // Spans are also exported when an OTLP endpoint is configured
+    let telemetry = Telemetry::from_env(env!("CARGO_PKG_NAME"))?.map(Arc::new);
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc_3339()),
+        )
+        .with(telemetry.as_ref().map(|telemetry| telemetry.layer()));
     let _guard = tracing::subscriber::set_global_default(subscriber);
-    lambda_runtime::run(lambda_runtime::handler_fn(handler)).await?;
+    // Settings are read from the file named by CONFIG_FILE, if any, then the environment
+    let config_file = env::var_os(CONFIG_FILE).map(PathBuf::from);
+    let config = load_config(config_file.as_deref())
+        .await
+        .map_err(config_error)?;
+    // Region and credentials are read from the Lambda environment, every call is bounded so a
+    // hung request can not outlast the visibility timeout of the messages being processed
+    let timeouts = CallTimeouts::new(
+        Duration::from_secs(config.connect_timeout),
+        Duration::from_secs(config.operation_timeout),
+    );
+    let aws_config = aws_config::defaults(BehaviorVersion::latest())
+        .timeout_config(timeouts.timeout_config())
+        .load()
+        .await;
+    // Every client acts as the assumed role, if any, so another account can be used
+    let assume_role = AssumeRole::from_config(&config);
+    let aws_config = match &assume_role {
+        Some(assume_role) => assume_role.apply(aws_config).await,
+        None => aws_config,
+    };
+    if let Some(region) = aws_config.region() {
+        config.check_region(region.as_ref()).map_err(config_error)?;
+    }
+    // Log the configuration as resolved from the file and environment, secrets are never included
+    event!(
+        Level::INFO,
+        alert_failure_rate = config.alert_failure_rate,
+        alert_topic_arn = ?config.alert_topic_arn,
+        alert_window = config.alert_window,
+        allow_domains = ?config.allow_domains,
+        archive_bcc = ?config.archive_bcc,
+        assume_role_arn = ?config.assume_role_arn,
+        assume_role_session_name = ?assume_role.as_ref().map(|role| &role.session_name),
+        attachment_max_bytes = config.attachment_max_bytes,
+        attachment_timeout = config.attachment_timeout,
+        cache_max_entries = config.cache_max_entries,
+        circuit_breaker_cooldown = config.circuit_breaker_cooldown,
+        circuit_breaker_threshold = ?config.circuit_breaker_threshold,
+        config_file = ?config_file,
+        connect_timeout = config.connect_timeout,
+        deny_domains = ?config.deny_domains,
+        dynamo_endpoint = ?config.dynamo_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+        dynamo_retry = ?config.dynamo_retry,
+        endpoint_url = %aws_config.endpoint_url().map(redact_url).unwrap_or_default(),
+        event_bus = ?config.event_bus,
+        failure_queue_url = ?config.failure_queue_url.as_ref().map(|url| redact_url(url.as_str())),
+        max_attempts = config.max_attempts,
+        max_message_age = ?config.max_message_age,
+        message_budget = ?config.message_budget,
+        metrics_format = %env::var(METRICS_FORMAT).unwrap_or_default(),
+        metrics_namespace = ?config.metrics_namespace,
+        mime_store = ?config.mime_store,
+        mime_store_encryption = ?config.mime_store_encryption,
+        operation_timeout = config.operation_timeout,
+        provider_retry = ?config.provider_retry,
+        quarantine_allow_fields = ?config.quarantine_allow_fields,
+        quarantine_store = ?config.quarantine_store,
+        queue_name = %config.queue_url.name(),
+        queue_url = %redact_url(config.queue_url.as_str()),
+        rate_limit = ?config.rate_limit,
+        rate_limit_max_delay = config.rate_limit_max_delay,
+        recipient_table = ?config.recipient_table,
+        redirect_to = ?config.redirect_to,
+        region = %aws_config.region().map(|r| r.as_ref()).unwrap_or_default(),
+        retention = ?config.retention,
+        sanitize_html = config.sanitize_html,
+        sending_lease = config.sending_lease,
+        sqs_endpoint = ?config.sqs_endpoint.as_ref().map(|url| redact_url(url.as_str())),
+        sqs_retry = ?config.sqs_retry,
+        suppression_cache_ttl = ?config.suppression_cache_ttl,
+        suppression_table = ?config.suppression_table,
+        table_name = %config.table_name,
+        template_source = ?config.template_source,
+        template_ttl = config.template_ttl,
+        tenants = ?config.tenants.iter().map(|(name, tenant)| format!("{}={}", name, tenant.table_name)).collect::<Vec<_>>(),
+        tracking_table = ?config.tracking_table,
+        tracking_url = ?config.tracking_url.as_ref().map(|url| redact_url(url.as_str())),
+        web_identity_token_file = ?config.web_identity_token_file,
+        "lambda init",
+    );
+    let dynamodb = DynamoDbClient::from_conf(
+        dynamodb_config(&aws_config, config.dynamo_endpoint.as_ref()).build(),
+    );
+    let s3 = S3Client::new(&aws_config);
+    let sqs = sqs_config(&aws_config, config.sqs_endpoint.as_ref());
+    // Pointers are sent again without a `Runner`, so the policy is set on the connection itself
+    let sqs = match config.sqs_retry {
+        Some(policy) => sqs.retry_config(policy.retry_config()),
+        None => sqs,
+    };
+    let sqs = SqsClient::from_conf(sqs.build());
+    // Templates are shared across invocations so loaded templates stay cached
+    let templates = config.template_source.clone().map(|source| {
+        Templates::new(
+            source,
+            Duration::from_secs(config.template_ttl),
+            dynamodb.clone(),
+            s3.clone(),
+        )
+        .with_cache_size(config.cache_max_entries)
+    });
+    let message_budget = config.message_budget.map(Duration::from_secs);
+    let http = HttpFetcher::new(
+        config.attachment_max_bytes,
+        Duration::from_secs(config.attachment_timeout),
+    )?;
+    let mime_store = config.mime_store.clone().map(|location| {
+        let mime_store = S3MimeStore::new(location, s3.clone());
+        match config.mime_store_encryption.clone() {
+            Some(encryption) => mime_store.with_encryption(encryption),
+            None => mime_store,
+        }
+    });
+    let quarantine = config.quarantine_store.clone().map(|location| {
+        let redaction = QuarantineRedaction::new(&config.quarantine_allow_fields);
+        Arc::new(S3QuarantineStore::new(location, redaction, s3.clone()))
+    });
+    let domains = DomainPolicy::new(&config.allow_domains, &config.deny_domains);
+    let domains = if domains.is_empty() {
+        None
+    } else {
+        Some(domains)
+    };
+    // The budget carries over between invocations handled by the same Lambda instance
+    let rate_limiter = config
+        .rate_limit
+        .as_ref()
+        .map(|limits| RateLimiter::new(limits, Duration::from_secs(config.rate_limit_max_delay)));
+    // The breaker stays open across invocations handled by the same Lambda instance
+    let circuit_breaker = config.circuit_breaker_threshold.map(|threshold| {
+        CircuitBreaker::new(
+            threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown),
+        )
+    });
+    // Embedded metrics reach CloudWatch through the function logs, needing no extra permissions
+    let metrics = match (
+        config.metrics_namespace.clone(),
+        env::var(METRICS_FORMAT).as_deref(),
+    ) {
+        (Some(namespace), Ok("emf")) | (Some(namespace), Err(_)) => {
+            Some(Arc::new(Metrics::default().with_embedded_format(namespace)))
+        }
+        (Some(namespace), Ok("api")) => Some(Arc::new(
+            Metrics::default().with_cloudwatch(namespace, CloudWatchClient::new(&aws_config)),
+        )),
+        (Some(_), Ok(_)) => return Err("METRICS_FORMAT is not one of emf or api".into()),
+        (None, _) => None,
+    };
+    let alerts = config.alert_topic_arn.clone().map(|topic_arn| {
+        Alerts::new(
+            topic_arn,
+            config.alert_failure_rate,
+            Duration::from_secs(config.alert_window),
+            SnsClient::new(&aws_config),
+        )
+    });
+    let event_bus = config
+        .event_bus
+        .clone()
+        .map(|bus_name| EventBus::new(bus_name, EventBridgeClient::new(&aws_config)));
+    let failure_queue = config
+        .failure_queue_url
+        .clone()
+        .map(|queue_url| FailureQueue::new(queue_url, config.max_attempts, sqs.clone()));
+    // An invalid sandbox address fails the cold start rather than mailing real recipients
+    let redirect_to = match config.redirect_to {
+        Some(address) => Some(normalize_address(&address).ok_or("REDIRECT_TO is not valid")?),
+        None => None,
+    };
+    // Suppression lookups stay cached across invocations handled by the same Lambda instance
+    let cache_max_entries = config.cache_max_entries;
+    let suppression_cache_ttl = config.suppression_cache_ttl.map(Duration::from_secs);
+    let suppressions = config.suppression_table.map(|table_name| {
+        let suppressions = Suppressions::new(dynamodb.clone(), table_name);
+        match suppression_cache_ttl {
+            Some(ttl) => suppressions.with_cache(ttl, cache_max_entries),
+            None => suppressions,
+        }
+    });
+    let tracking = match (config.tracking_url, config.tracking_table) {
+        (Some(url), Some(table_name)) => Some(Tracking::new(dynamodb.clone(), table_name, url)),
+        (Some(_), None) => return Err("TRACKING_URL requires TRACKING_TABLE".into()),
+        (None, _) => None,
+    };
+    let client_services = ClientServices {
+        alerts,
+        archive_bcc: config.archive_bcc,
+        attachments: AttachmentFetcher::new(s3).with_http(http),
+        circuit_breaker,
+        domains,
+        dynamodb,
+        dynamo_retry: config.dynamo_retry,
+        event_bus,
+        failure_queue,
+        max_age: config.max_message_age,
+        message_budget,
+        metrics,
+        mime_store,
+        provider_retry: config.provider_retry,
+        rate_limiter,
+        recipient_table: config.recipient_table,
+        redirect_to,
+        retention: config.retention.map(Duration::from_secs),
+        sanitize_html: config.sanitize_html,
+        sending_lease: Duration::from_secs(config.sending_lease),
+        sqs_retry: config.sqs_retry,
+        suppressions,
+        table_name: config.table_name,
+        templates,
+        tenants: config.tenants,
+        tracking,
+    };
+    let services = Services {
+        client_services: Arc::new(client_services),
+        quarantine,
+        queue_url: config.queue_url,
+        sqs,
+    };
+    lambda_runtime::run(lambda_runtime::handler_fn(move |event, context| {
+        let services = services.clone();
+        let telemetry = telemetry.clone();
+        async move {
+            let result = handler(event, context, services).await;
+            // The function may be frozen once it returns so spans are not left for a later batch
+            if let Some(telemetry) = &telemetry {
+                if let Err(error) = telemetry.flush() {
+                    event!(Level::WARN, %error, "telemetry flush failed");
+                }
+            }
+            result
+        }
+    }))
+    .await?;
     Ok(())
 }
 
+/// Read settings from `config_file`, if any, then the environment. Settings referring to SSM
+/// Parameter Store or Secrets Manager are read from there, only connecting to AWS when one does.
+async fn load_config(config_file: Option<&Path>) -> Result<Config, ConfigError> {
+    let sources = ConfigSources::new(config_file)?;
+    if sources.secret_references()?.is_empty() {
+        return sources.extract();
+    }
+    let aws_config = aws_config::defaults(BehaviorVersion::latest())
+        .timeout_config(CallTimeouts::default().timeout_config())
+        .load()
+        .await;
+    let secrets = Secrets::new(
+        SsmClient::new(&aws_config),
+        SecretsManagerClient::new(&aws_config),
+    );
+    sources.resolve(&secrets).await?.extract()
+}
+
+/// Log the missing or invalid setting of `error` so a failed start explains itself in the
+/// function logs.
+fn config_error(error: ConfigError) -> Error {
+    event!(Level::ERROR, %error, reason = %error.explain(), "config invalid");
+    error.into()
+}
+
+/// Send a pointer for each email the stream `changes` inserted as Pending. The whole batch is
+/// delivered again when any pointer could not be sent.
+async fn enqueue_inserted(
+    changes: &[StreamRecord],
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+) -> Result<CustomOutput, EmailHandlerError> {
+    let report = StreamEnqueuer::new(queue_url, sqs)
+        .run_once(changes)
+        .in_current_span()
+        .await;
+    if report.is_complete() {
+        event!(Level::INFO, ?report, "success");
+        Ok(CustomOutput {
+            message: format!("Goodbye {:?}", &report),
+        })
+    } else {
+        event!(Level::INFO, ?report, "partial failure");
+        Err(if report.enqueued > 0 {
+            EmailHandlerError::PartialBatchFailure
+        } else {
+            EmailHandlerError::BatchFailure
+        })
+    }
+}
+
+/// Publish the counters of this invocation, if metrics are enabled.
+async fn publish_metrics(metrics: Option<&Metrics>) {
+    if let Some(metrics) = metrics {
+        if let Err(error) = metrics.publish().await {
+            event!(Level::WARN, %error, "publish metrics failed");
+        }
+    }
+}
+
+/// Process SNS `notifications` delivered straight to the function. A notification can not be
+/// hidden and delivered again like a queued message, so the email of each one which must be
+/// retried or is scheduled later is handed to the queue with a pointer, delayed until it is due.
+/// Notifications which can never be processed are kept in the `quarantine` store when there is
+/// one. The invocation fails, for SNS to deliver it again, when a pointer could not be sent or a
+/// notification could not be quarantined.
+async fn process_notifications(
+    client: &Client<'_>,
+    notifications: Vec<Message>,
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+    quarantine: Option<&S3QuarantineStore>,
+) -> Result<CustomOutput, EmailHandlerError> {
+    let outcome = client.process_messages(notifications).await;
+    let mut failed = 0;
+    for (message, error) in &outcome.quarantine {
+        event!(Level::ERROR, message_id = ?message.message_id, %error, "quarantine message");
+        if let Some(store) = quarantine {
+            match store.put(message, error).await {
+                Ok(key) => event!(Level::INFO, %key, "quarantined message stored"),
+                Err(error) => {
+                    event!(Level::ERROR, %error, "store quarantined message failed");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    let handed_off = outcome.retry.iter().map(|pointer| (pointer, None)).chain(
+        outcome
+            .defer
+            .iter()
+            .map(|(pointer, delay)| (pointer, Some(*delay))),
+    );
+    for (pointer, delay) in handed_off {
+        let email_id = &pointer.email_id;
+        match forward_pointer(queue_url, sqs, pointer, delay).await {
+            Ok(_) => event!(Level::INFO, %email_id, "email handed to queue"),
+            Err(error) => {
+                event!(Level::ERROR, %email_id, error = %DisplayErrorContext(&error), "email pointer not sent");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        event!(Level::INFO, ?outcome, "partial failure");
+        return Err(EmailHandlerError::BatchFailure);
+    }
+    event!(Level::INFO, ?outcome, "success");
+    Ok(CustomOutput {
+        message: format!("Goodbye {:?}", &outcome),
+    })
+}
+
+/// Send, or with `resend` transmit again, the email named by a direct `invocation` before
+/// returning, so the caller learns whether it was sent.
+async fn send_direct(
+    client: &Client<'_>,
+    invocation: &DirectInvocation,
+) -> Result<CustomOutput, EmailHandlerError> {
+    let email_id = invocation.email_id.as_str();
+    let tenant = invocation.tenant.as_deref();
+    let sent = if invocation.resend {
+        client.resend(email_id, false, tenant).await
+    } else {
+        client.send_now(email_id, tenant).await.map(|_| ())
+    };
+    match sent {
+        Ok(()) => {
+            event!(Level::INFO, %email_id, resend = invocation.resend, "direct send complete");
+            Ok(CustomOutput {
+                message: format!("Sent {}", email_id),
+            })
+        }
+        Err(error) => {
+            event!(Level::ERROR, %email_id, %error, "direct send failed");
+            Err(EmailHandlerError::DirectSendFailed)
+        }
+    }
+}
+
 async fn handler(
-    event: SqsEvent,
+    event: LambdaEvent,
     context: lambda_runtime::Context,
+    services: Services,
 ) -> Result<CustomOutput, EmailHandlerError> {
+    let Services {
+        client_services,
+        quarantine,
+        queue_url,
+        sqs,
+    } = services;
     let handler_span = span!(
         Level::INFO,
         env!("CARGO_PKG_NAME"),
@@ -58,41 +497,317 @@ async fn handler(
         ARN = %context.invoked_function_arn,
     );
     let _handler_guard = handler_span.enter();
-    // Read dynamo db table name from config or environment
-    let table_name = env::var(DYNAMO_TABLE)?;
-    // Read queue url from config or environment
-    let queue_url = env::var(QUEUE_URL)?;
-    // Get the number of records received for comparison later
-    let record_count = event.records.len();
+    let (records, direct) = match event {
+        LambdaEvent::Records { records } => (records, None),
+        LambdaEvent::Direct(invocation) => (Vec::new(), Some(invocation)),
+    };
+    let mut messages = Vec::new();
+    let mut changes = Vec::new();
+    let mut notifications = Vec::new();
+    for record in records {
+        match record {
+            EventRecord::DynamoDb(change) => changes.push(change),
+            EventRecord::Sns(notification) => notifications.push(notification.into()),
+            EventRecord::Sqs(message) => messages.push(message.into()),
+        }
+    }
+    // An event source mapping on the table stream delivers only changes, never messages
+    if !changes.is_empty() {
+        return enqueue_inserted(&changes, &queue_url, &sqs)
+            .in_current_span()
+            .await;
+    }
     // Create a shared processing client
-    let client = Client::new(&DYNAMODB, &table_name);
-    // Process each event record
-    let entries_to_delete = client
-        .process_messages(event.records.into_iter().map(|record| record.into()))
+    let metrics = client_services.metrics.as_deref();
+    let client = client_services.client();
+    if !notifications.is_empty() {
+        let result = process_notifications(
+            &client,
+            notifications,
+            &queue_url,
+            &sqs,
+            quarantine.as_deref(),
+        )
         .in_current_span()
         .await;
-    // Compare the number of messages to be deleted with the number received
-    let entries_to_delete_count = entries_to_delete.len();
-    if record_count == entries_to_delete_count {
-        event!(Level::INFO, ?entries_to_delete, "success");
+        publish_metrics(metrics).in_current_span().await;
+        return result;
+    }
+    if let Some(invocation) = &direct {
+        let result = send_direct(&client, invocation).in_current_span().await;
+        publish_metrics(metrics).in_current_span().await;
+        return result;
+    }
+    let runner = Runner::new(client, &queue_url, &sqs);
+    let runner = match &quarantine {
+        Some(store) => runner.with_quarantine_store(store),
+        None => runner,
+    };
+    // Process each event record, deleting processed messages if any failed
+    let mut source = EventBatch::new(messages);
+    let report = runner.run_once(&mut source).in_current_span().await;
+    publish_metrics(metrics).in_current_span().await;
+    if report.is_complete() {
+        event!(Level::INFO, ?report, "success");
         Ok(CustomOutput {
-            message: format!("Goodbye {:?}", &entries_to_delete),
+            message: format!("Goodbye {:?}", &report),
         })
     } else {
-        // Delete "processed" messages from SQS
-        event!(Level::INFO, ?entries_to_delete, "partial failure");
-        let delete_response = &SQS
-            .delete_message_batch(DeleteMessageBatchRequest {
-                entries: entries_to_delete,
-                queue_url,
-            })
-            .instrument(tracing::info_span!("delete_message_batch"))
-            .await;
-        let error = match delete_response {
-            Ok(_) if entries_to_delete_count > 0 => EmailHandlerError::PartialBatchFailure,
-            Ok(_) => EmailHandlerError::BatchFailure,
-            Err(_) => EmailHandlerError::SqsDeleteFailed,
+        event!(Level::INFO, ?report, "partial failure");
+        let error = match report.delete {
+            DeleteOutcome::Failed(_) => EmailHandlerError::SqsDeleteFailed,
+            _ if report.processed > 0 => EmailHandlerError::PartialBatchFailure,
+            _ => EmailHandlerError::BatchFailure,
         };
         Err(error)
     }
 }
+
+#[cfg(test)]
+mod lambda_event {
+    use super::*;
+
+    /// Records of `json`, which must be an event delivered by an event source mapping.
+    fn records(json: &str) -> Vec<EventRecord> {
+        match serde_json::from_str(json).unwrap() {
+            LambdaEvent::Records { records } => records,
+            LambdaEvent::Direct(_) => panic!("parsed as a direct invocation"),
+        }
+    }
+
+    #[test]
+    fn parses_direct_invocations() {
+        let event = serde_json::from_str(r#"{"email_id":"Test EmailId","resend":true}"#).unwrap();
+        match event {
+            LambdaEvent::Direct(invocation) => {
+                assert_eq!(invocation.email_id.as_str(), "Test EmailId");
+                assert!(invocation.resend);
+                assert_eq!(invocation.tenant, None);
+            }
+            LambdaEvent::Records { .. } => panic!("parsed as records"),
+        }
+    }
+
+    #[test]
+    fn parses_sqs_batches() {
+        let records = records(
+            r#"{"Records":[{
+                "messageId":"Test MessageId",
+                "receiptHandle":"Test ReceiptHandle",
+                "body":"{\"email_id\":\"Test EmailId\"}",
+                "attributes":{"ApproximateReceiveCount":"1"},
+                "messageAttributes":{},
+                "md5OfBody":"0",
+                "eventSource":"aws:sqs",
+                "eventSourceARN":"arn:aws:sqs:us-east-1:000000000000:emails",
+                "awsRegion":"us-east-1"
+            }]}"#,
+        );
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], EventRecord::Sqs(message)
+            if message.message_id.as_deref() == Some("Test MessageId")));
+    }
+
+    #[test]
+    fn parses_dynamodb_stream_batches() {
+        let records = records(
+            r#"{"Records":[{
+                "eventID":"1",
+                "eventName":"INSERT",
+                "eventSource":"aws:dynamodb",
+                "awsRegion":"us-east-1",
+                "dynamodb":{
+                    "NewImage":{"EmailId":{"S":"Test EmailId"},"EmailStatus":{"S":"Pending"}},
+                    "SequenceNumber":"100",
+                    "StreamViewType":"NEW_IMAGE"
+                }
+            }]}"#,
+        );
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], EventRecord::DynamoDb(change)
+            if change.event_name == "INSERT"));
+    }
+
+    #[test]
+    fn parses_sns_batches() {
+        let records = records(
+            r#"{"Records":[{
+                "EventSource":"aws:sns",
+                "EventVersion":"1.0",
+                "EventSubscriptionArn":"arn:aws:sns:us-east-1:000000000000:emails:1",
+                "Sns":{
+                    "Type":"Notification",
+                    "MessageId":"Test MessageId",
+                    "TopicArn":"arn:aws:sns:us-east-1:000000000000:emails",
+                    "Message":"{\"email_id\":\"Test EmailId\"}",
+                    "MessageAttributes":{}
+                }
+            }]}"#,
+        );
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], EventRecord::Sns(record)
+            if record.sns.message_id == "Test MessageId"));
+    }
+}
+
+#[cfg(test)]
+mod process_notifications {
+    use super::*;
+    use email_shared::test_support::{email, InMemoryDynamoDb, InMemorySqs};
+    use email_shared::{EmailMessage, EmailStatus, MimeStoreLocation};
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/emails";
+
+    /// A notification as SNS delivers it, carrying `body`.
+    fn notification(body: &str) -> Message {
+        let record = serde_json::json!({
+            "Sns": { "MessageId": "Test MessageId", "Message": body }
+        });
+        serde_json::from_value::<SnsRecordDef>(record)
+            .unwrap()
+            .into()
+    }
+
+    /// Process `notifications` for `table` handing pointers to `sqs`.
+    async fn process(
+        table: &InMemoryDynamoDb,
+        sqs: &InMemorySqs,
+        notifications: Vec<Message>,
+        quarantine: Option<&S3QuarantineStore>,
+    ) -> Result<CustomOutput, EmailHandlerError> {
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let queue_url = QUEUE_URL.parse::<QueueUrl>().unwrap();
+        process_notifications(
+            &client,
+            notifications,
+            &queue_url,
+            &sqs.client(),
+            quarantine,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn hands_retried_pointers_to_the_queue() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let sqs = InMemorySqs::default();
+        // No provider answers in tests so the email is released to be retried
+        let notifications = vec![notification(r#"{"email_id":"Test EmailId"}"#)];
+        assert!(process(&table, &sqs, notifications, None).await.is_ok());
+        let requests = sqs.requests("SendMessage");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["DelaySeconds"], serde_json::Value::Null);
+        let messages = sqs.queue().receive();
+        assert_eq!(messages[0].body(), Some(r#"{"email_id":"Test EmailId"}"#));
+    }
+
+    #[tokio::test]
+    async fn delays_deferred_pointers() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            scheduled_at: Some("2999-01-01T00:00:00Z".into()),
+            ..email("Test EmailId", EmailStatus::Pending)
+        });
+        let sqs = InMemorySqs::default();
+        let notifications = vec![notification(r#"{"email_id":"Test EmailId"}"#)];
+        assert!(process(&table, &sqs, notifications, None).await.is_ok());
+        let requests = sqs.requests("SendMessage");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["DelaySeconds"], 900);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+    }
+
+    #[tokio::test]
+    async fn drops_malformed_notifications_without_a_store() {
+        let table = InMemoryDynamoDb::default();
+        let sqs = InMemorySqs::default();
+        let notifications = vec![notification("not a pointer")];
+        assert!(process(&table, &sqs, notifications, None).await.is_ok());
+        assert!(sqs.queue().is_empty());
+        assert_eq!(table.calls("GetItem"), 0);
+    }
+
+    #[tokio::test]
+    async fn fails_when_malformed_notifications_are_not_stored() {
+        let table = InMemoryDynamoDb::default();
+        let sqs = InMemorySqs::default();
+        // With no region configured every call to S3 fails
+        let s3 = S3Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let location = "s3://quarantine/emails/"
+            .parse::<MimeStoreLocation>()
+            .unwrap();
+        let store = S3QuarantineStore::new(location, QuarantineRedaction::new(&[] as &[&str]), s3);
+        let notifications = vec![notification("not a pointer")];
+        let result = process(&table, &sqs, notifications, Some(&store)).await;
+        assert!(matches!(result, Err(EmailHandlerError::BatchFailure)));
+        assert!(sqs.queue().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod enqueue_inserted {
+    use super::*;
+    use email_shared::test_support::InMemorySqs;
+
+    /// A change of kind `event_name` to an email left with `status`.
+    fn change(event_name: &str, email_id: &str, status: &str) -> StreamRecord {
+        serde_json::from_value(serde_json::json!({
+            "eventName": event_name,
+            "dynamodb": {
+                "NewImage": {
+                    "EmailId": { "S": email_id },
+                    "EmailStatus": { "S": status },
+                },
+                "SequenceNumber": "100",
+            },
+        }))
+        .unwrap()
+    }
+
+    async fn enqueue(sqs: &InMemorySqs, changes: &[StreamRecord]) -> bool {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        enqueue_inserted(changes, &queue_url, &sqs.client())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn enqueues_pending_inserts() {
+        let sqs = InMemorySqs::default();
+        assert!(enqueue(&sqs, &[change("INSERT", "Test EmailId", "Pending")]).await);
+        let messages = sqs.queue().receive();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body(), Some(r#"{"email_id":"Test EmailId"}"#));
+    }
+
+    #[tokio::test]
+    async fn ignores_inserts_not_pending() {
+        let sqs = InMemorySqs::default();
+        assert!(enqueue(&sqs, &[change("INSERT", "Test EmailId", "Sent")]).await);
+        assert!(sqs.queue().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ignores_modifications_and_removals() {
+        let sqs = InMemorySqs::default();
+        let changes = [
+            change("MODIFY", "Test EmailId", "Pending"),
+            serde_json::from_value(serde_json::json!({
+                "eventName": "REMOVE",
+                "dynamodb": { "SequenceNumber": "101" },
+            }))
+            .unwrap(),
+        ];
+        assert!(enqueue(&sqs, &changes).await);
+        assert!(sqs.queue().is_empty());
+        assert!(sqs.requests("SendMessage").is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::email_message::EmailId;
+use aws_sdk_sns::error::DisplayErrorContext;
+use aws_sdk_sns::Client as SnsClient;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+/// Attempts to send which must be made in a window before its failure rate is alerted on, so a
+/// single failure in a quiet window does not page anyone.
+pub const ALERT_MIN_ATTEMPTS: u32 = 10;
+/// Longest subject SNS accepts.
+const SUBJECT_MAX_CHARS: usize = 100;
+
+/// What is published to the alert topic, as JSON tagged with the kind of alert under `alert`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "alert")]
+pub enum Alert {
+    /// An email was marked `EmailStatus::Failed` and will not be sent.
+    EmailFailed {
+        /// Id of the email which failed.
+        email_id: EmailId,
+        /// Error the email failed with.
+        error: String,
+        /// When the email failed.
+        failed_at: String,
+    },
+    /// More of the attempts to send made in a window failed than allowed.
+    FailureRateExceeded {
+        /// Attempts to send made in the window so far.
+        attempts: u32,
+        /// Attempts in the window which failed.
+        failures: u32,
+        /// Length of the window in seconds.
+        window_seconds: u64,
+        /// Id of the email whose attempt failed last.
+        last_email_id: EmailId,
+        /// Error of the attempt which failed last.
+        last_error: String,
+        /// When the rate was exceeded.
+        detected_at: String,
+    },
+}
+
+impl Alert {
+    pub fn email_failed(email_id: &EmailId, error: &str) -> Self {
+        Alert::EmailFailed {
+            email_id: email_id.clone(),
+            error: error.to_owned(),
+            failed_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Single line summary of the alert, at most as long as SNS allows a subject to be.
+    pub fn subject(&self) -> String {
+        let subject = match self {
+            Alert::EmailFailed { email_id, .. } => format!("Email failed: {}", email_id),
+            Alert::FailureRateExceeded {
+                attempts,
+                failures,
+                window_seconds,
+                ..
+            } => format!(
+                "Email failure rate exceeded: {} of {} sends failed in {}s",
+                failures, attempts, window_seconds
+            ),
+        };
+        subject
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(SUBJECT_MAX_CHARS)
+            .collect()
+    }
+}
+
+/// Attempts counted in the current window.
+#[derive(Debug)]
+struct AlertWindow {
+    /// When the window started.
+    started_at: Instant,
+    attempts: u32,
+    failures: u32,
+    /// Whether the failure rate has already been alerted on in this window.
+    alerted: bool,
+}
+
+/// SNS topic on-call is paged through when an email permanently fails or when more than
+/// `failure_rate` of the attempts to send in a `window` fail, instead of a growing backlog being
+/// found later. The failure rate is alerted on at most once a window and only once
+/// `ALERT_MIN_ATTEMPTS` attempts have been made in it.
+///
+/// # Examples
+///
+/// ```
+/// use aws_sdk_sns::config::BehaviorVersion;
+/// use email_shared::Alerts;
+/// use std::time::Duration;
+///
+/// let sns = aws_sdk_sns::Client::from_conf(
+///     aws_sdk_sns::Config::builder()
+///         .behavior_version(BehaviorVersion::latest())
+///         .build(),
+/// );
+/// let topic_arn = "arn:aws:sns:us-east-1:000000000000:email-alerts";
+/// let alerts = Alerts::new(topic_arn, 0.5, Duration::from_secs(300), sns);
+/// assert_eq!(alerts.topic_arn(), topic_arn);
+/// ```
+#[derive(Debug)]
+pub struct Alerts {
+    /// Share of the attempts in a window which may fail before alerting.
+    failure_rate: f64,
+    /// Connection to SNS.
+    sns: SnsClient,
+    /// ARN of the topic alerts are published to.
+    topic_arn: String,
+    /// Length of each window attempts are counted over.
+    window: Duration,
+    state: Mutex<AlertWindow>,
+}
+
+impl Alerts {
+    pub fn new(
+        topic_arn: impl Into<String>,
+        failure_rate: f64,
+        window: Duration,
+        sns: SnsClient,
+    ) -> Self {
+        Alerts {
+            failure_rate: failure_rate.clamp(0.0, 1.0),
+            sns,
+            topic_arn: topic_arn.into(),
+            window,
+            state: Mutex::new(AlertWindow {
+                started_at: Instant::now(),
+                attempts: 0,
+                failures: 0,
+                alerted: false,
+            }),
+        }
+    }
+
+    /// ARN of the topic alerts are published to.
+    pub fn topic_arn(&self) -> &str {
+        &self.topic_arn
+    }
+
+    /// Record an attempt to send made at `now` which succeeded.
+    pub(crate) fn record_success(&self, now: Instant) {
+        self.lock_window(now).attempts += 1;
+    }
+
+    /// Record an attempt to send the email `email_id` made at `now` which failed with `error`.
+    /// Returns the alert to publish when this failure takes the window over the failure rate.
+    pub(crate) fn record_failure(
+        &self,
+        email_id: &EmailId,
+        error: &str,
+        now: Instant,
+    ) -> Option<Alert> {
+        let mut state = self.lock_window(now);
+        state.attempts += 1;
+        state.failures += 1;
+        let rate = f64::from(state.failures) / f64::from(state.attempts);
+        if state.alerted || state.attempts < ALERT_MIN_ATTEMPTS || rate <= self.failure_rate {
+            return None;
+        }
+        state.alerted = true;
+        event!(
+            Level::ERROR,
+            attempts = state.attempts,
+            failures = state.failures,
+            window_seconds = self.window.as_secs(),
+            "email failure rate exceeded"
+        );
+        Some(Alert::FailureRateExceeded {
+            attempts: state.attempts,
+            failures: state.failures,
+            window_seconds: self.window.as_secs(),
+            last_email_id: email_id.clone(),
+            last_error: error.to_owned(),
+            detected_at: Utc::now().to_rfc3339(),
+        })
+    }
+
+    /// Publish `alert` to the topic.
+    pub async fn publish(&self, alert: &Alert) -> Result<(), String> {
+        let message = serde_json::to_string(alert).map_err(|error| error.to_string())?;
+        self.sns
+            .publish()
+            .message(message)
+            .subject(alert.subject())
+            .topic_arn(&self.topic_arn)
+            .send()
+            .await
+            .map_err(|error| format!("{}", DisplayErrorContext(&error)))?;
+        event!(Level::INFO, subject = %alert.subject(), "alert published");
+        Ok(())
+    }
+
+    /// The window containing `now`, starting a new one when the current window has passed.
+    fn lock_window(&self, now: Instant) -> std::sync::MutexGuard<'_, AlertWindow> {
+        let mut state = self.state.lock().expect("Alerts lock poisoned");
+        if now.saturating_duration_since(state.started_at) >= self.window {
+            *state = AlertWindow {
+                started_at: now,
+                attempts: 0,
+                failures: 0,
+                alerted: false,
+            };
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod record_failure {
+    use super::*;
+    use aws_sdk_sns::config::BehaviorVersion;
+
+    fn alerts(failure_rate: f64) -> Alerts {
+        let sns = SnsClient::from_conf(
+            aws_sdk_sns::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        Alerts::new("alerts", failure_rate, Duration::from_secs(60), sns)
+    }
+
+    #[test]
+    fn alerts_once_a_window_above_failure_rate() {
+        let alerts = alerts(0.5);
+        let email_id = EmailId::from("Test EmailId");
+        let now = Instant::now();
+        for _ in 0..5 {
+            alerts.record_success(now);
+        }
+        for _ in 0..5 {
+            assert_eq!(alerts.record_failure(&email_id, "Throttling", now), None);
+        }
+        let alert = alerts.record_failure(&email_id, "Throttling", now).unwrap();
+        match alert {
+            Alert::FailureRateExceeded {
+                attempts,
+                failures,
+                window_seconds,
+                last_email_id,
+                last_error,
+                ..
+            } => {
+                assert_eq!((attempts, failures, window_seconds), (11, 6, 60));
+                assert_eq!(last_email_id, email_id);
+                assert_eq!(last_error, "Throttling");
+            }
+            alert => panic!("unexpected alert {:?}", alert),
+        }
+        assert_eq!(alerts.record_failure(&email_id, "Throttling", now), None);
+    }
+
+    #[test]
+    fn waits_for_minimum_attempts_in_a_new_window() {
+        let alerts = alerts(0.0);
+        let email_id = EmailId::from("Test EmailId");
+        let now = Instant::now();
+        for _ in 1..ALERT_MIN_ATTEMPTS {
+            assert_eq!(alerts.record_failure(&email_id, "Throttling", now), None);
+        }
+        let later = now + Duration::from_secs(60);
+        for _ in 1..ALERT_MIN_ATTEMPTS {
+            assert_eq!(alerts.record_failure(&email_id, "Throttling", later), None);
+        }
+        assert!(alerts
+            .record_failure(&email_id, "Throttling", later)
+            .is_some());
+    }
+}
+
+#[cfg(test)]
+mod subject {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fits_sns_limits() {
+        let alert = Alert::email_failed(&EmailId::from("Test EmailId"), "Rejected");
+        assert_eq!(alert.subject(), "Email failed: Test EmailId");
+        let mut value = serde_json::to_value(&alert).unwrap();
+        value.as_object_mut().unwrap().remove("failed_at");
+        assert_eq!(
+            value,
+            json!({ "alert": "EmailFailed", "email_id": "Test EmailId", "error": "Rejected" })
+        );
+        let long = Alert::email_failed(&EmailId::from("x\n".repeat(100).as_str()), "Rejected");
+        let subject = long.subject();
+        assert_eq!(subject.chars().count(), SUBJECT_MAX_CHARS);
+        assert!(!subject.contains('\n'));
+    }
+}
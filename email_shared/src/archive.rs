@@ -0,0 +1,144 @@
+use crate::email_message::{EmailMessage, Recipient};
+use crate::email_message_builder::normalize_address;
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Possible errors while parsing an `ArchiveBcc`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ArchiveBccError {
+    /// An archival address is not a valid email address.
+    #[error("InvalidAddress({0})")]
+    InvalidAddress(String),
+    /// More than one rule without a category was given.
+    #[error("DuplicateDefault({0})")]
+    DuplicateDefault(String),
+}
+
+/// Archival address blind copied on every message sent, as compliance journaling requires.
+/// Emails with a `Category` are copied to the address configured for that category, falling
+/// back to the default address. Without an applicable address emails are sent unchanged.
+///
+/// Rules are separated by commas, a rule is either an address or `<category>=<address>`.
+///
+/// ```
+/// use email_shared::ArchiveBcc;
+///
+/// let archive: ArchiveBcc = "journal@example.com,billing=billing-journal@example.com"
+///     .parse()
+///     .unwrap();
+/// assert_eq!(archive.for_category(None), Some("journal@example.com"));
+/// assert_eq!(archive.for_category(Some("billing")), Some("billing-journal@example.com"));
+/// assert_eq!(archive.for_category(Some("otp")), Some("journal@example.com"));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ArchiveBcc {
+    /// Address copied on emails without a category, or with a category without its own address.
+    default: Option<Recipient>,
+    /// Address copied on emails of a category.
+    categories: HashMap<String, Recipient>,
+}
+
+impl ArchiveBcc {
+    /// Archival address of an email in `category`.
+    pub fn for_category(&self, category: Option<&str>) -> Option<&str> {
+        category
+            .and_then(|category| self.categories.get(category))
+            .or(self.default.as_ref())
+            .map(Recipient::address)
+    }
+
+    /// Add the archival address of `email` to its BCC recipients unless it is already a
+    /// recipient.
+    pub(crate) fn apply(&self, email: &mut EmailMessage) {
+        let address = match self.for_category(email.category.as_deref()) {
+            Some(address) => address,
+            None => return,
+        };
+        let is_recipient = email
+            .recipients_to
+            .iter()
+            .chain(email.recipients_cc.iter())
+            .chain(email.recipients_bcc.iter())
+            .any(|recipient| recipient.address().eq_ignore_ascii_case(address));
+        if !is_recipient {
+            email.recipients_bcc.push(address.into());
+        }
+    }
+}
+
+impl FromStr for ArchiveBcc {
+    type Err = ArchiveBccError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut archive = ArchiveBcc::default();
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule.find('=') {
+                Some(index) => {
+                    let address = parse_address(&rule[index + 1..])?;
+                    let category = rule[..index].trim().to_owned();
+                    archive.categories.insert(category, address);
+                }
+                None if archive.default.is_some() => {
+                    return Err(ArchiveBccError::DuplicateDefault(rule.into()));
+                }
+                None => archive.default = Some(parse_address(rule)?),
+            }
+        }
+        Ok(archive)
+    }
+}
+
+/// Normalize `s` as an email address.
+fn parse_address(s: &str) -> Result<Recipient, ArchiveBccError> {
+    normalize_address(s)
+        .map(Recipient::from)
+        .ok_or_else(|| ArchiveBccError::InvalidAddress(s.trim().into()))
+}
+
+#[cfg(test)]
+mod apply {
+    use super::*;
+
+    #[test]
+    fn adds_category_address_once() {
+        let archive: ArchiveBcc = "billing=Journal@Example.com".parse().unwrap();
+        let mut email = EmailMessage {
+            category: Some("billing".into()),
+            recipients_to: vec!["to@example.com".into()],
+            ..EmailMessage::default()
+        };
+        archive.apply(&mut email);
+        archive.apply(&mut email);
+        assert_eq!(email.recipients_bcc, vec!["Journal@example.com"]);
+        assert_eq!(email.recipients_to, vec!["to@example.com"]);
+    }
+
+    #[test]
+    fn leaves_uncovered_categories_unchanged() {
+        let archive: ArchiveBcc = "billing=journal@example.com".parse().unwrap();
+        let mut email = EmailMessage {
+            category: Some("otp".into()),
+            ..EmailMessage::default()
+        };
+        archive.apply(&mut email);
+        assert!(email.recipients_bcc.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(
+            "journal".parse::<ArchiveBcc>(),
+            Err(ArchiveBccError::InvalidAddress("journal".into()))
+        );
+        assert_eq!(
+            "a@example.com,b@example.com".parse::<ArchiveBcc>(),
+            Err(ArchiveBccError::DuplicateDefault("b@example.com".into()))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::Config;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use std::path::PathBuf;
+
+/// Name of the session a role is assumed with unless configured.
+pub const DEFAULT_SESSION_NAME: &str = "sqs-email-sender";
+
+/// IAM role assumed for every call to AWS, so the queues and tables of another account can be
+/// used. The role is assumed with the credentials the process already has, or with a web
+/// identity token such as the one EKS mounts for IAM roles for service accounts.
+///
+/// ```
+/// use email_shared::{AssumeRole, Config};
+/// use std::collections::HashMap;
+///
+/// let mut overrides = HashMap::new();
+/// overrides.insert("queue_url", "https://sqs.us-east-1.amazonaws.com/111111111111/emails");
+/// overrides.insert("table_name", "emails");
+/// overrides.insert("assume_role_arn", "arn:aws:iam::111111111111:role/emails");
+/// overrides.insert("assume_role_external_id", "example");
+/// let config = Config::load_with_overrides(None, &overrides).unwrap();
+/// let role = AssumeRole::from_config(&config).unwrap();
+/// assert_eq!(role.external_id.as_deref(), Some("example"));
+/// assert_eq!(role.source(), "assumed role");
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssumeRole {
+    /// ARN of the role assumed.
+    pub role_arn: String,
+    /// External ID required by the trust policy of the role, not used with a web identity.
+    pub external_id: Option<String>,
+    /// Name of the session recorded in CloudTrail.
+    pub session_name: String,
+    /// File holding a web identity token exchanged for credentials of the role.
+    pub web_identity_token_file: Option<PathBuf>,
+}
+
+impl AssumeRole {
+    /// The role configured by `config`, `None` when no role ARN is set.
+    pub fn from_config(config: &Config) -> Option<AssumeRole> {
+        let role_arn = config.assume_role_arn.clone()?;
+        Some(AssumeRole {
+            role_arn,
+            external_id: config.assume_role_external_id.clone(),
+            session_name: config
+                .assume_role_session_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_owned()),
+            web_identity_token_file: config.web_identity_token_file.clone(),
+        })
+    }
+
+    /// Describe where credentials come from without exposing them.
+    pub fn source(&self) -> &'static str {
+        match self.web_identity_token_file {
+            Some(_) => "assumed role with web identity",
+            None => "assumed role",
+        }
+    }
+
+    /// `sdk_config` with its credentials replaced by those of the role. Credentials are fetched
+    /// when first used and refreshed before they expire.
+    pub async fn apply(&self, sdk_config: SdkConfig) -> SdkConfig {
+        let provider = match &self.web_identity_token_file {
+            Some(token_file) => {
+                let provider_config =
+                    ProviderConfig::default().with_region(sdk_config.region().cloned());
+                let provider = WebIdentityTokenCredentialsProvider::builder()
+                    .configure(&provider_config)
+                    .static_configuration(StaticConfiguration {
+                        web_identity_token_file: token_file.clone(),
+                        role_arn: self.role_arn.clone(),
+                        session_name: self.session_name.clone(),
+                    })
+                    .build();
+                SharedCredentialsProvider::new(provider)
+            }
+            None => {
+                let mut builder = AssumeRoleProvider::builder(&self.role_arn)
+                    .configure(&sdk_config)
+                    .session_name(&self.session_name);
+                if let Some(external_id) = &self.external_id {
+                    builder = builder.external_id(external_id);
+                }
+                SharedCredentialsProvider::new(builder.build().await)
+            }
+        };
+        sdk_config
+            .into_builder()
+            .credentials_provider(provider)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod from_config {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(settings: &[(&str, &str)]) -> Config {
+        let mut overrides = settings.iter().cloned().collect::<HashMap<_, _>>();
+        overrides.insert(
+            "queue_url",
+            "https://sqs.us-east-1.amazonaws.com/111111111111/emails",
+        );
+        overrides.insert("table_name", "emails");
+        Config::load_with_overrides(None, &overrides).unwrap()
+    }
+
+    #[test]
+    fn is_none_without_role_arn() {
+        let config = config(&[("assume_role_external_id", "example")]);
+        assert_eq!(AssumeRole::from_config(&config), None);
+    }
+
+    #[test]
+    fn uses_web_identity_token_file() {
+        let config = config(&[
+            ("assume_role_arn", "arn:aws:iam::111111111111:role/emails"),
+            ("web_identity_token_file", "/var/run/secrets/token"),
+        ]);
+        let role = AssumeRole::from_config(&config).unwrap();
+        assert_eq!(role.session_name, DEFAULT_SESSION_NAME);
+        assert_eq!(
+            role.web_identity_token_file,
+            Some(PathBuf::from("/var/run/secrets/token"))
+        );
+        assert_eq!(role.source(), "assumed role with web identity");
+    }
+}
@@ -0,0 +1,504 @@
+use crate::config::redact_url;
+use crate::email_message::{EmailMessageAttachment, S3Object};
+use crate::mime::MimeAttachment;
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{event, Level};
+
+/// Possible errors while getting the contents of an attachment.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum AttachmentError {
+    /// The inline body of the attachment is not valid base64.
+    #[error("DecodeError({name}, {message})")]
+    DecodeError { name: String, message: String },
+    /// The S3 object changed since the attachment was recorded.
+    #[error("ETagMismatch({name}, expected {expected}, found {found})")]
+    ETagMismatch {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    /// The attachment could not be fetched from its URL.
+    #[error("FetchError({name}, {message})")]
+    FetchError { name: String, message: String },
+    /// The webserver responded to the request for the attachment with an unexpected status.
+    #[error("HttpStatus({name}, {status})")]
+    HttpStatus { name: String, status: u16 },
+    /// The attachment has no inline body, S3 object, or URL to read.
+    #[error("MissingContent({0})")]
+    MissingContent(String),
+    /// The S3 object of the attachment does not exist.
+    #[error("NotFound({bucket}/{key})")]
+    NotFound { bucket: String, key: String },
+    /// The size of the S3 object does not match the size recorded for the attachment.
+    #[error("SizeMismatch({name}, expected {expected}, found {found})")]
+    SizeMismatch {
+        name: String,
+        expected: i64,
+        found: i64,
+    },
+    /// The S3 object could not be read.
+    #[error("SourceError({0})")]
+    SourceError(String),
+    /// The attachment fetched from its URL is larger than allowed.
+    #[error("TooLarge({name}, limit {limit})")]
+    TooLarge { name: String, limit: u64 },
+}
+
+/// The contents of an attachment ready to be written into a message.
+#[derive(Debug)]
+pub struct AttachmentContent {
+    /// File name of the attachment.
+    pub name: String,
+    /// MIME type of the attachment.
+    pub content_type: String,
+    /// Raw, not base64 encoded, bytes of the attachment. S3 objects are streamed as they are read.
+    pub body: ByteStream,
+}
+
+impl AttachmentContent {
+    /// Read the whole body into memory so it can be encoded into a message.
+    pub async fn read(self) -> Result<MimeAttachment, AttachmentError> {
+        let body = self
+            .body
+            .collect()
+            .await
+            .map_err(|e| AttachmentError::SourceError(e.to_string()))?;
+        Ok(MimeAttachment {
+            name: self.name,
+            content_type: self.content_type,
+            body: body.into_bytes().to_vec(),
+        })
+    }
+}
+
+/// Get the contents of attachments whether stored inline, in S3, or on a webserver.
+#[derive(Clone, Debug)]
+pub struct AttachmentFetcher {
+    /// Fetcher for attachments with a URL, without one the inline body is used.
+    http: Option<HttpFetcher>,
+    /// Connection to S3.
+    s3: S3Client,
+}
+
+impl AttachmentFetcher {
+    pub fn new(s3: S3Client) -> Self {
+        AttachmentFetcher { http: None, s3 }
+    }
+
+    /// Fetch attachments which have a URL with `http`.
+    pub fn with_http(self, http: HttpFetcher) -> Self {
+        AttachmentFetcher {
+            http: Some(http),
+            ..self
+        }
+    }
+
+    /// Forget attachments fetched for the previous batch so changed files are fetched again.
+    pub fn start_batch(&self) {
+        if let Some(http) = &self.http {
+            http.clear();
+        }
+    }
+
+    /// Open the contents of every attachment of an email, in order.
+    pub async fn open_all(
+        &self,
+        attachments: &[EmailMessageAttachment],
+    ) -> Result<Vec<AttachmentContent>, AttachmentError> {
+        let mut contents = Vec::with_capacity(attachments.len());
+        for attachment in attachments {
+            contents.push(self.open(attachment).await?);
+        }
+        Ok(contents)
+    }
+
+    /// Open the contents of `attachment`. An S3 object is preferred over a URL, and a URL over an
+    /// inline body. Before the object is streamed its ETag and size are checked against those
+    /// recorded with the attachment so a changed object is never sent.
+    pub async fn open(
+        &self,
+        attachment: &EmailMessageAttachment,
+    ) -> Result<AttachmentContent, AttachmentError> {
+        let body = match (&attachment.s3_object, &attachment.url, &self.http) {
+            (Some(object), _, _) => self.open_s3(attachment, object).await?,
+            (None, Some(url), Some(http)) => http.fetch(attachment, url).await?,
+            _ if !attachment.body.is_empty() => decode_inline(attachment)?,
+            _ => return Err(AttachmentError::MissingContent(attachment.name.clone())),
+        };
+        Ok(AttachmentContent {
+            name: attachment.name.clone(),
+            content_type: attachment.content_type.clone(),
+            body,
+        })
+    }
+
+    /// Get the S3 object of `attachment` after verifying it is the recorded object.
+    async fn open_s3(
+        &self,
+        attachment: &EmailMessageAttachment,
+        object: &S3Object,
+    ) -> Result<ByteStream, AttachmentError> {
+        event!(Level::DEBUG, bucket = %object.bucket, key = %object.key, "get attachment");
+        let output = match self
+            .s3
+            .get_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(SdkError::ServiceError(context))
+                if matches!(context.err(), GetObjectError::NoSuchKey(_)) =>
+            {
+                return Err(AttachmentError::NotFound {
+                    bucket: object.bucket.clone(),
+                    key: object.key.clone(),
+                });
+            }
+            Err(error) => {
+                let message = format!("{}", DisplayErrorContext(&error));
+                return Err(AttachmentError::SourceError(message));
+            }
+        };
+        verify_e_tag(attachment, output.e_tag())?;
+        verify_size(attachment, output.content_length())?;
+        Ok(output.body)
+    }
+}
+
+/// A fetched file kept for the rest of a batch along with the validators it was fetched with.
+#[derive(Clone, Debug)]
+struct CachedBody {
+    /// ETag of the file, may be empty.
+    e_tag: String,
+    /// Last modified date of the file, may be empty.
+    last_modified: String,
+    /// Raw bytes of the file.
+    body: Vec<u8>,
+}
+
+/// Fetch attachments from webservers by URL. Requests are conditional on the ETag and last
+/// modified date of the inline body, or of an earlier response in the same batch, so an unchanged
+/// file is never transferred twice.
+#[derive(Clone, Debug)]
+pub struct HttpFetcher {
+    /// Files fetched during the current batch keyed by URL.
+    cache: Arc<Mutex<HashMap<String, CachedBody>>>,
+    /// Connection pool used for requests.
+    http: reqwest::Client,
+    /// Largest file, in bytes, which will be fetched.
+    max_bytes: u64,
+}
+
+impl HttpFetcher {
+    /// Create a fetcher refusing files larger than `max_bytes` and abandoning requests which take
+    /// longer than `timeout`.
+    pub fn new(max_bytes: u64, timeout: Duration) -> Result<Self, AttachmentError> {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| AttachmentError::FetchError {
+                name: String::new(),
+                message: e.to_string(),
+            })?;
+        Ok(HttpFetcher {
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            http,
+            max_bytes,
+        })
+    }
+
+    /// Forget every cached file.
+    fn clear(&self) {
+        self.cache.lock().expect("cache lock poisoned").clear();
+    }
+
+    /// Fetch the file at `url` for `attachment` unless the cached or inline copy is current.
+    async fn fetch(
+        &self,
+        attachment: &EmailMessageAttachment,
+        url: &str,
+    ) -> Result<ByteStream, AttachmentError> {
+        let name = attachment.name.as_str();
+        let cached = self
+            .cache
+            .lock()
+            .expect("cache lock poisoned")
+            .get(url)
+            .cloned();
+        let current = match cached {
+            Some(cached) => Some(cached),
+            None if !attachment.body.is_empty() => Some(CachedBody {
+                e_tag: attachment.e_tag.clone(),
+                last_modified: attachment.last_modified.clone(),
+                body: decode_body(attachment)?,
+            }),
+            None => None,
+        };
+        let mut request = self.http.get(url);
+        if let Some(current) = &current {
+            if !current.e_tag.is_empty() {
+                request = request.header(IF_NONE_MATCH, quote_e_tag(&current.e_tag));
+            }
+            if !current.last_modified.is_empty() {
+                request = request.header(IF_MODIFIED_SINCE, current.last_modified.as_str());
+            }
+        }
+        event!(Level::DEBUG, url = %redact_url(url), "fetch attachment");
+        let fetch_error = |e: reqwest::Error| AttachmentError::FetchError {
+            name: name.into(),
+            message: e.without_url().to_string(),
+        };
+        let response = request.send().await.map_err(fetch_error)?;
+        let fetched = match (response.status(), current) {
+            (StatusCode::NOT_MODIFIED, Some(current)) => current,
+            (status, _) if status.is_success() => CachedBody {
+                e_tag: header_value(&response, ETAG),
+                last_modified: header_value(&response, LAST_MODIFIED),
+                body: self.read_body(name, response).await?,
+            },
+            (status, _) => {
+                return Err(AttachmentError::HttpStatus {
+                    name: name.into(),
+                    status: status.as_u16(),
+                })
+            }
+        };
+        let body = fetched.body.clone();
+        self.cache
+            .lock()
+            .expect("cache lock poisoned")
+            .insert(url.into(), fetched);
+        Ok(ByteStream::from(body))
+    }
+
+    /// Read the body of `response` as long as it fits within `max_bytes`.
+    async fn read_body(
+        &self,
+        name: &str,
+        mut response: Response,
+    ) -> Result<Vec<u8>, AttachmentError> {
+        check_size(name, response.content_length().unwrap_or(0), self.max_bytes)?;
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| AttachmentError::FetchError {
+                name: name.into(),
+                message: e.without_url().to_string(),
+            })?
+        {
+            body.extend_from_slice(&chunk);
+            check_size(name, body.len() as u64, self.max_bytes)?;
+        }
+        Ok(body)
+    }
+}
+
+/// Get the value of the `name` header of `response`, or an empty string.
+fn header_value(response: &Response, name: reqwest::header::HeaderName) -> String {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .into()
+}
+
+/// Quote `e_tag` for an If-None-Match header unless it is already quoted or weak.
+fn quote_e_tag(e_tag: &str) -> String {
+    if e_tag.starts_with('"') || e_tag.starts_with("W/") {
+        e_tag.into()
+    } else {
+        format!("\"{}\"", e_tag)
+    }
+}
+
+/// Fail when `size` bytes of the attachment `name` exceed `limit`.
+fn check_size(name: &str, size: u64, limit: u64) -> Result<(), AttachmentError> {
+    if size > limit {
+        Err(AttachmentError::TooLarge {
+            name: name.into(),
+            limit,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Decode the base64 inline body of `attachment`.
+fn decode_inline(attachment: &EmailMessageAttachment) -> Result<ByteStream, AttachmentError> {
+    decode_body(attachment).map(ByteStream::from)
+}
+
+/// Decode the base64 inline body of `attachment` into raw bytes.
+fn decode_body(attachment: &EmailMessageAttachment) -> Result<Vec<u8>, AttachmentError> {
+    STANDARD
+        .decode(attachment.body.trim())
+        .map_err(|e| AttachmentError::DecodeError {
+            name: attachment.name.clone(),
+            message: e.to_string(),
+        })
+}
+
+/// Compare the recorded ETag of `attachment`, if any, with the ETag of the object. S3 quotes
+/// ETags so quotes are ignored.
+fn verify_e_tag(
+    attachment: &EmailMessageAttachment,
+    found: Option<&str>,
+) -> Result<(), AttachmentError> {
+    let expected = attachment.e_tag.trim_matches('"');
+    let found = found.unwrap_or_default().trim_matches('"');
+    if expected.is_empty() || expected == found {
+        Ok(())
+    } else {
+        Err(AttachmentError::ETagMismatch {
+            name: attachment.name.clone(),
+            expected: expected.into(),
+            found: found.into(),
+        })
+    }
+}
+
+/// Compare the recorded size of `attachment`, if any, with the size of the object.
+fn verify_size(
+    attachment: &EmailMessageAttachment,
+    found: Option<i64>,
+) -> Result<(), AttachmentError> {
+    let expected = i64::from(attachment.size);
+    match found {
+        Some(found) if expected > 0 && expected != found => Err(AttachmentError::SizeMismatch {
+            name: attachment.name.clone(),
+            expected,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod decode_inline {
+    use super::*;
+
+    #[tokio::test]
+    async fn decodes_base64() {
+        let attachment = EmailMessageAttachment {
+            body: "aGVsbG8=".into(),
+            name: "hello.txt".into(),
+            ..EmailMessageAttachment::default()
+        };
+        let bytes = decode_inline(&attachment).unwrap().collect().await.unwrap();
+        assert_eq!(bytes.into_bytes().as_ref(), b"hello");
+    }
+
+    #[test]
+    fn fails_on_invalid_base64() {
+        let attachment = EmailMessageAttachment {
+            body: "not base64!".into(),
+            name: "hello.txt".into(),
+            ..EmailMessageAttachment::default()
+        };
+        match decode_inline(&attachment) {
+            Err(AttachmentError::DecodeError { name, .. }) => assert_eq!(name, "hello.txt"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod verify {
+    use super::*;
+
+    fn attachment(e_tag: &str, size: i32) -> EmailMessageAttachment {
+        EmailMessageAttachment {
+            e_tag: e_tag.into(),
+            name: "report.pdf".into(),
+            size,
+            ..EmailMessageAttachment::default()
+        }
+    }
+
+    #[test]
+    fn ignores_quotes_on_e_tag() {
+        assert_eq!(verify_e_tag(&attachment("abc", 0), Some("\"abc\"")), Ok(()));
+    }
+
+    #[test]
+    fn skips_unrecorded_values() {
+        assert_eq!(verify_e_tag(&attachment("", 0), Some("\"abc\"")), Ok(()));
+        assert_eq!(verify_size(&attachment("", 0), Some(10)), Ok(()));
+    }
+
+    #[test]
+    fn fails_on_changed_object() {
+        assert_eq!(
+            verify_e_tag(&attachment("abc", 0), Some("\"def\"")),
+            Err(AttachmentError::ETagMismatch {
+                name: "report.pdf".into(),
+                expected: "abc".into(),
+                found: "def".into(),
+            })
+        );
+        assert_eq!(
+            verify_size(&attachment("", 5), Some(10)),
+            Err(AttachmentError::SizeMismatch {
+                name: "report.pdf".into(),
+                expected: 5,
+                found: 10,
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod http_fetcher {
+    use super::*;
+
+    #[test]
+    fn quotes_strong_e_tags() {
+        assert_eq!(quote_e_tag("abc"), "\"abc\"");
+        assert_eq!(quote_e_tag("\"abc\""), "\"abc\"");
+        assert_eq!(quote_e_tag("W/\"abc\""), "W/\"abc\"");
+    }
+
+    #[test]
+    fn limits_size() {
+        assert_eq!(check_size("report.pdf", 10, 10), Ok(()));
+        assert_eq!(
+            check_size("report.pdf", 11, 10),
+            Err(AttachmentError::TooLarge {
+                name: "report.pdf".into(),
+                limit: 10,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn uses_inline_body_without_fetcher() {
+        let attachment = EmailMessageAttachment {
+            body: "aGVsbG8=".into(),
+            name: "hello.txt".into(),
+            url: Some("https://example.com/hello.txt".into()),
+            ..EmailMessageAttachment::default()
+        };
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .build();
+        let fetcher = AttachmentFetcher::new(S3Client::from_conf(config));
+        let content = fetcher.open(&attachment).await.unwrap();
+        let bytes = content.body.collect().await.unwrap();
+        assert_eq!(bytes.into_bytes().as_ref(), b"hello");
+    }
+}
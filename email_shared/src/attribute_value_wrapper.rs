@@ -1,4 +1,7 @@
-use rusoto_dynamodb::AttributeValue;
+use crate::dynamo::from_hashmap;
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use std::collections::HashMap;
 
 pub struct AttributeValueMap {}
@@ -14,18 +17,12 @@ impl AttributeValueMap {
     /// use email_shared::attribute_value_wrapper::AttributeValueMap;
     ///
     /// let item = AttributeValueMap::with_entry("foo", "bar".into());
-    /// assert!(item.get("foo").unwrap().s == Some("bar".into()));
+    /// assert!(item.get("foo").unwrap().as_s() == Ok(&"bar".to_owned()));
     /// assert!(item.get("other_foo") == None);
     /// ```
     pub fn with_entry(key: &str, value: String) -> HashMap<String, AttributeValue> {
         let mut attrs = HashMap::new();
-        attrs.insert(
-            key.into(),
-            AttributeValue {
-                s: Some(value),
-                ..AttributeValue::default()
-            },
-        );
+        attrs.insert(key.into(), AttributeValue::S(value));
         attrs
     }
 
@@ -41,8 +38,8 @@ impl AttributeValueMap {
     ///     (":expected".into(), "bar".into()),
     ///     (":next".into(), "Test Next".into()),
     /// ]);
-    /// assert!(item.get(":expected").unwrap().s == Some("bar".into()));
-    /// assert!(item.get(":next").unwrap().s == Some("Test Next".into()));
+    /// assert!(item.get(":expected").unwrap().as_s() == Ok(&"bar".to_owned()));
+    /// assert!(item.get(":next").unwrap().as_s() == Ok(&"Test Next".to_owned()));
     /// assert!(item.get("other_foo") == None);
     /// ```
     pub fn with_entries<I>(entries: I) -> HashMap<String, AttributeValue>
@@ -51,26 +48,30 @@ impl AttributeValueMap {
     {
         let mut attrs = HashMap::new();
         for (key, value) in entries {
-            attrs.insert(
-                key,
-                AttributeValue {
-                    s: Some(value),
-                    ..AttributeValue::default()
-                },
-            );
+            attrs.insert(key, AttributeValue::S(value));
         }
         attrs
     }
 }
 
-/// Wrap the `item` representation provided by `rusoto_dynamodb::GetItemOutput` in order to more
+/// Key under which `DynamoItemWrapper::get_as` deserializes a single attribute.
+const ATTRIBUTE: &str = "Attribute";
+
+/// A single attribute deserialized by `DynamoItemWrapper::get_as`.
+#[derive(Deserialize)]
+struct Attribute<T> {
+    #[serde(rename = "Attribute")]
+    value: T,
+}
+
+/// Wrap the `item` representation provided by `aws_sdk_dynamodb::operation::get_item::GetItemOutput` in order to more
 /// conveniently access the properties of an `AttributeValue` hiddent behind an arbitrary `&str`
 /// key.
 ///
 /// # Examples
 ///
 /// ```
-/// use rusoto_dynamodb::AttributeValue;
+/// use aws_sdk_dynamodb::types::AttributeValue;
 /// use std::collections::HashMap;
 /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
 ///
@@ -91,7 +92,7 @@ impl DynamoItemWrapper {
     /// # Examples
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
@@ -109,24 +110,18 @@ impl DynamoItemWrapper {
     /// # Examples
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
     /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
-    /// item.insert(
-    ///     "foo".into(),
-    ///     AttributeValue {
-    ///         s: Some("My String".into()),
-    ///         ..AttributeValue::default()
-    ///     }
-    /// );
+    /// item.insert("foo".into(), AttributeValue::S("My String".into()));
     /// let wrapper = DynamoItemWrapper::new(item);
     /// assert!(wrapper.s("foo", "bar") == Ok("My String".into()));
     /// ```
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
@@ -136,17 +131,21 @@ impl DynamoItemWrapper {
     /// ```
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
     /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
-    /// item.insert("foo".into(), AttributeValue::default());
+    /// item.insert("foo".into(), AttributeValue::Null(true));
     /// let wrapper = DynamoItemWrapper::new(item);
     /// assert!(wrapper.s("foo", "bar") == Err("bar"));
     /// ```
     pub fn s<E>(&self, key: &str, error: E) -> Result<String, E> {
-        self.item.get(key).and_then(|av| av.s.clone()).ok_or(error)
+        self.item
+            .get(key)
+            .and_then(|av| av.as_s().ok())
+            .cloned()
+            .ok_or(error)
     }
 
     /// Try to retrieve an `AttributeValue` for `key` and then try to get the number value from the
@@ -159,18 +158,12 @@ impl DynamoItemWrapper {
     /// ## Get a numeric value
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
     /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
-    /// item.insert(
-    ///     "foo".into(),
-    ///     AttributeValue {
-    ///         n: Some("123.45".into()),
-    ///         ..AttributeValue::default()
-    ///     }
-    /// );
+    /// item.insert("foo".into(), AttributeValue::N("123.45".into()));
     /// let wrapper = DynamoItemWrapper::new(item);
     /// assert!(wrapper.n("foo", "bar") == Ok("123.45".into()));
     /// ```
@@ -178,7 +171,7 @@ impl DynamoItemWrapper {
     /// ## `Err` for non-existant attribute
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
@@ -187,20 +180,202 @@ impl DynamoItemWrapper {
     /// assert!(wrapper.n("foo", "bar") == Err("bar"));
     /// ```
     ///
-    /// ## `Err` for empty number
+    /// ## `Err` for non-number attribute
     ///
     /// ```
-    /// use rusoto_dynamodb::AttributeValue;
+    /// use aws_sdk_dynamodb::types::AttributeValue;
     /// use std::collections::HashMap;
     /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
     ///
     /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
-    /// item.insert("foo".into(), AttributeValue::default());
+    /// item.insert("foo".into(), AttributeValue::Null(true));
     /// let wrapper = DynamoItemWrapper::new(item);
     /// assert!(wrapper.n("foo", "bar") == Err("bar"));
     /// ```
     pub fn n<E>(&self, key: &str, error: E) -> Result<String, E> {
-        self.item.get(key).and_then(|av| av.n.clone()).ok_or(error)
+        self.item
+            .get(key)
+            .and_then(|av| av.as_n().ok())
+            .cloned()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the boolean value from
+    /// the associated `AttributeValue`. If either fails provide the given `error`. Named `bool_`
+    /// because `bool` is the name of the type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::Bool(true));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.bool_("foo", "bar") == Ok(true));
+    /// assert!(wrapper.bool_("other_foo", "bar") == Err("bar"));
+    /// ```
+    pub fn bool_<E>(&self, key: &str, error: E) -> Result<bool, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_bool().ok())
+            .copied()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the string set value
+    /// from the associated `AttributeValue`. If either fails provide the given `error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::Ss(vec!["a".into(), "b".into()]));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.ss("foo", "bar") == Ok(vec!["a".into(), "b".into()]));
+    /// assert!(wrapper.ns("foo", "bar") == Err("bar"));
+    /// ```
+    pub fn ss<E>(&self, key: &str, error: E) -> Result<Vec<String>, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_ss().ok())
+            .cloned()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the number set value
+    /// from the associated `AttributeValue`. If either fails provide the given `error`. As with
+    /// `n` each number is held as a `String`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::Ns(vec!["1".into(), "2.5".into()]));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.ns("foo", "bar") == Ok(vec!["1".into(), "2.5".into()]));
+    /// ```
+    pub fn ns<E>(&self, key: &str, error: E) -> Result<Vec<String>, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_ns().ok())
+            .cloned()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the list value from the
+    /// associated `AttributeValue`. If either fails provide the given `error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::L(vec![AttributeValue::Bool(false)]));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.l("foo", "bar") == Ok(vec![AttributeValue::Bool(false)]));
+    /// ```
+    pub fn l<E>(&self, key: &str, error: E) -> Result<Vec<AttributeValue>, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_l().ok())
+            .cloned()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the map value from the
+    /// associated `AttributeValue`. If either fails provide the given `error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::M(HashMap::new()));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.m("foo", "bar") == Ok(HashMap::new()));
+    /// ```
+    pub fn m<E>(&self, key: &str, error: E) -> Result<HashMap<String, AttributeValue>, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_m().ok())
+            .cloned()
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to get the bytes of the binary
+    /// value from the associated `AttributeValue`. If either fails provide the given `error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use aws_sdk_dynamodb::primitives::Blob;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert("foo".into(), AttributeValue::B(Blob::new("My Bytes")));
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.b("foo", "bar") == Ok(b"My Bytes".to_vec()));
+    /// ```
+    pub fn b<E>(&self, key: &str, error: E) -> Result<Vec<u8>, E> {
+        self.item
+            .get(key)
+            .and_then(|av| av.as_b().ok())
+            .map(|blob| blob.as_ref().to_vec())
+            .ok_or(error)
+    }
+
+    /// Try to retrieve an `AttributeValue` for `key` and then try to deserialize it as `T`, the
+    /// same way a whole item is read into an `EmailMessage`. If retrieving the `AttributeValue`
+    /// fails or it does not hold a `T` provide the given `error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aws_sdk_dynamodb::types::AttributeValue;
+    /// use std::collections::HashMap;
+    /// use email_shared::attribute_value_wrapper::DynamoItemWrapper;
+    ///
+    /// let mut item: HashMap<String, AttributeValue> = HashMap::new();
+    /// item.insert(
+    ///     "foo".into(),
+    ///     AttributeValue::L(vec![AttributeValue::N("1".into()), AttributeValue::N("2".into())]),
+    /// );
+    /// let wrapper = DynamoItemWrapper::new(item);
+    /// assert!(wrapper.get_as::<Vec<u32>, _>("foo", "bar") == Ok(vec![1, 2]));
+    /// assert!(wrapper.get_as::<String, _>("foo", "bar") == Err("bar"));
+    /// ```
+    pub fn get_as<T, E>(&self, key: &str, error: E) -> Result<T, E>
+    where
+        T: DeserializeOwned,
+    {
+        let value = match self.item.get(key) {
+            Some(value) => value.clone(),
+            None => return Err(error),
+        };
+        let mut attribute = HashMap::new();
+        attribute.insert(ATTRIBUTE.to_owned(), value);
+        from_hashmap::<Attribute<T>, _>(attribute)
+            .map(|attribute| attribute.value)
+            .map_err(|_| error)
     }
 }
 
@@ -226,10 +401,7 @@ mod s {
         let mut attributes = HashMap::new();
         attributes.insert(
             EMAIL_ID_KEY.into(),
-            AttributeValue {
-                n: Some(EMAIL_ID_VALUE.into()),
-                ..AttributeValue::default()
-            },
+            AttributeValue::N(EMAIL_ID_VALUE.into()),
         );
         let wrapper = DynamoItemWrapper::new(attributes);
         assert_eq!(wrapper.s(EMAIL_ID_KEY, ERROR_MSG), Err(ERROR_MSG));
@@ -240,10 +412,7 @@ mod s {
         let mut attributes = HashMap::new();
         attributes.insert(
             EMAIL_ID_KEY.into(),
-            AttributeValue {
-                s: Some(EMAIL_ID_VALUE.into()),
-                ..AttributeValue::default()
-            },
+            AttributeValue::S(EMAIL_ID_VALUE.into()),
         );
         let wrapper = DynamoItemWrapper::new(attributes);
         assert_eq!(
@@ -275,10 +444,7 @@ mod n {
         let mut attributes = HashMap::new();
         attributes.insert(
             EMAIL_ID_KEY.into(),
-            AttributeValue {
-                s: Some(EMAIL_ID_VALUE.into()),
-                ..AttributeValue::default()
-            },
+            AttributeValue::S(EMAIL_ID_VALUE.into()),
         );
         let wrapper = DynamoItemWrapper::new(attributes);
         assert_eq!(wrapper.n(EMAIL_ID_KEY, ERROR_MSG), Err(ERROR_MSG));
@@ -289,10 +455,7 @@ mod n {
         let mut attributes = HashMap::new();
         attributes.insert(
             EMAIL_ID_KEY.into(),
-            AttributeValue {
-                n: Some(EMAIL_ID_VALUE.into()),
-                ..AttributeValue::default()
-            },
+            AttributeValue::N(EMAIL_ID_VALUE.into()),
         );
         let wrapper = DynamoItemWrapper::new(attributes);
         assert_eq!(
@@ -301,3 +464,49 @@ mod n {
         );
     }
 }
+
+#[cfg(test)]
+mod get_as {
+    use super::*;
+    use crate::dynamo::to_hashmap;
+    use crate::email_message::{EmailStatus, StatusChange};
+    use std::collections::HashMap;
+
+    const ERROR_MSG: &str = "error";
+    const HISTORY_KEY: &str = "StatusHistory";
+
+    #[test]
+    fn error_when_missing() {
+        let wrapper = DynamoItemWrapper::new(HashMap::new());
+        assert_eq!(
+            wrapper.get_as::<Vec<StatusChange>, _>(HISTORY_KEY, ERROR_MSG),
+            Err(ERROR_MSG)
+        );
+    }
+
+    #[test]
+    fn error_when_wrong_type() {
+        let mut attributes = HashMap::new();
+        attributes.insert(HISTORY_KEY.into(), AttributeValue::S("Pending".into()));
+        let wrapper = DynamoItemWrapper::new(attributes);
+        assert_eq!(
+            wrapper.get_as::<Vec<StatusChange>, _>(HISTORY_KEY, ERROR_MSG),
+            Err(ERROR_MSG)
+        );
+    }
+
+    #[test]
+    fn ok_when_exists() {
+        let change = StatusChange::new(Some(EmailStatus::Pending), EmailStatus::Sending);
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            HISTORY_KEY.into(),
+            AttributeValue::L(vec![AttributeValue::M(to_hashmap(&change).unwrap())]),
+        );
+        let wrapper = DynamoItemWrapper::new(attributes);
+        assert_eq!(
+            wrapper.get_as::<Vec<StatusChange>, _>(HISTORY_KEY, ERROR_MSG),
+            Ok(vec![change])
+        );
+    }
+}
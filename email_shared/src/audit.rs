@@ -0,0 +1,218 @@
+use crate::email_message::{EmailId, EmailStatus};
+use crate::email_message_builder::ValidationError;
+use crate::queue::PointerError;
+use crate::queue_url::QueueUrl;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::types::QueueAttributeName;
+use aws_sdk_sqs::Client as SqsClient;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use thiserror::Error;
+
+/// What processing a message would do, as determined without processing it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuditFinding {
+    /// The message is not a valid pointer and would be quarantined.
+    InvalidPointer(PointerError),
+    /// The record does not exist, the message would be retried until it expires from the queue.
+    MissingRecord,
+    /// The record exists but is not a valid `EmailMessage`, the message would be retried.
+    SchemaViolation(String),
+    /// The record could not be read, the message would be retried.
+    Unreachable(String),
+    /// The record is not `EmailStatus::Pending` so sending would be skipped.
+    NotPending(EmailStatus),
+    /// The email is scheduled to be sent later so the message would be hidden until it is due.
+    Scheduled(Duration),
+    /// The message is older than allowed so the email would be failed.
+    Expired(Duration),
+    /// The record is readable but not sendable, sending would fail.
+    Invalid(Vec<ValidationError>),
+    /// Every recipient is on a blocked domain so the email would be suppressed.
+    Suppressed(Vec<String>),
+    /// The email would be sent.
+    Send,
+}
+
+impl AuditFinding {
+    /// Name of the kind of finding, used to summarize findings.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditFinding::InvalidPointer(_) => "InvalidPointer",
+            AuditFinding::MissingRecord => "MissingRecord",
+            AuditFinding::SchemaViolation(_) => "SchemaViolation",
+            AuditFinding::Unreachable(_) => "Unreachable",
+            AuditFinding::NotPending(_) => "NotPending",
+            AuditFinding::Scheduled(_) => "Scheduled",
+            AuditFinding::Expired(_) => "Expired",
+            AuditFinding::Invalid(_) => "Invalid",
+            AuditFinding::Suppressed(_) => "Suppressed",
+            AuditFinding::Send => "Send",
+        }
+    }
+}
+
+/// The finding for a single received message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEntry {
+    /// Id of the SQS `Message`, if it had one.
+    pub message_id: Option<String>,
+    /// Id of the email the message points to, if it could be parsed.
+    pub email_id: Option<EmailId>,
+    /// What processing the message would do.
+    pub finding: AuditFinding,
+}
+
+/// Findings counted across every batch of an audit. Messages are delivered again once their
+/// visibility is reset so each message is only counted the first time it is seen.
+#[derive(Clone, Debug, Default)]
+pub struct AuditSummary {
+    /// Number of messages by `AuditFinding::kind`.
+    pub counts: BTreeMap<&'static str, usize>,
+    /// Ids of messages already counted.
+    seen: HashSet<String>,
+}
+
+impl AuditSummary {
+    /// Count the `entries` of a batch which have not been seen before, returning how many were new.
+    pub fn record(&mut self, entries: &[AuditEntry]) -> usize {
+        let mut new = 0;
+        for entry in entries {
+            let is_new = match &entry.message_id {
+                Some(message_id) => self.seen.insert(message_id.clone()),
+                None => true,
+            };
+            if is_new {
+                *self.counts.entry(entry.finding.kind()).or_insert(0) += 1;
+                new += 1;
+            }
+        }
+        new
+    }
+
+    /// Total number of distinct messages counted.
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+}
+
+/// Why an audit was refused before any message was received.
+#[derive(Debug, Error)]
+pub enum AuditRefusal {
+    /// Emails are dead lettered once received `max_attempts` times, counting audit receives.
+    #[error("a failure queue is configured, audit receives would count towards max attempts")]
+    FailureQueue,
+    /// Messages are moved to a dead letter queue once received `maxReceiveCount` times.
+    #[error("{0} has a redrive policy, audit receives would count towards its maxReceiveCount")]
+    RedrivePolicy(String),
+    /// The attributes of the queue could not be read to look for a redrive policy.
+    #[error("unable to read attributes of {queue}: {message}")]
+    Unreadable { queue: String, message: String },
+}
+
+/// Refuse to audit `queues` when receiving from them could cause a message to be given up on.
+/// Every receive adds to the `ApproximateReceiveCount` of a message, even with a visibility
+/// timeout of 0, so repeated audits would bring messages to the redrive policy of their queue, or
+/// to the attempts allowed by a failure queue, without them ever being processed.
+pub async fn check_audit<'a>(
+    queues: impl IntoIterator<Item = &'a QueueUrl>,
+    failure_queue: Option<&QueueUrl>,
+    sqs: &SqsClient,
+) -> Result<(), AuditRefusal> {
+    if failure_queue.is_some() {
+        return Err(AuditRefusal::FailureQueue);
+    }
+    for queue in queues {
+        let output = sqs
+            .get_queue_attributes()
+            .queue_url(queue.as_str())
+            .attribute_names(QueueAttributeName::RedrivePolicy)
+            .send()
+            .await
+            .map_err(|error| AuditRefusal::Unreadable {
+                queue: queue.name().to_owned(),
+                message: format!("{}", DisplayErrorContext(&error)),
+            })?;
+        let redrive = output
+            .attributes()
+            .and_then(|attributes| attributes.get(&QueueAttributeName::RedrivePolicy));
+        if redrive.is_some_and(|policy| !policy.is_empty()) {
+            return Err(AuditRefusal::RedrivePolicy(queue.name().to_owned()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod check_audit {
+    use super::*;
+    use crate::test_support::InMemorySqs;
+
+    fn queue_url() -> QueueUrl {
+        "https://sqs.us-east-1.amazonaws.com/123456789012/emails"
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn allows_queues_without_redrive_policies() {
+        let sqs = InMemorySqs::default();
+        let queue = queue_url();
+        let result = check_audit(vec![&queue], None, &sqs.client()).await;
+        assert!(result.is_ok());
+        assert_eq!(sqs.requests("GetQueueAttributes").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_queues_with_redrive_policies() {
+        let sqs = InMemorySqs::default();
+        sqs.set_attribute(
+            "RedrivePolicy",
+            r#"{"deadLetterTargetArn":"arn:aws:sqs:us-east-1:123456789012:dlq","maxReceiveCount":5}"#,
+        );
+        let queue = queue_url();
+        let result = check_audit(vec![&queue], None, &sqs.client()).await;
+        assert!(matches!(result, Err(AuditRefusal::RedrivePolicy(name)) if name == "emails"));
+    }
+
+    #[tokio::test]
+    async fn refuses_failure_queues() {
+        let sqs = InMemorySqs::default();
+        let queue = queue_url();
+        let result = check_audit(vec![&queue], Some(&queue), &sqs.client()).await;
+        assert!(matches!(result, Err(AuditRefusal::FailureQueue)));
+        assert!(sqs.requests("GetQueueAttributes").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod record {
+    use super::*;
+
+    fn entry(message_id: &str, finding: AuditFinding) -> AuditEntry {
+        AuditEntry {
+            message_id: Some(message_id.into()),
+            email_id: None,
+            finding,
+        }
+    }
+
+    #[test]
+    fn counts_each_message_once() {
+        let mut summary = AuditSummary::default();
+        let batch = vec![
+            entry("a", AuditFinding::Send),
+            entry("b", AuditFinding::MissingRecord),
+        ];
+        assert_eq!(summary.record(&batch), 2);
+        let batch = vec![
+            entry("b", AuditFinding::MissingRecord),
+            entry("c", AuditFinding::Send),
+        ];
+        assert_eq!(summary.record(&batch), 1);
+        assert_eq!(summary.record(&batch), 0);
+        assert_eq!(summary.counts.get("Send"), Some(&2));
+        assert_eq!(summary.counts.get("MissingRecord"), Some(&1));
+        assert_eq!(summary.total(), 3);
+    }
+}
@@ -0,0 +1,147 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Values kept in memory unless configured.
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 10_000;
+
+/// Values loaded recently, each used until `ttl` has passed since it was loaded so lookups made
+/// for every message do not reach DynamoDB or S3 each time. At most `capacity` values are kept,
+/// expired values and then the oldest are dropped to make room. Hits and misses are counted until
+/// taken so they can be published as metrics.
+///
+/// ```
+/// use email_shared::TtlCache;
+/// use std::time::Duration;
+///
+/// let cache = TtlCache::new(Duration::from_secs(60), 1);
+/// cache.insert("a".to_string(), 1);
+/// cache.insert("b".to_string(), 2);
+/// assert_eq!(cache.get("a"), None);
+/// assert_eq!(cache.get("b"), Some(2));
+/// assert_eq!(cache.take_counts(), (1, 1));
+/// ```
+#[derive(Debug)]
+pub struct TtlCache<K, V> {
+    /// Most values kept.
+    capacity: usize,
+    /// Loaded values along with the time they were loaded.
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+    /// Lookups answered from the cache since counts were last taken.
+    hits: AtomicU64,
+    /// Lookups not answered from the cache since counts were last taken.
+    misses: AtomicU64,
+    /// How long a loaded value is used before it is loaded again.
+    ttl: Duration,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            ttl,
+        }
+    }
+
+    /// How long a loaded value is used before it is loaded again.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Get the value for `key` if it was loaded within the TTL.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let value = entries
+            .get(key)
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone());
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    /// Store `value` for `key` as loaded now, dropping expired values, or failing that the oldest
+    /// value, when the cache is full.
+    pub fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, (loaded_at, _)| loaded_at.elapsed() < self.ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (loaded_at, _))| *loaded_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Number of values held, including expired values not yet dropped.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether no values are held.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hits and misses counted since counts were last taken, resetting both.
+    pub fn take_counts(&self) -> (u64, u64) {
+        (
+            self.hits.swap(0, Ordering::Relaxed),
+            self.misses.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod insert {
+    use super::*;
+
+    #[test]
+    fn drops_expired_entries_first() {
+        let cache = TtlCache::new(Duration::from_secs(0), 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn replaces_existing_keys_when_full() {
+        let cache = TtlCache::new(Duration::from_secs(60), 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("a", 3);
+        assert_eq!(cache.get("a"), Some(3));
+        assert_eq!(cache.get("b"), Some(2));
+    }
+
+    #[test]
+    fn keeps_nothing_without_capacity() {
+        let cache = TtlCache::new(Duration::from_secs(60), 0);
+        cache.insert("a", 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.take_counts(), (0, 1));
+    }
+}
@@ -0,0 +1,201 @@
+use aws_sdk_dynamodb::config::interceptors::{
+    AfterDeserializationInterceptorContextRef, BeforeSerializationInterceptorContextMut,
+};
+use aws_sdk_dynamodb::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_sdk_dynamodb::operation::batch_get_item::{BatchGetItemInput, BatchGetItemOutput};
+use aws_sdk_dynamodb::operation::get_item::{GetItemInput, GetItemOutput};
+use aws_sdk_dynamodb::operation::put_item::{PutItemInput, PutItemOutput};
+use aws_sdk_dynamodb::operation::query::{QueryInput, QueryOutput};
+use aws_sdk_dynamodb::operation::scan::{ScanInput, ScanOutput};
+use aws_sdk_dynamodb::operation::transact_write_items::{
+    TransactWriteItemsInput, TransactWriteItemsOutput,
+};
+use aws_sdk_dynamodb::operation::update_item::{UpdateItemInput, UpdateItemOutput};
+use aws_sdk_dynamodb::types::{ConsumedCapacity, ReturnConsumedCapacity};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::sync::{Arc, Mutex};
+
+/// Read and write capacity units consumed by calls to DynamoDB.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Capacity {
+    /// Read capacity units consumed.
+    pub read: f64,
+    /// Write capacity units consumed.
+    pub write: f64,
+}
+
+impl Capacity {
+    /// Whether no capacity was consumed.
+    pub fn is_empty(&self) -> bool {
+        self.read == 0.0 && self.write == 0.0
+    }
+
+    /// Add the capacity consumed by `other` to this.
+    pub fn add(&mut self, other: Capacity) {
+        self.read += other.read;
+        self.write += other.write;
+    }
+
+    /// Capacity reported in `consumed` for a call which reads when `reads` is set, or writes.
+    /// `CapacityUnits` is attributed by the kind of call when the read and write units are not
+    /// reported separately.
+    fn reported(consumed: &ConsumedCapacity, reads: bool) -> Capacity {
+        let units = consumed.capacity_units().unwrap_or_default();
+        let (read, write) = match (
+            consumed.read_capacity_units(),
+            consumed.write_capacity_units(),
+        ) {
+            (None, None) if reads => (units, 0.0),
+            (None, None) => (0.0, units),
+            (read, write) => (read.unwrap_or_default(), write.unwrap_or_default()),
+        };
+        Capacity { read, write }
+    }
+}
+
+/// Tallies the capacity DynamoDB consumed by every call made through the clients it is installed
+/// on. Installing it asks DynamoDB to return `ConsumedCapacity` with each response, which costs
+/// nothing, so the capacity an email table needs can be planned from what it uses.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CapacityMeter {
+    /// Capacity consumed since it was last taken.
+    consumed: Arc<Mutex<Capacity>>,
+}
+
+impl CapacityMeter {
+    /// A client calling DynamoDB as `dynamodb` does whose consumed capacity is tallied.
+    pub fn install(&self, dynamodb: &DynamoDbClient) -> DynamoDbClient {
+        let config = dynamodb
+            .config()
+            .to_builder()
+            .interceptor(self.clone())
+            .build();
+        DynamoDbClient::from_conf(config)
+    }
+
+    /// Capacity consumed since the last time it was taken, resetting the tally.
+    pub fn take(&self) -> Capacity {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Capacity> {
+        self.consumed.lock().expect("CapacityMeter lock poisoned")
+    }
+}
+
+impl Intercept for CapacityMeter {
+    fn name(&self) -> &'static str {
+        "CapacityMeter"
+    }
+
+    fn modify_before_serialization(
+        &self,
+        context: &mut BeforeSerializationInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let total = Some(ReturnConsumedCapacity::Total);
+        let input = context.input_mut();
+        if let Some(input) = input.downcast_mut::<GetItemInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<BatchGetItemInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<QueryInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<ScanInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<PutItemInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<UpdateItemInput>() {
+            input.return_consumed_capacity = total;
+        } else if let Some(input) = input.downcast_mut::<TransactWriteItemsInput>() {
+            input.return_consumed_capacity = total;
+        }
+        Ok(())
+    }
+
+    fn read_after_deserialization(
+        &self,
+        context: &AfterDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Failed calls report no capacity even though a refused condition consumes some
+        let output = match context.output_or_error() {
+            Ok(output) => output,
+            Err(_) => return Ok(()),
+        };
+        let (consumed, reads) = if let Some(output) = output.downcast_ref::<GetItemOutput>() {
+            (output.consumed_capacity().into_iter().collect(), true)
+        } else if let Some(output) = output.downcast_ref::<BatchGetItemOutput>() {
+            (output.consumed_capacity().iter().collect(), true)
+        } else if let Some(output) = output.downcast_ref::<QueryOutput>() {
+            (output.consumed_capacity().into_iter().collect(), true)
+        } else if let Some(output) = output.downcast_ref::<ScanOutput>() {
+            (output.consumed_capacity().into_iter().collect(), true)
+        } else if let Some(output) = output.downcast_ref::<PutItemOutput>() {
+            (output.consumed_capacity().into_iter().collect(), false)
+        } else if let Some(output) = output.downcast_ref::<UpdateItemOutput>() {
+            (output.consumed_capacity().into_iter().collect(), false)
+        } else if let Some(output) = output.downcast_ref::<TransactWriteItemsOutput>() {
+            (output.consumed_capacity().iter().collect(), false)
+        } else {
+            (Vec::<&ConsumedCapacity>::new(), false)
+        };
+        let mut tally = self.lock();
+        for consumed in consumed {
+            tally.add(Capacity::reported(consumed, reads));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod capacity_meter {
+    use super::*;
+    use crate::dynamo::{get_email_message, set_email_status, StatusTransition};
+    use crate::email_message::{EmailMessage, EmailStatus};
+    use crate::queue::EmailPointerMessage;
+    use crate::test_support::InMemoryDynamoDb;
+
+    #[tokio::test]
+    async fn tallies_reads_and_writes() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        let meter = CapacityMeter::default();
+        let dynamodb = meter.install(&table.client());
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let email = get_email_message(&dynamodb, "Test Table", &pointer)
+            .await
+            .unwrap();
+        let transition = StatusTransition {
+            from: EmailStatus::Pending,
+            to: EmailStatus::Failed,
+        };
+        set_email_status(
+            &dynamodb,
+            "Test Table",
+            &pointer,
+            email.version,
+            transition,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            table.requests("GetItem")[0]["ReturnConsumedCapacity"],
+            "TOTAL"
+        );
+        assert_eq!(
+            meter.take(),
+            Capacity {
+                read: 0.5,
+                write: 1.0
+            }
+        );
+        assert!(meter.take().is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+/// Whether calls to the email provider are being made.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CircuitState {
+    /// Calls are made as normal.
+    Closed,
+    /// The provider has been failing, calls are not made until the cooldown has passed.
+    Open,
+    /// The cooldown has passed, a single trial call decides whether to close or open again.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "Closed"),
+            CircuitState::Open => write!(f, "Open"),
+            CircuitState::HalfOpen => write!(f, "HalfOpen"),
+        }
+    }
+}
+
+/// State shared by every caller of a `CircuitBreaker`.
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    /// Failures since the last success.
+    consecutive_failures: u32,
+    /// When the breaker last opened.
+    opened_at: Option<Instant>,
+    /// Whether the trial call of a half-open breaker has been made and not yet recorded.
+    trial_in_flight: bool,
+}
+
+/// Stop calling the email provider after `failure_threshold` consecutive failures so an outage
+/// does not turn every message into a slow failed send. While open, messages are left on the
+/// queue to be delivered again. After `cooldown` a single trial call is let through, closing the
+/// breaker when it succeeds and opening it again when it fails.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::{CircuitBreaker, CircuitState};
+/// use std::time::Duration;
+///
+/// let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+/// assert_eq!(breaker.state(), CircuitState::Closed);
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Consecutive failures which open the breaker.
+    failure_threshold: u32,
+    /// Time an open breaker waits before letting a trial call through.
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    /// Current state of the breaker.
+    pub fn state(&self) -> CircuitState {
+        self.lock().state
+    }
+
+    /// Whether a call made at `now` would be refused, without taking the trial call of a
+    /// half-open breaker.
+    pub(crate) fn is_open(&self, now: Instant) -> bool {
+        let state = self.lock();
+        match state.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => !self.cooled_down(&state, now),
+            CircuitState::HalfOpen => state.trial_in_flight,
+        }
+    }
+
+    /// Whether a call may be made at `now`. An open breaker which has cooled down becomes
+    /// half-open and lets this call through as its trial.
+    pub(crate) fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.lock();
+        match state.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if self.cooled_down(&state, now) => {
+                state.state = CircuitState::HalfOpen;
+                state.trial_in_flight = true;
+                event!(Level::INFO, circuit_state = %state.state, "circuit breaker half-open");
+                true
+            }
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if state.trial_in_flight => false,
+            CircuitState::HalfOpen => {
+                state.trial_in_flight = true;
+                true
+            }
+        }
+    }
+
+    /// Record a call which succeeded, closing the breaker.
+    pub(crate) fn record_success(&self) {
+        let mut state = self.lock();
+        state.consecutive_failures = 0;
+        state.trial_in_flight = false;
+        if state.state != CircuitState::Closed {
+            state.state = CircuitState::Closed;
+            state.opened_at = None;
+            event!(Level::INFO, circuit_state = %state.state, "circuit breaker closed");
+        }
+    }
+
+    /// Record a call which failed at `now`, opening the breaker when the trial call failed or
+    /// the threshold was reached.
+    pub(crate) fn record_failure(&self, now: Instant) {
+        let mut state = self.lock();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        state.trial_in_flight = false;
+        let open = match state.state {
+            CircuitState::Closed => state.consecutive_failures >= self.failure_threshold,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => true,
+        };
+        if open {
+            state.state = CircuitState::Open;
+            state.opened_at = Some(now);
+            event!(
+                Level::WARN,
+                circuit_state = %state.state,
+                consecutive_failures = state.consecutive_failures,
+                cooldown = ?self.cooldown,
+                "circuit breaker opened"
+            );
+        }
+    }
+
+    /// Whether an open breaker has waited out its cooldown by `now`.
+    fn cooled_down(&self, state: &BreakerState, now: Instant) -> bool {
+        match state.opened_at {
+            Some(opened_at) => now.saturating_duration_since(opened_at) >= self.cooldown,
+            None => true,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().expect("CircuitBreaker lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod try_acquire {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record_failure(now);
+        assert!(breaker.try_acquire(now));
+        breaker.record_failure(now);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.is_open(now));
+        assert!(!breaker.try_acquire(now + Duration::from_secs(29)));
+    }
+
+    #[test]
+    fn lets_one_trial_through_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record_failure(now);
+        let later = now + Duration::from_secs(30);
+        assert!(!breaker.is_open(later));
+        assert!(breaker.try_acquire(later));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire(later));
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        let much_later = later + Duration::from_secs(30);
+        assert!(breaker.try_acquire(much_later));
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire(much_later));
+    }
+}
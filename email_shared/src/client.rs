@@ -1,146 +1,2188 @@
-use crate::dynamo::{get_email_message, set_email_status, StatusTransition};
-use crate::email_message::{EmailMessage, EmailStatus};
-use crate::error::ProcessError;
-use crate::queue::EmailPointerMessage;
-use rusoto_dynamodb::DynamoDbClient;
-use rusoto_sqs::{DeleteMessageBatchRequestEntry, Message};
+use crate::alerts::{Alert, Alerts};
+use crate::archive::ArchiveBcc;
+use crate::attachments::AttachmentFetcher;
+use crate::audit::{AuditEntry, AuditFinding};
+use crate::capacity::CapacityMeter;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::DEFAULT_SENDING_LEASE;
+use crate::dead_letter::{DeadLetter, FailureQueue};
+use crate::domains::DomainPolicy;
+use crate::dynamo::{
+    claim_email, claim_recipient, get_email_message, get_email_message_with, get_email_messages,
+    get_recipient_statuses, put_email_message, record_email_sent, release_claim, set_email_status,
+    set_email_status_with_reason, set_recipient_status, ReadOptions, StatusTransition,
+};
+use crate::email_message::{EmailId, EmailMessage, EmailStatus, Recipient, S3Object};
+use crate::email_message_builder::{header_injections, EmailMessageBuilder, ValidationError};
+use crate::error::{DirectSendError, GetError, ProcessError, UpdateError};
+use crate::events::{EmailEvent, EmailEventType, EventBus};
+use crate::markdown::render_bodies;
+use crate::max_age::MaxMessageAge;
+use crate::metrics::{Counter, Metrics};
+use crate::mime::{build_message, MimeMessage};
+use crate::mime_store::MimeStore;
+use crate::personalization::expand;
+use crate::queue::{EmailPointerMessage, PointerError};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::sandbox::redirect;
+use crate::sanitize::sanitize_body;
+use crate::schema::attribute;
+use crate::suppression::{recipients, remove_suppressed, retain_recipients, Suppressions};
+use crate::telemetry::trace_context;
+use crate::templates::Templates;
+use crate::tenants::{Tenant, Tenants};
+use crate::tracking::Tracking;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::types::{DeleteMessageBatchRequestEntry, Message};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use tracing::{event, span, Instrument, Level};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{event, field, span, Instrument, Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
 
-const TO_SENDING: StatusTransition = StatusTransition {
-    from: EmailStatus::Pending,
-    to: EmailStatus::Sending,
-};
 const TO_PENDING: StatusTransition = StatusTransition {
     from: EmailStatus::Sending,
     to: EmailStatus::Pending,
 };
+const TO_FAILED: StatusTransition = StatusTransition {
+    from: EmailStatus::Pending,
+    to: EmailStatus::Failed,
+};
 const TO_SENT: StatusTransition = StatusTransition {
     from: EmailStatus::Sending,
     to: EmailStatus::Sent,
 };
+/// Category of canary emails, allowing them to be told apart from real mail.
+const CANARY_CATEGORY: &str = "canary";
+/// Reason a message is retried when its email was not ready to transmit within its budget.
+const BUDGET_EXCEEDED: &str = "Message budget exceeded before transmit";
+
+/// Dispositions of the messages in a batch after processing.
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// Messages which were handled, or skipped, and should be deleted from the queue.
+    pub delete: Vec<DeleteMessageBatchRequestEntry>,
+    /// Messages which could not be handled because of a temporary condition and should be
+    /// delivered again.
+    pub retry: Vec<EmailPointerMessage>,
+    /// Messages for emails scheduled to be sent later along with how long until they are due.
+    pub defer: Vec<(EmailPointerMessage, Duration)>,
+    /// Messages which can never be handled along with the reason they can not.
+    pub quarantine: Vec<(Message, PointerError)>,
+}
+
+impl BatchOutcome {
+    /// Total number of messages in the batch.
+    pub fn len(&self) -> usize {
+        self.delete.len() + self.retry.len() + self.defer.len() + self.quarantine.len()
+    }
+
+    /// Whether the batch contained no messages.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
 
 /// Hold references to external service clients so they only need to be allocated once.
 pub struct Client<'a> {
+    /// Topic on-call is alerted through when emails fail.
+    alerts: Option<&'a Alerts>,
+    /// Archival address blind copied on every message sent.
+    archive_bcc: Option<&'a ArchiveBcc>,
+    /// Fetcher for attachment contents stored outside of the email record.
+    attachments: Option<&'a AttachmentFetcher>,
+    /// Tally of the capacity DynamoDB consumed processing the current batch.
+    capacity: Option<CapacityMeter>,
+    /// Stops calls to the email provider while it is failing.
+    circuit_breaker: Option<&'a CircuitBreaker>,
     /// Connection to DynamoDB
-    dynamodb: &'a DynamoDbClient,
+    dynamodb: DynamoDbClient,
+    /// Bus the life of each email is announced on.
+    event_bus: Option<&'a EventBus>,
+    /// Queue receiving emails which ran out of attempts.
+    failure_queue: Option<&'a FailureQueue>,
+    /// Oldest a pointer message may be before its email is failed instead of sent.
+    max_age: Option<&'a MaxMessageAge>,
+    /// Longest a message may take to reach its transmission before its email is released.
+    message_budget: Option<Duration>,
+    /// Counters and timers published to CloudWatch.
+    metrics: Option<&'a Metrics>,
+    /// Storage for the exact messages sent so they can be resent unchanged.
+    mime_store: Option<&'a dyn MimeStore>,
+    /// Recipient domains which may be sent mail.
+    domains: Option<&'a DomainPolicy>,
     /// DynamoDB table from which email data will be read.
     table_name: &'a str,
+    /// DynamoDB table tracking the status of each recipient of personalized emails.
+    recipient_table: Option<&'a str>,
+    /// How long an email is kept once it is no longer being sent, forever when `None`.
+    retention: Option<Duration>,
+    /// Address every email is sent to in place of its recipients.
+    redirect_to: Option<&'a str>,
+    /// Whether HTML bodies are sanitized before they are sent.
+    sanitize_html: bool,
+    /// Budget of sends per second.
+    rate_limiter: Option<&'a RateLimiter>,
+    /// Attempts made to transmit a message through the email provider.
+    provider_retry: RetryPolicy,
+    /// Longest an email is claimed for sending before another delivery may take it over.
+    sending_lease: Duration,
+    /// Attempts made by the calls a `Runner` makes to SQS, the SDK default when `None`.
+    sqs_retry: Option<RetryPolicy>,
+    /// Addresses which are never sent mail.
+    suppressions: Option<&'a Suppressions>,
+    /// Templates used to render bodies of emails which have none.
+    templates: Option<&'a Templates>,
+    /// Tables and senders of the products pointers may name as their tenant.
+    tenants: Option<&'a Tenants>,
+    /// Rewrites links so opens and clicks are recorded.
+    tracking: Option<&'a Tracking>,
 }
 
 impl Client<'_> {
     pub fn new<'a>(dynamodb: &'a DynamoDbClient, table_name: &'a str) -> Client<'a> {
         Client {
-            dynamodb,
+            alerts: None,
+            archive_bcc: None,
+            attachments: None,
+            capacity: None,
+            circuit_breaker: None,
+            domains: None,
+            dynamodb: dynamodb.clone(),
+            event_bus: None,
+            failure_queue: None,
+            max_age: None,
+            message_budget: None,
+            metrics: None,
+            mime_store: None,
             table_name,
+            recipient_table: None,
+            retention: None,
+            redirect_to: None,
+            sanitize_html: false,
+            rate_limiter: None,
+            provider_retry: RetryPolicy::none(),
+            sending_lease: Duration::from_secs(DEFAULT_SENDING_LEASE),
+            sqs_retry: None,
+            suppressions: None,
+            templates: None,
+            tenants: None,
+            tracking: None,
         }
     }
 
-    #[tracing::instrument(skip(messages), level = Level::INFO)]
-    pub async fn process_messages<I>(&self, messages: I) -> Vec<DeleteMessageBatchRequestEntry>
+    /// Attempts made by the calls a `Runner` makes to SQS, the SDK default when `None`.
+    pub(crate) fn sqs_retry(&self) -> Option<RetryPolicy> {
+        self.sqs_retry
+    }
+
+    /// Counters and timers published to CloudWatch, if any.
+    pub(crate) fn metrics(&self) -> Option<&Metrics> {
+        self.metrics
+    }
+
+    /// The `Tenant` named by `pointer`, `None` for pointers to emails in the default table.
+    fn tenant(&self, pointer: &EmailPointerMessage) -> Result<Option<&Tenant>, PointerError> {
+        match &pointer.tenant {
+            Some(name) => self
+                .tenants
+                .and_then(|tenants| tenants.get(name))
+                .map(Some)
+                .ok_or(PointerError::UnknownTenant),
+            None => Ok(None),
+        }
+    }
+
+    /// DynamoDB table holding the email `pointer` points to.
+    fn table_for(&self, pointer: &EmailPointerMessage) -> Result<&str, PointerError> {
+        Ok(self
+            .tenant(pointer)?
+            .map_or(self.table_name, |tenant| tenant.table_name.as_str()))
+    }
+
+    /// Add `count` to `counter` when metrics are recorded.
+    fn count(&self, counter: Counter, count: u64) {
+        if let Some(metrics) = self.metrics {
+            metrics.count(counter, count);
+        }
+    }
+
+    /// Publish `email_event` when an event bus is configured. Events are announcements rather
+    /// than part of sending so an event which can not be published is logged and dropped.
+    async fn publish(&self, email_event: EmailEvent) {
+        if let Some(event_bus) = self.event_bus {
+            if let Err(error) = event_bus.publish(&email_event).await {
+                event!(Level::WARN, %error, detail_type = %email_event.event_type, "email event not published");
+            }
+        }
+    }
+
+    /// Publish `alert` when alerting is configured, logging an alert which can not be published.
+    async fn alert(&self, alert: Alert) {
+        if let Some(alerts) = self.alerts {
+            if let Err(error) = alerts.publish(&alert).await {
+                event!(Level::ERROR, %error, subject = %alert.subject(), "alert not published");
+            }
+        }
+    }
+
+    /// Announce the email of `failed` will not be sent, alerting with its reason as the error.
+    async fn email_failed(&self, failed: EmailEvent) {
+        let error = failed.reason.as_deref().unwrap_or("Failed");
+        self.alert(Alert::email_failed(&failed.email_id, error))
+            .await;
+        self.publish(failed).await;
+    }
+
+    #[tracing::instrument(skip(messages), fields(batch_id = field::Empty), level = Level::INFO)]
+    pub async fn process_messages<I>(&self, messages: I) -> BatchOutcome
     where
         I: IntoIterator<Item = Message>,
     {
-        // Keep track of the successfully processed messages so in the event of partial (or total)
+        // Every span of the batch carries its id so one batch can be found across traces
+        let batch_id = Uuid::new_v4().to_string();
+        Span::current().record("batch_id", batch_id.as_str());
+        // Keep track of the disposition of each message so in the event of partial (or total)
         // batch failure the successful messages can be deleted but the errored messages will get
         // redelivered.
-        let mut processed_message_handles = Vec::new();
+        let mut outcome = BatchOutcome::default();
+        if let Some(attachments) = self.attachments {
+            attachments.start_batch();
+        }
+        let messages = messages.into_iter().collect::<Vec<_>>();
+        let mut prefetched = self.prefetch(&messages).await;
         for message in messages {
-            let message_span =
-                span!(Level::INFO, "process_message", message_id = ?&message.message_id);
-            match self.process_message(message).instrument(message_span).await {
-                Ok(pointer) | Err(ProcessError::Skip(pointer)) => {
-                    processed_message_handles.push(DeleteMessageBatchRequestEntry::from(&pointer));
+            let message_span = span!(
+                Level::INFO,
+                "process_message",
+                message_id = ?&message.message_id,
+                batch_id = %batch_id,
+                email_id = field::Empty,
+                attempt = field::Empty,
+                provider = field::Empty,
+            );
+            // Continue the trace of the request which enqueued the email when one was sent along
+            if let Some(context) = trace_context(&message) {
+                let _ = message_span.set_parent(context);
+            }
+            self.count(Counter::Received, 1);
+            match self
+                .process_message(message, &mut prefetched)
+                .instrument(message_span)
+                .await
+            {
+                Ok(pointer) => {
+                    self.count(Counter::Sent, 1);
+                    if let Some(alerts) = self.alerts {
+                        alerts.record_success(Instant::now());
+                    }
+                    outcome
+                        .delete
+                        .push(DeleteMessageBatchRequestEntry::from(&pointer));
                 }
-                Err(ProcessError::SkipMessage(message)) => {
-                    processed_message_handles.push(DeleteMessageBatchRequestEntry {
-                        id: message.message_id.unwrap(),
-                        receipt_handle: message.receipt_handle.unwrap(),
-                    });
+                Err(ProcessError::Skip(pointer)) => {
+                    self.count(Counter::Skipped, 1);
+                    outcome
+                        .delete
+                        .push(DeleteMessageBatchRequestEntry::from(&pointer));
                 }
-                Err(ProcessError::Retry) => {
-                    continue;
+                Err(ProcessError::SkipMessage(message, error)) => {
+                    self.count(Counter::Failed, 1);
+                    outcome.quarantine.push((message, error));
+                }
+                // Not an attempt at sending, so never counts against the failure queue
+                Err(ProcessError::Defer(pointer, delay)) => {
+                    self.count(Counter::Retried, 1);
+                    outcome.defer.push((pointer, delay));
+                }
+                Err(ProcessError::Retry(pointer, error)) => {
+                    let exceeded = self.alerts.and_then(|alerts| {
+                        alerts.record_failure(&pointer.email_id, &error, Instant::now())
+                    });
+                    if let Some(alert) = exceeded {
+                        self.alert(alert).await;
+                    }
+                    let dead_lettered = match self.failure_queue {
+                        Some(queue) if queue.is_exhausted(&pointer) => {
+                            self.dead_letter(queue, &pointer, &error).await
+                        }
+                        _ => false,
+                    };
+                    if dead_lettered {
+                        self.count(Counter::Failed, 1);
+                        outcome
+                            .delete
+                            .push(DeleteMessageBatchRequestEntry::from(&pointer));
+                    } else {
+                        self.count(Counter::Retried, 1);
+                        outcome.retry.push(pointer);
+                    }
                 }
             }
         }
-        processed_message_handles
+        self.count_cache_lookups();
+        self.count_consumed_capacity();
+        outcome
+    }
+
+    /// Log the DynamoDB capacity consumed since last counted, adding it to the metrics.
+    fn count_consumed_capacity(&self) {
+        let capacity = match &self.capacity {
+            Some(meter) => meter.take(),
+            None => return,
+        };
+        event!(
+            Level::INFO,
+            read_capacity_units = capacity.read,
+            write_capacity_units = capacity.write,
+            "consumed capacity"
+        );
+        if let Some(metrics) = self.metrics {
+            metrics.consume(capacity);
+        }
+    }
+
+    /// Add the template and suppression cache lookups made since last counted to the cache
+    /// counters.
+    fn count_cache_lookups(&self) {
+        let (template_hits, template_misses) = self
+            .templates
+            .map_or((0, 0), |templates| templates.take_cache_counts());
+        let (suppression_hits, suppression_misses) = self
+            .suppressions
+            .map_or((0, 0), |suppressions| suppressions.take_cache_counts());
+        self.count(Counter::CacheHits, template_hits + suppression_hits);
+        self.count(Counter::CacheMisses, template_misses + suppression_misses);
+    }
+
+    /// Give up on the email of `pointer`, which failed its last attempt with `error`.
+    ///
+    /// 1. Send the pointer, `error`, and a snapshot of the email to the failure queue.
+    /// 2. Mark the email `EmailStatus::Failed` with `error` as the reason, unless it is
+    ///    `EmailStatus::Sending`. A claimed email may have been sent by the delivery holding the
+    ///    claim, so it is left for `StuckEmailSweeper` to recover once the claim lapses.
+    ///
+    /// Returns whether the pointer may be deleted, which is once the dead letter was sent.
+    async fn dead_letter(
+        &self,
+        queue: &FailureQueue,
+        pointer: &EmailPointerMessage,
+        error: &str,
+    ) -> bool {
+        let table_name = match self.table_for(pointer) {
+            Ok(table_name) => table_name,
+            Err(error) => {
+                event!(Level::ERROR, %error, "dead letter not sent");
+                return false;
+            }
+        };
+        // 1. Send the pointer, `error`, and a snapshot of the email to the failure queue.
+        let email = get_email_message(&self.dynamodb, table_name, pointer)
+            .await
+            .ok();
+        let letter = DeadLetter::new(pointer, error, email.as_ref());
+        if let Err(send_error) = queue.send(&letter).await {
+            event!(Level::ERROR, error = %send_error, "dead letter not sent");
+            return false;
+        }
+        // 2. Mark the email `EmailStatus::Failed` with `error` as the reason.
+        if let Some(EmailStatus::Sending) = email.as_ref().map(|email| email.status) {
+            event!(Level::WARN, receive_count = pointer.receive_count, %error, "claimed email left for sweeper");
+            return true;
+        }
+        let (version, transition) = match &email {
+            Some(email) => (
+                email.version,
+                StatusTransition {
+                    from: email.status,
+                    to: EmailStatus::Failed,
+                },
+            ),
+            None => (0, TO_FAILED),
+        };
+        match set_email_status_with_reason(
+            &self.dynamodb,
+            table_name,
+            pointer,
+            version,
+            transition,
+            error,
+            self.retention,
+        )
+        .await
+        {
+            Ok(_) => {
+                event!(Level::ERROR, receive_count = pointer.receive_count, %error, "email failed after final attempt");
+                let failed = match &email {
+                    Some(email) => EmailEvent::new(EmailEventType::EmailFailed, email),
+                    None => {
+                        EmailEvent::for_email_id(EmailEventType::EmailFailed, &pointer.email_id)
+                    }
+                };
+                self.email_failed(failed.with_reason(error)).await;
+            }
+            Err(UpdateError::ConditionalCheckFailed(_) | UpdateError::VersionConflict(_)) => {
+                event!(Level::WARN, "email changed while failing");
+            }
+            // Already sent or otherwise finished, so there is nothing to fail
+            Err(UpdateError::IllegalTransition(reason)) => {
+                event!(Level::WARN, %reason, "email not failed");
+            }
+            Err(update_error) => {
+                event!(Level::ERROR, error = %update_error, "failed status not set");
+            }
+        }
+        true
+    }
+
+    /// Determine what processing each of `messages` would do without changing any record.
+    #[tracing::instrument(skip(messages), level = Level::INFO)]
+    pub async fn audit_messages<I>(&self, messages: I) -> Vec<AuditEntry>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        let mut entries = Vec::new();
+        for message in messages {
+            let message_id = message.message_id.clone();
+            let (email_id, finding) = self.audit_message(message).await;
+            event!(
+                Level::INFO,
+                ?message_id,
+                ?email_id,
+                kind = finding.kind(),
+                ?finding,
+                "audit"
+            );
+            entries.push(AuditEntry {
+                message_id,
+                email_id,
+                finding,
+            });
+        }
+        entries
+    }
+
+    /// Determine what processing `message` would do along with the `EmailId` it points to.
+    async fn audit_message(&self, message: Message) -> (Option<EmailId>, AuditFinding) {
+        let pointer = match EmailPointerMessage::try_from(message) {
+            Ok(pointer) => pointer,
+            Err(error) => return (None, AuditFinding::InvalidPointer(error)),
+        };
+        let email_id = Some(pointer.email_id.clone());
+        let tenant = match self.tenant(&pointer) {
+            Ok(tenant) => tenant,
+            Err(error) => return (email_id, AuditFinding::InvalidPointer(error)),
+        };
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        let mut email = match get_email_message(&self.dynamodb, table_name, &pointer).await {
+            Ok(email) => email,
+            Err(GetError::RecordNotFound) => return (email_id, AuditFinding::MissingRecord),
+            Err(error @ GetError::ParseError(_)) | Err(error @ GetError::PropertyMissing(_)) => {
+                return (email_id, AuditFinding::SchemaViolation(error.to_string()))
+            }
+            Err(error) => return (email_id, AuditFinding::Unreachable(error.to_string())),
+        };
+        if let Some(tenant) = tenant {
+            tenant.apply(&mut email);
+        }
+        let finding = if !email.is_claimable(Utc::now()) {
+            AuditFinding::NotPending(email.status)
+        } else if let Some(delay) = email.scheduled_delay(Utc::now()) {
+            AuditFinding::Scheduled(delay)
+        } else if let Some(age) = self.expired_age(&pointer, &email) {
+            AuditFinding::Expired(age)
+        } else {
+            match EmailMessageBuilder::from(email).build() {
+                Ok(email) => match self.blocked_recipients(&email) {
+                    (blocked, false) => AuditFinding::Suppressed(blocked),
+                    _ => AuditFinding::Send,
+                },
+                Err(errors) => AuditFinding::Invalid(errors),
+            }
+        };
+        (email_id, finding)
+    }
+
+    /// Read the emails `messages` point to with one `BatchGetItem` rather than a `GetItem` per
+    /// message, when more than one email is pointed to. Only emails in the default table are
+    /// read, the emails of a tenant are read from its own table as each pointer is processed.
+    async fn prefetch(
+        &self,
+        messages: &[Message],
+    ) -> HashMap<EmailId, Result<EmailMessage, GetError>> {
+        let pointers = messages
+            .iter()
+            .filter_map(|message| EmailPointerMessage::try_from(message.clone()).ok())
+            .filter(|pointer| pointer.tenant.is_none())
+            .collect::<Vec<_>>();
+        let distinct = pointers
+            .iter()
+            .map(|pointer| pointer.email_id.as_str())
+            .collect::<HashSet<_>>();
+        if distinct.len() < 2 {
+            return HashMap::new();
+        }
+        let options = ReadOptions::default().with_consistent_read(true);
+        get_email_messages(&self.dynamodb, self.table_name, &pointers, &options).await
     }
 
     /// For the given `Message` attempt to extract an `EmailPointerMessage` and transmit the associated
-    /// `EmailMessage` with the declared sending service.
-    async fn process_message(&self, message: Message) -> Result<EmailPointerMessage, ProcessError> {
-        let dynamodb = self.dynamodb;
-        let table_name = self.table_name;
+    /// `EmailMessage` with the declared sending service. The email is taken from `prefetched` when
+    /// it was read there, only once so a second pointer to the same email reads the email as the
+    /// first left it.
+    async fn process_message(
+        &self,
+        message: Message,
+        prefetched: &mut HashMap<EmailId, Result<EmailMessage, GetError>>,
+    ) -> Result<EmailPointerMessage, ProcessError> {
         // Which errors mean try again and which errors mean skip message?
         // 1. Parse email_id from SQS message
-        let pointer = EmailPointerMessage::try_from(message.clone());
-        let pointer = match pointer {
-            Ok(record) => record,
-            Err(msg) => {
-                event!(Level::ERROR, error = msg, "pointer parse failure");
-                return Err(ProcessError::SkipMessage(message));
+        let pointer = EmailPointerMessage::try_from(message.clone()).and_then(|pointer| {
+            // A pointer naming a tenant which is not configured must never touch another table,
+            // and can not become configured by being received again so it is quarantined
+            self.tenant(&pointer).map(|tenant| (pointer, tenant))
+        });
+        match pointer {
+            Ok((pointer, tenant)) => {
+                let span = Span::current();
+                span.record("email_id", pointer.email_id.as_str());
+                span.record("attempt", pointer.receive_count);
+                // A record the batch could not read is looked up again on its own
+                let email = match pointer.tenant {
+                    Some(_) => None,
+                    None => prefetched.remove(&pointer.email_id).filter(Result::is_ok),
+                };
+                self.process_pointer(pointer, tenant, email).await
             }
-        };
-        // Create logger for this record
+            Err(error) => {
+                event!(Level::ERROR, %error, "pointer parse failure");
+                Err(ProcessError::SkipMessage(message, error))
+            }
+        }
+    }
+
+    /// Transmit the `EmailMessage` identified by `pointer` from the table of `tenant` and track its
+    /// status, reading it unless it was `prefetched`. The tenant has already been resolved so a
+    /// pointer naming one which is not configured never reaches here.
+    async fn process_pointer(
+        &self,
+        pointer: EmailPointerMessage,
+        tenant: Option<&Tenant>,
+        prefetched: Option<Result<EmailMessage, GetError>>,
+    ) -> Result<EmailPointerMessage, ProcessError> {
+        let dynamodb = &self.dynamodb;
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        let deadline = self.message_budget.map(|budget| Instant::now() + budget);
         // 2. Get email data from dynamo db table
         // 3. Parse dynamo data into object for sending
-        event!(Level::INFO, %table_name, "get email");
-        let email = get_email_message(dynamodb, table_name, &pointer).await;
-        // 4. If status of email is not `EmailStatus::Pending` log a warning and skip sending. The
-        //    message to remove will automatically be created.
-        let email = match email {
-            Ok(mail) if mail.status != EmailStatus::Pending => {
+        let email = match prefetched {
+            Some(email) => email,
+            None => {
+                event!(Level::INFO, %table_name, "get email");
+                // A pointer may be received moments after its email was written or requeued
+                let options = ReadOptions::default().with_consistent_read(true);
+                get_email_message_with(dynamodb, table_name, &pointer, &options).await
+            }
+        };
+        // 4. If status of email is not `EmailStatus::Pending`, or `EmailStatus::Sending` with a
+        //    lapsed claim, log a warning and skip sending. The message to remove will
+        //    automatically be created.
+        let mut email = match email {
+            Ok(mail) if !mail.is_claimable(Utc::now()) => {
                 event!(Level::WARN, email_status = %mail.status, "email not {}", EmailStatus::Pending);
                 // See 8.
                 // Skipping doesn't work unless the pointer is recorded as an entry to be deleted.
                 return Err(ProcessError::Skip(pointer));
             }
-            Ok(mail) => mail,
+            Ok(mut mail) => {
+                if mail.status == EmailStatus::Sending {
+                    event!(Level::WARN, expired_at = ?mail.sending_lock_expires_at, "claim lapsed, taking over email");
+                }
+                // The sender and provider of a tenant replace whatever the record holds
+                if let Some(tenant) = tenant {
+                    tenant.apply(&mut mail);
+                }
+                Span::current().record("provider", mail.provider.as_str());
+                mail
+            }
             Err(error) => {
                 event!(Level::ERROR, %error, "get email failed");
-                return Err(ProcessError::Retry);
+                return Err(ProcessError::Retry(pointer, error.to_string()));
             }
         };
+        // 4'. If the email is scheduled to be sent later hide the pointer until it is due rather
+        //     than claiming the email.
+        if let Some(delay) = email.scheduled_delay(Utc::now()) {
+            event!(Level::INFO, scheduled_at = ?email.scheduled_at, "email deferred until scheduled");
+            return Err(ProcessError::Defer(pointer, delay));
+        }
+        // 4a. If the pointer is older than allowed for the category of the email mark it
+        //     `EmailStatus::Failed` rather than sending stale mail.
+        if let Some(age) = self.expired_age(&pointer, &email) {
+            return match set_email_status(
+                dynamodb,
+                table_name,
+                &pointer,
+                email.version,
+                rejected(&email, EmailStatus::Failed),
+                self.retention,
+            )
+            .await
+            {
+                Ok(_) => {
+                    event!(
+                        Level::ERROR,
+                        age_seconds = age.as_secs(),
+                        category = ?email.category,
+                        email_id = %email.email_id,
+                        "email expired before sending"
+                    );
+                    let failed = EmailEvent::new(EmailEventType::EmailFailed, &email);
+                    self.email_failed(failed.with_reason("Expired before sending"))
+                        .await;
+                    Err(ProcessError::Skip(pointer))
+                }
+                Err(error) => {
+                    event!(Level::ERROR, %error, "update email status to Failed failed");
+                    Err(ProcessError::Retry(pointer, error.to_string()))
+                }
+            };
+        }
+        // 4a'. If a field written into a header holds a line break mark the email
+        //      `EmailStatus::Failed` rather than letting it inject headers.
+        let injected = header_injections(&email);
+        if !injected.is_empty() {
+            let reason = format!("Header injection in: {}", injected.join(", "));
+            return match set_email_status_with_reason(
+                dynamodb,
+                table_name,
+                &pointer,
+                email.version,
+                rejected(&email, EmailStatus::Failed),
+                &reason,
+                self.retention,
+            )
+            .await
+            {
+                Ok(_) => {
+                    event!(Level::ERROR, %reason, "email rejected");
+                    let failed = EmailEvent::new(EmailEventType::EmailFailed, &email);
+                    self.email_failed(failed.with_reason(&reason)).await;
+                    Err(ProcessError::Skip(pointer))
+                }
+                Err(error) => {
+                    event!(Level::ERROR, %error, "update email status to Failed failed");
+                    Err(ProcessError::Retry(pointer, error.to_string()))
+                }
+            };
+        }
+        // 4b. Drop recipients on blocked domains. When none remain mark the email
+        //     `EmailStatus::Suppressed` with the blocked addresses as the reason so it is never
+        //     handed to the provider.
+        if let Some(domains) = self.domains {
+            let (blocked, _) = self.blocked_recipients(&email);
+            if !blocked.is_empty() {
+                event!(Level::INFO, ?blocked, "blocked domain recipients dropped");
+            }
+            if !retain_recipients(&mut email, |address| domains.is_allowed(address)) {
+                let reason = format!("All recipients on blocked domains: {}", blocked.join(", "));
+                return match set_email_status_with_reason(
+                    dynamodb,
+                    table_name,
+                    &pointer,
+                    email.version,
+                    rejected(&email, EmailStatus::Suppressed),
+                    &reason,
+                    self.retention,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        event!(Level::WARN, %reason, "email suppressed");
+                        Err(ProcessError::Skip(pointer))
+                    }
+                    Err(error) => {
+                        event!(Level::ERROR, %error, "update email status to Suppressed failed");
+                        Err(ProcessError::Retry(pointer, error.to_string()))
+                    }
+                };
+            }
+        }
+        // 4c. Drop suppressed recipients. When none remain mark the email `EmailStatus::Skipped`
+        //     with the reason rather than sending it to nobody.
+        if let Some(suppressions) = self.suppressions {
+            let suppressed = match suppressions.check(recipients(&email)).await {
+                Ok(suppressed) => suppressed,
+                Err(error) => {
+                    event!(Level::ERROR, %error, "check suppressions failed");
+                    return Err(ProcessError::Retry(pointer, error.to_string()));
+                }
+            };
+            if !suppressed.is_empty() {
+                event!(Level::INFO, ?suppressed, "suppressed recipients dropped");
+            }
+            if !remove_suppressed(&mut email, &suppressed) {
+                let mut reasons = suppressed
+                    .iter()
+                    .map(|(address, reason)| format!("{} ({})", address, reason))
+                    .collect::<Vec<_>>();
+                reasons.sort();
+                let reason = format!("All recipients suppressed: {}", reasons.join(", "));
+                return match set_email_status_with_reason(
+                    dynamodb,
+                    table_name,
+                    &pointer,
+                    email.version,
+                    rejected(&email, EmailStatus::Skipped),
+                    &reason,
+                    self.retention,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        event!(Level::WARN, %reason, "email skipped");
+                        Err(ProcessError::Skip(pointer))
+                    }
+                    Err(error) => {
+                        event!(Level::ERROR, %error, "update email status to Skipped failed");
+                        Err(ProcessError::Retry(pointer, error.to_string()))
+                    }
+                };
+            }
+        }
+        // 4d. Render bodies from the template of the email when it has none. A template which can
+        //     not be rendered is left for a later attempt, nothing has been changed yet.
+        //     Personalized emails are rendered for each recipient as they are sent.
+        if let (Some(templates), None) = (self.templates, &email.personalization) {
+            if let Err(error) = templates.render(&mut email).await {
+                event!(Level::ERROR, %error, "render template failed");
+                return Err(ProcessError::Retry(pointer, error.to_string()));
+            }
+        }
+        // 4e. Leave the email for a later attempt while the provider is failing, nothing has been
+        //     changed yet.
+        if let Some(breaker) = self.circuit_breaker {
+            if breaker.is_open(Instant::now()) {
+                event!(Level::WARN, circuit_state = %breaker.state(), "circuit breaker open, email left for later");
+                return Err(ProcessError::Retry(pointer, "Circuit breaker open".into()));
+            }
+        }
         // 5. Update the message status in dynamo so that a second receiver for this message will
         //    not try to send the same email
-        let update_result = set_email_status(dynamodb, table_name, &pointer, TO_SENDING).await;
+        let update_result = claim_email(
+            dynamodb,
+            table_name,
+            &pointer,
+            email.version,
+            self.sending_lease,
+        )
+        .await;
+        // Every later update expects the version written by the claim
+        let version = email.version + 1;
         if let Err(error) = update_result {
             event!(Level::ERROR, %error, "update email status to Sending failed");
-            return Err(ProcessError::Retry);
+            return Err(ProcessError::Retry(pointer, error.to_string()));
         }
+        let sending = EmailEvent::new(EmailEventType::EmailSending, &email);
+        self.publish(sending.clone()).await;
         // 6. TODO: Send the message
         event!(Level::INFO, email_status = %email.status, "start email transmit");
-        let send_result = Client::send_email(email).await;
-        if let Err(error) = send_result {
-            event!(Level::ERROR, %error, "send email failed");
-            // 6a. If unable to send, set the status back to `EmailStatus::Pending`
-            return match set_email_status(dynamodb, table_name, &pointer, TO_PENDING).await {
-                Ok(_) => Err(ProcessError::Retry),
-                Err(error) => {
-                    // 6b. If unable to reset to Pending the next run through will skip anyway
-                    event!(Level::ERROR, %error, "reset email status to Pending failed");
-                    Err(ProcessError::Skip(pointer))
-                }
-            };
-        }
-        // 7. Update the message status in dynamo to sent
-        let update_result = set_email_status(dynamodb, table_name, &pointer, TO_SENT).await;
+        let send_result = match email.personalization {
+            Some(_) => self.send_personalized(email, deadline).await.map(|_| None),
+            None => self.send_email(email, deadline).await.map(Some),
+        };
+        let message = match send_result {
+            Ok(message) => message,
+            Err(error) => {
+                event!(Level::ERROR, %error, "send email failed");
+                // 6a. If unable to send, or out of budget before sending, release the claim so the
+                //     status is back to `EmailStatus::Pending`
+                return match release_claim(dynamodb, table_name, &pointer, version).await {
+                    Ok(_) => Err(ProcessError::Retry(pointer, error)),
+                    Err(error) => {
+                        // 6b. If unable to reset to Pending the next run through will skip anyway
+                        event!(Level::ERROR, %error, "reset email status to Pending failed");
+                        Err(ProcessError::Skip(pointer))
+                    }
+                };
+            }
+        };
+        // 7. Keep the exact message sent so it can be resent unchanged. Personalized emails have
+        //    a different message for each recipient and are not kept.
+        let rendered_mime = match &message {
+            Some(message) => self.store_mime(pointer.email_id.as_str(), message).await,
+            None => None,
+        };
+        // 7a. Record the email sent, when, and where its message is kept in one transaction so
+        //     the record is never left partly updated
+        let update_result = record_email_sent(
+            dynamodb,
+            table_name,
+            &pointer,
+            version,
+            None,
+            rendered_mime.as_ref(),
+            self.retention,
+        )
+        .await;
         if let Err(error) = update_result {
             event!(Level::ERROR, %error, "update email failed");
-            return Err(ProcessError::Retry);
+            return Err(ProcessError::Retry(pointer, error.to_string()));
         }
+        self.publish(sending.followed_by(EmailEventType::EmailSent))
+            .await;
         // 8. Messages delivered and state tracked successfully
         Ok(pointer)
     }
 
-    async fn send_email(email: EmailMessage) -> Result<(), String> {
+    /// Recipients of `email` on domains the domain policy blocks, along with whether any
+    /// recipient is allowed. Every recipient is allowed without a domain policy.
+    fn blocked_recipients(&self, email: &EmailMessage) -> (Vec<String>, bool) {
+        let domains = match self.domains {
+            Some(domains) => domains,
+            None => return (Vec::new(), true),
+        };
+        let (allowed, blocked): (Vec<&str>, Vec<&str>) = recipients(email)
+            .into_iter()
+            .partition(|address| domains.is_allowed(address));
+        let blocked = blocked.into_iter().map(String::from).collect();
+        (blocked, !allowed.is_empty())
+    }
+
+    /// Pointer to the email identified by `email_id` which was never received from a queue, along
+    /// with the `Tenant` named by `tenant` whose table holds it. A `tenant` which is not
+    /// configured is refused rather than read from the default table.
+    fn unqueued(
+        &self,
+        email_id: &str,
+        tenant: Option<&str>,
+    ) -> Result<(EmailPointerMessage, Option<&Tenant>), GetError> {
+        let mut pointer = EmailPointerMessage::unqueued(email_id);
+        pointer.tenant = tenant.map(String::from);
+        match self.tenant(&pointer) {
+            Ok(tenant) => Ok((pointer, tenant)),
+            Err(_) => Err(GetError::UnknownTenant(tenant.unwrap_or_default().into())),
+        }
+    }
+
+    /// Read the record of the email identified by `email_id` from the table of `tenant`, or the
+    /// default table without one.
+    pub async fn get_email(
+        &self,
+        email_id: &str,
+        tenant: Option<&str>,
+    ) -> Result<EmailMessage, GetError> {
+        let (pointer, tenant) = self.unqueued(email_id, tenant)?;
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        get_email_message(&self.dynamodb, table_name, &pointer).await
+    }
+
+    /// Read the record of the email identified by `email_id` to be sent again or previewed, from
+    /// the table of `tenant` and with the sender and provider of `tenant` applied.
+    async fn get_tenant_email(
+        &self,
+        email_id: &str,
+        tenant: Option<&str>,
+    ) -> Result<EmailMessage, GetError> {
+        let (pointer, tenant) = self.unqueued(email_id, tenant)?;
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        let mut email = get_email_message(&self.dynamodb, table_name, &pointer).await?;
+        if let Some(tenant) = tenant {
+            tenant.apply(&mut email);
+        }
+        Ok(email)
+    }
+
+    /// Read the status of each recipient of the personalized email identified by `email_id`, or
+    /// `None` when no recipient table is configured.
+    pub async fn get_recipient_statuses(
+        &self,
+        email_id: &str,
+    ) -> Result<Option<HashMap<Recipient, EmailStatus>>, GetError> {
+        match self.recipient_table {
+            Some(table_name) => get_recipient_statuses(&self.dynamodb, table_name, email_id)
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a synthetic email to `recipient` through the same steps as a queued email and verify
+    /// it was recorded as `EmailStatus::Sent`. Used as a smoke test before consuming a queue.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn send_canary(&self, recipient: &str) -> Result<EmailId, DirectSendError> {
+        let email = canary_email(Uuid::new_v4().to_string().into(), recipient)
+            .map_err(DirectSendError::Invalid)?;
+        self.send_direct(email, true, None).await
+    }
+
+    /// Send `email` immediately instead of waiting for a pointer to it to be received.
+    ///
+    /// When `persist` is set:
+    /// 1. Write the email record as `EmailStatus::Pending`.
+    /// 2. Process the email as if a pointer to it had been received.
+    /// 3. Read the record back and check it is `EmailStatus::Sent`.
+    ///
+    /// Otherwise nothing is written, the email is rendered and sent without tracking its status.
+    ///
+    /// With `tenant` the record is written to the table of the tenant and the email is sent from
+    /// its sender through its provider.
+    #[tracing::instrument(skip(self, email), fields(email_id = %email.email_id), level = Level::INFO)]
+    pub async fn send_direct(
+        &self,
+        mut email: EmailMessage,
+        persist: bool,
+        tenant: Option<&str>,
+    ) -> Result<EmailId, DirectSendError> {
+        let (pointer, tenant) = self.unqueued(email.email_id.as_str(), tenant)?;
+        if !persist {
+            if let Some(tenant) = tenant {
+                tenant.apply(&mut email);
+            }
+            if let Some(templates) = self.templates {
+                templates
+                    .render(&mut email)
+                    .await
+                    .map_err(|error| DirectSendError::ProcessError(error.to_string()))?;
+            }
+            let email_id = email.email_id.clone();
+            self.send_email(email, None)
+                .await
+                .map_err(DirectSendError::ProcessError)?;
+            event!(Level::INFO, %email_id, "email sent without record");
+            return Ok(email_id);
+        }
+        // 1. Write the email record as `EmailStatus::Pending`.
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        put_email_message(&self.dynamodb, table_name, &email).await?;
+        event!(Level::INFO, email_id = %email.email_id, "email record written");
+        self.publish(EmailEvent::new(EmailEventType::EmailQueued, &email))
+            .await;
+        // 2. Process the email as if a pointer to it had been received.
+        // 3. Read the record back and check it is `EmailStatus::Sent`.
+        self.send_pending(pointer, tenant, None).await
+    }
+
+    /// Send the `EmailStatus::Pending` email identified by `email_id` now instead of waiting for
+    /// its pointer to be received, for testing or an urgent send. A pointer still on the queue is
+    /// skipped once received because the email is no longer `Pending`.
+    ///
+    /// 1. Read the record and check it can be claimed.
+    /// 2. Process the email as if a pointer to it had been received.
+    /// 3. Read the record back and check it is `EmailStatus::Sent`.
+    ///
+    /// With `tenant` the record is read from and written to the table of the tenant.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn send_now(
+        &self,
+        email_id: &str,
+        tenant: Option<&str>,
+    ) -> Result<EmailId, DirectSendError> {
+        // 1. Read the record and check it can be claimed.
+        let (pointer, tenant) = self.unqueued(email_id, tenant)?;
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        let options = ReadOptions::default().with_consistent_read(true);
+        let email = get_email_message_with(&self.dynamodb, table_name, &pointer, &options).await?;
+        if !email.is_claimable(Utc::now()) {
+            return Err(DirectSendError::UnexpectedStatus(email.status));
+        }
+        self.send_pending(pointer, tenant, Some(email)).await
+    }
+
+    /// Process the email `pointer` points to in the table of `tenant` as if the pointer had been
+    /// received, using its record when already read, then check it was recorded as
+    /// `EmailStatus::Sent`.
+    async fn send_pending(
+        &self,
+        pointer: EmailPointerMessage,
+        tenant: Option<&Tenant>,
+        email: Option<EmailMessage>,
+    ) -> Result<EmailId, DirectSendError> {
+        let pointer = self
+            .process_pointer(pointer, tenant, email.map(Ok))
+            .await
+            .map_err(|error| DirectSendError::ProcessError(error.to_string()))?;
+        let table_name = tenant.map_or(self.table_name, |tenant| tenant.table_name.as_str());
+        let options = ReadOptions::default()
+            .with_attributes(&[attribute::EMAIL_STATUS])
+            .with_consistent_read(true);
+        let email = get_email_message_with(&self.dynamodb, table_name, &pointer, &options).await?;
+        match email.status {
+            EmailStatus::Sent => {
+                event!(Level::INFO, email_id = %email.email_id, "email sent");
+                Ok(email.email_id)
+            }
+            status => Err(DirectSendError::UnexpectedStatus(status)),
+        }
+    }
+
+    /// Transmit the email identified by `email_id` again. With `exact` the message stored when it
+    /// was first sent is transmitted byte for byte, otherwise the message is rendered again from
+    /// the record. Only emails which have been sent may be resent and the record is not changed.
+    /// With `tenant` the record is read from the table of the tenant.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn resend(
+        &self,
+        email_id: &str,
+        exact: bool,
+        tenant: Option<&str>,
+    ) -> Result<(), DirectSendError> {
+        let mut email = self.get_tenant_email(email_id, tenant).await?;
+        if email.status != EmailStatus::Sent {
+            return Err(DirectSendError::UnexpectedStatus(email.status));
+        }
+        if email.personalization.is_some() {
+            return Err(DirectSendError::ProcessError(
+                "personalized emails can not be resent".into(),
+            ));
+        }
+        if !exact {
+            if let Some(templates) = self.templates {
+                templates
+                    .render(&mut email)
+                    .await
+                    .map_err(|error| DirectSendError::ProcessError(error.to_string()))?;
+            }
+            self.send_email(email, None)
+                .await
+                .map_err(DirectSendError::ProcessError)?;
+            event!(Level::INFO, %email_id, "email rendered and resent");
+            return Ok(());
+        }
+        let store = self.mime_store.ok_or_else(|| {
+            DirectSendError::ProcessError("No MimeStore to read the sent message".into())
+        })?;
+        let location = email.rendered_mime.clone().ok_or_else(|| {
+            DirectSendError::ProcessError("no message was stored when the email was sent".into())
+        })?;
+        let raw = store
+            .get(&location)
+            .await
+            .map_err(|error| DirectSendError::ProcessError(error.to_string()))?;
+        // The stored message is not changed but it is only delivered to the redirect address
+        if let Some(archive_bcc) = self.archive_bcc {
+            archive_bcc.apply(&mut email);
+        }
+        if let Some(address) = self.redirect_to {
+            redirect(&mut email, address);
+        }
+        self.transmit(&email, &raw)
+            .await
+            .map_err(DirectSendError::ProcessError)?;
+        event!(Level::INFO, %email_id, bucket = %location.bucket, key = %location.key, "email resent exactly");
+        Ok(())
+    }
+
+    /// The message which would be sent for the email identified by `email_id`, rendered from its
+    /// template, with its attachments, archival copy, and redirect applied, without sending it or
+    /// changing any record. Links are not rewritten for tracking so no tracked links are written.
+    /// With `tenant` the record is read from the table of the tenant.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn preview(
+        &self,
+        email_id: &str,
+        tenant: Option<&str>,
+    ) -> Result<MimeMessage, DirectSendError> {
+        let mut email = self.get_tenant_email(email_id, tenant).await?;
+        if email.personalization.is_some() {
+            return Err(DirectSendError::ProcessError(
+                "personalized emails can not be previewed".into(),
+            ));
+        }
+        if let Some(templates) = self.templates {
+            templates
+                .render(&mut email)
+                .await
+                .map_err(|error| DirectSendError::ProcessError(error.to_string()))?;
+        }
+        let (_, message) = self
+            .assemble(email, false)
+            .await
+            .map_err(DirectSendError::ProcessError)?;
+        Ok(message)
+    }
+
+    /// Store `message` sent for the email identified by `email_id`, returning where it is kept to
+    /// be recorded on the email record. The email has already been sent so failures are logged
+    /// and ignored.
+    async fn store_mime(&self, email_id: &str, message: &MimeMessage) -> Option<S3Object> {
+        let store = self.mime_store?;
+        match store.put(email_id, message).await {
+            Ok(location) => {
+                event!(Level::DEBUG, bucket = %location.bucket, key = %location.key, "sent message stored");
+                Some(location)
+            }
+            Err(error) => {
+                event!(Level::WARN, %error, "store sent message failed");
+                None
+            }
+        }
+    }
+
+    /// The age of `pointer` when it is older than allowed for the category of `email`. A scheduled
+    /// email is only as old as the time since it was due.
+    fn expired_age(&self, pointer: &EmailPointerMessage, email: &EmailMessage) -> Option<Duration> {
+        let max_age = self.max_age?;
+        let mut age = pointer.age(SystemTime::now())?;
+        if let Some(due) = email.scheduled_time() {
+            let since_due = Utc::now()
+                .signed_duration_since(due)
+                .to_std()
+                .unwrap_or_default();
+            age = age.min(since_due);
+        }
+        if max_age.is_expired(email.category.as_deref(), age) {
+            Some(age)
+        } else {
+            None
+        }
+    }
+
+    /// Send a copy of `email` to each of its personalized recipients. When a recipient table is
+    /// configured the status of each recipient is tracked so recipients who have been sent their
+    /// copy are not sent it again when the email is retried. Copies not ready to transmit by
+    /// `deadline` are left for the retry.
+    async fn send_personalized(
+        &self,
+        email: EmailMessage,
+        deadline: Option<Instant>,
+    ) -> Result<(), String> {
+        let email_id = email.email_id.as_str();
+        let statuses = match self.recipient_table {
+            Some(table_name) => get_recipient_statuses(&self.dynamodb, table_name, email_id)
+                .await
+                .map_err(|error| error.to_string())?,
+            None => {
+                event!(
+                    Level::WARN,
+                    "no recipient table, recipient status is not tracked"
+                );
+                HashMap::new()
+            }
+        };
+        let copies = expand(&email);
+        let total = copies.len();
+        let mut failures = 0;
+        for (recipient, mut copy) in copies {
+            let address = recipient.address.to_string();
+            if statuses.get(&recipient.address) == Some(&EmailStatus::Sent) {
+                event!(Level::DEBUG, %address, "recipient already sent");
+                continue;
+            }
+            if let Some(templates) = self.templates {
+                if let Err(error) = templates.render(&mut copy).await {
+                    event!(Level::ERROR, %address, %error, "render template failed");
+                    failures += 1;
+                    continue;
+                }
+            }
+            if let Err(error) = self.claim_recipient(email_id, &address).await {
+                event!(Level::ERROR, %address, %error, "claim recipient failed");
+                failures += 1;
+                continue;
+            }
+            let transition = match self.send_email(copy, deadline).await {
+                Ok(_) => TO_SENT,
+                Err(error) => {
+                    event!(Level::ERROR, %address, %error, "send recipient email failed");
+                    failures += 1;
+                    TO_PENDING
+                }
+            };
+            if let Err(error) = self
+                .set_recipient_status(email_id, &address, transition)
+                .await
+            {
+                event!(Level::ERROR, %address, %error, "update recipient status failed");
+            }
+        }
+        if failures > 0 {
+            Err(format!("{} of {} recipients failed", failures, total))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Claim `recipient` for sending, held for the sending lease, when a recipient table is
+    /// configured. A recipient left claimed by a worker which stopped is taken over once the lease
+    /// lapses, so the retry of the email sends it.
+    async fn claim_recipient(&self, email_id: &str, recipient: &str) -> Result<(), UpdateError> {
+        match self.recipient_table {
+            Some(table_name) => {
+                claim_recipient(
+                    &self.dynamodb,
+                    table_name,
+                    email_id,
+                    recipient,
+                    self.sending_lease,
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Update the status of `recipient` when a recipient table is configured.
+    async fn set_recipient_status(
+        &self,
+        email_id: &str,
+        recipient: &str,
+        transition: StatusTransition,
+    ) -> Result<(), UpdateError> {
+        match self.recipient_table {
+            Some(table_name) => {
+                set_recipient_status(&self.dynamodb, table_name, email_id, recipient, transition)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Assemble and transmit `email`. When the message can not be assembled by `deadline` it is
+    /// not transmitted, so a slow attachment or rate limit can not hold the email past the
+    /// visibility timeout of its pointer and have it sent twice.
+    async fn send_email(
+        &self,
+        email: EmailMessage,
+        deadline: Option<Instant>,
+    ) -> Result<MimeMessage, String> {
         event!(Level::INFO, email = ?email, "send_email");
+        let prepared = match deadline {
+            Some(deadline) => {
+                let prepare = self.prepare_email(email);
+                tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), prepare)
+                    .await
+                    .map_err(|_| BUDGET_EXCEEDED.to_owned())?
+            }
+            None => self.prepare_email(email).await,
+        };
+        let (email, message) = prepared?;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(BUDGET_EXCEEDED.into());
+        }
+        self.transmit(&email, &message.raw).await?;
+        Ok(message)
+    }
+
+    /// Wait for rate limit budget, redirect, and assemble the message for `email`.
+    async fn prepare_email(
+        &self,
+        email: EmailMessage,
+    ) -> Result<(EmailMessage, MimeMessage), String> {
+        // Wait briefly for budget, otherwise fail the send so the message is retried later
+        if let Some(rate_limiter) = self.rate_limiter {
+            if let Err(wait) = rate_limiter.acquire(&email.provider).await {
+                return Err(format!("Rate limited, budget available in {:?}", wait));
+            }
+        }
+        self.assemble(email, true).await
+    }
+
+    /// Archive, render, sanitize, redirect, and assemble the message for `email`, rewriting its
+    /// links for tracking when `track` is set.
+    async fn assemble(
+        &self,
+        mut email: EmailMessage,
+        track: bool,
+    ) -> Result<(EmailMessage, MimeMessage), String> {
+        // Archived here, where every send passes, so no producer can leave the copy out. The
+        // archival copy is redirected along with every other recipient.
+        if let Some(archive_bcc) = self.archive_bcc {
+            archive_bcc.apply(&mut email);
+        }
+        // Rendered here, where every send passes, after any template was skipped for having a body
+        render_bodies(&mut email);
+        // Sanitized after rendering so any TXT body generated when the message is built is taken
+        // from what is sent
+        if self.sanitize_html {
+            sanitize_body(&mut email);
+        }
+        // Tracked after sanitizing so the rewritten links and pixel are sent as written
+        if let (Some(tracking), true) = (self.tracking, track) {
+            tracking
+                .instrument(&mut email)
+                .await
+                .map_err(|error| error.to_string())?;
+        }
+        // Redirected here, where every send passes, so no path can reach real recipients
+        if let Some(address) = self.redirect_to {
+            redirect(&mut email, address);
+            event!(Level::INFO, %address, subject = %email.subject, "email redirected");
+        }
+        // Attachments are opened at send time so their contents never need to be in the record
+        let attachments = match (self.attachments, email.attachments.is_empty()) {
+            (_, true) => Vec::new(),
+            (Some(fetcher), false) => fetcher
+                .open_all(&email.attachments)
+                .await
+                .map_err(|error| error.to_string())?,
+            (None, false) => return Err("No AttachmentFetcher to open attachments".into()),
+        };
+        let mut contents = Vec::with_capacity(attachments.len());
+        for attachment in attachments {
+            contents.push(attachment.read().await.map_err(|error| error.to_string())?);
+        }
+        // Raw senders transmit the assembled message along with every recipient, including BCC
+        let message = build_message(&email, &contents, chrono::Utc::now());
+        event!(
+            Level::DEBUG,
+            attachments = contents.len(),
+            message_id = %message.message_id,
+            size = message.raw.len(),
+            "message assembled"
+        );
+        Ok((email, message))
+    }
+
+    /// Transmit the assembled `raw` message to every recipient of `email`, including BCC.
+    async fn transmit(&self, email: &EmailMessage, raw: &[u8]) -> Result<(), String> {
+        if let Some(breaker) = self.circuit_breaker {
+            if !breaker.try_acquire(Instant::now()) {
+                return Err("Circuit breaker open, provider not called".into());
+            }
+        }
+        let started = Instant::now();
+        let result = self
+            .provider_retry
+            .retry(|| self.transmit_once(email, raw))
+            .await;
+        if let Some(metrics) = self.metrics {
+            metrics.time_send(started.elapsed());
+        }
+        if let Some(breaker) = self.circuit_breaker {
+            match &result {
+                Ok(()) => breaker.record_success(),
+                Err(_) => breaker.record_failure(Instant::now()),
+            }
+        }
+        result
+    }
+
+    /// Make a single attempt to hand `raw` to the email provider.
+    async fn transmit_once(&self, email: &EmailMessage, raw: &[u8]) -> Result<(), String> {
+        event!(
+            Level::DEBUG,
+            recipients =
+                email.recipients_to.len() + email.recipients_cc.len() + email.recipients_bcc.len(),
+            size = raw.len(),
+            "transmit"
+        );
         Err("Unimplemented".into())
     }
 }
 
+impl<'a> Client<'a> {
+    /// Fail emails whose pointer message is older than `max_age` allows instead of sending them.
+    pub fn with_max_age(self, max_age: &'a MaxMessageAge) -> Self {
+        Client {
+            max_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Blind copy the archival address `archive_bcc` gives for each email on every message
+    /// sent, including resends and each copy of a personalized email.
+    pub fn with_archive_bcc(self, archive_bcc: &'a ArchiveBcc) -> Self {
+        Client {
+            archive_bcc: Some(archive_bcc),
+            ..self
+        }
+    }
+
+    /// Open attachment contents with `attachments` when sending.
+    pub fn with_attachments(self, attachments: &'a AttachmentFetcher) -> Self {
+        Client {
+            attachments: Some(attachments),
+            ..self
+        }
+    }
+
+    /// Track the status of each recipient of personalized emails in `recipient_table`.
+    pub fn with_recipient_table(self, recipient_table: &'a str) -> Self {
+        Client {
+            recipient_table: Some(recipient_table),
+            ..self
+        }
+    }
+
+    /// Store the exact message sent for each email in `mime_store` so it can be resent unchanged.
+    pub fn with_mime_store(self, mime_store: &'a dyn MimeStore) -> Self {
+        Client {
+            mime_store: Some(mime_store),
+            ..self
+        }
+    }
+
+    /// Only send to recipients on domains `domains` allows.
+    pub fn with_domain_policy(self, domains: &'a DomainPolicy) -> Self {
+        Client {
+            domains: Some(domains),
+            ..self
+        }
+    }
+
+    /// Keep sends within the budget of `rate_limiter`.
+    pub fn with_rate_limiter(self, rate_limiter: &'a RateLimiter) -> Self {
+        Client {
+            rate_limiter: Some(rate_limiter),
+            ..self
+        }
+    }
+
+    /// Record counts of processed messages and the time taken by sends in `metrics`.
+    pub fn with_metrics(self, metrics: &'a Metrics) -> Self {
+        Client {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Send emails which fail their last attempt to `failure_queue` and mark them
+    /// `EmailStatus::Failed` rather than leaving them to the redrive policy of the queue.
+    pub fn with_failure_queue(self, failure_queue: &'a FailureQueue) -> Self {
+        Client {
+            failure_queue: Some(failure_queue),
+            ..self
+        }
+    }
+
+    /// Stop calling the email provider while `breaker` is open, leaving messages to be delivered
+    /// again.
+    pub fn with_circuit_breaker(self, breaker: &'a CircuitBreaker) -> Self {
+        Client {
+            circuit_breaker: Some(breaker),
+            ..self
+        }
+    }
+
+    /// Ask DynamoDB for the capacity each call consumes, logging the read and write capacity units
+    /// of every batch and adding them to the metrics, so the capacity of the email table can be
+    /// planned from what it uses.
+    pub fn with_consumed_capacity(self) -> Self {
+        let meter = CapacityMeter::default();
+        Client {
+            dynamodb: meter.install(&self.dynamodb),
+            capacity: Some(meter),
+            ..self
+        }
+    }
+
+    /// Attempt calls to DynamoDB as `policy` allows instead of the SDK default.
+    pub fn with_dynamo_retry(self, policy: RetryPolicy) -> Self {
+        let config = self
+            .dynamodb
+            .config()
+            .to_builder()
+            .retry_config(policy.retry_config())
+            .build();
+        Client {
+            dynamodb: DynamoDbClient::from_conf(config),
+            ..self
+        }
+    }
+
+    /// Attempt calls a `Runner` makes to SQS as `policy` allows instead of the SDK default.
+    pub fn with_sqs_retry(self, policy: RetryPolicy) -> Self {
+        Client {
+            sqs_retry: Some(policy),
+            ..self
+        }
+    }
+
+    /// Release the claim on an email and retry its message when it is not ready to transmit within
+    /// `budget` of starting to process it. Keep `budget` well inside the visibility timeout of the queue.
+    pub fn with_message_budget(self, budget: Duration) -> Self {
+        Client {
+            message_budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Hold the claim on an email being sent for `lease` before another delivery may take it over
+    /// from a worker which stopped before recording the send. Keep `lease` longer than a send
+    /// takes or an email may be sent twice.
+    pub fn with_sending_lease(self, lease: Duration) -> Self {
+        Client {
+            sending_lease: lease,
+            ..self
+        }
+    }
+
+    /// Write `ExpiresAt` on emails once they are no longer being sent so DynamoDB TTL deletes them
+    /// `retention` later, rather than the table growing forever.
+    pub fn with_retention(self, retention: Duration) -> Self {
+        Client {
+            retention: Some(retention),
+            ..self
+        }
+    }
+
+    /// Attempt to transmit each message through the email provider as `policy` allows. By default
+    /// a failed transmission is left for the message to be delivered again.
+    pub fn with_provider_retry(self, policy: RetryPolicy) -> Self {
+        Client {
+            provider_retry: policy,
+            ..self
+        }
+    }
+
+    /// Alert through `alerts` when an email fails and when too many sends fail.
+    pub fn with_alerts(self, alerts: &'a Alerts) -> Self {
+        Client {
+            alerts: Some(alerts),
+            ..self
+        }
+    }
+
+    /// Announce emails being queued, claimed for sending, sent, and failed on `event_bus`.
+    pub fn with_event_bus(self, event_bus: &'a EventBus) -> Self {
+        Client {
+            event_bus: Some(event_bus),
+            ..self
+        }
+    }
+
+    /// Send every email to `address` instead of its recipients, which are named at the start of
+    /// the subject. For non-production deployments which must never mail real users.
+    pub fn with_redirect_to(self, address: &'a str) -> Self {
+        Client {
+            redirect_to: Some(address),
+            ..self
+        }
+    }
+
+    /// Strip scripts, event handlers, and dangerous URLs from the HTML body of every email before
+    /// it is sent, for deployments rendering data from untrusted producers into their templates.
+    pub fn with_html_sanitizer(self) -> Self {
+        Client {
+            sanitize_html: true,
+            ..self
+        }
+    }
+
+    /// Drop recipients found in `suppressions` before sending.
+    pub fn with_suppressions(self, suppressions: &'a Suppressions) -> Self {
+        Client {
+            suppressions: Some(suppressions),
+            ..self
+        }
+    }
+
+    /// Render the bodies of emails which have a template but no body with `templates`.
+    pub fn with_templates(self, templates: &'a Templates) -> Self {
+        Client {
+            templates: Some(templates),
+            ..self
+        }
+    }
+
+    /// Read the emails of pointers naming a tenant from the table of that tenant and send them
+    /// from its sender through its provider. Pointers naming a tenant not in `tenants` are
+    /// quarantined rather than read from any table.
+    pub fn with_tenants(self, tenants: &'a Tenants) -> Self {
+        Client {
+            tenants: Some(tenants),
+            ..self
+        }
+    }
+
+    /// Rewrite the links of every HTML body and add a tracking pixel so opens and clicks are
+    /// recorded by the endpoint of `tracking`.
+    pub fn with_tracking(self, tracking: &'a Tracking) -> Self {
+        Client {
+            tracking: Some(tracking),
+            ..self
+        }
+    }
+}
+
+/// The transition rejecting `email` with `to` before it is sent, from `EmailStatus::Pending` or
+/// from `EmailStatus::Sending` when a lapsed claim was taken over.
+fn rejected(email: &EmailMessage, to: EmailStatus) -> StatusTransition {
+    StatusTransition {
+        from: email.status,
+        to,
+    }
+}
+
+/// A canary email identified by `email_id` sent from and to `recipient`.
+fn canary_email(email_id: EmailId, recipient: &str) -> Result<EmailMessage, Vec<ValidationError>> {
+    EmailMessageBuilder::new(email_id)
+        .category(CANARY_CATEGORY)
+        .sender(recipient)
+        .to(recipient)
+        .subject(format!("{} canary", env!("CARGO_PKG_NAME")))
+        .body_text(format!(
+            "Canary email sent by {} {} to verify a deployment.",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+}
+
 impl<'a> std::fmt::Debug for Client<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Client").finish()
     }
 }
+
+#[cfg(test)]
+mod canary_email {
+    use super::*;
+
+    #[test]
+    fn builds_pending_email_to_recipient() {
+        let email = canary_email("Test EmailId".into(), "Canary@Example.com").unwrap();
+        assert_eq!(email.status, EmailStatus::Pending);
+        assert_eq!(email.category.as_deref(), Some(CANARY_CATEGORY));
+        assert_eq!(email.recipients_to, vec!["Canary@example.com"]);
+        assert_eq!(email.sender, "Canary@example.com");
+    }
+
+    #[test]
+    fn rejects_invalid_recipient() {
+        assert!(canary_email("Test EmailId".into(), "not an address").is_err());
+    }
+}
+
+#[cfg(test)]
+mod prepare_email {
+    use super::*;
+    use crate::test_support::{email, InMemoryDynamoDb};
+
+    #[tokio::test]
+    async fn sanitizes_html_when_configured() {
+        let email = EmailMessage {
+            body_html: "<p onclick=\"steal()\">Hi<script>steal()</script></p>".into(),
+            body_text: String::new(),
+            ..email("Test EmailId", EmailStatus::Pending)
+        };
+        let dynamodb = InMemoryDynamoDb::default().client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let (prepared, _) = client.prepare_email(email.clone()).await.unwrap();
+        assert_eq!(prepared.body_html, email.body_html);
+        let client = client.with_html_sanitizer();
+        let (prepared, _) = client.prepare_email(email).await.unwrap();
+        assert_eq!(prepared.body_html, "<p>Hi</p>");
+    }
+}
+
+#[cfg(test)]
+mod preview {
+    use super::*;
+    use crate::test_support::{email, InMemoryDynamoDb};
+
+    #[tokio::test]
+    async fn renders_without_changing_record() {
+        let email = EmailMessage {
+            body_markdown: "Hello *there*".into(),
+            body_text: String::new(),
+            ..email("Test EmailId", EmailStatus::Pending)
+        };
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email);
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table").with_redirect_to("qa@example.com");
+        let message = client.preview("Test EmailId", None).await.unwrap();
+        let raw = String::from_utf8(message.raw).unwrap();
+        assert!(raw.contains("To: qa@example.com\r\n"));
+        assert!(raw.contains("<em>there</em>"));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.calls("TransactWriteItems"), 0);
+    }
+
+    /// The email of a tenant is read from the table of the tenant and previewed from its sender.
+    #[tokio::test]
+    async fn reads_tenant_emails_from_their_table() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let tenants = Tenants::from([(
+            "acme".to_owned(),
+            Tenant {
+                table_name: "Acme Table".into(),
+                provider: None,
+                sender: Some("Acme <mail@acme.com>".into()),
+            },
+        )]);
+        let client = Client::new(&dynamodb, "Test Table").with_tenants(&tenants);
+        let message = client.preview("Test EmailId", Some("acme")).await.unwrap();
+        let raw = String::from_utf8(message.raw).unwrap();
+        assert!(raw.contains("From: Acme <mail@acme.com>\r\n"));
+        let tables = table
+            .requests("GetItem")
+            .iter()
+            .map(|request| request["TableName"].as_str().unwrap_or_default().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(tables, vec!["Acme Table".to_owned()]);
+    }
+}
+
+#[cfg(test)]
+mod send_now {
+    use super::*;
+    use crate::test_support::{email, InMemoryDynamoDb};
+
+    #[tokio::test]
+    async fn refuses_emails_not_pending() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Sent));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let error = client.send_now("Test EmailId", None).await.unwrap_err();
+        assert_eq!(error, DirectSendError::UnexpectedStatus(EmailStatus::Sent));
+        assert_eq!(table.calls("UpdateItem"), 0);
+    }
+
+    /// A tenant which is not configured is refused without any table being read.
+    #[tokio::test]
+    async fn refuses_unknown_tenants() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let error = client
+            .send_now("Test EmailId", Some("acme"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error,
+            DirectSendError::GetError(GetError::UnknownTenant("acme".into()))
+        );
+        assert_eq!(table.calls("GetItem"), 0);
+    }
+
+    #[tokio::test]
+    async fn claims_pending_email() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        // No provider answers in tests so the claim is released for the pointer to retry
+        let error = client.send_now("Test EmailId", None).await.unwrap_err();
+        assert!(matches!(error, DirectSendError::ProcessError(_)));
+        assert!(table.calls("UpdateItem") > 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+    }
+}
+
+#[cfg(test)]
+mod dead_letter {
+    use super::*;
+    use crate::test_support::{email, InMemoryDynamoDb, InMemoryQueue, InMemorySqs};
+
+    fn pointer() -> EmailPointerMessage {
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        EmailPointerMessage::try_from(queue.receive().remove(0)).unwrap()
+    }
+
+    fn failure_queue(sqs: &InMemorySqs) -> FailureQueue {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/123456789012/failures"
+            .parse()
+            .unwrap();
+        FailureQueue::new(queue_url, 1, sqs.client())
+    }
+
+    #[tokio::test]
+    async fn fails_unclaimed_emails() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let sqs = InMemorySqs::default();
+        let queue = failure_queue(&sqs);
+        let client = Client::new(&dynamodb, "Test Table");
+        assert!(client.dead_letter(&queue, &pointer(), "Test Error").await);
+        assert_eq!(sqs.queue().len(), 1);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Failed"));
+    }
+
+    /// A claimed email may have been sent by the delivery holding the claim, so only the dead
+    /// letter is sent and the claim is left to lapse.
+    #[tokio::test]
+    async fn leaves_claimed_emails_for_the_sweeper() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Sending));
+        let dynamodb = table.client();
+        let sqs = InMemorySqs::default();
+        let queue = failure_queue(&sqs);
+        let client = Client::new(&dynamodb, "Test Table");
+        assert!(client.dead_letter(&queue, &pointer(), "Test Error").await);
+        assert_eq!(sqs.queue().len(), 1);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+    }
+}
+
+#[cfg(test)]
+mod process_messages {
+    use super::*;
+    use crate::test_support::{email, InMemoryDynamoDb, InMemoryQueue};
+
+    /// Messages processed by the throughput test.
+    const MESSAGES: usize = 500;
+    /// Fewest messages per second the pipeline must process, set well below what a CI runner
+    /// manages with a debug build so only real regressions fail.
+    const MIN_MESSAGES_PER_SECOND: f64 = 50.0;
+
+    fn message(index: usize) -> Message {
+        Message::builder()
+            .message_id(format!("Test MessageId {}", index))
+            .receipt_handle(format!("Test ReceiptHandle {}", index))
+            .body(format!(r#"{{"email_id":"Test EmailId {}"}}"#, index))
+            .build()
+    }
+
+    /// Guards against redesigns of the pipeline lowering throughput. Every message is read,
+    /// validated, rendered, marked `EmailStatus::Sending`, and reset to `EmailStatus::Pending`
+    /// once the provider fails, with DynamoDB answered in memory. Each message points at its own
+    /// email so none accumulates a long `StatusHistory`.
+    #[tokio::test]
+    async fn sustains_minimum_throughput() {
+        let table = InMemoryDynamoDb::default();
+        for index in 0..MESSAGES {
+            table.insert(&email(
+                &format!("Test EmailId {}", index),
+                EmailStatus::Pending,
+            ));
+        }
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::try_from(message(0)).unwrap();
+        let stored = get_email_message(&dynamodb, "Test Table", &pointer).await;
+        assert_eq!(stored.map(|stored| stored.status), Ok(EmailStatus::Pending));
+        let client = Client::new(&dynamodb, "Test Table");
+        let started = Instant::now();
+        let outcome = client.process_messages((0..MESSAGES).map(message)).await;
+        let elapsed = started.elapsed();
+        assert_eq!(outcome.len(), MESSAGES);
+        assert_eq!(outcome.retry.len(), MESSAGES);
+        let per_second = MESSAGES as f64 / elapsed.as_secs_f64();
+        assert!(
+            per_second >= MIN_MESSAGES_PER_SECOND,
+            "processed {:.0} messages per second, fewer than {}",
+            per_second,
+            MIN_MESSAGES_PER_SECOND
+        );
+    }
+
+    /// A pointer whose email can not be sent stays on the queue, each delivery backing off
+    /// further and leaving the email `EmailStatus::Pending` for the next attempt.
+    #[tokio::test]
+    async fn redelivers_retried_messages() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        let message_id = queue.send_pointer("Test EmailId");
+        let mut timeouts = Vec::new();
+        for attempt in 1..=4 {
+            let outcome = client.process_messages(queue.receive()).await;
+            assert_eq!(outcome.retry.len(), 1);
+            assert_eq!(outcome.retry[0].receive_count, attempt);
+            timeouts.push(outcome.retry[0].retry_visibility_timeout());
+            queue.apply(&outcome);
+            assert_eq!(queue.receive_count(&message_id), Some(attempt));
+            assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        }
+        assert_eq!(queue.len(), 1);
+        assert!(timeouts.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(timeouts[0] < timeouts[3]);
+    }
+
+    /// An email not ready to transmit within the message budget is released back to
+    /// `EmailStatus::Pending` and its message retried without the provider being called.
+    #[tokio::test]
+    async fn releases_claim_when_budget_exceeded() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let metrics = Metrics::default();
+        let client = Client::new(&dynamodb, "Test Table")
+            .with_message_budget(Duration::from_secs(0))
+            .with_metrics(&metrics);
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.retry.len(), 1);
+        assert_eq!(table.calls("UpdateItem"), 2);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        assert!(metrics
+            .render_prometheus()
+            .contains("send_latency_seconds_count 0\n"));
+    }
+
+    /// An email left `EmailStatus::Sending` by a worker which stopped is taken over once the
+    /// claim lapses, while one still within its lease is left to the worker holding it.
+    #[tokio::test]
+    async fn takes_over_lapsed_claims() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            sending_lock_expires_at: Some("2021-03-24T00:00:00Z".into()),
+            ..email("Lapsed EmailId", EmailStatus::Sending)
+        });
+        table.insert(&EmailMessage {
+            sending_lock_expires_at: Some("9999-12-31T23:59:59Z".into()),
+            ..email("Held EmailId", EmailStatus::Sending)
+        });
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Lapsed EmailId");
+        queue.send_pointer("Held EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.retry.len(), 1);
+        assert_eq!(outcome.retry[0].email_id, "Lapsed EmailId");
+        assert_eq!(outcome.delete.len(), 1);
+        assert_eq!(table.status("Lapsed EmailId").as_deref(), Some("Pending"));
+        assert_eq!(table.status("Held EmailId").as_deref(), Some("Sending"));
+    }
+
+    /// An email taken over from a lapsed claim which has expired is failed from
+    /// `EmailStatus::Sending`, removing the claim, rather than retried.
+    #[tokio::test]
+    async fn fails_expired_lapsed_claims() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            sending_lock_expires_at: Some("2021-03-24T00:00:00Z".into()),
+            ..email("Test EmailId", EmailStatus::Sending)
+        });
+        let dynamodb = table.client();
+        let max_age: MaxMessageAge = "1h".parse().unwrap();
+        let client = Client::new(&dynamodb, "Test Table").with_max_age(&max_age);
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId"}"#)
+            .attributes(
+                aws_sdk_sqs::types::MessageSystemAttributeName::SentTimestamp,
+                "1616544000000",
+            )
+            .build();
+        let outcome = client.process_messages(vec![message]).await;
+        assert_eq!(outcome.delete.len(), 1);
+        assert!(outcome.retry.is_empty());
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Failed"));
+        assert_eq!(
+            table.string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT),
+            None
+        );
+    }
+
+    /// A pointer received before the email is scheduled is hidden until it is due, leaving the
+    /// email `EmailStatus::Pending` and unclaimed.
+    #[tokio::test]
+    async fn defers_scheduled_emails() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            scheduled_at: Some((Utc::now() + chrono::Duration::hours(2)).to_rfc3339()),
+            ..email("Test EmailId", EmailStatus::Pending)
+        });
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.defer.len(), 1);
+        let (pointer, delay) = &outcome.defer[0];
+        assert!(delay > &Duration::from_secs(60 * 60));
+        assert_eq!(
+            pointer.defer_entry(*delay).visibility_timeout(),
+            Some(delay.as_secs() as i32)
+        );
+        queue.apply(&outcome);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+    }
+
+    /// The capacity DynamoDB consumed processing a batch is added to the metrics once the batch
+    /// is processed.
+    #[tokio::test]
+    async fn counts_consumed_capacity() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Sent));
+        let dynamodb = table.client();
+        let metrics = Metrics::default();
+        let client = Client::new(&dynamodb, "Test Table")
+            .with_consumed_capacity()
+            .with_metrics(&metrics);
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 1);
+        let text = metrics.render_prometheus();
+        // A single consistent read of the email found it already sent
+        assert!(text.contains("consumed_read_capacity_units_total 1\n"));
+        assert!(text.contains("consumed_write_capacity_units_total 0\n"));
+    }
+
+    /// The emails of a batch are read with one `BatchGetItem`, while a second pointer to an email
+    /// reads it again as the first left it.
+    #[tokio::test]
+    async fn prefetches_emails_of_a_batch() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("First EmailId", EmailStatus::Sent));
+        table.insert(&email("Second EmailId", EmailStatus::Sent));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("First EmailId");
+        queue.send_pointer("Second EmailId");
+        queue.send_pointer("First EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 3);
+        assert_eq!(table.calls("BatchGetItem"), 1);
+        assert_eq!(table.calls("GetItem"), 1);
+    }
+
+    /// Pointers delivered more than once for an email which was already sent are deleted
+    /// without the email being touched again.
+    #[tokio::test]
+    async fn skips_duplicate_deliveries() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Sent));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 2);
+        queue.apply(&outcome);
+        assert_eq!(queue.len(), 0);
+        assert!(queue.receive().is_empty());
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sent"));
+    }
+
+    /// A pointer to a cancelled email is deleted without the email being claimed or sent.
+    #[tokio::test]
+    async fn skips_cancelled_emails() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Cancelled));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 1);
+        queue.apply(&outcome);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Cancelled"));
+    }
+
+    /// A pointer naming a tenant has its email read from and claimed in the table of the tenant,
+    /// while a pointer without one still uses the default table.
+    #[tokio::test]
+    async fn routes_tenant_pointers_to_their_table() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let tenants = Tenants::from([(
+            "acme".to_owned(),
+            Tenant {
+                table_name: "Acme Table".into(),
+                provider: Some("ses-acme".into()),
+                sender: Some("mail@acme.com".into()),
+            },
+        )]);
+        let client = Client::new(&dynamodb, "Test Table").with_tenants(&tenants);
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId","tenant":"acme"}"#)
+            .build();
+        let outcome = client.process_messages(vec![message]).await;
+        assert_eq!(outcome.retry.len(), 1);
+        assert_eq!(outcome.retry[0].tenant.as_deref(), Some("acme"));
+        let tables = ["GetItem", "UpdateItem"]
+            .iter()
+            .flat_map(|operation| table.requests(operation))
+            .map(|request| request["TableName"].as_str().unwrap_or_default().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(tables.len(), 3);
+        assert!(tables.iter().all(|table_name| table_name == "Acme Table"));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+    }
+
+    /// A pointer naming a tenant which is not configured is quarantined without any table being
+    /// read.
+    #[tokio::test]
+    async fn quarantines_unknown_tenants() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId","tenant":"acme"}"#)
+            .build();
+        let outcome = client.process_messages(vec![message]).await;
+        assert_eq!(outcome.quarantine.len(), 1);
+        assert_eq!(outcome.quarantine[0].1, PointerError::UnknownTenant);
+        assert!(outcome.retry.is_empty());
+        assert_eq!(table.calls("GetItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+    }
+
+    /// An email whose every recipient is on a blocked domain is marked `EmailStatus::Suppressed`
+    /// and its message deleted rather than handed to the provider.
+    #[tokio::test]
+    async fn suppresses_blocked_domains() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let dynamodb = table.client();
+        let domains = DomainPolicy::new(vec!["mycompany.com"], Vec::<String>::new());
+        let client = Client::new(&dynamodb, "Test Table").with_domain_policy(&domains);
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 1);
+        assert_eq!(table.calls("UpdateItem"), 1);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Suppressed"));
+    }
+
+    #[tokio::test]
+    async fn fails_header_injection() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            subject: "Test Subject\r\nBcc: victim@example.com".into(),
+            ..email("Test EmailId", EmailStatus::Pending)
+        });
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let outcome = client.process_messages(queue.receive()).await;
+        assert_eq!(outcome.delete.len(), 1);
+        assert_eq!(table.calls("UpdateItem"), 1);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Failed"));
+    }
+
+    /// A delete made with the receipt handle of an earlier delivery leaves the message on the
+    /// queue, as SQS does once a message has been received again.
+    #[tokio::test]
+    async fn ignores_stale_receipt_handles() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Sent));
+        let dynamodb = table.client();
+        let client = Client::new(&dynamodb, "Test Table");
+        let mut queue = InMemoryQueue::default();
+        let message_id = queue.send_pointer("Test EmailId");
+        let first = queue.receive();
+        queue.apply(&BatchOutcome::default());
+        let second = queue.receive();
+        let stale = client.process_messages(first).await;
+        queue.apply(&stale);
+        assert_eq!(queue.receive_count(&message_id), Some(2));
+        let current = client.process_messages(second).await;
+        queue.apply(&current);
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::email_message::{EmailId, EmailMessage};
+use crate::queue::EmailPointerMessage;
+use crate::queue_url::QueueUrl;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::Client as SqsClient;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{event, Level};
+
+/// Attributes of the email snapshot left out of dead letters. Attachment contents can exceed the
+/// size limit of an SQS message and bodies are available from the record itself.
+const SNAPSHOT_OMITTED: [&str; 4] = ["Attachments", "BodyHtml", "BodyMarkdown", "BodyText"];
+
+/// What is sent to the failure queue for an email which ran out of attempts.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeadLetter {
+    /// Id of the email which could not be sent.
+    pub email_id: EmailId,
+    /// Id of the SQS message pointing to the email.
+    pub message_id: String,
+    /// Number of times the pointer was received.
+    pub receive_count: u32,
+    /// Error from the final attempt.
+    pub error: String,
+    /// When the email was given up on.
+    pub failed_at: String,
+    /// The email record when it was given up on, without attachments or bodies.
+    pub email: Option<Value>,
+}
+
+impl DeadLetter {
+    pub fn new(pointer: &EmailPointerMessage, error: &str, email: Option<&EmailMessage>) -> Self {
+        DeadLetter {
+            email_id: pointer.email_id.clone(),
+            message_id: pointer.message_id().to_owned(),
+            receive_count: pointer.receive_count,
+            error: error.to_owned(),
+            failed_at: Utc::now().to_rfc3339(),
+            email: email.and_then(snapshot),
+        }
+    }
+}
+
+/// `email` as JSON without the attributes in `SNAPSHOT_OMITTED`.
+fn snapshot(email: &EmailMessage) -> Option<Value> {
+    let mut value = serde_json::to_value(email).ok()?;
+    if let Value::Object(fields) = &mut value {
+        for name in SNAPSHOT_OMITTED.iter() {
+            fields.remove(*name);
+        }
+    }
+    Some(value)
+}
+
+/// Queue receiving emails which failed on every one of `max_attempts` attempts. Pointers which
+/// run out of attempts are sent here with the final error, and their email is marked
+/// `EmailStatus::Failed`, instead of relying on the redrive policy of the queue.
+#[derive(Clone, Debug)]
+pub struct FailureQueue {
+    /// Most times a pointer is received before its email is given up on.
+    max_attempts: u32,
+    /// URL of the queue dead letters are sent to.
+    queue_url: QueueUrl,
+    /// Connection to SQS.
+    sqs: SqsClient,
+}
+
+impl FailureQueue {
+    pub fn new(queue_url: QueueUrl, max_attempts: u32, sqs: SqsClient) -> Self {
+        FailureQueue {
+            max_attempts: max_attempts.max(1),
+            queue_url,
+            sqs,
+        }
+    }
+
+    /// Whether `pointer` has been received as many times as allowed.
+    pub fn is_exhausted(&self, pointer: &EmailPointerMessage) -> bool {
+        pointer.receive_count >= self.max_attempts
+    }
+
+    /// Send `letter` to the failure queue.
+    pub async fn send(&self, letter: &DeadLetter) -> Result<(), String> {
+        let body = serde_json::to_string(letter).map_err(|error| error.to_string())?;
+        self.sqs
+            .send_message()
+            .message_body(body)
+            .queue_url(self.queue_url.as_str())
+            .send()
+            .await
+            .map_err(|error| format!("{}", DisplayErrorContext(&error)))?;
+        event!(Level::INFO, email_id = %letter.email_id, "dead letter sent");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod snapshot {
+    use super::*;
+    use crate::email_message::EmailStatus;
+
+    #[test]
+    fn omits_bodies_and_attachments() {
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            body_html: "<p>Test Body</p>".into(),
+            body_text: "Test Body".into(),
+            status: EmailStatus::Pending,
+            ..EmailMessage::default()
+        };
+        let value = snapshot(&email).unwrap();
+        assert_eq!(value["EmailId"], "Test EmailId");
+        assert_eq!(value["EmailStatus"], "Pending");
+        assert!(value.get("BodyHtml").is_none());
+        assert!(value.get("BodyText").is_none());
+        assert!(value.get("Attachments").is_none());
+    }
+}
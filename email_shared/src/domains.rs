@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+
+/// Recipient domains which may be sent mail. A domain also covers its subdomains, so denying
+/// "example.com" denies "mail.example.com". Denied domains are blocked even when allowed.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::DomainPolicy;
+///
+/// let policy = DomainPolicy::new(vec!["example.com"], vec!["spam.example.com"]);
+/// assert!(policy.is_allowed("a@Example.com"));
+/// assert!(policy.is_allowed("a@mail.example.com"));
+/// assert!(!policy.is_allowed("a@spam.example.com"));
+/// assert!(!policy.is_allowed("a@example.org"));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DomainPolicy {
+    /// Domains which may be sent mail, every domain when empty.
+    allow: BTreeSet<String>,
+    /// Domains which are never sent mail.
+    deny: BTreeSet<String>,
+}
+
+impl DomainPolicy {
+    pub fn new<I, J, S, T>(allow: I, deny: J) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        J: IntoIterator<Item = T>,
+        S: AsRef<str>,
+        T: AsRef<str>,
+    {
+        DomainPolicy {
+            allow: normalize_domains(allow),
+            deny: normalize_domains(deny),
+        }
+    }
+
+    /// Whether the policy blocks no domain.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether mail may be sent to `address`. Addresses without a domain are never allowed.
+    pub fn is_allowed(&self, address: &str) -> bool {
+        let domain = match address.trim().rsplit_once('@') {
+            Some((_, domain)) if !domain.is_empty() => domain.to_ascii_lowercase(),
+            _ => return false,
+        };
+        let matches = |listed: &String| {
+            domain == *listed
+                || (domain.len() > listed.len()
+                    && domain.ends_with(listed.as_str())
+                    && domain.as_bytes()[domain.len() - listed.len() - 1] == b'.')
+        };
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+/// Lower case `domains` without surrounding whitespace or a leading "@" or ".", dropping empty
+/// entries.
+fn normalize_domains<I, S>(domains: I) -> BTreeSet<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    domains
+        .into_iter()
+        .map(|domain| {
+            domain
+                .as_ref()
+                .trim()
+                .trim_start_matches(['@', '.'])
+                .to_ascii_lowercase()
+        })
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod is_allowed {
+    use super::*;
+
+    #[test]
+    fn allows_everything_when_empty() {
+        let policy = DomainPolicy::new(Vec::<String>::new(), Vec::<String>::new());
+        assert!(policy.is_empty());
+        assert!(policy.is_allowed("a@example.com"));
+        assert!(!policy.is_allowed("example.com"));
+    }
+
+    #[test]
+    fn denies_listed_domains_and_subdomains() {
+        let policy = DomainPolicy::new(Vec::<String>::new(), vec![" @Example.COM "]);
+        assert!(!policy.is_allowed("a@example.com"));
+        assert!(!policy.is_allowed("a@mail.example.com"));
+        assert!(policy.is_allowed("a@notexample.com"));
+        assert!(policy.is_allowed("a@example.org"));
+    }
+}
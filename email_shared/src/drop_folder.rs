@@ -0,0 +1,480 @@
+use crate::error::EnqueueError;
+use crate::events::{EmailEvent, EmailEventType, EventBus};
+use crate::fifo::MessageGroup;
+use crate::producer::{enqueue_email, EmailMessageDraft};
+use crate::queue::delete_entry;
+use crate::queue_url::QueueUrl;
+use crate::runner::{delete_messages, BatchReport, DeleteOutcome, MessageSource};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_sqs::types::Message;
+use aws_sdk_sqs::Client as SqsClient;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{event, Instrument, Level};
+
+/// Prefix of the `eventName` of every S3 notification for a newly written object.
+const OBJECT_CREATED: &str = "ObjectCreated:";
+
+/// Possible errors ingesting an object dropped into the bucket.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum DropError {
+    /// The message body is not an S3 event notification. Reprocessing the message will also fail.
+    #[error("InvalidBody({0})")]
+    InvalidBody(String),
+    /// The object is neither an .eml file nor a JSON email description. Reprocessing the message
+    /// will also fail.
+    #[error("InvalidObject({key}, {message})")]
+    InvalidObject { key: String, message: String },
+    /// The object could not be read from S3.
+    #[error("GetObjectError({0})")]
+    GetObjectError(String),
+    /// The email described by the object could not be enqueued.
+    #[error("EnqueueError({0})")]
+    EnqueueError(#[from] EnqueueError),
+}
+
+impl DropError {
+    /// Whether reprocessing the notification will fail the same way.
+    fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            DropError::InvalidBody(_)
+                | DropError::InvalidObject { .. }
+                | DropError::EnqueueError(EnqueueError::Invalid(_))
+        )
+    }
+}
+
+/// An object written to the drop bucket, as named by an S3 event notification.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DroppedObject {
+    /// Bucket the object was written to.
+    pub bucket: String,
+    /// Key of the object, decoded from the form S3 notifications use.
+    pub key: String,
+}
+
+/// SNS notification wrapping the S3 notification when raw message delivery is not enabled.
+#[derive(Deserialize)]
+struct SnsEnvelope {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct S3Notification {
+    /// Missing from the `s3:TestEvent` sent when notifications are configured.
+    #[serde(default, rename = "Records")]
+    records: Vec<S3EventRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct S3EventRecord {
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+struct S3Entity {
+    bucket: S3Bucket,
+    object: S3ObjectKey,
+}
+
+#[derive(Deserialize)]
+struct S3Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct S3ObjectKey {
+    key: String,
+}
+
+/// Parse the body of a drop queue message, either an SNS notification or the S3 event
+/// notification itself, into the objects it reports as created. Other events, such as deletions
+/// or the test event sent when notifications are configured, report no objects.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::parse_drop_notification;
+///
+/// let body = r#"{"Records": [{
+///     "eventName": "ObjectCreated:Put",
+///     "s3": {"bucket": {"name": "drop"}, "object": {"key": "welcome+mail%281%29.eml"}}
+/// }]}"#;
+/// let objects = parse_drop_notification(body).unwrap();
+/// assert_eq!(objects[0].bucket, "drop");
+/// assert_eq!(objects[0].key, "welcome mail(1).eml");
+/// ```
+pub fn parse_drop_notification(body: &str) -> Result<Vec<DroppedObject>, DropError> {
+    let body = match serde_json::from_str::<SnsEnvelope>(body) {
+        Ok(envelope) => envelope.message,
+        Err(_) => body.to_owned(),
+    };
+    let notification = serde_json::from_str::<S3Notification>(&body)
+        .map_err(|e| DropError::InvalidBody(e.to_string()))?;
+    Ok(notification
+        .records
+        .into_iter()
+        .filter(|record| record.event_name.starts_with(OBJECT_CREATED))
+        .map(|record| DroppedObject {
+            bucket: record.s3.bucket.name,
+            key: decode_key(&record.s3.object.key),
+        })
+        .collect())
+}
+
+/// `key` as written, S3 notifications form encode keys so spaces arrive as `+` and other
+/// reserved characters percent encoded. A key which does not decode to UTF-8 is kept as given.
+fn decode_key(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let escaped = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| key.to_owned())
+}
+
+/// Read `contents` of the dropped `object` as a draft to enqueue. Objects whose key ends in
+/// `.eml` are read as RFC 5322 messages and objects whose key ends in `.json` as an
+/// `EmailMessageDraft`. A JSON draft without an idempotency key is given one naming the object
+/// and `e_tag` so a notification delivered twice enqueues the email once, while writing new
+/// contents to the same key enqueues another.
+fn draft_from_object(
+    object: &DroppedObject,
+    contents: &[u8],
+    e_tag: Option<&str>,
+) -> Result<EmailMessageDraft, DropError> {
+    let invalid = |message: String| DropError::InvalidObject {
+        key: object.key.clone(),
+        message,
+    };
+    let extension = object
+        .key
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("eml") => EmailMessageDraft::from_eml(contents).map_err(|e| invalid(e.to_string())),
+        Some("json") => {
+            let mut draft = serde_json::from_slice::<EmailMessageDraft>(contents)
+                .map_err(|e| invalid(e.to_string()))?;
+            if draft.idempotency_key.is_none() {
+                draft.idempotency_key = Some(format!(
+                    "s3:{}/{}:{}",
+                    object.bucket,
+                    object.key,
+                    e_tag.unwrap_or_default().trim_matches('"')
+                ));
+            }
+            Ok(draft)
+        }
+        _ => Err(invalid("expected an .eml or .json object".into())),
+    }
+}
+
+/// Turn an S3 bucket into a drop folder. Notifications of objects created in the bucket are read
+/// from a drop queue, each `.eml` file or JSON email description is read from the bucket, and
+/// the email it describes is written to the email table and its pointer sent to the email queue.
+pub struct DropFolder<'a> {
+    /// Connection to DynamoDB.
+    dynamodb: &'a DynamoDbClient,
+    /// URL of the drop queue processed notifications are deleted from.
+    drop_queue_url: &'a QueueUrl,
+    /// Event bus the queued emails are announced on.
+    event_bus: Option<&'a EventBus>,
+    /// Attribute grouping pointers on a FIFO email queue.
+    message_group: MessageGroup,
+    /// URL of the queue email pointers are sent to.
+    queue_url: &'a QueueUrl,
+    /// Connection to S3.
+    s3: &'a S3Client,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+    /// DynamoDB table email records are written to.
+    table_name: &'a str,
+}
+
+impl DropFolder<'_> {
+    pub fn new<'a>(
+        dynamodb: &'a DynamoDbClient,
+        table_name: &'a str,
+        queue_url: &'a QueueUrl,
+        drop_queue_url: &'a QueueUrl,
+        sqs: &'a SqsClient,
+        s3: &'a S3Client,
+        message_group: MessageGroup,
+    ) -> DropFolder<'a> {
+        DropFolder {
+            dynamodb,
+            drop_queue_url,
+            event_bus: None,
+            message_group,
+            queue_url,
+            s3,
+            sqs,
+            table_name,
+        }
+    }
+
+    /// Run a single pass over the next batch from `source`.
+    ///
+    /// 1. Receive a batch of notifications from the source.
+    /// 2. Enqueue the email described by each object created.
+    /// 3. Delete handled and invalid notifications. Notifications which failed are left to be
+    ///    delivered again once their visibility timeout expires.
+    #[tracing::instrument(skip(self, source), level = Level::INFO)]
+    pub async fn run_once<S>(&self, source: &mut S) -> BatchReport
+    where
+        S: MessageSource + Send,
+    {
+        // 1. Receive a batch of notifications from the source.
+        let (messages, receive_failed) = match source.receive().in_current_span().await {
+            Ok(messages) => (messages, false),
+            Err(error) => {
+                event!(Level::ERROR, %error, "ReceiveMessageError");
+                (Vec::new(), true)
+            }
+        };
+        let received = messages.len();
+        let mut entries = Vec::new();
+        let mut retried = 0;
+        let mut quarantined = 0;
+        // 2. Enqueue the email described by each object created.
+        for message in messages {
+            let Message {
+                body,
+                message_id,
+                receipt_handle,
+                ..
+            } = message;
+            match self
+                .process(body.as_deref().unwrap_or_default())
+                .in_current_span()
+                .await
+            {
+                Ok(()) => {}
+                Err(error) if error.is_permanent() => {
+                    event!(Level::ERROR, ?message_id, %error, "quarantine message");
+                    quarantined += 1;
+                }
+                Err(error) => {
+                    event!(Level::ERROR, ?message_id, %error, "dropped email not enqueued");
+                    retried += 1;
+                    continue;
+                }
+            }
+            if let (Some(id), Some(handle)) = (message_id, receipt_handle) {
+                entries.push(delete_entry(id, handle));
+            }
+        }
+        let processed = received - retried;
+        // 3. Delete handled and invalid notifications.
+        let delete = if entries.is_empty() {
+            DeleteOutcome::NotNeeded
+        } else {
+            delete_messages(self.sqs, self.drop_queue_url, entries)
+                .in_current_span()
+                .await
+        };
+        BatchReport {
+            received,
+            processed,
+            retried,
+            quarantined,
+            delete,
+            receive_failed,
+        }
+    }
+
+    /// Enqueue the email of every object created in the notification `body`.
+    async fn process(&self, body: &str) -> Result<(), DropError> {
+        for object in parse_drop_notification(body)? {
+            self.ingest(&object).await?;
+        }
+        Ok(())
+    }
+
+    /// Read `object` from the bucket and enqueue the email it describes.
+    async fn ingest(&self, object: &DroppedObject) -> Result<(), DropError> {
+        let output = match self
+            .s3
+            .get_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            // The object was removed before it was read, there is nothing to send.
+            Err(SdkError::ServiceError(context))
+                if matches!(context.err(), GetObjectError::NoSuchKey(_)) =>
+            {
+                event!(Level::WARN, bucket = %object.bucket, key = %object.key, "dropped object not found");
+                return Ok(());
+            }
+            Err(error) => {
+                return Err(DropError::GetObjectError(format!(
+                    "{}",
+                    DisplayErrorContext(&error)
+                )))
+            }
+        };
+        let e_tag = output.e_tag().map(str::to_owned);
+        let contents = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| DropError::GetObjectError(e.to_string()))?
+            .into_bytes();
+        let draft = draft_from_object(object, &contents, e_tag.as_deref())?;
+        let email_id = enqueue_email(
+            self.dynamodb,
+            self.table_name,
+            self.sqs,
+            self.queue_url,
+            self.message_group,
+            draft,
+        )
+        .await?;
+        event!(Level::INFO, %email_id, bucket = %object.bucket, key = %object.key, "dropped email enqueued");
+        if let Some(event_bus) = self.event_bus {
+            let queued = EmailEvent::for_email_id(EmailEventType::EmailQueued, &email_id);
+            if let Err(error) = event_bus.publish(&queued).await {
+                event!(Level::WARN, %error, "email event not published");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DropFolder<'a> {
+    /// Announce each email enqueued on `event_bus`.
+    pub fn with_event_bus(self, event_bus: &'a EventBus) -> Self {
+        DropFolder {
+            event_bus: Some(event_bus),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod parse_drop_notification {
+    use super::*;
+
+    #[test]
+    fn reads_created_objects_through_sns() {
+        let notification = r#"{"Records": [
+            {"eventName": "ObjectCreated:Put",
+             "s3": {"bucket": {"name": "drop"}, "object": {"key": "a%2Fb%20c.json"}}},
+            {"eventName": "ObjectRemoved:Delete",
+             "s3": {"bucket": {"name": "drop"}, "object": {"key": "old.eml"}}}
+        ]}"#;
+        let body = serde_json::json!({ "Type": "Notification", "Message": notification });
+        let objects = parse_drop_notification(&body.to_string()).unwrap();
+        assert_eq!(
+            objects,
+            vec![DroppedObject {
+                bucket: "drop".into(),
+                key: "a/b c.json".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_test_event() {
+        let body = r#"{"Service": "Amazon S3", "Event": "s3:TestEvent", "Bucket": "drop"}"#;
+        assert_eq!(parse_drop_notification(body).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn rejects_other_bodies() {
+        assert!(matches!(
+            parse_drop_notification("not json"),
+            Err(DropError::InvalidBody(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(decode_key("100%+off%2"), "100% off%2");
+        assert_eq!(decode_key("%zz%41"), "%zzA");
+    }
+}
+
+#[cfg(test)]
+mod draft_from_object {
+    use super::*;
+
+    fn object(key: &str) -> DroppedObject {
+        DroppedObject {
+            bucket: "drop".into(),
+            key: key.into(),
+        }
+    }
+
+    #[test]
+    fn reads_json_description() {
+        let contents = br#"{
+            "sender": "from@example.com",
+            "recipients_to": ["to@example.com"],
+            "subject": "Hello",
+            "body_text": "Hi there"
+        }"#;
+        let draft =
+            draft_from_object(&object("emails/hello.JSON"), contents, Some("\"abc\"")).unwrap();
+        assert_eq!(draft.sender, "from@example.com");
+        assert_eq!(draft.recipients_to, vec!["to@example.com"]);
+        assert_eq!(
+            draft.idempotency_key.as_deref(),
+            Some("s3:drop/emails/hello.JSON:abc")
+        );
+        assert!(draft.into_email().is_ok());
+    }
+
+    #[test]
+    fn keeps_given_idempotency_key() {
+        let contents = br#"{"idempotency_key": "order-1"}"#;
+        let draft = draft_from_object(&object("order.json"), contents, Some("\"abc\"")).unwrap();
+        assert_eq!(draft.idempotency_key.as_deref(), Some("order-1"));
+    }
+
+    #[test]
+    fn reads_eml_file() {
+        let contents =
+            b"From: from@example.com\r\nTo: to@example.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let draft = draft_from_object(&object("hi.eml"), contents, None).unwrap();
+        assert_eq!(draft.subject, "Hi");
+        assert!(draft.idempotency_key.unwrap().starts_with("eml:"));
+    }
+
+    #[test]
+    fn rejects_other_objects() {
+        let error = draft_from_object(&object("notes.txt"), b"Hello", None).unwrap_err();
+        assert!(error.is_permanent());
+        let error = draft_from_object(&object("bad.json"), b"[", None).unwrap_err();
+        assert!(matches!(error, DropError::InvalidObject { .. }));
+    }
+}
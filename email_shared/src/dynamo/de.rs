@@ -1,6 +1,6 @@
 //! Deserialize an HashMap into a Rust data structure.
 
-use rusoto_dynamodb::AttributeValue;
+use aws_sdk_dynamodb::types::AttributeValue;
 use serde::de::IntoDeserializer;
 use std::collections::HashMap;
 
@@ -18,9 +18,8 @@ macro_rules! impl_deserialize_n {
                 self.read
                     .get_attribute_value(&self.current_field)
                     .ok_or_else(field_missing_error)?
-                    .clone()
-                    .n
-                    .ok_or_else(field_missing_error)?
+                    .as_n()
+                    .map_err(|_| field_missing_error())?
                     .parse::<$type>()
                     .map_err(parse_error)?,
             )
@@ -76,7 +75,7 @@ impl Read for VecRead {
         }
     }
     fn get_keys(&self) -> Vec<String> {
-        return vec![];
+        vec![]
     }
 }
 
@@ -87,7 +86,7 @@ struct Deserializer<R> {
     as_key: bool,
 }
 
-impl<'de, R> Deserializer<R>
+impl<R> Deserializer<R>
 where
     R: Read,
 {
@@ -108,7 +107,7 @@ where
     }
 }
 
-impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R> {
+impl<'de, R: Read> serde::de::Deserializer<'de> for &mut Deserializer<R> {
     type Error = DeserializeError;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
@@ -121,23 +120,22 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
             .ok_or_else(|| self.field_missing_error_current())?
             .clone();
 
-        if f.b.is_some() {
-            self.deserialize_bytes(visitor)
-        } else if f.bool.is_some() {
-            self.deserialize_bool(visitor)
-        } else if f.l.is_some() || f.ns.is_some() || f.ss.is_some() || f.bs.is_some() {
-            self.deserialize_seq(visitor)
-        } else if f.m.is_some() {
-            self.deserialize_map(visitor)
-        } else if f.n.is_some() {
-            self.deserialize_f64(visitor)
-        } else if f.null.is_some() {
-            self.deserialize_unit(visitor)
-        } else if f.s.is_some() {
-            self.deserialize_str(visitor)
-        } else {
-            // This part should be unreachable as all possibilities are tested before
-            unreachable!()
+        match f {
+            AttributeValue::B(_) => self.deserialize_bytes(visitor),
+            AttributeValue::Bool(_) => self.deserialize_bool(visitor),
+            AttributeValue::L(_)
+            | AttributeValue::Ns(_)
+            | AttributeValue::Ss(_)
+            | AttributeValue::Bs(_) => self.deserialize_seq(visitor),
+            AttributeValue::M(_) => self.deserialize_map(visitor),
+            AttributeValue::N(n) if n.parse::<i64>().is_ok() => self.deserialize_i64(visitor),
+            AttributeValue::N(n) if n.parse::<u64>().is_ok() => self.deserialize_u64(visitor),
+            AttributeValue::N(_) => self.deserialize_f64(visitor),
+            AttributeValue::Null(_) => self.deserialize_unit(visitor),
+            AttributeValue::S(_) => self.deserialize_str(visitor),
+            _ => Err(DeserializeError::Custom(
+                "unknown attribute value type".to_owned(),
+            )),
         }
     }
 
@@ -146,12 +144,12 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
         V: serde::de::Visitor<'de>,
     {
         visitor.visit_bool(
-            self.read
+            *self
+                .read
                 .get_attribute_value(&self.current_field)
                 .ok_or_else(|| self.field_missing_error_current())?
-                .clone()
-                .bool
-                .ok_or_else(|| DeserializeError::InvalidType {
+                .as_bool()
+                .map_err(|_| DeserializeError::InvalidType {
                     expected: String::from("bool"),
                     unexpected: String::from("None"),
                 })?,
@@ -179,9 +177,8 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
             self.read
                 .get_attribute_value(&self.current_field)
                 .ok_or_else(|| self.field_missing_error_current())?
-                .clone()
-                .s
-                .ok_or_else(|| self.field_missing_error_current())?
+                .as_s()
+                .map_err(|_| self.field_missing_error_current())?
                 .parse::<char>()
                 .map_err(|_| self.parse_error_current())?,
         )
@@ -198,10 +195,9 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
             }
         } else if let Some(field) = self.read.get_attribute_value(&self.current_field) {
             field
-                .clone()
-                .s
-                .ok_or_else(|| self.field_missing_error_current())
-                .and_then(|string_field| visitor.visit_str(&string_field))
+                .as_s()
+                .map_err(|_| self.field_missing_error_current())
+                .and_then(|string_field| visitor.visit_str(string_field))
         } else {
             visitor.visit_str("")
         }
@@ -220,10 +216,9 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
     {
         if let Some(field) = self.read.get_attribute_value(&self.current_field) {
             field
-                .clone()
-                .b
-                .ok_or_else(|| self.field_missing_error_current())
-                .and_then(|bytes_field| visitor.visit_bytes(&bytes_field))
+                .as_b()
+                .map_err(|_| self.field_missing_error_current())
+                .and_then(|bytes_field| visitor.visit_bytes(bytes_field.as_ref()))
         } else {
             visitor.visit_bytes(b"")
         }
@@ -247,9 +242,8 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
             .read
             .get_attribute_value(&self.current_field)
             .ok_or_else(|| self.field_missing_error_current())?
-            .null
         {
-            Some(true) => visitor.visit_none(),
+            AttributeValue::Null(true) => visitor.visit_none(),
             _ => visitor.visit_some(self),
         }
     }
@@ -261,8 +255,8 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
         self.read
             .get_attribute_value(&self.current_field)
             .ok_or_else(|| self.field_missing_error_current())?
-            .null
-            .ok_or_else(|| DeserializeError::InvalidType {
+            .as_null()
+            .map_err(|_| DeserializeError::InvalidType {
                 expected: String::from("null"),
                 unexpected: String::from("None"),
             })
@@ -292,43 +286,23 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
             .get_attribute_value(&self.current_field)
             .ok_or_else(|| self.field_missing_error_current())?
             .clone();
-        let read = if let Some(alist) = list.l {
-            VecRead { vec: alist }
-        } else if let Some(numlist) = list.ns {
-            VecRead {
-                vec: numlist
-                    .into_iter()
-                    .map(|n| AttributeValue {
-                        n: Some(n),
-                        ..Default::default()
-                    })
-                    .collect(),
-            }
-        } else if let Some(slist) = list.ss {
-            VecRead {
-                vec: slist
-                    .into_iter()
-                    .map(|s| AttributeValue {
-                        s: Some(s),
-                        ..Default::default()
-                    })
-                    .collect(),
-            }
-        } else if let Some(blist) = list.bs {
-            VecRead {
-                vec: blist
-                    .into_iter()
-                    .map(|s| AttributeValue {
-                        b: Some(s),
-                        ..Default::default()
-                    })
-                    .collect(),
+        let read = match list {
+            AttributeValue::L(alist) => VecRead { vec: alist },
+            AttributeValue::Ns(numlist) => VecRead {
+                vec: numlist.into_iter().map(AttributeValue::N).collect(),
+            },
+            AttributeValue::Ss(slist) => VecRead {
+                vec: slist.into_iter().map(AttributeValue::S).collect(),
+            },
+            AttributeValue::Bs(blist) => VecRead {
+                vec: blist.into_iter().map(AttributeValue::B).collect(),
+            },
+            _ => {
+                return Err(DeserializeError::InvalidType {
+                    expected: String::from("sequence"),
+                    unexpected: String::from("None"),
+                });
             }
-        } else {
-            return Err(DeserializeError::InvalidType {
-                expected: String::from("sequence"),
-                unexpected: String::from("None"),
-            });
         };
         let mut des = Deserializer::new(read);
         visitor.visit_seq(SeqAccess::new(&mut des))
@@ -349,9 +323,9 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
                         .read
                         .get_attribute_value(&self.current_field)
                         .ok_or_else(|| self.field_missing_error_current())?
-                        .m
-                        .clone()
-                        .unwrap(),
+                        .as_m()
+                        .map_err(|_| self.field_missing_error_current())?
+                        .clone(),
                 };
                 let mut des = Deserializer::new(subread);
                 visitor.visit_seq(TupleAccess::new(&mut des))
@@ -379,9 +353,9 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
                         .read
                         .get_attribute_value(&self.current_field)
                         .ok_or_else(|| self.field_missing_error_current())?
-                        .m
-                        .clone()
-                        .unwrap(),
+                        .as_m()
+                        .map_err(|_| self.field_missing_error_current())?
+                        .clone(),
                 };
                 let mut des = Deserializer::new(subread);
                 visitor.visit_seq(TupleAccess::new(&mut des))
@@ -400,7 +374,7 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
                     .read
                     .get_attribute_value(&self.current_field)
                     .ok_or_else(|| self.field_missing_error_current())?;
-                let hm = map.clone().m.unwrap_or_else(HashMap::new);
+                let hm = map.as_m().cloned().unwrap_or_else(|_| HashMap::new());
                 let keys = hm.keys().cloned().collect();
                 let mut des = Deserializer::new(HashMapRead::new(hm));
                 visitor.visit_map(MapAccess::new(&mut des, keys))
@@ -425,9 +399,9 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
                     .get_attribute_value(&self.current_field)
                     .ok_or_else(|| self.field_missing_error_current())?;
                 let hm = map
-                    .clone()
-                    .m
-                    .ok_or_else(|| DeserializeError::Custom("Missing struct fields".to_owned()))?;
+                    .as_m()
+                    .cloned()
+                    .map_err(|_| DeserializeError::Custom("Missing struct fields".to_owned()))?;
                 let keys = hm.keys().cloned().collect();
                 let mut des = Deserializer::new(HashMapRead::new(hm));
                 visitor.visit_map(MapAccess::new(&mut des, keys))
@@ -444,39 +418,39 @@ impl<'de, 'a, R: Read> serde::de::Deserializer<'de> for &'a mut Deserializer<R>
     where
         V: serde::de::Visitor<'de>,
     {
-        let (variant, values) =
-            if let Index::None = self.current_field {
-                let variant = self
-                    .read
-                    .get_attribute_value(&Index::String(String::from("___enum_tag")))
-                    .ok_or_else(|| DeserializeError::Custom("Missing enum tag field".to_owned()))?
-                    .clone()
-                    .s
-                    .ok_or_else(|| DeserializeError::Custom("Missing enum tag value".to_owned()))?;
-                let values = self
-                    .read
-                    .get_attribute_value(&Index::String(String::from("___enum_values")))
-                    .and_then(|v| v.m.clone());
-                (variant, values)
-            } else {
-                let enum_field = self
-                    .read
-                    .get_attribute_value(&self.current_field)
-                    .ok_or_else(|| self.field_missing_error_current())?;
-                if let Some(shortstyle) = enum_field.s.clone() {
-                    return visitor.visit_enum(shortstyle.into_deserializer());
-                }
-                let base = enum_field
-                    .m
-                    .clone()
-                    .ok_or_else(|| DeserializeError::Custom("Missing enum data".to_owned()))?;
-                (
-                    base.get("___enum_tag").unwrap().s.clone().ok_or_else(|| {
-                        DeserializeError::Custom("Missing enum tag value".to_owned())
-                    })?,
-                    base.get("___enum_values").and_then(|v| v.m.clone()),
-                )
-            };
+        let (variant, values) = if let Index::None = self.current_field {
+            let variant = self
+                .read
+                .get_attribute_value(&Index::String(String::from("___enum_tag")))
+                .ok_or_else(|| DeserializeError::Custom("Missing enum tag field".to_owned()))?
+                .as_s()
+                .cloned()
+                .map_err(|_| DeserializeError::Custom("Missing enum tag value".to_owned()))?;
+            let values = self
+                .read
+                .get_attribute_value(&Index::String(String::from("___enum_values")))
+                .and_then(|v| v.as_m().ok().cloned());
+            (variant, values)
+        } else {
+            let enum_field = self
+                .read
+                .get_attribute_value(&self.current_field)
+                .ok_or_else(|| self.field_missing_error_current())?;
+            if let Ok(shortstyle) = enum_field.as_s() {
+                return visitor.visit_enum(shortstyle.clone().into_deserializer());
+            }
+            let base = enum_field
+                .as_m()
+                .map_err(|_| DeserializeError::Custom("Missing enum data".to_owned()))?;
+            (
+                base.get("___enum_tag")
+                    .and_then(|v| v.as_s().ok())
+                    .cloned()
+                    .ok_or_else(|| DeserializeError::Custom("Missing enum tag value".to_owned()))?,
+                base.get("___enum_values")
+                    .and_then(|v| v.as_m().ok().cloned()),
+            )
+        };
         let mut des = Deserializer::new(HashMapRead::new(values.unwrap_or_else(HashMap::new)));
         visitor.visit_enum(EnumAccess::new(&mut des, variant))
     }
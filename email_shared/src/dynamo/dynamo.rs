@@ -1,11 +1,102 @@
-use rusoto_dynamodb::{DynamoDb, DynamoDbClient, GetItemInput, GetItemOutput, UpdateItemInput};
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::Builder as DynamoDbConfigBuilder;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::get_item::builders::GetItemFluentBuilder;
+use aws_sdk_dynamodb::operation::get_item::GetItemOutput;
+use aws_sdk_dynamodb::operation::transact_write_items::builders::TransactWriteItemsFluentBuilder;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, KeysAndAttributes, ReturnValuesOnConditionCheckFailure, TransactWriteItem,
+    Update,
+};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::time::Duration;
 
 use crate::attribute_value_wrapper::AttributeValueMap;
 use crate::dynamo::error::DeserializeError;
-use crate::email_message::{EmailMessage, EmailStatus};
-use crate::error::{GetError, UpdateError};
+use crate::email_message::{EmailId, EmailMessage, EmailStatus, Recipient, S3Object, StatusChange};
+use crate::error::{GetError, PutError, UpdateError};
+use crate::feedback::Feedback;
 use crate::queue::EmailPointerMessage;
+use crate::retry::RetryPolicy;
+use crate::schema::{
+    attribute, attribute_exists, attribute_not_exists, email_key, equals, placeholder, set,
+};
+use crate::status_machine::StatusMachine;
+
+/// Attributes read whatever the projection of a `ReadOptions`, those an `EmailMessage` can not be
+/// parsed without and the `Version` an update is conditional on.
+const REQUIRED_ATTRIBUTES: [&str; 4] = [
+    attribute::EMAIL_ID,
+    attribute::EMAIL_STATUS,
+    attribute::SUBJECT,
+    attribute::VERSION,
+];
+
+/// How an email is read. By default every attribute is read eventually consistently, which may
+/// return a record as it was before a write made moments ago.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReadOptions {
+    /// Attributes to read, along with `REQUIRED_ATTRIBUTES`, every attribute when empty.
+    attributes: Vec<String>,
+    /// Whether the read reflects every write which succeeded before it.
+    consistent_read: bool,
+}
+
+impl ReadOptions {
+    /// Read only `attributes` of the email, and those it can not be parsed without, leaving the
+    /// others, such as large attachment bodies, at their defaults.
+    pub fn with_attributes(self, attributes: &[&str]) -> Self {
+        ReadOptions {
+            attributes: attributes.iter().map(|name| (*name).to_owned()).collect(),
+            ..self
+        }
+    }
+
+    /// Read the email as of every write which succeeded before the read, at twice the read
+    /// capacity, so an email updated moments ago is not read with its previous status.
+    pub fn with_consistent_read(self, consistent_read: bool) -> Self {
+        ReadOptions {
+            consistent_read,
+            ..self
+        }
+    }
+
+    /// `ProjectionExpression` of the attributes to read and the `ExpressionAttributeNames` it
+    /// refers to, so no attribute name collides with a reserved word, or `None` to read every
+    /// attribute.
+    fn projection(&self) -> Option<(String, HashMap<String, String>)> {
+        if self.attributes.is_empty() {
+            return None;
+        }
+        let mut attributes = REQUIRED_ATTRIBUTES
+            .iter()
+            .copied()
+            .chain(self.attributes.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        attributes.sort_unstable();
+        attributes.dedup();
+        let names = attributes
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (format!("#a{}", index), (*name).to_owned()))
+            .collect::<Vec<_>>();
+        let expression = names
+            .iter()
+            .map(|(placeholder, _)| placeholder.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some((expression, names.into_iter().collect()))
+    }
+}
 
 /// Get email data from Dynamo DB and then parse it into an `EmailMessage`. Uses the given
 /// `DynamoDbClient` and attempts to get the item from the given `table_name`. Errors from they
@@ -15,48 +106,950 @@ pub async fn get_email_message(
     table_name: &str,
     message: &EmailPointerMessage,
 ) -> Result<EmailMessage, GetError> {
-    let input = GetItemInput {
-        key: AttributeValueMap::with_entry("EmailId", message.email_id.clone()),
-        table_name: table_name.into(),
-        ..GetItemInput::default()
-    };
+    get_email_message_with(dynamodb, table_name, message, &ReadOptions::default()).await
+}
+
+/// `get_email_message` reading the email as `options` describe. Attributes left out of the
+/// projection of `options` have their default value in the `EmailMessage` returned.
+pub async fn get_email_message_with(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    message: &EmailPointerMessage,
+    options: &ReadOptions,
+) -> Result<EmailMessage, GetError> {
+    let (projection, names) = options.projection().unzip();
+    let request = dynamodb
+        .get_item()
+        .consistent_read(options.consistent_read)
+        .set_expression_attribute_names(names)
+        .set_key(Some(email_key(message.email_id.as_str())))
+        .set_projection_expression(projection)
+        .table_name(table_name);
+    send_get(request).await.and_then(EmailMessage::try_from)
+}
+
+/// Attempts made of a read or update DynamoDB throttles before giving up, leaving the message to
+/// be delivered again. Waits are jittered so workers throttled together spread out their retries.
+fn throttle_retry() -> RetryPolicy {
+    RetryPolicy::exponential(Duration::from_millis(50), 4)
+        .with_max_backoff(Duration::from_secs(1))
+        .with_jitter()
+}
+
+/// Configuration override turning off the retries of the SDK for a call `throttle_retry` attempts
+/// again, so each attempt is a single request rather than up to as many as the client retries.
+fn without_sdk_retry() -> DynamoDbConfigBuilder {
+    DynamoDbConfigBuilder::default().retry_config(RetryConfig::disabled())
+}
+
+/// Send `request`, attempting it again as `throttle_retry` allows while DynamoDB throttles it.
+async fn send_get(request: GetItemFluentBuilder) -> Result<GetItemOutput, GetError> {
+    throttle_retry()
+        .retry_if(GetError::is_throttled, move || {
+            let request = request
+                .clone()
+                .customize()
+                .config_override(without_sdk_retry());
+            async move { request.send().await.map_err(GetError::from) }
+        })
+        .await
+}
+
+/// Send the update `request`, attempting it again as `throttle_retry` allows while DynamoDB
+/// throttles it. A failure is converted by `map_error`.
+async fn send_update<F>(request: UpdateItemFluentBuilder, map_error: F) -> Result<(), UpdateError>
+where
+    F: Fn(SdkError<UpdateItemError>) -> UpdateError,
+{
+    let map_error = &map_error;
+    throttle_retry()
+        .retry_if(UpdateError::is_throttled, move || {
+            let request = request
+                .clone()
+                .customize()
+                .config_override(without_sdk_retry());
+            async move { request.send().await.map(|_| ()).map_err(map_error) }
+        })
+        .await
+}
+
+/// Send the transaction `request`, attempting it again as `throttle_retry` allows while DynamoDB
+/// throttles it. A failure is converted by `map_error`.
+async fn send_transact<F>(
+    request: TransactWriteItemsFluentBuilder,
+    map_error: F,
+) -> Result<(), UpdateError>
+where
+    F: Fn(SdkError<TransactWriteItemsError>) -> UpdateError,
+{
+    let map_error = &map_error;
+    throttle_retry()
+        .retry_if(UpdateError::is_throttled, move || {
+            let request = request
+                .clone()
+                .customize()
+                .config_override(without_sdk_retry());
+            async move { request.send().await.map(|_| ()).map_err(map_error) }
+        })
+        .await
+}
+
+/// Most keys DynamoDB accepts in a single `BatchGetItem` request.
+const BATCH_GET_LIMIT: usize = 100;
+/// Number of requests made for keys DynamoDB leaves unprocessed before giving up.
+const BATCH_GET_ATTEMPTS: usize = 3;
+
+/// Get the emails identified by `pointers` with `BatchGetItem`, making as few calls as possible
+/// rather than one `GetItem` per pointer. Keys DynamoDB leaves unprocessed are requested again.
+/// Each `EmailId` is mapped to its email or to the `GetError` reading it, `GetError::RecordNotFound`
+/// when there is no record and `GetError::ProvisionedThroughputExceeded` when its key was still
+/// unprocessed after the last attempt. Each email is read as `options` describe.
+pub async fn get_email_messages(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    pointers: &[EmailPointerMessage],
+    options: &ReadOptions,
+) -> HashMap<EmailId, Result<EmailMessage, GetError>> {
+    // A request naming the same key twice is rejected
+    let mut email_ids = pointers
+        .iter()
+        .map(|pointer| pointer.email_id.clone())
+        .collect::<Vec<_>>();
+    email_ids.sort();
+    email_ids.dedup();
+    let mut emails = HashMap::new();
+    for chunk in email_ids.chunks(BATCH_GET_LIMIT) {
+        if let Err(error) = get_email_chunk(dynamodb, table_name, chunk, options, &mut emails).await
+        {
+            for email_id in chunk {
+                emails
+                    .entry(email_id.clone())
+                    .or_insert_with(|| Err(error.clone()));
+            }
+        }
+        for email_id in chunk {
+            emails
+                .entry(email_id.clone())
+                .or_insert(Err(GetError::RecordNotFound));
+        }
+    }
+    emails
+}
+
+/// Read the emails identified by `email_ids`, at most `BATCH_GET_LIMIT`, into `emails`. Fails
+/// with the error of a call which failed, or `GetError::ProvisionedThroughputExceeded` when keys
+/// are still unprocessed after `BATCH_GET_ATTEMPTS` calls, leaving the emails not yet read out of
+/// `emails`.
+async fn get_email_chunk(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_ids: &[EmailId],
+    options: &ReadOptions,
+    emails: &mut HashMap<EmailId, Result<EmailMessage, GetError>>,
+) -> Result<(), GetError> {
+    let (projection, names) = options.projection().unzip();
+    let mut request = Some(
+        email_ids
+            .iter()
+            .map(|id| email_key(id.as_str()))
+            .collect::<Vec<_>>(),
+    );
+    for _ in 0..BATCH_GET_ATTEMPTS {
+        let keys = match request.take() {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => return Ok(()),
+        };
+        let keys_and_attributes = KeysAndAttributes::builder()
+            .consistent_read(options.consistent_read)
+            .set_expression_attribute_names(names.clone())
+            .set_keys(Some(keys))
+            .set_projection_expression(projection.clone())
+            .build()
+            .map_err(|e| GetError::ServiceError(e.to_string()))?;
+        let output = dynamodb
+            .batch_get_item()
+            .request_items(table_name, keys_and_attributes)
+            .send()
+            .await?;
+        let items = output
+            .responses
+            .and_then(|mut responses| responses.remove(table_name))
+            .unwrap_or_default();
+        for item in items {
+            let email_id = match item.get(attribute::EMAIL_ID) {
+                Some(AttributeValue::S(email_id)) => EmailId::from(email_id.as_str()),
+                _ => continue,
+            };
+            let output = GetItemOutput::builder().set_item(Some(item)).build();
+            emails.insert(email_id, EmailMessage::try_from(output));
+        }
+        request = output
+            .unprocessed_keys
+            .and_then(|mut unprocessed| unprocessed.remove(table_name))
+            .map(|unprocessed| unprocessed.keys);
+    }
+    match request {
+        Some(keys) if !keys.is_empty() => Err(GetError::ProvisionedThroughputExceeded(format!(
+            "{} email keys unprocessed",
+            keys.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// An email listed by `query_by_status`, holding only the attributes a status index projects.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StatusEntry {
+    /// Identifier of the email.
+    pub email_id: EmailId,
+    /// Status the email was listed by.
+    #[serde(rename = "EmailStatus")]
+    pub status: EmailStatus,
+    /// When the status of the email last changed, empty for an email written before `UpdatedAt`
+    /// was kept.
+    #[serde(default)]
+    pub updated_at: String,
+    /// Number of times the email has been updated.
+    #[serde(default)]
+    pub version: u64,
+    /// When the claim on an email which is `Sending` lapses, when the index projects it.
+    #[serde(default)]
+    pub sending_lock_expires_at: Option<String>,
+}
+
+/// One page of the emails listed by `query_by_status`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StatusPage {
+    /// Emails with the status queried, least recently updated first.
+    pub emails: Vec<StatusEntry>,
+    /// Opaque position to pass to `query_by_status` for the next page, `None` once every email
+    /// has been listed. A page which ends exactly at the last email may be followed by an empty
+    /// one.
+    pub cursor: Option<String>,
+}
+
+/// List up to `limit` emails with `status` from `index_name`, a global secondary index of
+/// `table_name` with `EmailStatus` as its partition key and `UpdatedAt` as its sort key, starting
+/// after `cursor` when it is given. Emails are listed least recently updated first, so the
+/// longest waiting emails come first. Fails with `GetError::ParseError` when `cursor` was not
+/// returned by an earlier page. An index is eventually consistent, an email which changed status
+/// moments ago may be listed with its previous status.
+pub async fn query_by_status(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    index_name: &str,
+    status: EmailStatus,
+    limit: i32,
+    cursor: Option<&str>,
+) -> Result<StatusPage, GetError> {
+    let start_key = cursor.map(decode_cursor).transpose()?;
+    let output = dynamodb
+        .query()
+        .index_name(index_name)
+        .key_condition_expression(equals(attribute::EMAIL_STATUS, placeholder::EXPECTED))
+        .set_expression_attribute_values(Some(AttributeValueMap::with_entry(
+            placeholder::EXPECTED,
+            status.to_string(),
+        )))
+        .set_exclusive_start_key(start_key)
+        .limit(limit)
+        .table_name(table_name)
+        .send()
+        .await
+        .map_err(GetError::from)?;
+    let emails = output
+        .items
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| super::from_hashmap(item).map_err(|e| GetError::ParseError(e.to_string())))
+        .collect::<Result<Vec<StatusEntry>, GetError>>()?;
+    Ok(StatusPage {
+        emails,
+        cursor: output.last_evaluated_key.map(encode_cursor),
+    })
+}
+
+/// The last evaluated key of a status index query as the cursor of the next page, URL safe
+/// base64 of its string attributes as JSON.
+fn encode_cursor(key: HashMap<String, AttributeValue>) -> String {
+    let key = key
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            AttributeValue::S(value) => Some((name, value)),
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(&key).unwrap_or_default())
+}
+
+/// The exclusive start key encoded by `encode_cursor` as `cursor`.
+fn decode_cursor(cursor: &str) -> Result<HashMap<String, AttributeValue>, GetError> {
+    let invalid = || GetError::ParseError(format!("invalid cursor \"{}\"", cursor));
+    let json = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let key: BTreeMap<String, String> = serde_json::from_slice(&json).map_err(|_| invalid())?;
+    Ok(key
+        .into_iter()
+        .map(|(name, value)| (name, AttributeValue::S(value)))
+        .collect())
+}
+
+/// Create a Dynamo record from the given `EmailMessage`. The write is conditional on no record
+/// with the same `EmailId` existing so an existing email is never overwritten, in that case
+/// `PutError::ConditionalCheckFailed` is returned.
+pub async fn put_email_message(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email: &EmailMessage,
+) -> Result<(), PutError> {
+    let item = super::to_hashmap(email).map_err(|e| PutError::SerializeError(e.to_string()))?;
     dynamodb
-        .get_item(input)
+        .put_item()
+        .condition_expression(attribute_not_exists(attribute::EMAIL_ID))
+        .set_item(Some(item))
+        .table_name(table_name)
+        .send()
         .await
-        .map_err(GetError::from)
-        .and_then(EmailMessage::try_from)
+        .map_err(PutError::from)
+        .map(|_| ())
 }
 
 /// Update the `EmailStatus` of the Dynamo record identified by `pointer` based on the `FromTo`
-/// structure.
+/// structure, appending the change to its `StatusHistory`. Fails with
+/// `UpdateError::VersionConflict` when the record is no longer at `version`.
 pub async fn set_email_status(
     dynamodb: &DynamoDbClient,
     table_name: &str,
     message: &EmailPointerMessage,
+    version: u64,
+    args: StatusTransition,
+    retention: Option<Duration>,
+) -> Result<(), UpdateError> {
+    let StatusTransition {
+        from: current_status,
+        to: next_status,
+    } = StatusMachine::check(args)?;
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), current_status.to_string()),
+        (placeholder::NEXT.into(), next_status.to_string()),
+    ]);
+    let change =
+        StatusChange::new(Some(current_status), next_status).with_worker(&message.claim_id());
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let mut assignments = vec![
+        (attribute::EMAIL_STATUS, placeholder::NEXT),
+        (attribute::VERSION, placeholder::NEXT_VERSION),
+    ];
+    let removals = expire(&mut values, &mut assignments, next_status, retention);
+    let removals = remove_claim(removals, current_status);
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{} AND {}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(message.email_id.as_str())))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!("{}, {}{}", set(&assignments), history, removals));
+    send_update(request, |error| update_error(error, version)).await
+}
+
+/// Longest a claim is held, longer leases are cut short so the time it lapses can be written.
+const MAX_LEASE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Claim the email identified by `pointer` for sending by moving it from `EmailStatus::Pending`
+/// to `EmailStatus::Sending` and recording the delivery of `pointer` as `ClaimedBy`. The claim is
+/// a lease held for `lease`, recorded as `SendingLockExpiresAt`, after which another delivery may
+/// take over an email still `EmailStatus::Sending` because the worker holding it stopped. Fails
+/// with `UpdateError::ConditionalCheckFailed` when the email is neither `EmailStatus::Pending`
+/// nor held by a lapsed claim, or `UpdateError::VersionConflict` when it is no longer at
+/// `version`.
+pub async fn claim_email(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    pointer: &EmailPointerMessage,
+    version: u64,
+    lease: Duration,
+) -> Result<(), UpdateError> {
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::from_std(lease.min(MAX_LEASE)).unwrap_or_default();
+    let claim = StatusMachine::transition(EmailStatus::Pending, EmailStatus::Sending)?;
+    let takeover = StatusMachine::transition(EmailStatus::Sending, EmailStatus::Sending)?;
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), claim.from.to_string()),
+        (placeholder::SENDING.into(), takeover.from.to_string()),
+        (placeholder::NOW.into(), lease_timestamp(now)),
+        (placeholder::NEXT.into(), claim.to.to_string()),
+        (placeholder::CLAIM.into(), pointer.claim_id()),
+        (placeholder::EXPIRES.into(), lease_timestamp(expires_at)),
+    ]);
+    // Claimed from `Pending` or taken over from a lapsed claim, which is not known until written
+    let change = StatusChange::new(None, claim.to).with_worker(&pointer.claim_id());
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{0} AND {4} OR {1} AND {2} < {3} AND {4}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            equals(attribute::EMAIL_STATUS, placeholder::SENDING),
+            attribute::SENDING_LOCK_EXPIRES_AT,
+            placeholder::NOW,
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(pointer.email_id.as_str())))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!(
+            "{}, {}",
+            set(&[
+                (attribute::EMAIL_STATUS, placeholder::NEXT),
+                (attribute::CLAIMED_BY, placeholder::CLAIM),
+                (attribute::SENDING_LOCK_EXPIRES_AT, placeholder::EXPIRES),
+                (attribute::VERSION, placeholder::NEXT_VERSION),
+            ]),
+            history
+        ));
+    send_update(request, |error| update_error(error, version)).await
+}
+
+/// Give up the claim `pointer` holds on its email, returning it from `EmailStatus::Sending` to
+/// `EmailStatus::Pending` so a later delivery can send it. Used whenever work is knowingly
+/// abandoned before the email is transmitted. The claim is only released while `pointer` still
+/// holds it at `version`, otherwise `UpdateError::ConditionalCheckFailed` or
+/// `UpdateError::VersionConflict` is returned and the email is left to whichever delivery
+/// claimed it since.
+pub async fn release_claim(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    pointer: &EmailPointerMessage,
+    version: u64,
+) -> Result<(), UpdateError> {
+    let StatusTransition { from, to } =
+        StatusMachine::transition(EmailStatus::Sending, EmailStatus::Pending)?;
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), from.to_string()),
+        (placeholder::NEXT.into(), to.to_string()),
+        (placeholder::CLAIM.into(), pointer.claim_id()),
+    ]);
+    let change = StatusChange::new(Some(from), to).with_worker(&pointer.claim_id());
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{} AND {} AND {}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            equals(attribute::CLAIMED_BY, placeholder::CLAIM),
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(pointer.email_id.as_str())))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!(
+            "{}, {} REMOVE {}, {}",
+            set(&[
+                (attribute::EMAIL_STATUS, placeholder::NEXT),
+                (attribute::VERSION, placeholder::NEXT_VERSION),
+            ]),
+            history,
+            attribute::CLAIMED_BY,
+            attribute::SENDING_LOCK_EXPIRES_AT
+        ));
+    send_update(request, |error| update_error(error, version)).await
+}
+
+/// Record the email claimed by `pointer` as `EmailStatus::Sent` in a single transaction, so a
+/// worker stopping part way never leaves a record half updated. Along with the status the
+/// transaction writes `SentAt`, the `ProviderResponse` and `RenderedMime` when there are any,
+/// appends the change to `StatusHistory`, and removes the claim. Fails with
+/// `UpdateError::ConditionalCheckFailed` when the email is not `EmailStatus::Sending`, or
+/// `UpdateError::VersionConflict` when it is no longer at `version`.
+pub async fn record_email_sent(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    pointer: &EmailPointerMessage,
+    version: u64,
+    provider_response: Option<&str>,
+    rendered_mime: Option<&S3Object>,
+    retention: Option<Duration>,
+) -> Result<(), UpdateError> {
+    let StatusTransition { from, to } =
+        StatusMachine::transition(EmailStatus::Sending, EmailStatus::Sent)?;
+    let change = StatusChange::new(Some(from), to).with_worker(&pointer.claim_id());
+    let sent_at = change.at.clone();
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), from.to_string()),
+        (placeholder::NEXT.into(), to.to_string()),
+        (placeholder::SENT_AT.into(), sent_at),
+    ]);
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let mut assignments = vec![
+        (attribute::EMAIL_STATUS, placeholder::NEXT),
+        (attribute::SENT_AT, placeholder::SENT_AT),
+        (attribute::VERSION, placeholder::NEXT_VERSION),
+    ];
+    if let Some(response) = provider_response {
+        values.insert(
+            placeholder::RESPONSE.to_owned(),
+            AttributeValue::S(response.to_owned()),
+        );
+        assignments.push((attribute::PROVIDER_RESPONSE, placeholder::RESPONSE));
+    }
+    if let Some(location) = rendered_mime {
+        let location =
+            super::to_hashmap(location).map_err(|e| UpdateError::SerializeError(e.to_string()))?;
+        values.insert(
+            placeholder::LOCATION.to_owned(),
+            AttributeValue::M(location),
+        );
+        assignments.push((attribute::RENDERED_MIME, placeholder::LOCATION));
+    }
+    // A sent email never had `ExpiresAt` to remove
+    expire(&mut values, &mut assignments, to, retention);
+    let update = Update::builder()
+        .condition_expression(format!(
+            "{} AND {}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(pointer.email_id.as_str())))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!(
+            "{}, {} REMOVE {}, {}",
+            set(&assignments),
+            history,
+            attribute::CLAIMED_BY,
+            attribute::SENDING_LOCK_EXPIRES_AT
+        ))
+        .build()
+        .map_err(|e| UpdateError::SerializeError(e.to_string()))?;
+    let request = dynamodb
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().update(update).build());
+    send_transact(request, |error| transact_error(error, version)).await
+}
+
+/// Return the email identified by `email_id` from `EmailStatus::Sending` to
+/// `EmailStatus::Pending` once the claim on it has lapsed, removing the claim, so a worker which
+/// stopped mid-send does not leave it stuck. Fails with `UpdateError::ConditionalCheckFailed`
+/// when the email is no longer `EmailStatus::Sending`, or `UpdateError::VersionConflict` when it
+/// has been updated since it was found at `version`, such as by being claimed again.
+pub async fn release_lapsed_claim(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &str,
+    version: u64,
+) -> Result<(), UpdateError> {
+    let StatusTransition { from, to } =
+        StatusMachine::transition(EmailStatus::Sending, EmailStatus::Pending)?;
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), from.to_string()),
+        (placeholder::NOW.into(), lease_timestamp(Utc::now())),
+        (placeholder::NEXT.into(), to.to_string()),
+    ]);
+    let change = StatusChange::new(Some(from), to).with_error("claim lapsed");
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{} AND {} < {} AND {}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            attribute::SENDING_LOCK_EXPIRES_AT,
+            placeholder::NOW,
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(email_id)))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!(
+            "{}, {} REMOVE {}, {}",
+            set(&[
+                (attribute::EMAIL_STATUS, placeholder::NEXT),
+                (attribute::VERSION, placeholder::NEXT_VERSION),
+            ]),
+            history,
+            attribute::CLAIMED_BY,
+            attribute::SENDING_LOCK_EXPIRES_AT
+        ));
+    send_update(request, |error| update_error(error, version)).await
+}
+
+/// `time` as written to `SendingLockExpiresAt`. Whole seconds in UTC keep every value the same
+/// length so DynamoDB comparing them as strings orders them by time.
+pub(crate) fn lease_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Update expression assignments appending `change` to the `StatusHistory` of an email and
+/// setting its `UpdatedAt` to when the change was made, adding the values they refer to to
+/// `values`. Every status transition includes them in its own update so the history is written
+/// with the change it records or not at all, and a status index sorted by `UpdatedAt` orders
+/// emails by when they reached their status.
+pub(crate) fn append_status_history(
+    values: &mut HashMap<String, AttributeValue>,
+    change: &StatusChange,
+) -> Result<String, UpdateError> {
+    values.insert(
+        placeholder::UPDATED_AT.to_owned(),
+        AttributeValue::S(change.at.clone()),
+    );
+    let change =
+        super::to_hashmap(change).map_err(|e| UpdateError::SerializeError(e.to_string()))?;
+    values.insert(placeholder::EMPTY.to_owned(), AttributeValue::L(Vec::new()));
+    values.insert(
+        placeholder::HISTORY.to_owned(),
+        AttributeValue::L(vec![AttributeValue::M(change)]),
+    );
+    Ok(format!(
+        "{0} = list_append(if_not_exists({0}, {1}), {2}), {3}",
+        attribute::STATUS_HISTORY,
+        placeholder::EMPTY,
+        placeholder::HISTORY,
+        equals(attribute::UPDATED_AT, placeholder::UPDATED_AT)
+    ))
+}
+
+/// Statuses an email rests in, never sent again unless it is requeued, which expire once the
+/// retention of the table has passed.
+const EXPIRING_STATUSES: [EmailStatus; 5] = [
+    EmailStatus::Sent,
+    EmailStatus::Failed,
+    EmailStatus::Skipped,
+    EmailStatus::Suppressed,
+    EmailStatus::Cancelled,
+];
+
+/// Add the assignment of `ExpiresAt`, `retention` from now in epoch seconds as DynamoDB TTL reads
+/// it, to `assignments` when an email reaching `status` expires and `retention` is given. Returns
+/// the clause removing `ExpiresAt` otherwise, so an email returned to `EmailStatus::Pending` is
+/// not deleted before it is sent.
+fn expire(
+    values: &mut HashMap<String, AttributeValue>,
+    assignments: &mut Vec<(&str, &str)>,
+    status: EmailStatus,
+    retention: Option<Duration>,
+) -> String {
+    match retention {
+        Some(retention) if EXPIRING_STATUSES.contains(&status) => {
+            let expires_at = Utc::now().timestamp() as u64 + retention.as_secs();
+            values.insert(
+                placeholder::EXPIRES_AT.to_owned(),
+                AttributeValue::N(expires_at.to_string()),
+            );
+            assignments.push((attribute::EXPIRES_AT, placeholder::EXPIRES_AT));
+            String::new()
+        }
+        _ => format!(" REMOVE {}", attribute::EXPIRES_AT),
+    }
+}
+
+/// `removals`, the clause `expire` returned, extended to remove `ClaimedBy` and
+/// `SendingLockExpiresAt` when an email leaves `EmailStatus::Sending`, so an email rejected after
+/// a lapsed claim was taken over keeps no claim.
+fn remove_claim(removals: String, from: EmailStatus) -> String {
+    if from != EmailStatus::Sending {
+        return removals;
+    }
+    let claim = format!(
+        "{}, {}",
+        attribute::CLAIMED_BY,
+        attribute::SENDING_LOCK_EXPIRES_AT
+    );
+    if removals.is_empty() {
+        format!(" REMOVE {}", claim)
+    } else {
+        format!("{}, {}", removals, claim)
+    }
+}
+
+/// Condition that the email is at `version`, adding the values it and the assignment of the next
+/// version to `placeholder::NEXT_VERSION` refer to to `values`. An email never updated has no
+/// `Version`.
+fn expect_version(values: &mut HashMap<String, AttributeValue>, version: u64) -> String {
+    values.insert(
+        placeholder::NEXT_VERSION.to_owned(),
+        AttributeValue::N((version + 1).to_string()),
+    );
+    if version == 0 {
+        return attribute_not_exists(attribute::VERSION);
+    }
+    values.insert(
+        placeholder::VERSION.to_owned(),
+        AttributeValue::N(version.to_string()),
+    );
+    equals(attribute::VERSION, placeholder::VERSION)
+}
+
+/// `UpdateError::VersionConflict` when the `stored` item returned by a failed condition is not
+/// at `version`, meaning another writer updated it since it was read.
+fn version_conflict(
+    stored: Option<&HashMap<String, AttributeValue>>,
+    version: u64,
+) -> Option<UpdateError> {
+    let found = stored?
+        .get(attribute::VERSION)
+        .and_then(|value| value.as_n().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or_default();
+    (found != version).then(|| {
+        UpdateError::VersionConflict(format!("expected Version {}, found {}", version, found))
+    })
+}
+
+/// `UpdateError` of an update made at `version`.
+fn update_error(error: SdkError<UpdateItemError>, version: u64) -> UpdateError {
+    let stored = match error.as_service_error() {
+        Some(UpdateItemError::ConditionalCheckFailedException(failed)) => failed.item(),
+        _ => None,
+    };
+    version_conflict(stored, version).unwrap_or_else(|| UpdateError::from(error))
+}
+
+/// `UpdateError` of a transaction updating a single email made at `version`.
+fn transact_error(error: SdkError<TransactWriteItemsError>, version: u64) -> UpdateError {
+    let stored = match error.as_service_error() {
+        Some(TransactWriteItemsError::TransactionCanceledException(canceled)) => canceled
+            .cancellation_reasons()
+            .iter()
+            .find_map(|reason| reason.item()),
+        _ => None,
+    };
+    version_conflict(stored, version).unwrap_or_else(|| UpdateError::from(error))
+}
+
+/// Update the `EmailStatus` of the Dynamo record identified by `pointer` as `set_email_status`
+/// does, also recording why the status was reached as `StatusReason`.
+pub async fn set_email_status_with_reason(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    message: &EmailPointerMessage,
+    version: u64,
+    args: StatusTransition,
+    reason: &str,
+    retention: Option<Duration>,
+) -> Result<(), UpdateError> {
+    let StatusTransition {
+        from: current_status,
+        to: next_status,
+    } = StatusMachine::check(args)?;
+    let mut values = AttributeValueMap::with_entries(vec![
+        (placeholder::EXPECTED.into(), current_status.to_string()),
+        (placeholder::NEXT.into(), next_status.to_string()),
+        (placeholder::REASON.into(), reason.to_owned()),
+    ]);
+    let change = StatusChange::new(Some(current_status), next_status)
+        .with_worker(&message.claim_id())
+        .with_error(reason);
+    let history = append_status_history(&mut values, &change)?;
+    let current = expect_version(&mut values, version);
+    let mut assignments = vec![
+        (attribute::EMAIL_STATUS, placeholder::NEXT),
+        (attribute::STATUS_REASON, placeholder::REASON),
+        (attribute::VERSION, placeholder::NEXT_VERSION),
+    ];
+    let removals = expire(&mut values, &mut assignments, next_status, retention);
+    let removals = remove_claim(removals, current_status);
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{} AND {}",
+            equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+            current
+        ))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(message.email_id.as_str())))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .table_name(table_name)
+        .update_expression(format!("{}, {}{}", set(&assignments), history, removals));
+    send_update(request, |error| update_error(error, version)).await
+}
+
+/// Append `feedback` to the `Feedback` list of the Dynamo record identified by `email_id`. Fails
+/// with `UpdateError::ConditionalCheckFailed` when there is no such record. Appending is safe
+/// whatever else changed so the `Version` is incremented without being checked.
+pub async fn add_email_feedback(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &str,
+    feedback: &Feedback,
+) -> Result<(), UpdateError> {
+    let entry =
+        super::to_hashmap(feedback).map_err(|e| UpdateError::SerializeError(e.to_string()))?;
+    let mut values = HashMap::new();
+    values.insert(placeholder::EMPTY.to_owned(), AttributeValue::L(Vec::new()));
+    values.insert(
+        placeholder::FEEDBACK.to_owned(),
+        AttributeValue::L(vec![AttributeValue::M(entry)]),
+    );
+    values.insert(placeholder::ONE.to_owned(), AttributeValue::N("1".into()));
+    let request = dynamodb
+        .update_item()
+        .condition_expression(attribute_exists(attribute::EMAIL_ID))
+        .set_expression_attribute_values(Some(values))
+        .set_key(Some(email_key(email_id)))
+        .table_name(table_name)
+        .update_expression(format!(
+            "SET {0} = list_append(if_not_exists({0}, {1}), {2}) ADD {3} {4}",
+            attribute::FEEDBACK,
+            placeholder::EMPTY,
+            placeholder::FEEDBACK,
+            attribute::VERSION,
+            placeholder::ONE
+        ));
+    send_update(request, UpdateError::from).await
+}
+
+/// Get the `EmailStatus` of each recipient of the personalized email identified by `email_id`
+/// from the recipient item collection in `table_name`. Recipients without an item have not been
+/// attempted yet and are not included.
+pub async fn get_recipient_statuses(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &str,
+) -> Result<HashMap<Recipient, EmailStatus>, GetError> {
+    let mut statuses = HashMap::new();
+    let mut start_key = None;
+    loop {
+        let output = dynamodb
+            .query()
+            .set_exclusive_start_key(start_key)
+            .key_condition_expression(equals(attribute::EMAIL_ID, placeholder::EMAIL_ID))
+            .set_expression_attribute_values(Some(AttributeValueMap::with_entry(
+                placeholder::EMAIL_ID,
+                email_id.into(),
+            )))
+            .table_name(table_name)
+            .send()
+            .await
+            .map_err(GetError::from)?;
+        for item in output.items.unwrap_or_default() {
+            let recipient = item.get(attribute::RECIPIENT).and_then(|v| v.as_s().ok());
+            let status = item
+                .get(attribute::RECIPIENT_STATUS)
+                .and_then(|v| v.as_s().ok());
+            if let (Some(recipient), Some(status)) = (recipient, status) {
+                statuses.insert(
+                    Recipient::from(recipient.as_str()),
+                    EmailStatus::from(status.as_str()),
+                );
+            }
+        }
+        start_key = output.last_evaluated_key;
+        if start_key.is_none() {
+            return Ok(statuses);
+        }
+    }
+}
+
+/// Update the `EmailStatus` of a single recipient of the personalized email identified by
+/// `email_id`, removing the claim of a recipient leaving `EmailStatus::Sending`. A recipient
+/// without an item is treated as `EmailStatus::Pending`.
+pub async fn set_recipient_status(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &str,
+    recipient: &str,
     args: StatusTransition,
 ) -> Result<(), UpdateError> {
     let StatusTransition {
         from: current_status,
         to: next_status,
-    } = args;
-    let input = UpdateItemInput {
-        condition_expression: Some("EmailStatus = :expected".to_owned()),
-        expression_attribute_values: Some(AttributeValueMap::with_entries(vec![
-            (":expected".into(), current_status.to_string()),
-            (":next".into(), next_status.to_string()),
-        ])),
-        key: AttributeValueMap::with_entry("EmailId", message.email_id.clone()),
-        table_name: table_name.into(),
-        update_expression: Some("SET EmailStatus = :next".to_owned()),
-        ..UpdateItemInput::default()
+    } = StatusMachine::check(args)?;
+    let condition = if current_status == EmailStatus::Pending {
+        format!(
+            "{} OR {}",
+            attribute_not_exists(attribute::RECIPIENT_STATUS),
+            equals(attribute::RECIPIENT_STATUS, placeholder::EXPECTED)
+        )
+    } else {
+        equals(attribute::RECIPIENT_STATUS, placeholder::EXPECTED)
     };
-    dynamodb
-        .update_item(input)
-        .await
-        .map_err(UpdateError::from)
-        .and_then(|_| Ok(()))
+    let request = dynamodb
+        .update_item()
+        .condition_expression(condition)
+        .set_expression_attribute_values(Some(AttributeValueMap::with_entries(vec![
+            (placeholder::EXPECTED.into(), current_status.to_string()),
+            (placeholder::NEXT.into(), next_status.to_string()),
+        ])))
+        .set_key(Some(AttributeValueMap::with_entries(vec![
+            (attribute::EMAIL_ID.into(), email_id.into()),
+            (attribute::RECIPIENT.into(), recipient.into()),
+        ])))
+        .table_name(table_name);
+    // A recipient which is no longer `Sending` holds no claim
+    let update = set(&[(attribute::RECIPIENT_STATUS, placeholder::NEXT)]);
+    let request = if next_status == EmailStatus::Sending {
+        request.update_expression(update)
+    } else {
+        request.update_expression(format!(
+            "{} REMOVE {}",
+            update,
+            attribute::SENDING_LOCK_EXPIRES_AT
+        ))
+    };
+    send_update(request, UpdateError::from).await
 }
 
+/// Claim `recipient` of the personalized email identified by `email_id` for sending by moving it
+/// from `EmailStatus::Pending`, or from having no item, to `EmailStatus::Sending`. As with
+/// `claim_email` the claim is a lease held for `lease`, recorded as `SendingLockExpiresAt`, after
+/// which a later delivery of the email may take over a recipient left `EmailStatus::Sending` by a
+/// worker which stopped. Fails with `UpdateError::ConditionalCheckFailed` when the recipient is
+/// neither `EmailStatus::Pending` nor held by a lapsed claim.
+pub async fn claim_recipient(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &str,
+    recipient: &str,
+    lease: Duration,
+) -> Result<(), UpdateError> {
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::from_std(lease.min(MAX_LEASE)).unwrap_or_default();
+    let claim = StatusMachine::transition(EmailStatus::Pending, EmailStatus::Sending)?;
+    let takeover = StatusMachine::transition(EmailStatus::Sending, EmailStatus::Sending)?;
+    let request = dynamodb
+        .update_item()
+        .condition_expression(format!(
+            "{} OR {} OR {} AND {} < {}",
+            attribute_not_exists(attribute::RECIPIENT_STATUS),
+            equals(attribute::RECIPIENT_STATUS, placeholder::EXPECTED),
+            equals(attribute::RECIPIENT_STATUS, placeholder::SENDING),
+            attribute::SENDING_LOCK_EXPIRES_AT,
+            placeholder::NOW
+        ))
+        .set_expression_attribute_values(Some(AttributeValueMap::with_entries(vec![
+            (placeholder::EXPECTED.into(), claim.from.to_string()),
+            (placeholder::SENDING.into(), takeover.from.to_string()),
+            (placeholder::NOW.into(), lease_timestamp(now)),
+            (placeholder::NEXT.into(), claim.to.to_string()),
+            (placeholder::EXPIRES.into(), lease_timestamp(expires_at)),
+        ])))
+        .set_key(Some(AttributeValueMap::with_entries(vec![
+            (attribute::EMAIL_ID.into(), email_id.into()),
+            (attribute::RECIPIENT.into(), recipient.into()),
+        ])))
+        .table_name(table_name)
+        .update_expression(set(&[
+            (attribute::RECIPIENT_STATUS, placeholder::NEXT),
+            (attribute::SENDING_LOCK_EXPIRES_AT, placeholder::EXPIRES),
+        ]));
+    send_update(request, UpdateError::from).await
+}
+
+/// A change of `EmailStatus` made by an update, checked against the `StatusMachine` before it is
+/// written.
 #[derive(Clone, Copy, Debug)]
 pub struct StatusTransition {
     pub from: EmailStatus,
@@ -78,15 +1071,11 @@ impl TryFrom<GetItemOutput> for EmailMessage {
 #[cfg(test)]
 mod try_from {
     use super::*;
-    use rusoto_dynamodb::AttributeValue;
-    use std::collections::HashMap;
+    use crate::feedback::FeedbackType;
 
     #[test]
     fn fails_on_empty_result() {
-        let output = GetItemOutput {
-            consumed_capacity: None,
-            item: None,
-        };
+        let output = GetItemOutput::builder().build();
         match EmailMessage::try_from(output) {
             Ok(_) => panic!("Should not have parsed."),
             Err(code) => assert_eq!(code, GetError::RecordNotFound),
@@ -97,10 +1086,7 @@ mod try_from {
     fn fails_missing_id() {
         let attrs = HashMap::new();
         let item = Some(attrs);
-        let output = GetItemOutput {
-            consumed_capacity: None,
-            item,
-        };
+        let output = GetItemOutput::builder().set_item(item).build();
         match EmailMessage::try_from(output) {
             Ok(_) => panic!("Should not have parsed."),
             Err(code) => assert_eq!(code, GetError::PropertyMissing("EmailId".into())),
@@ -110,25 +1096,10 @@ mod try_from {
     #[test]
     fn fails_missing_subject() {
         let mut attrs = HashMap::new();
-        attrs.insert(
-            "EmailId".into(),
-            AttributeValue {
-                s: Some("foo".into()),
-                ..AttributeValue::default()
-            },
-        );
-        attrs.insert(
-            "EmailStatus".into(),
-            AttributeValue {
-                s: Some("Pending".into()),
-                ..AttributeValue::default()
-            },
-        );
+        attrs.insert("EmailId".into(), AttributeValue::S("foo".into()));
+        attrs.insert("EmailStatus".into(), AttributeValue::S("Pending".into()));
         let item = Some(attrs);
-        let output = GetItemOutput {
-            consumed_capacity: None,
-            item,
-        };
+        let output = GetItemOutput::builder().set_item(item).build();
         match EmailMessage::try_from(output) {
             Ok(_) => panic!("Should not have parsed."),
             Err(code) => assert_eq!(code, GetError::PropertyMissing("Subject".into())),
@@ -138,25 +1109,10 @@ mod try_from {
     #[test]
     fn fails_missing_status() {
         let mut attrs = HashMap::new();
-        attrs.insert(
-            "EmailId".into(),
-            AttributeValue {
-                s: Some("Test EmailId".into()),
-                ..AttributeValue::default()
-            },
-        );
-        attrs.insert(
-            "Subject".into(),
-            AttributeValue {
-                s: Some("Test Subject".into()),
-                ..AttributeValue::default()
-            },
-        );
+        attrs.insert("EmailId".into(), AttributeValue::S("Test EmailId".into()));
+        attrs.insert("Subject".into(), AttributeValue::S("Test Subject".into()));
         let item = Some(attrs);
-        let output = GetItemOutput {
-            consumed_capacity: None,
-            item,
-        };
+        let output = GetItemOutput::builder().set_item(item).build();
         match EmailMessage::try_from(output) {
             Ok(_) => panic!("Should not have parsed."),
             Err(code) => assert_eq!(code, GetError::PropertyMissing("EmailStatus".into())),
@@ -166,32 +1122,11 @@ mod try_from {
     #[test]
     fn succeeds() {
         let mut attrs = HashMap::new();
-        attrs.insert(
-            "EmailId".into(),
-            AttributeValue {
-                s: Some("Test EmailId".into()),
-                ..AttributeValue::default()
-            },
-        );
-        attrs.insert(
-            "Subject".into(),
-            AttributeValue {
-                s: Some("Test Subject".into()),
-                ..AttributeValue::default()
-            },
-        );
-        attrs.insert(
-            "EmailStatus".into(),
-            AttributeValue {
-                s: Some("Pending".into()),
-                ..AttributeValue::default()
-            },
-        );
+        attrs.insert("EmailId".into(), AttributeValue::S("Test EmailId".into()));
+        attrs.insert("Subject".into(), AttributeValue::S("Test Subject".into()));
+        attrs.insert("EmailStatus".into(), AttributeValue::S("Pending".into()));
         let item = Some(attrs);
-        let output = GetItemOutput {
-            consumed_capacity: None,
-            item,
-        };
+        let output = GetItemOutput::builder().set_item(item).build();
         match EmailMessage::try_from(output) {
             Ok(email) => {
                 assert_eq!(&email.email_id, "Test EmailId");
@@ -201,4 +1136,926 @@ mod try_from {
             Err(_) => panic!("Should have parsed."),
         };
     }
+
+    #[test]
+    fn reads_flags_string_set() {
+        let mut attrs = HashMap::new();
+        attrs.insert("EmailId".into(), AttributeValue::S("Test EmailId".into()));
+        attrs.insert("Subject".into(), AttributeValue::S("Test Subject".into()));
+        attrs.insert("EmailStatus".into(), AttributeValue::S("Pending".into()));
+        attrs.insert(
+            "Flags".into(),
+            AttributeValue::Ss(vec!["use-template-v2".into(), "track-opens".into()]),
+        );
+        let output = GetItemOutput::builder().set_item(Some(attrs)).build();
+        let email = EmailMessage::try_from(output).unwrap();
+        assert!(email.has_flag("use-template-v2"));
+        assert!(email.has_flag("track-opens"));
+        assert!(!email.has_flag("other"));
+    }
+
+    #[test]
+    fn reads_feedback_list() {
+        let feedback = Feedback {
+            feedback_type: FeedbackType::Bounce,
+            recipient: "a@example.com".into(),
+            sub_type: "Permanent".into(),
+            detail: Some("smtp; 550 5.1.1 user unknown".into()),
+            timestamp: "2021-01-01T00:00:00.000Z".into(),
+        };
+        let mut attrs = HashMap::new();
+        attrs.insert("EmailId".into(), AttributeValue::S("Test EmailId".into()));
+        attrs.insert("Subject".into(), AttributeValue::S("Test Subject".into()));
+        attrs.insert("EmailStatus".into(), AttributeValue::S("Sent".into()));
+        attrs.insert(
+            "Feedback".into(),
+            AttributeValue::L(vec![AttributeValue::M(
+                crate::dynamo::to_hashmap(&feedback).unwrap(),
+            )]),
+        );
+        let output = GetItemOutput::builder().set_item(Some(attrs)).build();
+        let email = EmailMessage::try_from(output).unwrap();
+        assert_eq!(email.feedback, vec![feedback]);
+    }
+}
+
+#[cfg(test)]
+mod get_email_message_with {
+    use super::*;
+    use crate::email_message::EmailMessageAttachment;
+    use crate::test_support::InMemoryDynamoDb;
+
+    #[tokio::test]
+    async fn reads_projected_attributes() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            attachments: vec![EmailMessageAttachment::inline(
+                "report.pdf",
+                "application/pdf",
+                &[0; 1024],
+            )],
+            email_id: "Test EmailId".into(),
+            subject: "Test Subject".into(),
+            version: 2,
+            ..EmailMessage::default()
+        });
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let options = ReadOptions::default()
+            .with_attributes(&[attribute::EMAIL_STATUS, attribute::SENT_AT])
+            .with_consistent_read(true);
+        let email = get_email_message_with(&table.client(), "Test Table", &pointer, &options)
+            .await
+            .unwrap();
+        assert!(email.attachments.is_empty());
+        assert_eq!(email.subject, "Test Subject");
+        assert_eq!(email.version, 2);
+        let request = &table.requests("GetItem")[0];
+        assert_eq!(request["ConsistentRead"], true);
+        assert_eq!(request["ProjectionExpression"], "#a0, #a1, #a2, #a3, #a4");
+        assert_eq!(request["ExpressionAttributeNames"]["#a2"], "SentAt");
+    }
+
+    #[tokio::test]
+    async fn retries_throttled_reads() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        table.throttle(1);
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let email = get_email_message(&table.client(), "Test Table", &pointer).await;
+        assert!(email.is_ok());
+        assert_eq!(table.calls("GetItem"), 2);
+    }
+
+    /// Throttled reads are attempted again by `throttle_retry` alone, even with a client which
+    /// retries calls itself.
+    #[tokio::test]
+    async fn retries_without_sdk_retries() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        table.throttle(4);
+        let config = table
+            .client()
+            .config()
+            .to_builder()
+            .retry_config(RetryConfig::standard().with_max_attempts(3))
+            .build();
+        let dynamodb = DynamoDbClient::from_conf(config);
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let email = get_email_message(&dynamodb, "Test Table", &pointer).await;
+        assert!(matches!(
+            email,
+            Err(GetError::ProvisionedThroughputExceeded(_))
+        ));
+        assert_eq!(table.calls("GetItem"), 4);
+    }
+
+    #[tokio::test]
+    async fn reads_every_attribute_by_default() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            attachments: vec![EmailMessageAttachment::inline("a.txt", "text/plain", b"a")],
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let email = get_email_message(&table.client(), "Test Table", &pointer)
+            .await
+            .unwrap();
+        assert_eq!(email.attachments.len(), 1);
+        let request = &table.requests("GetItem")[0];
+        assert!(request.get("ProjectionExpression").is_none());
+        assert_ne!(request["ConsistentRead"], true);
+    }
+}
+
+#[cfg(test)]
+mod get_email_messages {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    fn table(email_ids: &[&str]) -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        for email_id in email_ids {
+            table.insert(&EmailMessage {
+                email_id: (*email_id).into(),
+                ..EmailMessage::default()
+            });
+        }
+        table
+    }
+
+    fn pointers(email_ids: &[&str]) -> Vec<EmailPointerMessage> {
+        email_ids
+            .iter()
+            .map(|email_id| EmailPointerMessage::unqueued(email_id))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_batch_in_one_call() {
+        let table = table(&["First EmailId", "Second EmailId"]);
+        let pointers = pointers(&[
+            "First EmailId",
+            "Second EmailId",
+            "First EmailId",
+            "Missing",
+        ]);
+        let emails = get_email_messages(
+            &table.client(),
+            "Test Table",
+            &pointers,
+            &ReadOptions::default(),
+        )
+        .await;
+        assert_eq!(table.calls("BatchGetItem"), 1);
+        assert_eq!(table.calls("GetItem"), 0);
+        assert_eq!(emails.len(), 3);
+        assert_eq!(
+            emails["First EmailId"]
+                .as_ref()
+                .map(|email| email.email_id.as_str()),
+            Ok("First EmailId")
+        );
+        assert!(emails["Second EmailId"].is_ok());
+        assert_eq!(
+            emails["Missing"].as_ref().map(|email| email.status),
+            Err(&GetError::RecordNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_unprocessed_keys_again() {
+        let table = table(&["First EmailId", "Second EmailId", "Third EmailId"]);
+        table.leave_unprocessed(2);
+        let pointers = pointers(&["First EmailId", "Second EmailId", "Third EmailId"]);
+        let emails = get_email_messages(
+            &table.client(),
+            "Test Table",
+            &pointers,
+            &ReadOptions::default(),
+        )
+        .await;
+        assert_eq!(table.calls("BatchGetItem"), 2);
+        assert!(emails.values().all(Result::is_ok));
+    }
+}
+
+#[cfg(test)]
+mod query_by_status {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    fn email(email_id: &str, status: EmailStatus, updated_at: &str) -> EmailMessage {
+        EmailMessage {
+            email_id: email_id.into(),
+            status,
+            updated_at: updated_at.into(),
+            ..EmailMessage::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_through_least_recently_updated_first() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email(
+            "Newest EmailId",
+            EmailStatus::Pending,
+            "2021-03-24T00:02:00Z",
+        ));
+        table.insert(&email(
+            "Oldest EmailId",
+            EmailStatus::Pending,
+            "2021-03-24T00:00:00Z",
+        ));
+        table.insert(&email(
+            "Middle EmailId",
+            EmailStatus::Pending,
+            "2021-03-24T00:01:00Z",
+        ));
+        table.insert(&email(
+            "Sent EmailId",
+            EmailStatus::Sent,
+            "2021-03-23T00:00:00Z",
+        ));
+        let dynamodb = table.client();
+        let first = query_by_status(
+            &dynamodb,
+            "Test Table",
+            "Status Index",
+            EmailStatus::Pending,
+            2,
+            None,
+        )
+        .await
+        .unwrap();
+        let email_ids = |page: &StatusPage| {
+            page.emails
+                .iter()
+                .map(|email| email.email_id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(email_ids(&first), vec!["Oldest EmailId", "Middle EmailId"]);
+        assert_eq!(first.emails[0].status, EmailStatus::Pending);
+        assert_eq!(first.emails[0].updated_at, "2021-03-24T00:00:00Z");
+        let second = query_by_status(
+            &dynamodb,
+            "Test Table",
+            "Status Index",
+            EmailStatus::Pending,
+            2,
+            first.cursor.as_deref(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(email_ids(&second), vec!["Newest EmailId"]);
+        assert_eq!(second.cursor, None);
+        let request = &table.requests("Query")[0];
+        assert_eq!(request["IndexName"], "Status Index");
+        assert_eq!(request["Limit"], 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_cursor() {
+        let table = InMemoryDynamoDb::default();
+        let page = query_by_status(
+            &table.client(),
+            "Test Table",
+            "Status Index",
+            EmailStatus::Pending,
+            2,
+            Some("not a cursor"),
+        )
+        .await;
+        assert!(matches!(page, Err(GetError::ParseError(_))));
+        assert_eq!(table.calls("Query"), 0);
+    }
+}
+
+#[cfg(test)]
+mod set_email_status {
+    use super::*;
+    use crate::feedback::FeedbackType;
+    use crate::test_support::InMemoryDynamoDb;
+
+    const TO_FAILED: StatusTransition = StatusTransition {
+        from: EmailStatus::Pending,
+        to: EmailStatus::Failed,
+    };
+
+    fn table(version: u64) -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            version,
+            ..EmailMessage::default()
+        });
+        table
+    }
+
+    async fn version(dynamodb: &DynamoDbClient) -> u64 {
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        get_email_message(dynamodb, "Test Table", &pointer)
+            .await
+            .unwrap()
+            .version
+    }
+
+    #[tokio::test]
+    async fn retries_throttled_updates() {
+        let table = table(0);
+        table.throttle(2);
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        set_email_status(&dynamodb, "Test Table", &pointer, 0, TO_FAILED, None)
+            .await
+            .unwrap();
+        assert_eq!(table.calls("UpdateItem"), 3);
+        assert_eq!(table.status("Test EmailId"), Some("Failed".into()));
+    }
+
+    #[tokio::test]
+    async fn gives_up_while_throttled() {
+        let table = table(0);
+        table.throttle(10);
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let result = set_email_status(&dynamodb, "Test Table", &pointer, 0, TO_FAILED, None).await;
+        assert!(matches!(
+            result,
+            Err(UpdateError::ProvisionedThroughputExceeded(_))
+        ));
+        assert_eq!(table.calls("UpdateItem"), 4);
+    }
+
+    #[tokio::test]
+    async fn increments_version() {
+        let table = table(0);
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        set_email_status(&dynamodb, "Test Table", &pointer, 0, TO_FAILED, None)
+            .await
+            .unwrap();
+        assert_eq!(version(&dynamodb).await, 1);
+        let feedback = Feedback {
+            feedback_type: FeedbackType::Bounce,
+            recipient: "to@example.com".into(),
+            sub_type: "Permanent".into(),
+            detail: None,
+            timestamp: "2021-03-24T00:00:00Z".into(),
+        };
+        add_email_feedback(&dynamodb, "Test Table", "Test EmailId", &feedback)
+            .await
+            .unwrap();
+        assert_eq!(version(&dynamodb).await, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_illegal_transition() {
+        let table = table(0);
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let to_pending = StatusTransition {
+            from: EmailStatus::Sent,
+            to: EmailStatus::Pending,
+        };
+        let updated =
+            set_email_status(&table.client(), "Test Table", &pointer, 0, to_pending, None).await;
+        assert!(matches!(updated, Err(UpdateError::IllegalTransition(_))));
+        assert_eq!(table.calls("UpdateItem"), 0);
+    }
+
+    #[tokio::test]
+    async fn reports_version_conflict() {
+        let table = table(2);
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let updated = set_email_status(&dynamodb, "Test Table", &pointer, 1, TO_FAILED, None).await;
+        assert_eq!(
+            updated,
+            Err(UpdateError::VersionConflict(
+                "expected Version 1, found 2".into()
+            ))
+        );
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        // A status which no longer holds at the expected version is not a conflict
+        let to_sent = StatusTransition {
+            from: EmailStatus::Sending,
+            to: EmailStatus::Sent,
+        };
+        let updated = set_email_status(&dynamodb, "Test Table", &pointer, 2, to_sent, None).await;
+        assert!(matches!(
+            updated,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod claim_email {
+    use super::*;
+    use crate::test_support::{InMemoryDynamoDb, InMemoryQueue};
+
+    const LEASE: Duration = Duration::from_secs(300);
+
+    fn table(expires_at: &str) -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            status: EmailStatus::Sending,
+            sending_lock_expires_at: Some(expires_at.into()),
+            ..EmailMessage::default()
+        });
+        table
+    }
+
+    fn pointer() -> EmailPointerMessage {
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        EmailPointerMessage::try_from(queue.receive().remove(0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn takes_over_lapsed_claim() {
+        let table = table("2021-03-24T00:00:00Z");
+        let pointer = pointer();
+        claim_email(&table.client(), "Test Table", &pointer, 0, LEASE)
+            .await
+            .unwrap();
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+        assert_eq!(
+            table.string("Test EmailId", attribute::CLAIMED_BY),
+            Some(pointer.claim_id())
+        );
+        let expires_at = table
+            .string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT)
+            .unwrap();
+        assert!(expires_at > lease_timestamp(Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn leaves_claim_within_its_lease() {
+        let expires_at = lease_timestamp(Utc::now() + chrono::Duration::minutes(5));
+        let table = table(&expires_at);
+        let claimed = claim_email(&table.client(), "Test Table", &pointer(), 0, LEASE).await;
+        assert!(matches!(
+            claimed,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+        assert_eq!(
+            table.string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT),
+            Some(expires_at)
+        );
+    }
+}
+
+#[cfg(test)]
+mod claim_recipient {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    const LEASE: Duration = Duration::from_secs(300);
+
+    async fn claim(table: &InMemoryDynamoDb) -> Result<(), UpdateError> {
+        let dynamodb = table.client();
+        claim_recipient(
+            &dynamodb,
+            "Test Table",
+            "Test EmailId",
+            "to@example.com",
+            LEASE,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn claims_unattempted_recipient() {
+        let table = InMemoryDynamoDb::default();
+        claim(&table).await.unwrap();
+        assert_eq!(
+            table.string("Test EmailId", attribute::RECIPIENT_STATUS),
+            Some("Sending".into())
+        );
+        let expires_at = table
+            .string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT)
+            .unwrap();
+        assert!(expires_at > lease_timestamp(Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn leaves_claim_within_its_lease() {
+        let table = InMemoryDynamoDb::default();
+        claim(&table).await.unwrap();
+        let claimed = claim(&table).await;
+        assert!(matches!(
+            claimed,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn takes_over_lapsed_claim() {
+        let table = InMemoryDynamoDb::default();
+        claim(&table).await.unwrap();
+        table.set_string(
+            "Test EmailId",
+            attribute::SENDING_LOCK_EXPIRES_AT,
+            "2021-03-24T00:00:00Z",
+        );
+        claim(&table).await.unwrap();
+        let expires_at = table
+            .string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT)
+            .unwrap();
+        assert!(expires_at > lease_timestamp(Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn releases_claim_once_sent() {
+        let table = InMemoryDynamoDb::default();
+        claim(&table).await.unwrap();
+        let sent = StatusTransition {
+            from: EmailStatus::Sending,
+            to: EmailStatus::Sent,
+        };
+        let dynamodb = table.client();
+        set_recipient_status(
+            &dynamodb,
+            "Test Table",
+            "Test EmailId",
+            "to@example.com",
+            sent,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            table.string("Test EmailId", attribute::RECIPIENT_STATUS),
+            Some("Sent".into())
+        );
+        assert_eq!(
+            table.string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT),
+            None
+        );
+        assert!(claim(&table).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod record_email_sent {
+    use super::*;
+    use crate::test_support::{InMemoryDynamoDb, InMemoryQueue};
+
+    fn table(status: EmailStatus) -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            sending_lock_expires_at: Some("2021-03-24T00:05:00Z".into()),
+            status,
+            ..EmailMessage::default()
+        });
+        table
+    }
+
+    fn pointer() -> EmailPointerMessage {
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        EmailPointerMessage::try_from(queue.receive().remove(0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_send_in_one_transaction() {
+        let table = table(EmailStatus::Sending);
+        let dynamodb = table.client();
+        let pointer = pointer();
+        let location = S3Object {
+            bucket: "Test Bucket".into(),
+            key: "sent/Test EmailId.eml".into(),
+        };
+        record_email_sent(
+            &dynamodb,
+            "Test Table",
+            &pointer,
+            0,
+            Some("Test MessageId"),
+            Some(&location),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(table.calls("TransactWriteItems"), 1);
+        assert_eq!(table.calls("UpdateItem"), 0);
+        let email = get_email_message(&dynamodb, "Test Table", &pointer)
+            .await
+            .unwrap();
+        assert_eq!(email.status, EmailStatus::Sent);
+        assert_eq!(email.provider_response.as_deref(), Some("Test MessageId"));
+        assert_eq!(email.rendered_mime, Some(location));
+        assert_eq!(email.sending_lock_expires_at, None);
+        assert_eq!(
+            email.status_history,
+            vec![StatusChange {
+                at: email.sent_at.unwrap(),
+                error: None,
+                from: Some(EmailStatus::Sending),
+                to: EmailStatus::Sent,
+                worker: Some(pointer.claim_id()),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_version_conflict() {
+        let table = table(EmailStatus::Sending);
+        let recorded = record_email_sent(
+            &table.client(),
+            "Test Table",
+            &pointer(),
+            1,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(recorded, Err(UpdateError::VersionConflict(_))));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+    }
+
+    #[tokio::test]
+    async fn leaves_record_unless_sending() {
+        let table = table(EmailStatus::Pending);
+        let dynamodb = table.client();
+        let recorded =
+            record_email_sent(&dynamodb, "Test Table", &pointer(), 0, None, None, None).await;
+        assert!(matches!(
+            recorded,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        assert_eq!(table.string("Test EmailId", attribute::SENT_AT), None);
+    }
+}
+
+#[cfg(test)]
+mod append_status_history {
+    use super::*;
+    use crate::test_support::{InMemoryDynamoDb, InMemoryQueue};
+
+    #[tokio::test]
+    async fn records_each_transition_in_order() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        let dynamodb = table.client();
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let pointer = EmailPointerMessage::try_from(queue.receive().remove(0)).unwrap();
+        let lease = Duration::from_secs(300);
+        claim_email(&dynamodb, "Test Table", &pointer, 0, lease)
+            .await
+            .unwrap();
+        release_claim(&dynamodb, "Test Table", &pointer, 1)
+            .await
+            .unwrap();
+        claim_email(&dynamodb, "Test Table", &pointer, 2, lease)
+            .await
+            .unwrap();
+        let to_failed = StatusTransition {
+            from: EmailStatus::Sending,
+            to: EmailStatus::Failed,
+        };
+        set_email_status_with_reason(
+            &dynamodb,
+            "Test Table",
+            &pointer,
+            3,
+            to_failed,
+            "no body",
+            None,
+        )
+        .await
+        .unwrap();
+        // A transition refused by its condition is not recorded
+        let to_sent = StatusTransition {
+            from: EmailStatus::Sending,
+            to: EmailStatus::Sent,
+        };
+        assert!(
+            set_email_status(&dynamodb, "Test Table", &pointer, 4, to_sent, None)
+                .await
+                .is_err()
+        );
+        let history = get_email_message(&dynamodb, "Test Table", &pointer)
+            .await
+            .unwrap()
+            .status_history;
+        let steps = history
+            .iter()
+            .map(|change| (change.from, change.to, change.error.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                (None, EmailStatus::Sending, None),
+                (Some(EmailStatus::Sending), EmailStatus::Pending, None),
+                (None, EmailStatus::Sending, None),
+                (
+                    Some(EmailStatus::Sending),
+                    EmailStatus::Failed,
+                    Some("no body")
+                ),
+            ]
+        );
+        assert!(history
+            .iter()
+            .all(|change| change.worker == Some(pointer.claim_id())));
+        assert_eq!(
+            table.string("Test EmailId", attribute::UPDATED_AT),
+            history.last().map(|change| change.at.clone())
+        );
+    }
+}
+
+#[cfg(test)]
+mod release_lapsed_claim {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    fn table(expires_at: &str) -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            status: EmailStatus::Sending,
+            sending_lock_expires_at: Some(expires_at.into()),
+            ..EmailMessage::default()
+        });
+        table
+    }
+
+    #[tokio::test]
+    async fn returns_lapsed_claim_to_pending() {
+        let table = table("2021-03-24T00:00:00Z");
+        release_lapsed_claim(&table.client(), "Test Table", "Test EmailId", 0)
+            .await
+            .unwrap();
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        assert_eq!(
+            table.string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_claim_within_its_lease() {
+        let table = table("9999-12-31T23:59:59Z");
+        let released = release_lapsed_claim(&table.client(), "Test Table", "Test EmailId", 0).await;
+        assert!(matches!(
+            released,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+    }
+}
+
+#[cfg(test)]
+mod release_claim {
+    use super::*;
+    use crate::test_support::{InMemoryDynamoDb, InMemoryQueue};
+    use aws_sdk_sqs::types::Message;
+
+    const LEASE: Duration = Duration::from_secs(300);
+
+    fn table() -> InMemoryDynamoDb {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            status: EmailStatus::Pending,
+            ..EmailMessage::default()
+        });
+        table
+    }
+
+    fn pointers(queue: &mut InMemoryQueue) -> Vec<EmailPointerMessage> {
+        queue
+            .receive()
+            .into_iter()
+            .map(|message: Message| EmailPointerMessage::try_from(message).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn returns_claim_to_pending() {
+        let table = table();
+        let dynamodb = table.client();
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        let pointer = pointers(&mut queue).remove(0);
+        claim_email(&dynamodb, "Test Table", &pointer, 0, LEASE)
+            .await
+            .unwrap();
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+        assert_eq!(
+            table.string("Test EmailId", attribute::CLAIMED_BY),
+            Some(pointer.claim_id())
+        );
+        release_claim(&dynamodb, "Test Table", &pointer, 1)
+            .await
+            .unwrap();
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Pending"));
+        assert_eq!(table.string("Test EmailId", attribute::CLAIMED_BY), None);
+        assert_eq!(
+            table.string("Test EmailId", attribute::SENDING_LOCK_EXPIRES_AT),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_claim_of_another_delivery() {
+        let table = table();
+        let dynamodb = table.client();
+        let mut queue = InMemoryQueue::default();
+        queue.send_pointer("Test EmailId");
+        queue.send_pointer("Test EmailId");
+        let mut pointers = pointers(&mut queue);
+        let (first, second) = (pointers.remove(0), pointers.remove(0));
+        claim_email(&dynamodb, "Test Table", &first, 0, LEASE)
+            .await
+            .unwrap();
+        // Both deliveries read the email before either claimed it
+        let claimed = claim_email(&dynamodb, "Test Table", &second, 0, LEASE).await;
+        assert!(matches!(claimed, Err(UpdateError::VersionConflict(_))));
+        let released = release_claim(&dynamodb, "Test Table", &second, 1).await;
+        assert!(matches!(
+            released,
+            Err(UpdateError::ConditionalCheckFailed(_))
+        ));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+        assert_eq!(
+            table.string("Test EmailId", attribute::CLAIMED_BY),
+            Some(first.claim_id())
+        );
+    }
+}
+
+#[cfg(test)]
+mod expire {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    async fn expires_at(dynamodb: &DynamoDbClient) -> Option<u64> {
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        get_email_message(dynamodb, "Test Table", &pointer)
+            .await
+            .unwrap()
+            .expires_at
+    }
+
+    #[tokio::test]
+    async fn expires_emails_until_requeued() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..EmailMessage::default()
+        });
+        let dynamodb = table.client();
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let to_failed = StatusTransition {
+            from: EmailStatus::Pending,
+            to: EmailStatus::Failed,
+        };
+        set_email_status(
+            &dynamodb,
+            "Test Table",
+            &pointer,
+            0,
+            to_failed,
+            Some(RETENTION),
+        )
+        .await
+        .unwrap();
+        let expected = Utc::now().timestamp() as u64 + RETENTION.as_secs();
+        let written = expires_at(&dynamodb).await.unwrap();
+        assert!(written.abs_diff(expected) <= 1);
+        let to_pending = StatusTransition {
+            from: EmailStatus::Failed,
+            to: EmailStatus::Pending,
+        };
+        set_email_status(
+            &dynamodb,
+            "Test Table",
+            &pointer,
+            1,
+            to_pending,
+            Some(RETENTION),
+        )
+        .await
+        .unwrap();
+        assert_eq!(expires_at(&dynamodb).await, None);
+    }
 }
@@ -31,6 +31,12 @@ pub enum DeserializeError {
     UnknownField(String),
 }
 
+impl serde::ser::Error for DeserializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
 impl serde::de::Error for DeserializeError {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
         Self::Custom(msg.to_string())
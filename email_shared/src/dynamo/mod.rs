@@ -1,6 +1,16 @@
 mod de;
+#[allow(clippy::module_inception)]
 mod dynamo;
 mod error;
+mod ser;
 
 pub use de::from_hashmap;
-pub use dynamo::{get_email_message, set_email_status, StatusTransition};
+pub(crate) use dynamo::lease_timestamp;
+pub use dynamo::{
+    add_email_feedback, claim_email, claim_recipient, get_email_message, get_email_message_with,
+    get_email_messages, get_recipient_statuses, put_email_message, query_by_status,
+    record_email_sent, release_claim, release_lapsed_claim, set_email_status,
+    set_email_status_with_reason, set_recipient_status, ReadOptions, StatusEntry, StatusPage,
+    StatusTransition,
+};
+pub use ser::to_hashmap;
@@ -0,0 +1,488 @@
+//! Serialize a Rust data structure into a HashMap.
+
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::ser::{self, Serialize};
+use std::collections::HashMap;
+
+use crate::dynamo::error::{DeserializeError, Result};
+
+macro_rules! impl_serialize_n {
+    ($type:ty, $method:ident) => {
+        fn $method(self, v: $type) -> Result<AttributeValue> {
+            Ok(AttributeValue::N(v.to_string()))
+        }
+    };
+}
+
+/// Name of the attribute holding the variant of a non-unit enum. Matches the layout read by
+/// `from_hashmap`.
+const ENUM_TAG: &str = "___enum_tag";
+/// Name of the attribute holding the values of a non-unit enum variant.
+const ENUM_VALUES: &str = "___enum_values";
+
+/// Build the `AttributeValue` representing the `variant` of an enum holding `values`.
+fn enum_value(variant: &str, values: HashMap<String, AttributeValue>) -> AttributeValue {
+    let mut map = HashMap::new();
+    map.insert(ENUM_TAG.to_owned(), AttributeValue::S(variant.to_owned()));
+    map.insert(ENUM_VALUES.to_owned(), AttributeValue::M(values));
+    AttributeValue::M(map)
+}
+
+/// Serializes a value into a single `AttributeValue`.
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeTuple;
+    type SerializeTupleStruct = SerializeTuple;
+    type SerializeTupleVariant = SerializeTuple;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn serialize_bool(self, v: bool) -> Result<AttributeValue> {
+        Ok(AttributeValue::Bool(v))
+    }
+
+    impl_serialize_n!(i8, serialize_i8);
+    impl_serialize_n!(i16, serialize_i16);
+    impl_serialize_n!(i32, serialize_i32);
+    impl_serialize_n!(i64, serialize_i64);
+
+    impl_serialize_n!(u8, serialize_u8);
+    impl_serialize_n!(u16, serialize_u16);
+    impl_serialize_n!(u32, serialize_u32);
+    impl_serialize_n!(u64, serialize_u64);
+
+    impl_serialize_n!(f32, serialize_f32);
+    impl_serialize_n!(f64, serialize_f64);
+
+    fn serialize_char(self, v: char) -> Result<AttributeValue> {
+        Ok(AttributeValue::S(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<AttributeValue> {
+        Ok(AttributeValue::S(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<AttributeValue> {
+        Ok(AttributeValue::B(Blob::new(v)))
+    }
+
+    fn serialize_none(self) -> Result<AttributeValue> {
+        Ok(AttributeValue::Null(true))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<AttributeValue>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<AttributeValue> {
+        Ok(AttributeValue::Null(true))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<AttributeValue> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<AttributeValue> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<AttributeValue>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<AttributeValue>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut values = HashMap::new();
+        values.insert(String::from("_0"), value.serialize(Serializer)?);
+        Ok(enum_value(variant, values))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(SerializeVec {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Ok(SerializeTuple::new(None))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Ok(SerializeTuple::new(None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Ok(SerializeTuple::new(Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(SerializeMap::new(None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Ok(SerializeMap::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Ok(SerializeMap::new(Some(variant)))
+    }
+}
+
+/// Collects sequence elements into an `AttributeValue::L`.
+struct SerializeVec {
+    vec: Vec<AttributeValue>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.vec.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        Ok(AttributeValue::L(self.vec))
+    }
+}
+
+/// Collects tuple elements into a map keyed by position ("_0", "_1", ...) which is the layout
+/// read back by `from_hashmap`.
+struct SerializeTuple {
+    map: HashMap<String, AttributeValue>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeTuple {
+    fn new(variant: Option<&'static str>) -> Self {
+        SerializeTuple {
+            map: HashMap::new(),
+            variant,
+        }
+    }
+
+    fn push<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = format!("_{}", self.map.len());
+        self.map.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<AttributeValue> {
+        match self.variant {
+            Some(variant) => Ok(enum_value(variant, self.map)),
+            None => Ok(AttributeValue::M(self.map)),
+        }
+    }
+}
+
+impl ser::SerializeTuple for SerializeTuple {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTuple {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTuple {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+/// Collects map entries and struct fields into an `AttributeValue::M`.
+struct SerializeMap {
+    map: HashMap<String, AttributeValue>,
+    next_key: Option<String>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeMap {
+    fn new(variant: Option<&'static str>) -> Self {
+        SerializeMap {
+            map: HashMap::new(),
+            next_key: None,
+            variant,
+        }
+    }
+
+    fn finish(self) -> Result<AttributeValue> {
+        match self.variant {
+            Some(variant) => Ok(enum_value(variant, self.map)),
+            None => Ok(AttributeValue::M(self.map)),
+        }
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        // DynamoDB map keys are always strings, allow numeric keys to be stringified
+        let key = match key.serialize(Serializer)? {
+            AttributeValue::S(key) | AttributeValue::N(key) => key,
+            _ => {
+                return Err(DeserializeError::Custom(
+                    "map key must be a string".to_owned(),
+                ))
+            }
+        };
+        self.next_key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| DeserializeError::Custom("map value without a key".to_owned()))?;
+        self.map.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.map
+            .insert(key.to_owned(), value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = AttributeValue;
+    type Error = DeserializeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.map
+            .insert(key.to_owned(), value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AttributeValue> {
+        self.finish()
+    }
+}
+
+/// Serialize the given data structure as a `HashMap<String, AttributeValue>`.
+///
+/// # Errors
+///
+/// Serialization fails if `T`'s implementation of `Serialize` decides to fail, if `T` contains a
+/// map with non-string keys, or if `T` is not represented as a map or struct at the top level.
+pub fn to_hashmap<T>(value: &T) -> Result<HashMap<String, AttributeValue>>
+where
+    T: ?Sized + Serialize,
+{
+    match value.serialize(Serializer)? {
+        AttributeValue::M(map) => Ok(map),
+        _ => Err(DeserializeError::Custom(
+            "top level value must be a map or struct".to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod to_hashmap {
+    use super::*;
+    use crate::dynamo::from_hashmap;
+    use crate::email_message::{EmailMessage, EmailStatus};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    enum Shape {
+        Point,
+        Circle(u32),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Shapes {
+        shapes: Vec<Shape>,
+        name: Option<String>,
+    }
+
+    #[test]
+    fn fails_on_scalar() {
+        assert!(to_hashmap(&"foo").is_err());
+    }
+
+    #[test]
+    fn writes_struct_fields() {
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            recipients_to: vec!["to@example.com".into()],
+            subject: "Test Subject".into(),
+            ..EmailMessage::default()
+        };
+        let item = to_hashmap(&email).unwrap();
+        assert_eq!(
+            item.get("EmailId"),
+            Some(&AttributeValue::S("Test EmailId".into()))
+        );
+        assert_eq!(
+            item.get("EmailStatus"),
+            Some(&AttributeValue::S("Pending".into()))
+        );
+        assert_eq!(
+            item.get("RecipientsTo"),
+            Some(&AttributeValue::L(vec![AttributeValue::S(
+                "to@example.com".into()
+            )]))
+        );
+        assert_eq!(item.get("SentAt"), Some(&AttributeValue::Null(true)));
+    }
+
+    #[test]
+    fn round_trips_email_message() {
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            status: EmailStatus::Sent,
+            subject: "Test Subject".into(),
+            sent_at: Some("2021-03-14T15:09:26Z".into()),
+            ..EmailMessage::default()
+        };
+        let parsed: EmailMessage = from_hashmap(to_hashmap(&email).unwrap()).unwrap();
+        assert_eq!(parsed.email_id, email.email_id);
+        assert_eq!(parsed.status, email.status);
+        assert_eq!(parsed.subject, email.subject);
+        assert_eq!(parsed.sent_at, email.sent_at);
+    }
+
+    #[test]
+    fn round_trips_template_data() {
+        let data = serde_json::json!({ "count": 3, "ratio": 0.5, "names": ["a", "b"] });
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            template_data: data.as_object().cloned(),
+            template_id: Some("Test TemplateId".into()),
+            ..EmailMessage::default()
+        };
+        let parsed: EmailMessage = from_hashmap(to_hashmap(&email).unwrap()).unwrap();
+        assert_eq!(parsed.template_id, email.template_id);
+        assert_eq!(parsed.template_data, email.template_data);
+    }
+
+    #[test]
+    fn round_trips_enums() {
+        let shapes = Shapes {
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(3),
+                Shape::Rect {
+                    width: 4,
+                    height: 5,
+                },
+            ],
+            name: None,
+        };
+        let parsed: Shapes = from_hashmap(to_hashmap(&shapes).unwrap()).unwrap();
+        assert_eq!(parsed, shapes);
+    }
+}
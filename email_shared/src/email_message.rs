@@ -1,29 +1,386 @@
-use serde::Deserialize;
+use crate::email_message_builder::{is_atext, normalize_address};
+use crate::feedback::Feedback;
+use crate::markdown::render_bodies;
+use crate::mime::{build_message, MimeAttachment};
+use crate::personalization::PersonalizedRecipient;
+use crate::status_machine::StatusMachine;
+use crate::templates::{TemplateData, TemplateId};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::time::Duration;
 use thiserror::Error;
 
-/// A `Recipient` represents an address to which a message will be sent.
-type Recipient = String;
+/// Longest `EmailId` accepted, in bytes. Well under the 2048 bytes DynamoDB allows for a partition
+/// key since the id is also written into the `X-Email-Id` header of every message.
+const MAX_EMAIL_ID_BYTES: usize = 256;
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+/// Reasons a string can not be used as an `EmailId`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum EmailIdError {
+    #[error("InvalidEmailId({0})")]
+    InvalidEmailId(String),
+}
+
+/// An `EmailId` identifies an email record and is the key of the record in DynamoDB. An id parsed
+/// with `EmailId::new` or `str::parse`, or read from a pointer message, is known to be non-empty,
+/// at most 256 bytes, and free of control characters. `From` conversions do not check, they are
+/// for ids read back from records which were checked when written.
+///
+/// ```
+/// use email_shared::EmailId;
+///
+/// let email_id = "order-1234-receipt".parse::<EmailId>().unwrap();
+/// assert_eq!(email_id.as_str(), "order-1234-receipt");
+/// assert_eq!(email_id.to_string(), "order-1234-receipt");
+/// assert!("".parse::<EmailId>().is_err());
+/// assert!("line\nbreak".parse::<EmailId>().is_err());
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct EmailId(String);
+
+impl EmailId {
+    /// `email_id` as an `EmailId`, or `EmailIdError::InvalidEmailId` when it is empty, too long,
+    /// or holds a control character.
+    pub fn new(email_id: impl Into<String>) -> Result<Self, EmailIdError> {
+        let email_id = EmailId(email_id.into());
+        email_id.validate().map(|_| email_id)
+    }
+
+    /// The id as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check this id could have been created by `EmailId::new`.
+    pub fn validate(&self) -> Result<(), EmailIdError> {
+        let invalid = |reason: &str| {
+            Err(EmailIdError::InvalidEmailId(format!(
+                "{:?} {}",
+                self.0, reason
+            )))
+        };
+        if self.0.is_empty() {
+            invalid("is empty")
+        } else if self.0.len() > MAX_EMAIL_ID_BYTES {
+            invalid("is longer than 256 bytes")
+        } else if self.0.chars().any(char::is_control) {
+            invalid("holds a control character")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl std::str::FromStr for EmailId {
+    type Err = EmailIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EmailId::new(s)
+    }
+}
+
+impl std::fmt::Display for EmailId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for EmailId {
+    fn from(email_id: String) -> Self {
+        EmailId(email_id)
+    }
+}
+
+impl From<&str> for EmailId {
+    fn from(email_id: &str) -> Self {
+        EmailId(email_id.to_owned())
+    }
+}
+
+impl From<EmailId> for String {
+    fn from(email_id: EmailId) -> Self {
+        email_id.0
+    }
+}
+
+impl AsRef<str> for EmailId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::borrow::Borrow<str> for EmailId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for EmailId {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for EmailId {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// Reasons a string can not be used as a `Recipient`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RecipientError {
+    #[error("InvalidRecipient({0})")]
+    InvalidRecipient(String),
+}
+
+/// A `Recipient` represents an address to which a message will be sent, optionally with a display
+/// name as in `Jane Doe <jane@example.com>`. Parsing with `Recipient::parse` or `str::parse`
+/// trims the value, lower cases the domain, converts an internationalized domain to its ASCII
+/// form, and rejects anything which is not an RFC 5321 `Mailbox`. The value as it was given is
+/// kept for logging as `raw`.
+///
+/// A `Recipient` is read from a record by parsing, so a record holding an invalid address can
+/// not be read. An empty value is read as the default, empty, `Recipient`. `From` conversions do
+/// not check, a value which does not parse is kept as its address so it can be reported by
+/// `Recipient::validate` or an `EmailMessageBuilder`.
+///
+/// ```
+/// use email_shared::Recipient;
+///
+/// let recipient = "  \"Doe, Jane\" <Jane@Bücher.Example>".parse::<Recipient>().unwrap();
+/// assert_eq!(recipient.name(), Some("Doe, Jane"));
+/// assert_eq!(recipient.address(), "Jane@xn--bcher-kva.example");
+/// assert_eq!(recipient.raw(), "  \"Doe, Jane\" <Jane@Bücher.Example>");
+/// assert_eq!(recipient.to_string(), "\"Doe, Jane\" <Jane@xn--bcher-kva.example>");
+/// assert!("not an address".parse::<Recipient>().is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Recipient {
+    /// Display name, unquoted.
+    name: Option<String>,
+    /// `addr-spec` of the mailbox.
+    address: String,
+    /// The value as it was given.
+    raw: String,
+}
+
+impl Recipient {
+    /// `value` as a `Recipient`, either a bare `addr-spec` or a display name followed by an
+    /// `addr-spec` in angle brackets, or `RecipientError::InvalidRecipient` when it is neither.
+    pub fn parse(value: &str) -> Result<Self, RecipientError> {
+        let invalid = || RecipientError::InvalidRecipient(value.to_owned());
+        let trimmed = value.trim();
+        let (name, addr_spec) = match trimmed.strip_suffix('>') {
+            Some(mailbox) => {
+                let open = mailbox.rfind('<').ok_or_else(invalid)?;
+                let name = unquote(mailbox[..open].trim()).ok_or_else(invalid)?;
+                (
+                    Some(name).filter(|name| !name.is_empty()),
+                    &mailbox[open + 1..],
+                )
+            }
+            None => (None, trimmed),
+        };
+        if addr_spec.contains(char::is_whitespace) && !addr_spec.starts_with('"') {
+            return Err(invalid());
+        }
+        let address = normalize_address(addr_spec).ok_or_else(invalid)?;
+        Ok(Recipient {
+            name,
+            address,
+            raw: value.to_owned(),
+        })
+    }
+
+    /// The `addr-spec` of the mailbox, for example `jane@example.com`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Domain of the address, after the last `@`.
+    pub fn domain(&self) -> &str {
+        self.address
+            .rfind('@')
+            .map_or("", |at| &self.address[at + 1..])
+    }
+
+    /// Whether this is the default, empty, `Recipient`.
+    pub fn is_empty(&self) -> bool {
+        self.address.is_empty() && self.name.is_none()
+    }
+
+    /// Display name of the mailbox, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The value this `Recipient` was created from, before it was trimmed and normalized.
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// Check this recipient could have been created by `Recipient::parse`.
+    pub fn validate(&self) -> Result<(), RecipientError> {
+        Recipient::parse(&self.raw).map(|_| ())
+    }
+}
+
+/// `phrase` with surrounding double quotes removed and quoted-pairs resolved, or `None` when it
+/// holds a line break or unbalanced quotes.
+fn unquote(phrase: &str) -> Option<String> {
+    if phrase.contains(['\r', '\n', '\0', '<', '>']) {
+        return None;
+    }
+    let inner = match phrase.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"')?,
+        None if phrase.contains('"') => return None,
+        None => return Some(phrase.to_owned()),
+    };
+    let mut name = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.push(chars.next()?),
+            '"' => return None,
+            c => name.push(c),
+        }
+    }
+    Some(name)
+}
+
+impl std::str::FromStr for Recipient {
+    type Err = RecipientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Recipient::parse(s)
+    }
+}
+
+impl std::fmt::Display for Recipient {
+    /// The mailbox as written into a header, with the display name quoted when it holds anything
+    /// other than atoms and spaces.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            None => f.write_str(&self.address),
+            Some(name) if name.chars().all(|c| c == ' ' || is_atext(c)) => {
+                write!(f, "{} <{}>", name, self.address)
+            }
+            Some(name) => {
+                let quoted = name.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(f, "\"{}\" <{}>", quoted, self.address)
+            }
+        }
+    }
+}
+
+impl From<String> for Recipient {
+    fn from(value: String) -> Self {
+        Recipient::parse(&value).unwrap_or_else(|_| Recipient {
+            name: None,
+            address: value.clone(),
+            raw: value,
+        })
+    }
+}
+
+impl From<&str> for Recipient {
+    fn from(value: &str) -> Self {
+        Recipient::from(value.to_owned())
+    }
+}
+
+impl PartialEq for Recipient {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.address, &self.name) == (&other.address, &other.name)
+    }
+}
+
+impl Eq for Recipient {}
+
+impl std::hash::Hash for Recipient {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.address.hash(state);
+        self.name.hash(state);
+    }
+}
+
+impl PartialOrd for Recipient {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Recipient {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (&self.address, &self.name).cmp(&(&other.address, &other.name))
+    }
+}
+
+impl PartialEq<str> for Recipient {
+    /// Whether `other` is this recipient once parsed, or is its address when it does not parse.
+    fn eq(&self, other: &str) -> bool {
+        match Recipient::parse(other) {
+            Ok(other) => *self == other,
+            Err(_) => self.name.is_none() && self.address == other,
+        }
+    }
+}
+
+impl PartialEq<&str> for Recipient {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+impl Serialize for Recipient {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Recipient {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        if value.is_empty() {
+            return Ok(Recipient::default());
+        }
+        Recipient::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub enum EmailStatus {
+    #[default]
     Pending,
     Sending,
     Sent,
+    /// The email will never be sent, for example because it was too old when received.
+    Failed,
+    /// The email was deliberately not sent, for example because every recipient is suppressed.
+    Skipped,
+    /// The email was withheld by policy because every recipient is on a blocked domain.
+    Suppressed,
+    /// The email was retracted by its producer before it was sent.
+    Cancelled,
     Unknown,
 }
 
-impl Default for EmailStatus {
-    fn default() -> Self {
-        EmailStatus::Pending
-    }
-}
-
 impl From<&str> for EmailStatus {
     fn from(status: &str) -> Self {
         match status {
             "Pending" => EmailStatus::Pending,
             "Sending" => EmailStatus::Sending,
             "Sent" => EmailStatus::Sent,
+            "Failed" => EmailStatus::Failed,
+            "Skipped" => EmailStatus::Skipped,
+            "Suppressed" => EmailStatus::Suppressed,
+            "Cancelled" => EmailStatus::Cancelled,
             _ => EmailStatus::Unknown,
         }
     }
@@ -35,26 +392,100 @@ impl std::fmt::Display for EmailStatus {
     }
 }
 
-/// An attachment to an `EmailMessage`.
-#[derive(Clone, Debug, Default, Deserialize)]
+/// A change of the `EmailStatus` of an email, kept oldest first as its `StatusHistory` so what
+/// happened to an email can be read from its record.
+///
+/// ```
+/// use email_shared::{EmailStatus, StatusChange};
+///
+/// let change = StatusChange::new(Some(EmailStatus::Sending), EmailStatus::Failed)
+///     .with_worker("Test MessageId/1")
+///     .with_error("template not found");
+/// assert_eq!(change.to, EmailStatus::Failed);
+/// assert_eq!(change.worker.as_deref(), Some("Test MessageId/1"));
+/// assert_eq!(change.error.as_deref(), Some("template not found"));
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StatusChange {
+    /// DateTime the change was made.
+    pub at: String,
+    /// Why the change was made when the email was not sent.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Status the email changed from, `None` when it could have been more than one.
+    #[serde(default)]
+    pub from: Option<EmailStatus>,
+    /// Status the email changed to.
+    pub to: EmailStatus,
+    /// Delivery of the pointer which made the change, `None` when not made by a delivery.
+    #[serde(default)]
+    pub worker: Option<String>,
+}
+
+impl StatusChange {
+    /// Change from `from` to `to` made now.
+    pub fn new(from: Option<EmailStatus>, to: EmailStatus) -> Self {
+        StatusChange {
+            at: Utc::now().to_rfc3339(),
+            error: None,
+            from,
+            to,
+            worker: None,
+        }
+    }
+
+    /// Record why the change was made.
+    pub fn with_error(self, error: &str) -> Self {
+        StatusChange {
+            error: Some(error.to_owned()),
+            ..self
+        }
+    }
+
+    /// Record the delivery which made the change.
+    pub fn with_worker(self, worker: &str) -> Self {
+        StatusChange {
+            worker: Some(worker.to_owned()),
+            ..self
+        }
+    }
+}
+
+/// Location of an S3 object holding the contents of an attachment.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct S3Object {
+    /// Bucket containing the object.
+    pub bucket: String,
+    /// Key of the object within `bucket`.
+    pub key: String,
+}
+
+/// An attachment to an `EmailMessage`. The contents are either stored inline as `body`, in the S3
+/// object at `s3_object` to stay within the DynamoDB item size limit, or fetched from `url`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct EmailMessageAttachment {
     /// base64 encoded contents of the message.
-    body: String,
+    pub body: String,
     /// File name of the attached `body`.
-    name: String,
+    pub name: String,
     /// MIME type of the `body`.
-    content_type: String,
+    pub content_type: String,
     /// byte size of the `body`.
-    size: i32,
+    pub size: i32,
     /// Etag of the file retrieved from the webserver and included as `body`.
-    e_tag: String,
+    pub e_tag: String,
     /// Last modified date of the file retrieved from the webserver and included as `body`.
-    last_modified: String,
+    pub last_modified: String,
+    /// S3 object containing the attachment, read at send time in place of `body`.
+    pub s3_object: Option<S3Object>,
+    /// URL of the file on the webserver, fetched at send time unless it is unchanged from `body`.
+    pub url: Option<String>,
 }
 
 /// Represents data to be sent as an email via mail delivery services.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct EmailMessage {
     /// Attachments to include with the email message.
@@ -63,11 +494,39 @@ pub struct EmailMessage {
     /// The HTML email body.
     #[serde(default)]
     pub body_html: String,
+    /// Markdown from which the HTML and TXT bodies are rendered when the email has neither.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body_markdown: String,
     /// The TXT email body.
     #[serde(default)]
     pub body_text: String,
+    /// Kind of email, for example "otp" or "newsletter", used to select per category settings.
+    #[serde(default)]
+    pub category: Option<String>,
+    /// DateTime indicating when this record was created.
+    #[serde(default)]
+    pub created_at: String,
     /// Identifier of the email.
-    pub email_id: String,
+    pub email_id: EmailId,
+    /// Epoch seconds after which DynamoDB TTL deletes the record, written when the email stops
+    /// being sent and a retention is configured.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Bounces and complaints reported for recipients after the email was sent.
+    #[serde(default)]
+    pub feedback: Vec<Feedback>,
+    /// Names of experimental behaviors enabled for this email only, for example
+    /// "use-template-v2". Stored as a string set, flags unknown to the broker are ignored.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub flags: BTreeSet<String>,
+    /// Additional headers included in the message, for example `List-Unsubscribe`. Headers
+    /// the broker writes itself can not be replaced.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Recipients each sent an individual copy of the email rendered with their own merge fields.
+    /// When set the TO, CC, and BCC recipients are not used.
+    #[serde(default)]
+    pub personalization: Option<Vec<PersonalizedRecipient>>,
     /// Provider through which the email was sent.
     #[serde(default)]
     pub provider: String,
@@ -83,23 +542,167 @@ pub struct EmailMessage {
     /// List of `Recipient` in TO.
     #[serde(default)]
     pub recipients_to: Vec<Recipient>,
+    /// Where the exact message sent is stored, when a `MimeStore` is configured.
+    #[serde(default)]
+    pub rendered_mime: Option<S3Object>,
+    /// Addresses replies should be sent to instead of the sender.
+    #[serde(default)]
+    pub reply_to: Vec<Recipient>,
+    /// DateTime before which the email is not sent. A pointer received earlier is hidden until
+    /// then instead of being processed.
+    #[serde(default)]
+    pub scheduled_at: Option<String>,
     /// The FROM address.
     #[serde(default)]
     pub sender: Recipient,
+    /// DateTime after which the claim on the email while it is `Sending` lapses, so another
+    /// delivery may take it over from a worker which stopped before recording the send.
+    #[serde(default)]
+    pub sending_lock_expires_at: Option<String>,
     /// DateTime of first successful email send.
     #[serde(default)]
     pub sent_at: Option<String>,
     /// Last known state of the message.
     #[serde(rename = "EmailStatus")]
     pub status: EmailStatus,
+    /// Each change of `status`, oldest first.
+    #[serde(default)]
+    pub status_history: Vec<StatusChange>,
+    /// Why the email reached its `EmailStatus` when it was not sent.
+    #[serde(default)]
+    pub status_reason: Option<String>,
     /// SUBJECT of the email.
     pub subject: String,
+    /// Values used to render the template identified by `template_id`.
+    #[serde(default)]
+    pub template_data: Option<TemplateData>,
+    /// Template from which the bodies are rendered when neither body is set.
+    #[serde(default)]
+    pub template_id: Option<TemplateId>,
     /// DateTime indicating the last time this record was updated.
     #[serde(default)]
     pub updated_at: String,
+    /// Number of times the record has been updated. Every update is conditional on the version
+    /// read so a writer racing another fails with `UpdateError::VersionConflict`.
+    #[serde(default, skip_serializing_if = "is_unversioned")]
+    pub version: u64,
+}
+
+/// Whether `version` is that of a record never updated, which is written without a `Version`.
+fn is_unversioned(version: &u64) -> bool {
+    *version == 0
+}
+
+impl EmailMessageAttachment {
+    /// An attachment named `name` with `body` stored inline.
+    pub fn inline(name: impl Into<String>, content_type: impl Into<String>, body: &[u8]) -> Self {
+        EmailMessageAttachment {
+            body: STANDARD.encode(body),
+            name: name.into(),
+            content_type: content_type.into(),
+            size: i32::try_from(body.len()).unwrap_or(i32::MAX),
+            ..EmailMessageAttachment::default()
+        }
+    }
+}
+
+impl EmailMessage {
+    /// Whether the email has an HTML, TXT, or Markdown body.
+    pub fn has_body(&self) -> bool {
+        !self.body_html.is_empty() || !self.body_text.is_empty() || !self.body_markdown.is_empty()
+    }
+
+    /// Whether the producer of this email enabled the behavior named `flag`.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+
+    /// Whether a delivery may claim the email for sending at `now`. That is while the
+    /// `StatusMachine` allows it to become `EmailStatus::Sending`, once the claim on it has lapsed
+    /// when it is already `EmailStatus::Sending`.
+    ///
+    /// ```
+    /// use chrono::{TimeZone, Utc};
+    /// use email_shared::{EmailMessage, EmailStatus};
+    ///
+    /// let email = EmailMessage {
+    ///     status: EmailStatus::Sending,
+    ///     sending_lock_expires_at: Some("2021-03-24T00:05:00Z".into()),
+    ///     ..EmailMessage::default()
+    /// };
+    /// assert!(!email.is_claimable(Utc.with_ymd_and_hms(2021, 3, 24, 0, 0, 0).unwrap()));
+    /// assert!(email.is_claimable(Utc.with_ymd_and_hms(2021, 3, 24, 0, 10, 0).unwrap()));
+    /// ```
+    pub fn is_claimable(&self, now: DateTime<Utc>) -> bool {
+        match self.status {
+            EmailStatus::Sending => self
+                .sending_lock_expires_at
+                .as_deref()
+                .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+                .is_some_and(|expires_at| expires_at < now),
+            status => StatusMachine::allows(status, EmailStatus::Sending),
+        }
+    }
+
+    /// DateTime before which the email is not sent, if `scheduled_at` holds an RFC 3339 DateTime.
+    pub fn scheduled_time(&self) -> Option<DateTime<Utc>> {
+        self.scheduled_at
+            .as_deref()
+            .and_then(|scheduled_at| DateTime::parse_from_rfc3339(scheduled_at).ok())
+            .map(|scheduled_at| scheduled_at.with_timezone(&Utc))
+    }
+
+    /// How long after `now` the email is scheduled to be sent, `None` once it is due or when it is
+    /// not scheduled.
+    ///
+    /// ```
+    /// use chrono::{TimeZone, Utc};
+    /// use email_shared::EmailMessage;
+    /// use std::time::Duration;
+    ///
+    /// let email = EmailMessage {
+    ///     scheduled_at: Some("2021-03-24T09:00:00-04:00".into()),
+    ///     ..EmailMessage::default()
+    /// };
+    /// let now = Utc.with_ymd_and_hms(2021, 3, 24, 12, 0, 0).unwrap();
+    /// assert_eq!(email.scheduled_delay(now), Some(Duration::from_secs(3600)));
+    /// assert_eq!(email.scheduled_delay(now + chrono::Duration::hours(1)), None);
+    /// ```
+    pub fn scheduled_delay(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.scheduled_time()?
+            .signed_duration_since(now)
+            .to_std()
+            .ok()
+            .filter(|delay| !delay.is_zero())
+    }
+
+    /// The email as the RFC 5322 message, with `attachments`, which would be sent for it now,
+    /// ready to be saved as an `.eml` file and opened in a mail client. Bodies are rendered from
+    /// the `BodyMarkdown` when the email has no HTML or TXT body.
+    ///
+    /// ```
+    /// use email_shared::EmailMessage;
+    ///
+    /// let email = EmailMessage {
+    ///     body_markdown: "Hello *there*".into(),
+    ///     recipients_to: vec!["to@example.com".into()],
+    ///     sender: "from@example.com".into(),
+    ///     subject: "Greetings".into(),
+    ///     ..EmailMessage::default()
+    /// };
+    /// let eml = String::from_utf8(email.to_eml(&[])).unwrap();
+    /// assert!(eml.contains("Subject: Greetings\r\n"));
+    /// assert!(eml.contains("<em>there</em>"));
+    /// ```
+    pub fn to_eml(&self, attachments: &[MimeAttachment]) -> Vec<u8> {
+        let mut email = self.clone();
+        render_bodies(&mut email);
+        build_message(&email, attachments, Utc::now()).raw
+    }
 }
 
 /// Possible errors while attempting to pull fields out of `GetItemOutput`.
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum ParseEmailMessageCode {
     /// The specified record did not exist.
@@ -130,3 +733,62 @@ mod from {
         assert_eq!(output, "An error occurred attempting to access the record.");
     }
 }
+
+#[cfg(test)]
+mod parse {
+    use super::*;
+
+    #[test]
+    fn reads_display_names() {
+        let recipient = Recipient::parse("Jane Doe <Jane@Example.COM>").unwrap();
+        assert_eq!(recipient.name(), Some("Jane Doe"));
+        assert_eq!(recipient.address(), "Jane@example.com");
+        assert_eq!(recipient.to_string(), "Jane Doe <Jane@example.com>");
+        let recipient = Recipient::parse(r#""Doe, \"JD\" Jane" <jane@example.com>"#).unwrap();
+        assert_eq!(recipient.name(), Some(r#"Doe, "JD" Jane"#));
+        assert_eq!(
+            recipient.to_string().parse::<Recipient>().unwrap(),
+            recipient
+        );
+        let recipient = Recipient::parse("<jane@example.com>").unwrap();
+        assert_eq!(recipient.name(), None);
+        assert_eq!(recipient.to_string(), "jane@example.com");
+    }
+
+    #[test]
+    fn rejects_invalid_recipients() {
+        let invalid = [
+            "",
+            "Jane Doe",
+            "Jane Doe <jane@example.com",
+            "Jane Doe jane@example.com>",
+            "Jane \"Doe <jane@example.com>",
+            "Jane\r\nBcc: x@example.com <jane@example.com>",
+            "jane@example.com, john@example.com",
+            "jane@bücher..example",
+        ];
+        for value in invalid.iter() {
+            assert!(Recipient::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn keeps_unchecked_values() {
+        let recipient = Recipient::from("not an address");
+        assert_eq!(recipient.raw(), "not an address");
+        assert_eq!(recipient.address(), "not an address");
+        assert!(recipient.validate().is_err());
+        assert!(Recipient::from(" a@Example.com ").validate().is_ok());
+    }
+
+    #[test]
+    fn deserializes_only_valid_recipients() {
+        let mut record = serde_json::to_value(EmailMessage::default()).unwrap();
+        record["RecipientsTo"] = serde_json::json!(["To <to@Example.com>"]);
+        let email = serde_json::from_value::<EmailMessage>(record.clone()).unwrap();
+        assert!(email.sender.is_empty());
+        assert_eq!(email.recipients_to, vec!["To <to@example.com>"]);
+        record["RecipientsTo"] = serde_json::json!(["not an address"]);
+        assert!(serde_json::from_value::<EmailMessage>(record).is_err());
+    }
+}
@@ -0,0 +1,675 @@
+use crate::email_message::{EmailId, EmailMessage, EmailMessageAttachment, EmailStatus, Recipient};
+use crate::mime::is_custom_header_name;
+use crate::personalization::PersonalizedRecipient;
+use crate::templates::{TemplateData, TemplateId};
+use chrono::Utc;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use thiserror::Error;
+
+/// Maximum length of the local-part of an address, RFC 5321 section 4.5.3.1.1.
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+/// Maximum length of the domain of an address, RFC 5321 section 4.5.3.1.2.
+const MAX_DOMAIN_LENGTH: usize = 255;
+/// Header which must be present for `LIST_UNSUBSCRIBE_POST` to be used, RFC 8058.
+const LIST_UNSUBSCRIBE: &str = "list-unsubscribe";
+/// Header requesting one-click unsubscribe, RFC 8058.
+const LIST_UNSUBSCRIBE_POST: &str = "list-unsubscribe-post";
+/// Maximum length of an address, the 256 octet path limit of RFC 5321 section 4.5.3.1.3 less
+/// the surrounding angle brackets.
+const MAX_ADDRESS_LENGTH: usize = 254;
+
+/// Reasons an `EmailMessageBuilder` can not build an `EmailMessage`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ValidationError {
+    /// An address does not match the RFC 5321 `Mailbox` syntax.
+    #[error("InvalidAddress({field}, {address})")]
+    InvalidAddress {
+        /// Name of the `EmailMessage` field containing the address.
+        field: &'static str,
+        /// The address as it was given to the builder.
+        address: String,
+    },
+    /// A field written into a header holds a line break or NUL, which could end the header and
+    /// inject others. Records come from producers which are not trusted to prevent this.
+    #[error("HeaderInjection({0})")]
+    HeaderInjection(&'static str),
+    /// The `EmailId` is empty, longer than 256 bytes, or holds a control character.
+    #[error("InvalidEmailId({0})")]
+    InvalidEmailId(String),
+    /// A custom header can not be written, either because its name is not a valid field name, it
+    /// replaces a header written by the broker, or it requires another header which is missing.
+    #[error("InvalidHeader({0})")]
+    InvalidHeader(String),
+    /// Neither an HTML, TXT, nor Markdown body, nor a template to render them from, was provided.
+    #[error("MissingBody")]
+    MissingBody,
+    /// No TO, CC, BCC, or personalized recipient was provided.
+    #[error("MissingRecipient")]
+    MissingRecipient,
+    /// No FROM address was provided.
+    #[error("MissingSender")]
+    MissingSender,
+    /// The subject was missing or blank.
+    #[error("MissingSubject")]
+    MissingSubject,
+}
+
+/// Build an `EmailMessage` which is known to be sendable.
+///
+/// ```
+/// use email_shared::EmailMessageBuilder;
+///
+/// let email = EmailMessageBuilder::new("Test EmailId")
+///     .sender("from@Example.com")
+///     .to(" to@example.com ")
+///     .subject("Test Subject")
+///     .body_text("Test Body")
+///     .build()
+///     .unwrap();
+/// assert_eq!(email.sender, "from@example.com");
+/// assert_eq!(email.recipients_to, vec!["to@example.com"]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct EmailMessageBuilder {
+    email: EmailMessage,
+}
+
+impl EmailMessageBuilder {
+    /// Start building a `Pending` email identified by `email_id`.
+    pub fn new(email_id: impl Into<EmailId>) -> Self {
+        EmailMessageBuilder {
+            email: EmailMessage {
+                email_id: email_id.into(),
+                status: EmailStatus::Pending,
+                ..EmailMessage::default()
+            },
+        }
+    }
+
+    /// Include `attachment` with the email.
+    pub fn attachment(mut self, attachment: EmailMessageAttachment) -> Self {
+        self.email.attachments.push(attachment);
+        self
+    }
+
+    /// Add a BCC recipient.
+    pub fn bcc(mut self, recipient: impl Into<Recipient>) -> Self {
+        self.email.recipients_bcc.push(recipient.into());
+        self
+    }
+
+    /// Set the HTML body.
+    pub fn body_html(mut self, body: impl Into<String>) -> Self {
+        self.email.body_html = body.into();
+        self
+    }
+
+    /// Set the Markdown body, rendered as the HTML and TXT bodies when neither is set.
+    pub fn body_markdown(mut self, body: impl Into<String>) -> Self {
+        self.email.body_markdown = body.into();
+        self
+    }
+
+    /// Set the TXT body.
+    pub fn body_text(mut self, body: impl Into<String>) -> Self {
+        self.email.body_text = body.into();
+        self
+    }
+
+    /// Set the category used to choose the maximum message age of the email.
+    pub fn category(mut self, category: impl Into<String>) -> Self {
+        self.email.category = Some(category.into());
+        self
+    }
+
+    /// Add a CC recipient.
+    pub fn cc(mut self, recipient: impl Into<Recipient>) -> Self {
+        self.email.recipients_cc.push(recipient.into());
+        self
+    }
+
+    /// Set when the record was created, defaults to the time `build` is called.
+    pub fn created_at(mut self, created_at: impl Into<String>) -> Self {
+        self.email.created_at = created_at.into();
+        self
+    }
+
+    /// Enable the experimental behavior named `flag` for this email.
+    pub fn flag(mut self, flag: impl Into<String>) -> Self {
+        self.email.flags.insert(flag.into());
+        self
+    }
+
+    /// Include the custom header `name` with `value` in the message.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.email.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// Add a recipient who is sent their own copy of the email rendered with their merge fields.
+    pub fn personalized(mut self, recipient: PersonalizedRecipient) -> Self {
+        self.email
+            .personalization
+            .get_or_insert_with(Vec::new)
+            .push(recipient);
+        self
+    }
+
+    /// Add an address replies should be sent to.
+    pub fn reply_to(mut self, address: impl Into<Recipient>) -> Self {
+        self.email.reply_to.push(address.into());
+        self
+    }
+
+    /// Set the FROM address.
+    pub fn sender(mut self, sender: impl Into<Recipient>) -> Self {
+        self.email.sender = sender.into();
+        self
+    }
+
+    /// Set the SUBJECT.
+    pub fn subject(mut self, subject: impl Into<String>) -> Self {
+        self.email.subject = subject.into();
+        self
+    }
+
+    /// Render the bodies from the template identified by `template_id` using `data`.
+    pub fn template(mut self, template_id: impl Into<TemplateId>, data: TemplateData) -> Self {
+        self.email.template_id = Some(template_id.into());
+        self.email.template_data = Some(data);
+        self
+    }
+
+    /// Add a TO recipient.
+    pub fn to(mut self, recipient: impl Into<Recipient>) -> Self {
+        self.email.recipients_to.push(recipient.into());
+        self
+    }
+
+    /// Validate and normalize the email. Every problem found is returned rather than only the
+    /// first so a caller can correct them all at once. `updated_at` is set to the current time.
+    pub fn build(self) -> Result<EmailMessage, Vec<ValidationError>> {
+        let mut email = self.email;
+        let mut errors = Vec::new();
+        if email.email_id.validate().is_err() {
+            errors.push(ValidationError::InvalidEmailId(email.email_id.to_string()));
+        }
+        if email.sender.raw().trim().is_empty() {
+            errors.push(ValidationError::MissingSender);
+        } else {
+            email.sender = normalize_field("Sender", &email.sender, &mut errors);
+        }
+        email.recipients_to = normalize_list("RecipientsTo", email.recipients_to, &mut errors);
+        email.recipients_cc = normalize_list("RecipientsCc", email.recipients_cc, &mut errors);
+        email.recipients_bcc = normalize_list("RecipientsBcc", email.recipients_bcc, &mut errors);
+        email.reply_to = normalize_list("ReplyTo", email.reply_to, &mut errors);
+        validate_headers(&email, &mut errors);
+        for recipient in email.personalization.iter_mut().flatten() {
+            recipient.address = normalize_field("Personalization", &recipient.address, &mut errors);
+        }
+        if email.recipients_to.is_empty()
+            && email.recipients_cc.is_empty()
+            && email.recipients_bcc.is_empty()
+            && email.personalization.iter().flatten().next().is_none()
+        {
+            errors.push(ValidationError::MissingRecipient);
+        }
+        if email.subject.trim().is_empty() {
+            errors.push(ValidationError::MissingSubject);
+        } else if is_injection(&email.subject) {
+            errors.push(ValidationError::HeaderInjection("Subject"));
+        }
+        if !email.has_body() && email.template_id.is_none() {
+            errors.push(ValidationError::MissingBody);
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let now = Utc::now().to_rfc3339();
+        if email.created_at.is_empty() {
+            email.created_at = now.clone();
+        }
+        email.updated_at = now;
+        Ok(email)
+    }
+}
+
+/// Record an error for each custom header of `email` which can not be written.
+fn validate_headers(email: &EmailMessage, errors: &mut Vec<ValidationError>) {
+    for (name, value) in email.headers.iter() {
+        if !is_custom_header_name(name) {
+            errors.push(ValidationError::InvalidHeader(name.clone()));
+        } else if is_injection(value) {
+            errors.push(ValidationError::HeaderInjection("Headers"));
+        }
+    }
+    let has_header = |wanted: &str| {
+        email
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case(wanted))
+    };
+    if has_header(LIST_UNSUBSCRIBE_POST) && !has_header(LIST_UNSUBSCRIBE) {
+        errors.push(ValidationError::InvalidHeader(LIST_UNSUBSCRIBE_POST.into()));
+    }
+}
+
+impl From<EmailMessage> for EmailMessageBuilder {
+    /// Start from an existing `email`, for example to validate a record read from DynamoDB.
+    fn from(email: EmailMessage) -> Self {
+        EmailMessageBuilder { email }
+    }
+}
+
+/// Normalize every address in `addresses`, recording an error for each invalid one.
+fn normalize_list(
+    field: &'static str,
+    addresses: Vec<Recipient>,
+    errors: &mut Vec<ValidationError>,
+) -> Vec<Recipient> {
+    addresses
+        .iter()
+        .map(|address| normalize_field(field, address, errors))
+        .collect()
+}
+
+/// Normalize `address`, recording an error if it is invalid.
+fn normalize_field(
+    field: &'static str,
+    address: &Recipient,
+    errors: &mut Vec<ValidationError>,
+) -> Recipient {
+    if is_injection(address.raw()) {
+        errors.push(ValidationError::HeaderInjection(field));
+        return address.clone();
+    }
+    Recipient::parse(address.raw()).unwrap_or_else(|_| {
+        errors.push(ValidationError::InvalidAddress {
+            field,
+            address: address.raw().to_owned(),
+        });
+        address.clone()
+    })
+}
+
+/// Whether `value` holds a character which could end a header line when written into one.
+fn is_injection(value: &str) -> bool {
+    value.contains(['\r', '\n', '\0'])
+}
+
+/// Fields of `email` written into headers which would inject headers, for records which were not
+/// built by an `EmailMessageBuilder`.
+pub(crate) fn header_injections(email: &EmailMessage) -> Vec<&'static str> {
+    let addresses =
+        |addresses: &[Recipient]| addresses.iter().any(|address| is_injection(address.raw()));
+    let mut fields = Vec::new();
+    if is_injection(email.sender.raw()) {
+        fields.push("Sender");
+    }
+    if addresses(&email.recipients_to) {
+        fields.push("RecipientsTo");
+    }
+    if addresses(&email.recipients_cc) {
+        fields.push("RecipientsCc");
+    }
+    if addresses(&email.recipients_bcc) {
+        fields.push("RecipientsBcc");
+    }
+    if addresses(&email.reply_to) {
+        fields.push("ReplyTo");
+    }
+    if email
+        .personalization
+        .iter()
+        .flatten()
+        .any(|recipient| is_injection(recipient.address.raw()))
+    {
+        fields.push("Personalization");
+    }
+    if is_injection(&email.subject) {
+        fields.push("Subject");
+    }
+    if email.headers.values().any(|value| is_injection(value)) {
+        fields.push("Headers");
+    }
+    fields
+}
+
+/// Trim surrounding whitespace and lower case the domain of `address` if it is a valid RFC 5321
+/// `Mailbox`. The local-part is case sensitive so it is left as is. An internationalized domain
+/// is converted to its ASCII form, RFC 5890.
+pub fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim();
+    let at = address.rfind('@')?;
+    let local_part = &address[..at];
+    let domain = match &address[at + 1..] {
+        domain if domain.is_ascii() => domain.to_owned(),
+        domain => idna::domain_to_ascii(domain).ok()?,
+    };
+    let domain = domain.as_str();
+    let address = format!("{}@{}", local_part, domain);
+    let valid = address.len() <= MAX_ADDRESS_LENGTH
+        && local_part.len() <= MAX_LOCAL_PART_LENGTH
+        && domain.len() <= MAX_DOMAIN_LENGTH
+        && is_local_part(local_part);
+    if valid && is_domain(domain) {
+        Some(format!("{}@{}", local_part, domain.to_ascii_lowercase()))
+    } else if valid && is_address_literal(domain) {
+        Some(format!("{}@{}", local_part, domain))
+    } else {
+        None
+    }
+}
+
+/// `Local-part = Dot-string / Quoted-string`
+fn is_local_part(local_part: &str) -> bool {
+    is_dot_string(local_part) || is_quoted_string(local_part)
+}
+
+/// `Dot-string = Atom *("." Atom)`
+fn is_dot_string(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// `atext` from RFC 5322 section 3.2.3.
+pub(crate) fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+/// `Quoted-string = DQUOTE *QcontentSMTP DQUOTE`
+fn is_quoted_string(s: &str) -> bool {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return false;
+    }
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // quoted-pairSMTP
+            '\\' => match chars.next() {
+                Some(' '..='~') => {}
+                _ => return false,
+            },
+            // qtextSMTP
+            ' ' | '!' | '#'..='[' | ']'..='~' => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// `Domain = sub-domain *("." sub-domain)`
+fn is_domain(domain: &str) -> bool {
+    !domain.is_empty() && domain.split('.').all(is_sub_domain)
+}
+
+/// `sub-domain = Let-dig [Ldh-str]`
+fn is_sub_domain(label: &str) -> bool {
+    !label.is_empty()
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// `address-literal = "[" ( IPv4-address-literal / IPv6-address-literal ) "]"`
+fn is_address_literal(domain: &str) -> bool {
+    if !domain.starts_with('[') || !domain.ends_with(']') || domain.len() < 2 {
+        return false;
+    }
+    let literal = &domain[1..domain.len() - 1];
+    match literal.strip_prefix("IPv6:") {
+        Some(ipv6) => ipv6.parse::<Ipv6Addr>().is_ok(),
+        None => literal.parse::<Ipv4Addr>().is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod normalize_address {
+    use super::*;
+
+    #[test]
+    fn accepts_dot_string() {
+        assert_eq!(
+            normalize_address("first.last+tag@example.com"),
+            Some("first.last+tag@example.com".into())
+        );
+    }
+
+    #[test]
+    fn lower_cases_domain_only() {
+        assert_eq!(
+            normalize_address("  First.Last@Example.COM\n"),
+            Some("First.Last@example.com".into())
+        );
+    }
+
+    #[test]
+    fn accepts_quoted_string() {
+        assert_eq!(
+            normalize_address(r#""first last"@example.com"#),
+            Some(r#""first last"@example.com"#.into())
+        );
+    }
+
+    #[test]
+    fn accepts_address_literals() {
+        assert!(normalize_address("user@[192.0.2.1]").is_some());
+        assert_eq!(
+            normalize_address("user@[IPv6:2001:db8::1]"),
+            Some("user@[IPv6:2001:db8::1]".into())
+        );
+        assert!(normalize_address("user@[999.0.2.1]").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let invalid = [
+            "",
+            "example.com",
+            "@example.com",
+            "user@",
+            "user..name@example.com",
+            ".user@example.com",
+            "user name@example.com",
+            "user@-example.com",
+            "user@example..com",
+            "user@exa_mple.com",
+        ];
+        for address in invalid.iter() {
+            assert_eq!(normalize_address(address), None, "{}", address);
+        }
+    }
+
+    #[test]
+    fn rejects_long_local_part() {
+        let address = format!("{}@example.com", "a".repeat(MAX_LOCAL_PART_LENGTH + 1));
+        assert_eq!(normalize_address(&address), None);
+    }
+}
+
+#[cfg(test)]
+mod build {
+    use super::*;
+
+    #[test]
+    fn builds_valid_email() {
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .cc("cc@EXAMPLE.com")
+            .subject("Test Subject")
+            .body_html("<p>Test Body</p>")
+            .created_at("2021-03-24T00:00:00Z")
+            .build()
+            .unwrap();
+        assert_eq!(email.email_id, "Test EmailId");
+        assert_eq!(email.status, EmailStatus::Pending);
+        assert_eq!(email.recipients_cc, vec!["cc@example.com"]);
+        assert_eq!(email.created_at, "2021-03-24T00:00:00Z");
+        assert!(!email.updated_at.is_empty());
+    }
+
+    #[test]
+    fn fills_created_at() {
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .bcc("bcc@example.com")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build()
+            .unwrap();
+        assert_eq!(email.created_at, email.updated_at);
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let errors = EmailMessageBuilder::new("Test EmailId")
+            .to("not an address")
+            .subject("  ")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::MissingSender,
+                ValidationError::InvalidAddress {
+                    field: "RecipientsTo",
+                    address: "not an address".into(),
+                },
+                ValidationError::MissingSubject,
+                ValidationError::MissingBody,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_email_id() {
+        let errors = EmailMessageBuilder::new("Test\r\nEmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![ValidationError::InvalidEmailId("Test\r\nEmailId".into())]
+        );
+    }
+
+    #[test]
+    fn accepts_template_in_place_of_body() {
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .subject("Test Subject")
+            .template("Test TemplateId", TemplateData::new())
+            .build()
+            .unwrap();
+        assert_eq!(email.template_id, Some("Test TemplateId".into()));
+        assert_eq!(email.body_html, "");
+    }
+
+    #[test]
+    fn accepts_personalized_recipients() {
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .subject("Test Subject")
+            .template("Test TemplateId", TemplateData::new())
+            .personalized(PersonalizedRecipient {
+                address: "to@EXAMPLE.com".into(),
+                ..PersonalizedRecipient::default()
+            })
+            .build()
+            .unwrap();
+        let personalization = email.personalization.unwrap();
+        assert_eq!(personalization[0].address, "to@example.com");
+    }
+
+    #[test]
+    fn requires_a_recipient() {
+        let errors = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build()
+            .unwrap_err();
+        assert_eq!(errors, vec![ValidationError::MissingRecipient]);
+    }
+
+    #[test]
+    fn validates_headers_and_reply_to() {
+        let result = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .reply_to("not an address")
+            .header("Content-Type", "text/plain")
+            .header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            vec![
+                ValidationError::InvalidAddress {
+                    field: "ReplyTo",
+                    address: "not an address".into(),
+                },
+                ValidationError::InvalidHeader("Content-Type".into()),
+                ValidationError::InvalidHeader("list-unsubscribe-post".into()),
+            ]
+        );
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .reply_to("Reply@Example.com")
+            .header("List-Unsubscribe", "<https://example.com/unsubscribe>")
+            .header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build()
+            .unwrap();
+        assert_eq!(email.reply_to, vec!["Reply@example.com"]);
+        assert_eq!(email.headers.len(), 2);
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        let errors = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com\r\nBcc: victim@example.com")
+            .to("to@example.com")
+            .cc("cc@example.com\nX-Injected: 1")
+            .header("X-Campaign", "spring\r\nBcc: victim@example.com")
+            .subject("Test Subject\r\nBcc: victim@example.com")
+            .body_text("Test Body")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::HeaderInjection("Sender"),
+                ValidationError::HeaderInjection("RecipientsCc"),
+                ValidationError::HeaderInjection("Headers"),
+                ValidationError::HeaderInjection("Subject"),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod header_injections {
+    use super::*;
+
+    #[test]
+    fn names_each_injected_field() {
+        let email = EmailMessage {
+            recipients_bcc: vec!["bcc@example.com\0".into()],
+            sender: "from@example.com".into(),
+            subject: "Test Subject\nBcc: victim@example.com".into(),
+            ..EmailMessage::default()
+        };
+        assert_eq!(header_injections(&email), vec!["RecipientsBcc", "Subject"]);
+        assert!(header_injections(&EmailMessage::default()).is_empty());
+    }
+}
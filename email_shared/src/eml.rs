@@ -0,0 +1,244 @@
+use crate::email_message::EmailMessageAttachment;
+use crate::producer::EmailMessageDraft;
+use mail_parser::{Addr, Address, MessageParser, MimeHeaders, PartType};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+/// Headers carried over from an imported message. Any other header is either written by the
+/// broker when the email is sent or only describes the original delivery, such as `Received` or
+/// a `DKIM-Signature` which would no longer verify.
+const IMPORTED_HEADERS: [&str; 2] = ["List-Unsubscribe", "List-Unsubscribe-Post"];
+/// Name given to an attachment which has none.
+const DEFAULT_ATTACHMENT_NAME: &str = "attachment";
+
+/// Possible errors while reading an `.eml` file.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum EmlError {
+    /// The contents are not an RFC 5322 message.
+    #[error("InvalidMessage")]
+    InvalidMessage,
+}
+
+impl EmailMessageDraft {
+    /// Read the RFC 5322 message `raw`, such as the contents of an `.eml` file exported from
+    /// another system, as a draft to enqueue. The subject, sender, `To`, `Cc`, `Bcc`, and
+    /// `Reply-To` addresses, HTML and TXT bodies, attachments, and `List-Unsubscribe` headers are
+    /// kept. The idempotency key is taken from the `Message-ID`, or the contents when there is
+    /// none, so importing the same message twice enqueues it once.
+    ///
+    /// ```
+    /// use email_shared::EmailMessageDraft;
+    ///
+    /// let raw = b"From: \"Doe, Jane\" <jane@example.com>\r\n\
+    ///     To: to@example.com\r\n\
+    ///     Subject: Greetings\r\n\
+    ///     Message-ID: <1@example.com>\r\n\
+    ///     \r\n\
+    ///     Hello\r\n";
+    /// let draft = EmailMessageDraft::from_eml(raw).unwrap();
+    /// assert_eq!(draft.sender, "\"Doe, Jane\" <jane@example.com>");
+    /// assert_eq!(draft.recipients_to, vec!["to@example.com"]);
+    /// assert_eq!(draft.subject, "Greetings");
+    /// assert_eq!(draft.body_text, "Hello\r\n");
+    /// assert_eq!(draft.idempotency_key.as_deref(), Some("eml:1@example.com"));
+    /// ```
+    pub fn from_eml(raw: &[u8]) -> Result<Self, EmlError> {
+        let message = MessageParser::default()
+            .parse(raw)
+            .filter(|message| !message.headers().is_empty())
+            .ok_or(EmlError::InvalidMessage)?;
+        // A message without an HTML part lists its TXT part among the HTML bodies, and the other
+        // way round, so only parts of the matching type are taken
+        let body_html = message
+            .html_bodies()
+            .find_map(|part| match &part.body {
+                PartType::Html(html) => Some(html.to_string()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let body_text = message
+            .text_bodies()
+            .find_map(|part| match &part.body {
+                PartType::Text(text) => Some(text.to_string()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let attachments = message
+            .attachments()
+            .filter_map(|part| {
+                let content_type =
+                    part.content_type()
+                        .map(|content_type| match content_type.subtype() {
+                            Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                            None => content_type.ctype().to_owned(),
+                        });
+                let body = match &part.body {
+                    PartType::Binary(body) | PartType::InlineBinary(body) => body.as_ref(),
+                    PartType::Text(text) | PartType::Html(text) => text.as_bytes(),
+                    // Forwarded messages are attached as they were received
+                    PartType::Message(forwarded) => forwarded.raw_message(),
+                    PartType::Multipart(_) => return None,
+                };
+                Some(EmailMessageAttachment::inline(
+                    part.attachment_name().unwrap_or(DEFAULT_ATTACHMENT_NAME),
+                    content_type.unwrap_or_else(|| "application/octet-stream".into()),
+                    body,
+                ))
+            })
+            .collect();
+        let headers = message
+            .headers_raw()
+            .filter(|(name, _)| {
+                IMPORTED_HEADERS
+                    .iter()
+                    .any(|imported| imported.eq_ignore_ascii_case(name))
+            })
+            .map(|(name, value)| (name.to_owned(), unfold(value)))
+            .collect();
+        let idempotency_key = match message.message_id() {
+            Some(message_id) => format!("eml:{}", message_id),
+            None => {
+                let digest = Sha1::digest(raw);
+                let hex = digest.iter().map(|b| format!("{:02x}", b));
+                format!("eml:{}", hex.collect::<String>())
+            }
+        };
+        Ok(EmailMessageDraft {
+            attachments,
+            body_html,
+            body_text,
+            headers,
+            idempotency_key: Some(idempotency_key),
+            recipients_bcc: mailboxes(message.bcc()),
+            recipients_cc: mailboxes(message.cc()),
+            recipients_to: mailboxes(message.to()),
+            reply_to: mailboxes(message.reply_to()),
+            sender: mailboxes(message.from())
+                .into_iter()
+                .next()
+                .unwrap_or_default(),
+            subject: message.subject().unwrap_or_default().to_owned(),
+            ..EmailMessageDraft::default()
+        })
+    }
+}
+
+/// Every mailbox of `address`, including those within groups, in the form `Recipient::parse`
+/// reads.
+fn mailboxes(address: Option<&Address>) -> Vec<String> {
+    address
+        .map(|address| address.iter().filter_map(mailbox).collect())
+        .unwrap_or_default()
+}
+
+/// `addr` as a bare address, or with its display name quoted when it has one.
+fn mailbox(addr: &Addr) -> Option<String> {
+    let address = addr.address()?.trim();
+    let name = addr
+        .name()
+        .map(|name| {
+            name.chars()
+                .filter(|c| !c.is_control() && *c != '<' && *c != '>')
+                .collect::<String>()
+        })
+        .filter(|name| !name.trim().is_empty());
+    Some(match name {
+        Some(name) => format!(
+            "\"{}\" <{}>",
+            name.trim().replace('\\', "\\\\").replace('"', "\\\""),
+            address
+        ),
+        None => address.to_owned(),
+    })
+}
+
+/// `value` of a header with its folding line breaks removed.
+fn unfold(value: &str) -> String {
+    value
+        .split(['\r', '\n'])
+        .filter(|line| !line.is_empty())
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod from_eml {
+    use super::*;
+
+    #[test]
+    fn reads_multipart_message() {
+        let raw = b"From: from@example.com\r\n\
+            To: \"Doe, Jane\" <jane@example.com>, john@example.com\r\n\
+            Cc: Team: cc@example.com;\r\n\
+            Reply-To: replies@example.com\r\n\
+            Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n\
+            List-Unsubscribe: <https://example.com/u>\r\n\
+            DKIM-Signature: v=1; d=example.com\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+            \r\n\
+            --outer\r\n\
+            Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+            \r\n\
+            --inner\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            Hi there\r\n\
+            --inner\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            <p style=3D\"color: red\">Hi there</p>\r\n\
+            --inner--\r\n\
+            --outer\r\n\
+            Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+            Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            JVBERi0=\r\n\
+            --outer--\r\n";
+        let draft = EmailMessageDraft::from_eml(raw).unwrap();
+        assert_eq!(draft.sender, "from@example.com");
+        assert_eq!(
+            draft.recipients_to,
+            vec!["\"Doe, Jane\" <jane@example.com>", "john@example.com"]
+        );
+        assert_eq!(draft.recipients_cc, vec!["cc@example.com"]);
+        assert_eq!(draft.reply_to, vec!["replies@example.com"]);
+        assert_eq!(draft.subject, "Grüße");
+        assert_eq!(draft.body_text, "Hi there");
+        assert_eq!(draft.body_html, "<p style=\"color: red\">Hi there</p>");
+        assert_eq!(
+            draft.headers,
+            vec![(
+                "List-Unsubscribe".to_owned(),
+                "<https://example.com/u>".to_owned()
+            )]
+        );
+        assert_eq!(draft.attachments.len(), 1);
+        assert_eq!(draft.attachments[0].name, "report.pdf");
+        assert_eq!(draft.attachments[0].content_type, "application/pdf");
+        assert_eq!(draft.attachments[0].body, "JVBERi0=");
+        let email = draft.into_email().unwrap();
+        assert_eq!(email.recipients_to.len(), 2);
+    }
+
+    #[test]
+    fn keys_message_without_id_by_contents() {
+        let raw = b"From: from@example.com\r\nTo: to@example.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let draft = EmailMessageDraft::from_eml(raw).unwrap();
+        let again = EmailMessageDraft::from_eml(raw).unwrap();
+        assert!(draft.body_html.is_empty());
+        assert!(draft.idempotency_key.is_some());
+        assert_eq!(draft.idempotency_key, again.idempotency_key);
+    }
+
+    #[test]
+    fn rejects_empty_file() {
+        assert_eq!(
+            EmailMessageDraft::from_eml(b"").unwrap_err(),
+            EmlError::InvalidMessage
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use aws_config::SdkConfig;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Reasons a string can not be read as an `Endpoint`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum EndpointError {
+    #[error("InvalidEndpoint({0})")]
+    InvalidEndpoint(String),
+}
+
+/// URL of a single service used in place of the endpoint resolved for the region, so a local
+/// emulator of one service can be combined with the real instances of the others. The URL must
+/// name its scheme, and gives the host along with the port when it is not the default.
+///
+/// ```
+/// use email_shared::Endpoint;
+///
+/// let endpoint = "http://localhost:4566".parse::<Endpoint>().unwrap();
+/// assert_eq!(endpoint.as_str(), "http://localhost:4566");
+/// assert!("localhost:4566".parse::<Endpoint>().is_err());
+/// assert!("http://localhost:4566/emails".parse::<Endpoint>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Endpoint {
+    /// The URL as given, without a trailing slash.
+    url: String,
+}
+
+impl Endpoint {
+    /// The URL as given, without a trailing slash.
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = s.trim_end_matches('/');
+        let authority = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"));
+        match authority {
+            Some(authority) if !authority.is_empty() && !authority.contains(['/', '?', '#']) => {
+                Ok(Endpoint {
+                    url: url.to_owned(),
+                })
+            }
+            _ => Err(EndpointError::InvalidEndpoint(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+/// Configuration of DynamoDB clients made from `sdk_config`, sending requests to `endpoint`
+/// instead of the shared endpoint when one is given.
+pub fn dynamodb_config(
+    sdk_config: &SdkConfig,
+    endpoint: Option<&Endpoint>,
+) -> aws_sdk_dynamodb::config::Builder {
+    let builder = aws_sdk_dynamodb::config::Builder::from(sdk_config);
+    match endpoint {
+        Some(endpoint) => builder.endpoint_url(endpoint.as_str()),
+        None => builder,
+    }
+}
+
+/// Configuration of SQS clients made from `sdk_config`, sending requests to `endpoint` instead of
+/// the shared endpoint when one is given.
+pub fn sqs_config(
+    sdk_config: &SdkConfig,
+    endpoint: Option<&Endpoint>,
+) -> aws_sdk_sqs::config::Builder {
+    let builder = aws_sdk_sqs::config::Builder::from(sdk_config);
+    match endpoint {
+        Some(endpoint) => builder.endpoint_url(endpoint.as_str()),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn trims_trailing_slash() {
+        let endpoint = "https://dynamodb.us-east-1.amazonaws.com/"
+            .parse::<Endpoint>()
+            .unwrap();
+        assert_eq!(
+            endpoint.as_str(),
+            "https://dynamodb.us-east-1.amazonaws.com"
+        );
+    }
+
+    #[test]
+    fn rejects_urls_without_scheme_or_host() {
+        for endpoint in &[
+            "localhost",
+            "http://",
+            "ftp://localhost:21",
+            "http://host?x=1",
+        ] {
+            assert_eq!(
+                endpoint.parse::<Endpoint>(),
+                Err(EndpointError::InvalidEndpoint(endpoint.to_string()))
+            );
+        }
+    }
+}
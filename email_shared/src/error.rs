@@ -1,7 +1,15 @@
-use crate::queue::EmailPointerMessage;
-use rusoto_core::RusotoError;
-use rusoto_dynamodb::{GetItemError, UpdateItemError};
-use rusoto_sqs::Message;
+use crate::email_message::{EmailId, EmailStatus};
+use crate::email_message_builder::ValidationError;
+use crate::queue::{EmailPointerMessage, PointerError};
+use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::batch_get_item::BatchGetItemError;
+use aws_sdk_dynamodb::operation::get_item::GetItemError;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::operation::query::QueryError;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_sqs::types::Message;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Possible errors from updating an item in DynamoDB.
@@ -9,6 +17,9 @@ use thiserror::Error;
 pub enum UpdateError {
     #[error("ConditionalCheckFailed({0})")]
     ConditionalCheckFailed(String),
+    /// The `StatusMachine` does not allow the status change, DynamoDB was not called.
+    #[error("IllegalTransition({0})")]
+    IllegalTransition(String),
     #[error("InternalServerError({0})")]
     InternalServerError(String),
     #[error("ItemCollectionSizeLimitExceeded({0})")]
@@ -19,35 +30,190 @@ pub enum UpdateError {
     RequestLimitExceeded(String),
     #[error("ResourceNotFound({0})")]
     ResourceNotFound(String),
-    #[error("RusotoError({0})")]
+    #[error("SerializeError({0})")]
+    SerializeError(String),
+    #[error("SdkError({0})")]
     ServiceError(String),
     #[error("TransactionConflict({0})")]
     TransactionConflict(String),
+    /// The record was updated by another writer since it was read.
+    #[error("VersionConflict({0})")]
+    VersionConflict(String),
+}
+
+impl UpdateError {
+    /// Whether DynamoDB refused the update for exceeding the capacity of the table or account,
+    /// so the same update may succeed moments later.
+    pub fn is_throttled(&self) -> bool {
+        matches!(
+            self,
+            Self::ProvisionedThroughputExceeded(_) | Self::RequestLimitExceeded(_)
+        )
+    }
+}
+
+/// Get the message attached to a service error, falling back to the error's `Display` output when
+/// the service did not provide one.
+fn error_message<E: ProvideErrorMetadata + std::fmt::Display>(error: &E) -> String {
+    error
+        .message()
+        .map(String::from)
+        .unwrap_or_else(|| error.to_string())
 }
 
 impl From<UpdateItemError> for UpdateError {
     fn from(error: UpdateItemError) -> Self {
+        let msg = error_message(&error);
+        match error {
+            UpdateItemError::ConditionalCheckFailedException(_) => {
+                Self::ConditionalCheckFailed(msg)
+            }
+            UpdateItemError::InternalServerError(_) => Self::InternalServerError(msg),
+            UpdateItemError::ItemCollectionSizeLimitExceededException(_) => {
+                Self::ItemCollectionSizeLimitExceeded(msg)
+            }
+            UpdateItemError::ProvisionedThroughputExceededException(_) => {
+                Self::ProvisionedThroughputExceeded(msg)
+            }
+            UpdateItemError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            UpdateItemError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            UpdateItemError::TransactionConflictException(_) => Self::TransactionConflict(msg),
+            _ => Self::ServiceError(msg),
+        }
+    }
+}
+
+impl From<SdkError<UpdateItemError>> for UpdateError {
+    fn from(error: SdkError<UpdateItemError>) -> Self {
+        match error {
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
+        }
+    }
+}
+
+impl From<TransactWriteItemsError> for UpdateError {
+    fn from(error: TransactWriteItemsError) -> Self {
+        let msg = error_message(&error);
+        match error {
+            // A failed condition on any item cancels the whole transaction
+            TransactWriteItemsError::TransactionCanceledException(ref canceled)
+                if canceled
+                    .cancellation_reasons()
+                    .iter()
+                    .any(|reason| reason.code() == Some("ConditionalCheckFailed")) =>
+            {
+                Self::ConditionalCheckFailed(msg)
+            }
+            TransactWriteItemsError::TransactionCanceledException(_)
+            | TransactWriteItemsError::TransactionInProgressException(_) => {
+                Self::TransactionConflict(msg)
+            }
+            TransactWriteItemsError::InternalServerError(_) => Self::InternalServerError(msg),
+            TransactWriteItemsError::ProvisionedThroughputExceededException(_) => {
+                Self::ProvisionedThroughputExceeded(msg)
+            }
+            TransactWriteItemsError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            TransactWriteItemsError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            _ => Self::ServiceError(msg),
+        }
+    }
+}
+
+impl From<SdkError<TransactWriteItemsError>> for UpdateError {
+    fn from(error: SdkError<TransactWriteItemsError>) -> Self {
+        match error {
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
+        }
+    }
+}
+
+/// Possible errors from creating an item in DynamoDB.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum PutError {
+    #[error("ConditionalCheckFailed({0})")]
+    ConditionalCheckFailed(String),
+    #[error("InternalServerError({0})")]
+    InternalServerError(String),
+    #[error("ItemCollectionSizeLimitExceeded({0})")]
+    ItemCollectionSizeLimitExceeded(String),
+    #[error("ProvisionedThroughputExceeded({0})")]
+    ProvisionedThroughputExceeded(String),
+    #[error("RequestLimitExceeded({0})")]
+    RequestLimitExceeded(String),
+    #[error("ResourceNotFound({0})")]
+    ResourceNotFound(String),
+    #[error("SerializeError({0})")]
+    SerializeError(String),
+    #[error("SdkError({0})")]
+    ServiceError(String),
+    #[error("TransactionConflict({0})")]
+    TransactionConflict(String),
+}
+
+impl From<PutItemError> for PutError {
+    fn from(error: PutItemError) -> Self {
+        let msg = error_message(&error);
         match error {
-            UpdateItemError::ConditionalCheckFailed(msg) => Self::ConditionalCheckFailed(msg),
-            UpdateItemError::InternalServerError(msg) => Self::InternalServerError(msg),
-            UpdateItemError::ItemCollectionSizeLimitExceeded(msg) => {
+            PutItemError::ConditionalCheckFailedException(_) => Self::ConditionalCheckFailed(msg),
+            PutItemError::InternalServerError(_) => Self::InternalServerError(msg),
+            PutItemError::ItemCollectionSizeLimitExceededException(_) => {
                 Self::ItemCollectionSizeLimitExceeded(msg)
             }
-            UpdateItemError::ProvisionedThroughputExceeded(msg) => {
+            PutItemError::ProvisionedThroughputExceededException(_) => {
+                Self::ProvisionedThroughputExceeded(msg)
+            }
+            PutItemError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            PutItemError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            PutItemError::TransactionConflictException(_) => Self::TransactionConflict(msg),
+            _ => Self::ServiceError(msg),
+        }
+    }
+}
+
+impl From<SdkError<PutItemError>> for PutError {
+    fn from(error: SdkError<PutItemError>) -> Self {
+        match error {
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
+        }
+    }
+}
+
+impl From<TransactWriteItemsError> for PutError {
+    fn from(error: TransactWriteItemsError) -> Self {
+        let msg = error_message(&error);
+        match error {
+            // A failed condition on any item cancels the whole transaction
+            TransactWriteItemsError::TransactionCanceledException(ref canceled)
+                if canceled
+                    .cancellation_reasons()
+                    .iter()
+                    .any(|reason| reason.code() == Some("ConditionalCheckFailed")) =>
+            {
+                Self::ConditionalCheckFailed(msg)
+            }
+            TransactWriteItemsError::TransactionCanceledException(_)
+            | TransactWriteItemsError::TransactionInProgressException(_) => {
+                Self::TransactionConflict(msg)
+            }
+            TransactWriteItemsError::InternalServerError(_) => Self::InternalServerError(msg),
+            TransactWriteItemsError::ProvisionedThroughputExceededException(_) => {
                 Self::ProvisionedThroughputExceeded(msg)
             }
-            UpdateItemError::RequestLimitExceeded(msg) => Self::RequestLimitExceeded(msg),
-            UpdateItemError::ResourceNotFound(msg) => Self::ResourceNotFound(msg),
-            UpdateItemError::TransactionConflict(msg) => Self::TransactionConflict(msg),
+            TransactWriteItemsError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            TransactWriteItemsError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            _ => Self::ServiceError(msg),
         }
     }
 }
 
-impl From<RusotoError<UpdateItemError>> for UpdateError {
-    fn from(error: RusotoError<UpdateItemError>) -> Self {
+impl From<SdkError<TransactWriteItemsError>> for PutError {
+    fn from(error: SdkError<TransactWriteItemsError>) -> Self {
         match error {
-            RusotoError::Service(service_error) => Self::from(service_error),
-            rusoto_error => Self::ServiceError(format!("{}", rusoto_error)),
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
         }
     }
 }
@@ -69,45 +235,169 @@ pub enum GetError {
     RequestLimitExceeded(String),
     #[error("ResourceNotFound({0})")]
     ResourceNotFound(String),
-    #[error("RusotoError({0})")]
+    #[error("SdkError({0})")]
     ServiceError(String),
+    #[error("UnknownTenant({0})")]
+    UnknownTenant(String),
+}
+
+impl GetError {
+    /// Whether DynamoDB refused the read for exceeding the capacity of the table or account, so
+    /// the same read may succeed moments later.
+    pub fn is_throttled(&self) -> bool {
+        matches!(
+            self,
+            Self::ProvisionedThroughputExceeded(_) | Self::RequestLimitExceeded(_)
+        )
+    }
 }
 
 impl From<GetItemError> for GetError {
     fn from(error: GetItemError) -> Self {
+        let msg = error_message(&error);
         match error {
-            GetItemError::InternalServerError(msg) => Self::InternalServerError(msg),
-            GetItemError::ProvisionedThroughputExceeded(msg) => {
+            GetItemError::InternalServerError(_) => Self::InternalServerError(msg),
+            GetItemError::ProvisionedThroughputExceededException(_) => {
                 Self::ProvisionedThroughputExceeded(msg)
             }
-            GetItemError::RequestLimitExceeded(msg) => Self::RequestLimitExceeded(msg),
-            GetItemError::ResourceNotFound(msg) => Self::ResourceNotFound(msg),
+            GetItemError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            GetItemError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            _ => Self::ServiceError(msg),
         }
     }
 }
 
-impl From<RusotoError<GetItemError>> for GetError {
-    fn from(error: RusotoError<GetItemError>) -> Self {
+impl From<SdkError<GetItemError>> for GetError {
+    fn from(error: SdkError<GetItemError>) -> Self {
         match error {
-            RusotoError::Service(service_error) => Self::from(service_error),
-            rusoto_error => Self::ServiceError(format!("{}", rusoto_error)),
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
         }
     }
 }
 
+impl From<BatchGetItemError> for GetError {
+    fn from(error: BatchGetItemError) -> Self {
+        let msg = error_message(&error);
+        match error {
+            BatchGetItemError::InternalServerError(_) => Self::InternalServerError(msg),
+            BatchGetItemError::ProvisionedThroughputExceededException(_) => {
+                Self::ProvisionedThroughputExceeded(msg)
+            }
+            BatchGetItemError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            BatchGetItemError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            _ => Self::ServiceError(msg),
+        }
+    }
+}
+
+impl From<SdkError<BatchGetItemError>> for GetError {
+    fn from(error: SdkError<BatchGetItemError>) -> Self {
+        match error {
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
+        }
+    }
+}
+
+impl From<QueryError> for GetError {
+    fn from(error: QueryError) -> Self {
+        let msg = error_message(&error);
+        match error {
+            QueryError::InternalServerError(_) => Self::InternalServerError(msg),
+            QueryError::ProvisionedThroughputExceededException(_) => {
+                Self::ProvisionedThroughputExceeded(msg)
+            }
+            QueryError::RequestLimitExceeded(_) => Self::RequestLimitExceeded(msg),
+            QueryError::ResourceNotFoundException(_) => Self::ResourceNotFound(msg),
+            _ => Self::ServiceError(msg),
+        }
+    }
+}
+
+impl From<SdkError<QueryError>> for GetError {
+    fn from(error: SdkError<QueryError>) -> Self {
+        match error {
+            SdkError::ServiceError(context) => Self::from(context.into_err()),
+            sdk_error => Self::ServiceError(format!("{}", DisplayErrorContext(&sdk_error))),
+        }
+    }
+}
+
+/// Possible errors while creating an email record and sending the `EmailPointer` for it.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum EnqueueError {
+    /// The email was not valid, nothing was written or sent.
+    #[error("Invalid({0:?})")]
+    Invalid(Vec<ValidationError>),
+    /// The email record could not be written to DynamoDB, no pointer message was sent.
+    #[error("PutError({0})")]
+    PutError(#[from] PutError),
+    /// The email record to requeue, or the existing record of a duplicate request, could not be
+    /// read, no pointer message was sent.
+    #[error("GetError({0})")]
+    GetError(#[from] GetError),
+    /// The email record to requeue could not be returned to `EmailStatus::Pending`, no pointer
+    /// message was sent.
+    #[error("UpdateError({0})")]
+    UpdateError(#[from] UpdateError),
+    /// The email record was written as `EmailStatus::Pending` but the pointer message could not be
+    /// sent to SQS. Sending a pointer for `email_id` again will allow the email to be transmitted.
+    #[error("SendMessageError({email_id}, {message})")]
+    SendMessageError { email_id: EmailId, message: String },
+}
+
+/// Possible errors while cancelling an email.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum CancelError {
+    /// The email record to cancel could not be read.
+    #[error("GetError({0})")]
+    GetError(#[from] GetError),
+    /// The email record could not be set `EmailStatus::Cancelled`, for example because it is no
+    /// longer `EmailStatus::Pending`.
+    #[error("UpdateError({0})")]
+    UpdateError(#[from] UpdateError),
+}
+
+/// Possible errors while sending an email directly rather than from a queue, as a canary or from
+/// the command line.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum DirectSendError {
+    /// The email was not valid, nothing was written or sent.
+    #[error("Invalid({0:?})")]
+    Invalid(Vec<ValidationError>),
+    /// The email record could not be written to DynamoDB.
+    #[error("PutError({0})")]
+    PutError(#[from] PutError),
+    /// Processing or sending the email did not complete.
+    #[error("ProcessError({0})")]
+    ProcessError(String),
+    /// The email record could not be read back after processing.
+    #[error("GetError({0})")]
+    GetError(#[from] GetError),
+    /// The email was processed but its record does not have `EmailStatus::Sent`.
+    #[error("UnexpectedStatus({0})")]
+    UnexpectedStatus(EmailStatus),
+}
+
 /// Possible errors processing an SQS `Message` as an `EmailIdMessage`.
 #[derive(Clone, Debug, Error)]
 pub enum ProcessError {
     /// Indicates some necessary operation could not be completed due to a temporary condition the
-    /// processing the `Message` should be attempted again.
-    #[error("Retry")]
-    Retry,
+    /// processing the `Message` should be attempted again, along with a description of the
+    /// condition.
+    #[error("Retry({0}, {1})")]
+    Retry(EmailPointerMessage, String),
     /// Indicates processing has skipped sending the email associated with `EmailPointerMessage`
     /// and the `Message` should not be reprocessed later.
     #[error("Skip({0})")]
     Skip(EmailPointerMessage),
+    /// Indicates the email associated with `EmailPointerMessage` is scheduled to be sent later and
+    /// the `Message` should be delivered again once the `Duration` has passed.
+    #[error("Defer({0}, {1:?})")]
+    Defer(EmailPointerMessage, Duration),
     /// Processing result indicating the SQS `Message` must be skipped and can not be handled. This
     /// is not a temporary or ephemeral error. Reprocessing the `Message` will also fail.
-    #[error("SkipMessage({0:?})")]
-    SkipMessage(Message),
+    #[error("SkipMessage({0:?}, {1})")]
+    SkipMessage(Message, PointerError),
 }
@@ -0,0 +1,215 @@
+use crate::email_message::{EmailId, EmailMessage, EmailStatus};
+use aws_sdk_eventbridge::error::DisplayErrorContext;
+use aws_sdk_eventbridge::types::PutEventsRequestEntry;
+use aws_sdk_eventbridge::Client as EventBridgeClient;
+use chrono::Utc;
+use serde::Serialize;
+use std::fmt;
+use tracing::{event, Level};
+
+/// `source` of every event published, for EventBridge rules to match on.
+pub const EVENT_SOURCE: &str = "sqs_email_sender";
+
+/// Points in the life of an email announced on an `EventBus`, each published with its name as
+/// the `detail-type` of the event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EmailEventType {
+    /// The email was written as `EmailStatus::Pending` to be sent.
+    EmailQueued,
+    /// The email was claimed for sending.
+    EmailSending,
+    /// The email was handed to the provider and recorded `EmailStatus::Sent`.
+    EmailSent,
+    /// The email was marked `EmailStatus::Failed` and will not be sent.
+    EmailFailed,
+}
+
+impl EmailEventType {
+    /// Status the email has once the event has happened.
+    pub fn status(&self) -> EmailStatus {
+        match self {
+            EmailEventType::EmailQueued => EmailStatus::Pending,
+            EmailEventType::EmailSending => EmailStatus::Sending,
+            EmailEventType::EmailSent => EmailStatus::Sent,
+            EmailEventType::EmailFailed => EmailStatus::Failed,
+        }
+    }
+}
+
+impl fmt::Display for EmailEventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// The `detail` of an event published for an email. Only identifying and routing fields of the
+/// email are included so consumers do not depend on the schema of the email table.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EmailEvent {
+    /// What happened, published as the `detail-type` rather than in the detail.
+    #[serde(skip)]
+    pub event_type: EmailEventType,
+    /// Id of the email.
+    pub email_id: EmailId,
+    /// Status of the email once the event happened.
+    pub status: EmailStatus,
+    /// Category of the email, if any.
+    pub category: Option<String>,
+    /// Provider the email is sent through.
+    pub provider: String,
+    /// Why the email reached its status, for failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// When the event happened.
+    pub occurred_at: String,
+}
+
+impl EmailEvent {
+    pub fn new(event_type: EmailEventType, email: &EmailMessage) -> Self {
+        EmailEvent {
+            event_type,
+            email_id: email.email_id.clone(),
+            status: event_type.status(),
+            category: email.category.clone(),
+            provider: email.provider.clone(),
+            reason: None,
+            occurred_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// An event for the email identified by `email_id` when its record could not be read.
+    pub fn for_email_id(event_type: EmailEventType, email_id: &EmailId) -> Self {
+        EmailEvent {
+            email_id: email_id.clone(),
+            ..EmailEvent::new(event_type, &EmailMessage::default())
+        }
+    }
+
+    /// The same email reaching `event_type` now, without the reason of this event.
+    pub fn followed_by(&self, event_type: EmailEventType) -> Self {
+        EmailEvent {
+            event_type,
+            status: event_type.status(),
+            reason: None,
+            occurred_at: Utc::now().to_rfc3339(),
+            ..self.clone()
+        }
+    }
+
+    /// Give `reason` as why the email reached its status.
+    pub fn with_reason(self, reason: &str) -> Self {
+        EmailEvent {
+            reason: Some(reason.to_owned()),
+            ..self
+        }
+    }
+}
+
+/// EventBridge bus the life of each email is announced on, so downstream automation can react to
+/// emails being queued, sent, or failed without reading the email table.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    /// Name or ARN of the event bus.
+    bus_name: String,
+    /// Connection to EventBridge.
+    eventbridge: EventBridgeClient,
+}
+
+impl EventBus {
+    pub fn new(bus_name: impl Into<String>, eventbridge: EventBridgeClient) -> Self {
+        EventBus {
+            bus_name: bus_name.into(),
+            eventbridge,
+        }
+    }
+
+    /// Name or ARN of the event bus.
+    pub fn bus_name(&self) -> &str {
+        &self.bus_name
+    }
+
+    /// Publish `email_event` to the bus.
+    pub async fn publish(&self, email_event: &EmailEvent) -> Result<(), String> {
+        let output = self
+            .eventbridge
+            .put_events()
+            .entries(self.entry(email_event)?)
+            .send()
+            .await
+            .map_err(|error| format!("{}", DisplayErrorContext(&error)))?;
+        if output.failed_entry_count() > 0 {
+            let entry = output.entries().first();
+            return Err(format!(
+                "{}: {}",
+                entry
+                    .and_then(|entry| entry.error_code())
+                    .unwrap_or("Failed"),
+                entry
+                    .and_then(|entry| entry.error_message())
+                    .unwrap_or("event not published")
+            ));
+        }
+        event!(
+            Level::DEBUG,
+            email_id = %email_event.email_id,
+            detail_type = %email_event.event_type,
+            "email event published"
+        );
+        Ok(())
+    }
+
+    /// The entry publishing `email_event` to the bus.
+    fn entry(&self, email_event: &EmailEvent) -> Result<PutEventsRequestEntry, String> {
+        let detail = serde_json::to_string(email_event).map_err(|error| error.to_string())?;
+        Ok(PutEventsRequestEntry::builder()
+            .detail(detail)
+            .detail_type(email_event.event_type.to_string())
+            .event_bus_name(&self.bus_name)
+            .source(EVENT_SOURCE)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod entry {
+    use super::*;
+    use aws_sdk_eventbridge::config::BehaviorVersion;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn publishes_event_type_as_detail_type() {
+        let eventbridge = EventBridgeClient::from_conf(
+            aws_sdk_eventbridge::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let bus = EventBus::new("emails", eventbridge);
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            category: Some("receipts".into()),
+            provider: "ses".into(),
+            body_text: "Test Body".into(),
+            ..EmailMessage::default()
+        };
+        let failed = EmailEvent::new(EmailEventType::EmailSending, &email)
+            .followed_by(EmailEventType::EmailFailed)
+            .with_reason("Header injection in: Subject");
+        let entry = bus.entry(&failed).unwrap();
+        assert_eq!(entry.detail_type(), Some("EmailFailed"));
+        assert_eq!(entry.event_bus_name(), Some("emails"));
+        assert_eq!(entry.source(), Some(EVENT_SOURCE));
+        let mut detail: Value = serde_json::from_str(entry.detail().unwrap()).unwrap();
+        assert!(detail["occurred_at"].is_string());
+        detail.as_object_mut().unwrap().remove("occurred_at");
+        assert_eq!(
+            detail,
+            json!({
+                "email_id": "Test EmailId",
+                "status": "Failed",
+                "category": "receipts",
+                "provider": "ses",
+                "reason": "Header injection in: Subject",
+            })
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use crate::dynamo::add_email_feedback;
+use crate::email_message::{EmailId, Recipient};
+use crate::error::UpdateError;
+use crate::mime::EMAIL_ID_HEADER;
+use crate::queue::delete_entry;
+use crate::queue_url::QueueUrl;
+use crate::runner::{delete_messages, BatchReport, DeleteOutcome, MessageSource};
+use crate::suppression::{SuppressionReason, Suppressions};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::types::Message;
+use aws_sdk_sqs::Client as SqsClient;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+use tracing::{event, Instrument, Level};
+
+/// SES tag which may carry the `EmailId` when the original headers are not included in
+/// notifications.
+const EMAIL_ID_TAG: &str = "email_id";
+
+/// Kind of delivery feedback reported by SES.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FeedbackType {
+    /// Mail to the recipient bounced.
+    Bounce,
+    /// The recipient marked mail as spam.
+    Complaint,
+}
+
+impl std::fmt::Display for FeedbackType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Delivery feedback for one recipient of an email, recorded on the email record.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Feedback {
+    /// Whether the recipient bounced or complained.
+    pub feedback_type: FeedbackType,
+    /// Address the feedback is about.
+    pub recipient: Recipient,
+    /// Bounce type, for example "Permanent" or "Transient", or the complaint feedback type, for
+    /// example "abuse", when SES reported one.
+    #[serde(default)]
+    pub sub_type: String,
+    /// Diagnostic code from the receiving mail server, when available.
+    #[serde(default)]
+    pub detail: Option<String>,
+    /// DateTime SES reported the feedback.
+    #[serde(default)]
+    pub timestamp: String,
+}
+
+impl Feedback {
+    /// Reason to suppress future mail to `recipient`, if the feedback warrants it. Only permanent
+    /// bounces and complaints suppress an address, transient bounces are recorded but may succeed
+    /// when retried later.
+    pub fn suppression_reason(&self) -> Option<SuppressionReason> {
+        match self.feedback_type {
+            FeedbackType::Complaint => Some(SuppressionReason::Complaint),
+            FeedbackType::Bounce if self.sub_type == "Permanent" => Some(SuppressionReason::Bounce),
+            FeedbackType::Bounce => None,
+        }
+    }
+}
+
+/// Bounce or complaint notification parsed from a feedback queue message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeedbackNotification {
+    /// Email the notification is about when it could be identified from the headers or tags of
+    /// the original message.
+    pub email_id: Option<EmailId>,
+    /// Feedback for each recipient named in the notification.
+    pub feedback: Vec<Feedback>,
+}
+
+/// Possible errors handling a feedback queue message.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum FeedbackError {
+    /// The message body is not an SES notification. Reprocessing the message will also fail.
+    #[error("InvalidBody({0})")]
+    InvalidBody(String),
+    /// The address could not be added to the suppression table.
+    #[error("SuppressError({0})")]
+    SuppressError(String),
+    /// The feedback could not be recorded on the email record.
+    #[error("UpdateError({0})")]
+    UpdateError(#[from] UpdateError),
+}
+
+/// SNS notification wrapping the SES notification when raw message delivery is not enabled.
+#[derive(Deserialize)]
+struct SnsEnvelope {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SesNotification {
+    notification_type: String,
+    #[serde(default)]
+    bounce: Option<SesBounce>,
+    #[serde(default)]
+    complaint: Option<SesComplaint>,
+    #[serde(default)]
+    mail: SesMail,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SesBounce {
+    #[serde(default)]
+    bounce_type: String,
+    #[serde(default)]
+    bounced_recipients: Vec<SesRecipient>,
+    #[serde(default)]
+    timestamp: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SesComplaint {
+    #[serde(default)]
+    complaint_feedback_type: Option<String>,
+    #[serde(default)]
+    complained_recipients: Vec<SesRecipient>,
+    #[serde(default)]
+    timestamp: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SesRecipient {
+    email_address: String,
+    #[serde(default)]
+    diagnostic_code: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct SesMail {
+    #[serde(default)]
+    headers: Vec<SesHeader>,
+    #[serde(default)]
+    tags: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct SesHeader {
+    name: String,
+    value: String,
+}
+
+impl SesMail {
+    /// `EmailId` from the `X-Email-Id` header of the original message or the `email_id` tag.
+    fn email_id(&self) -> Option<EmailId> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(EMAIL_ID_HEADER))
+            .map(|header| header.value.trim().to_owned())
+            .or_else(|| {
+                self.tags
+                    .get(EMAIL_ID_TAG)
+                    .and_then(|values| values.first().cloned())
+            })
+            .and_then(|email_id| EmailId::new(email_id).ok())
+    }
+}
+
+/// Parse the body of a feedback queue message, either an SNS notification or the SES
+/// notification itself when SNS raw message delivery is enabled. Notifications other than bounces
+/// and complaints, such as deliveries, are `None`.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::{parse_notification, FeedbackType};
+///
+/// let body = r#"{
+///     "notificationType": "Complaint",
+///     "complaint": {
+///         "complainedRecipients": [{"emailAddress": "a@example.com"}],
+///         "timestamp": "2021-01-01T00:00:00.000Z"
+///     },
+///     "mail": {"headers": [{"name": "X-Email-Id", "value": "Test EmailId"}]}
+/// }"#;
+/// let notification = parse_notification(body).unwrap().unwrap();
+/// assert_eq!(notification.email_id.unwrap(), "Test EmailId");
+/// assert_eq!(notification.feedback[0].feedback_type, FeedbackType::Complaint);
+/// assert_eq!(notification.feedback[0].recipient, "a@example.com");
+/// ```
+pub fn parse_notification(body: &str) -> Result<Option<FeedbackNotification>, FeedbackError> {
+    let body = match serde_json::from_str::<SnsEnvelope>(body) {
+        Ok(envelope) => envelope.message,
+        Err(_) => body.to_owned(),
+    };
+    let notification = serde_json::from_str::<SesNotification>(&body)
+        .map_err(|e| FeedbackError::InvalidBody(e.to_string()))?;
+    let feedback = match notification.notification_type.as_str() {
+        "Bounce" => {
+            let SesBounce {
+                bounce_type,
+                bounced_recipients,
+                timestamp,
+            } = notification.bounce.ok_or_else(|| {
+                FeedbackError::InvalidBody("Bounce notification without bounce".into())
+            })?;
+            bounced_recipients
+                .into_iter()
+                .map(|recipient| Feedback {
+                    feedback_type: FeedbackType::Bounce,
+                    recipient: recipient.email_address.into(),
+                    sub_type: bounce_type.clone(),
+                    detail: recipient.diagnostic_code,
+                    timestamp: timestamp.clone(),
+                })
+                .collect()
+        }
+        "Complaint" => {
+            let SesComplaint {
+                complaint_feedback_type,
+                complained_recipients,
+                timestamp,
+            } = notification.complaint.ok_or_else(|| {
+                FeedbackError::InvalidBody("Complaint notification without complaint".into())
+            })?;
+            complained_recipients
+                .into_iter()
+                .map(|recipient| Feedback {
+                    feedback_type: FeedbackType::Complaint,
+                    recipient: recipient.email_address.into(),
+                    sub_type: complaint_feedback_type.clone().unwrap_or_default(),
+                    detail: recipient.diagnostic_code,
+                    timestamp: timestamp.clone(),
+                })
+                .collect()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(FeedbackNotification {
+        email_id: notification.mail.email_id(),
+        feedback,
+    }))
+}
+
+/// Receive SES bounce and complaint notifications from a feedback queue, add the addresses they
+/// report to the suppression table, and record the feedback on the email the notification is
+/// about.
+pub struct FeedbackWorker<'a> {
+    /// Connection to DynamoDB.
+    dynamodb: &'a DynamoDbClient,
+    /// DynamoDB table of email records.
+    table_name: &'a str,
+    /// Suppression table addresses are added to.
+    suppressions: &'a Suppressions,
+    /// URL of the feedback queue processed messages are deleted from.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+}
+
+impl FeedbackWorker<'_> {
+    pub fn new<'a>(
+        dynamodb: &'a DynamoDbClient,
+        table_name: &'a str,
+        suppressions: &'a Suppressions,
+        queue_url: &'a QueueUrl,
+        sqs: &'a SqsClient,
+    ) -> FeedbackWorker<'a> {
+        FeedbackWorker {
+            dynamodb,
+            table_name,
+            suppressions,
+            queue_url,
+            sqs,
+        }
+    }
+
+    /// Run a single pass over the next batch from `source`.
+    ///
+    /// 1. Receive a batch of notifications from the source.
+    /// 2. Suppress addresses and annotate email records for each notification.
+    /// 3. Delete handled and invalid notifications. Notifications which failed are left to be
+    ///    delivered again once their visibility timeout expires.
+    #[tracing::instrument(skip(self, source), level = Level::INFO)]
+    pub async fn run_once<S>(&self, source: &mut S) -> BatchReport
+    where
+        S: MessageSource + Send,
+    {
+        // 1. Receive a batch of notifications from the source.
+        let (messages, receive_failed) = match source.receive().in_current_span().await {
+            Ok(messages) => (messages, false),
+            Err(error) => {
+                event!(Level::ERROR, %error, "ReceiveMessageError");
+                (Vec::new(), true)
+            }
+        };
+        let received = messages.len();
+        let mut entries = Vec::new();
+        let mut retried = 0;
+        let mut quarantined = 0;
+        // 2. Suppress addresses and annotate email records for each notification.
+        for message in messages {
+            let Message {
+                body,
+                message_id,
+                receipt_handle,
+                ..
+            } = message;
+            match self
+                .process(body.as_deref().unwrap_or_default())
+                .in_current_span()
+                .await
+            {
+                Ok(()) => {}
+                Err(error @ FeedbackError::InvalidBody(_)) => {
+                    event!(Level::ERROR, ?message_id, %error, "quarantine message");
+                    quarantined += 1;
+                }
+                Err(error) => {
+                    event!(Level::ERROR, ?message_id, %error, "feedback not recorded");
+                    retried += 1;
+                    continue;
+                }
+            }
+            if let (Some(id), Some(handle)) = (message_id, receipt_handle) {
+                entries.push(delete_entry(id, handle));
+            }
+        }
+        let processed = received - retried;
+        // 3. Delete handled and invalid notifications.
+        let delete = if entries.is_empty() {
+            DeleteOutcome::NotNeeded
+        } else {
+            delete_messages(self.sqs, self.queue_url, entries)
+                .in_current_span()
+                .await
+        };
+        BatchReport {
+            received,
+            processed,
+            retried,
+            quarantined,
+            delete,
+            receive_failed,
+        }
+    }
+
+    /// Record the notification in `body`.
+    async fn process(&self, body: &str) -> Result<(), FeedbackError> {
+        let notification = match parse_notification(body)? {
+            Some(notification) => notification,
+            None => {
+                event!(Level::DEBUG, "ignore notification");
+                return Ok(());
+            }
+        };
+        for feedback in notification.feedback.iter() {
+            event!(
+                Level::INFO,
+                email_id = ?notification.email_id,
+                feedback_type = %feedback.feedback_type,
+                sub_type = %feedback.sub_type,
+                "feedback"
+            );
+            if let Some(reason) = feedback.suppression_reason() {
+                self.suppressions
+                    .add(feedback.recipient.address(), reason)
+                    .await
+                    .map_err(|e| FeedbackError::SuppressError(e.to_string()))?;
+            }
+            let email_id = match &notification.email_id {
+                Some(email_id) => email_id,
+                None => continue,
+            };
+            match add_email_feedback(self.dynamodb, self.table_name, email_id.as_str(), feedback)
+                .await
+            {
+                Ok(()) => {}
+                // The email was not sent by this broker or its record has been removed.
+                Err(UpdateError::ConditionalCheckFailed(_)) => {
+                    event!(Level::WARN, %email_id, "email record not found for feedback");
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod parse_notification {
+    use super::*;
+
+    const BOUNCE: &str = r#"{
+        "notificationType": "Bounce",
+        "bounce": {
+            "bounceType": "Permanent",
+            "bouncedRecipients": [
+                {"emailAddress": "a@example.com", "diagnosticCode": "smtp; 550 5.1.1 user unknown"},
+                {"emailAddress": "b@example.com"}
+            ],
+            "timestamp": "2021-01-01T00:00:00.000Z"
+        },
+        "mail": {
+            "messageId": "Test SES MessageId",
+            "headers": [{"name": "x-email-id", "value": "Test EmailId"}]
+        }
+    }"#;
+
+    #[test]
+    fn reads_sns_envelope() {
+        let body = serde_json::json!({
+            "Type": "Notification",
+            "Message": BOUNCE,
+        })
+        .to_string();
+        let notification = parse_notification(&body).unwrap().unwrap();
+        assert_eq!(
+            notification.email_id.as_ref().map(EmailId::as_str),
+            Some("Test EmailId")
+        );
+        assert_eq!(notification.feedback.len(), 2);
+        let feedback = &notification.feedback[0];
+        assert_eq!(feedback.feedback_type, FeedbackType::Bounce);
+        assert_eq!(feedback.recipient, "a@example.com");
+        assert_eq!(feedback.sub_type, "Permanent");
+        assert_eq!(
+            feedback.detail.as_deref(),
+            Some("smtp; 550 5.1.1 user unknown")
+        );
+        assert_eq!(feedback.timestamp, "2021-01-01T00:00:00.000Z");
+        assert_eq!(
+            feedback.suppression_reason(),
+            Some(SuppressionReason::Bounce)
+        );
+    }
+
+    #[test]
+    fn reads_raw_notification() {
+        let notification = parse_notification(BOUNCE).unwrap().unwrap();
+        assert_eq!(notification.feedback[1].recipient, "b@example.com");
+        assert_eq!(notification.feedback[1].detail, None);
+    }
+
+    #[test]
+    fn reads_email_id_tag() {
+        let body = serde_json::json!({
+            "notificationType": "Complaint",
+            "complaint": {
+                "complaintFeedbackType": "abuse",
+                "complainedRecipients": [{"emailAddress": "a@example.com"}],
+                "timestamp": "2021-01-01T00:00:00.000Z",
+            },
+            "mail": {"tags": {"email_id": ["Test EmailId"]}},
+        })
+        .to_string();
+        let notification = parse_notification(&body).unwrap().unwrap();
+        assert_eq!(
+            notification.email_id.as_ref().map(EmailId::as_str),
+            Some("Test EmailId")
+        );
+        assert_eq!(notification.feedback[0].sub_type, "abuse");
+        assert_eq!(
+            notification.feedback[0].suppression_reason(),
+            Some(SuppressionReason::Complaint)
+        );
+    }
+
+    #[test]
+    fn does_not_suppress_transient_bounce() {
+        let body = BOUNCE.replace("Permanent", "Transient");
+        let notification = parse_notification(&body).unwrap().unwrap();
+        assert_eq!(notification.feedback[0].suppression_reason(), None);
+    }
+
+    #[test]
+    fn ignores_delivery() {
+        let body = r#"{"notificationType": "Delivery", "mail": {}}"#;
+        assert_eq!(parse_notification(body), Ok(None));
+    }
+
+    #[test]
+    fn rejects_other_body() {
+        assert!(matches!(
+            parse_notification("not json"),
+            Err(FeedbackError::InvalidBody(_))
+        ));
+        assert!(matches!(
+            parse_notification(r#"{"notificationType": "Bounce"}"#),
+            Err(FeedbackError::InvalidBody(_))
+        ));
+    }
+}
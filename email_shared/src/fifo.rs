@@ -0,0 +1,163 @@
+use crate::email_message::EmailMessage;
+use crate::suppression::recipients;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::str::FromStr;
+
+/// Longest `MessageDeduplicationId` or `MessageGroupId` SQS accepts.
+const MAX_ID_LENGTH: usize = 128;
+
+/// Attribute of an email whose value is the `MessageGroupId` of its pointer on a FIFO queue.
+/// Pointers of the same group are received in order, one batch at a time, so a group which is
+/// too broad limits how many emails are sent at once. An email without the attribute is put in
+/// a group of its own.
+///
+/// ```
+/// use email_shared::{EmailMessage, MessageGroup};
+///
+/// let email = EmailMessage {
+///     email_id: "Test-EmailId".into(),
+///     recipients_to: vec!["a@Example.com".into()],
+///     ..EmailMessage::default()
+/// };
+/// assert_eq!(MessageGroup::EmailId.group_id(&email), "Test-EmailId");
+/// assert_eq!(MessageGroup::RecipientDomain.group_id(&email), "example.com");
+/// assert_eq!(MessageGroup::Category.group_id(&email), "Test-EmailId");
+/// assert_eq!("recipient_domain".parse(), Ok(MessageGroup::RecipientDomain));
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MessageGroup {
+    /// Every email is its own group, so emails are sent in no particular order.
+    #[default]
+    EmailId,
+    /// Emails of the same `Category` are sent in the order they were enqueued.
+    Category,
+    /// Emails to the same domain, of their first recipient, are sent in the order they were
+    /// enqueued.
+    RecipientDomain,
+    /// Emails from the same domain are sent in the order they were enqueued.
+    SenderDomain,
+}
+
+impl MessageGroup {
+    /// `MessageGroupId` of the pointer to `email`, the `EmailId` when `email` has no value for
+    /// the attribute.
+    pub fn group_id(&self, email: &EmailMessage) -> String {
+        let value = match self {
+            MessageGroup::EmailId => None,
+            MessageGroup::Category => email.category.clone(),
+            MessageGroup::RecipientDomain => recipients(email).first().and_then(|a| domain(a)),
+            MessageGroup::SenderDomain => domain(email.sender.address()),
+        };
+        fifo_id(value.as_deref().unwrap_or(email.email_id.as_str()))
+    }
+}
+
+impl FromStr for MessageGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "email_id" => Ok(MessageGroup::EmailId),
+            "category" => Ok(MessageGroup::Category),
+            "recipient_domain" => Ok(MessageGroup::RecipientDomain),
+            "sender_domain" => Ok(MessageGroup::SenderDomain),
+            _ => Err(format!(
+                "\"{}\" is not \"email_id\", \"category\", \"recipient_domain\", or \"sender_domain\"",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MessageGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageGroup::EmailId => write!(f, "email_id"),
+            MessageGroup::Category => write!(f, "category"),
+            MessageGroup::RecipientDomain => write!(f, "recipient_domain"),
+            MessageGroup::SenderDomain => write!(f, "sender_domain"),
+        }
+    }
+}
+
+/// Lower cased domain of `address`, if it has one.
+fn domain(address: &str) -> Option<String> {
+    match address.trim().rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => Some(domain.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// `MessageDeduplicationId` of pointers to the email identified by `email_id`, so a FIFO queue
+/// drops pointers to the same email sent within its five minute deduplication interval.
+pub(crate) fn deduplication_id(email_id: &str) -> String {
+    fifo_id(email_id)
+}
+
+/// `value` as an id SQS accepts, up to 128 ASCII letters, digits, and punctuation. Other values
+/// are replaced by their SHA-1 digest so distinct values keep distinct ids.
+fn fifo_id(value: &str) -> String {
+    let valid = !value.is_empty()
+        && value.len() <= MAX_ID_LENGTH
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b.is_ascii_punctuation());
+    if valid {
+        value.to_owned()
+    } else {
+        Sha1::digest(value.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod fifo_id {
+    use super::*;
+
+    #[test]
+    fn keeps_valid_ids() {
+        let email_id = "6f1c2a8e-93d4-4b57-a0e2-5c9d7b31f4a6";
+        assert_eq!(deduplication_id(email_id), email_id);
+    }
+
+    #[test]
+    fn digests_invalid_ids() {
+        let spaced = fifo_id("Test EmailId");
+        assert_eq!(spaced.len(), 40);
+        assert_ne!(spaced, fifo_id("Test  EmailId"));
+        assert_eq!(fifo_id(&"a".repeat(MAX_ID_LENGTH + 1)).len(), 40);
+        assert_eq!(fifo_id("").len(), 40);
+    }
+}
+
+#[cfg(test)]
+mod group_id {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_email_id() {
+        let email = EmailMessage {
+            email_id: "Test-EmailId".into(),
+            sender: "no domain".into(),
+            ..EmailMessage::default()
+        };
+        assert_eq!(
+            MessageGroup::RecipientDomain.group_id(&email),
+            "Test-EmailId"
+        );
+        assert_eq!(MessageGroup::SenderDomain.group_id(&email), "Test-EmailId");
+        let email = EmailMessage {
+            category: Some("otp".into()),
+            sender: "from@Mail.Example.com".into(),
+            ..email
+        };
+        assert_eq!(MessageGroup::Category.group_id(&email), "otp");
+        assert_eq!(
+            MessageGroup::SenderDomain.group_id(&email),
+            "mail.example.com"
+        );
+    }
+}
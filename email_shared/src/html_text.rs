@@ -0,0 +1,446 @@
+/// Elements whose content is not displayed.
+const HIDDEN_ELEMENTS: [&str; 6] = ["head", "noscript", "script", "style", "template", "title"];
+/// Elements separated from the text around them by a blank line.
+const PARAGRAPH_ELEMENTS: [&str; 13] = [
+    "blockquote",
+    "dl",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "p",
+    "pre",
+    "table",
+    "figure",
+];
+/// Elements which start on a line of their own.
+const LINE_ELEMENTS: [&str; 14] = [
+    "address",
+    "article",
+    "aside",
+    "dd",
+    "div",
+    "dt",
+    "figcaption",
+    "footer",
+    "form",
+    "header",
+    "main",
+    "nav",
+    "section",
+    "tr",
+];
+/// Named character references decoded in text, other names are left as written.
+const ENTITIES: [(&str, char); 16] = [
+    ("amp", '&'),
+    ("apos", '\''),
+    ("bull", '•'),
+    ("copy", '©'),
+    ("gt", '>'),
+    ("hellip", '…'),
+    ("ldquo", '“'),
+    ("lsquo", '‘'),
+    ("lt", '<'),
+    ("mdash", '—'),
+    ("nbsp", ' '),
+    ("ndash", '–'),
+    ("quot", '"'),
+    ("rdquo", '”'),
+    ("reg", '®'),
+    ("rsquo", '’'),
+];
+
+/// A readable plain text rendering of `html`, used as the TXT body of an email which only has an
+/// HTML body. Paragraphs and headings are separated by blank lines, list items are bulleted or
+/// numbered, links are followed by their URL in parentheses, and images are replaced by their alt
+/// text. Scripts, styles, and the document head are dropped and whitespace outside of `pre` is
+/// collapsed. This is not a full HTML parser, markup it does not understand is skipped.
+///
+/// ```
+/// use email_shared::html_to_text;
+///
+/// let text = html_to_text(
+///     "<h1>Welcome</h1><p>Hello&nbsp;<b>Jane</b>,</p>\
+///      <ul><li>One</li><li>Two</li></ul>\
+///      <p><a href=\"https://example.com/start\">Get started</a></p>",
+/// );
+/// assert_eq!(
+///     text,
+///     "Welcome\n\nHello Jane,\n\n* One\n* Two\n\nGet started (https://example.com/start)"
+/// );
+/// ```
+pub fn html_to_text(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut writer = TextWriter::default();
+    let mut rest = 0;
+    while rest < html.len() {
+        let remaining = &html[rest..];
+        if remaining.starts_with("<!--") {
+            rest = lower[rest..]
+                .find("-->")
+                .map_or(html.len(), |end| rest + end + 3);
+            continue;
+        }
+        let tag = match remaining.strip_prefix('<') {
+            Some(after)
+                if after.starts_with(|c: char| c.is_ascii_alphabetic() || "/!?".contains(c)) =>
+            {
+                tag_end(remaining).map(|end| &remaining[1..end])
+            }
+            _ => None,
+        };
+        match tag {
+            Some(tag) => {
+                rest += tag.len() + 2;
+                let tag = Tag::parse(tag);
+                let self_closing = tag.attributes.trim_end().ends_with('/');
+                if !tag.closing && !self_closing && HIDDEN_ELEMENTS.contains(&tag.name.as_str()) {
+                    // Skip past the closing tag of the hidden element
+                    let close = format!("</{}", tag.name);
+                    rest = match lower[rest..].find(&close) {
+                        Some(start) => {
+                            let start = rest + start;
+                            tag_end(&html[start..]).map_or(html.len(), |end| start + end + 1)
+                        }
+                        None => html.len(),
+                    };
+                } else {
+                    writer.tag(&tag);
+                }
+            }
+            None => {
+                let first = remaining.chars().next().map_or(1, char::len_utf8);
+                let end = remaining[first..]
+                    .find('<')
+                    .map_or(html.len(), |end| rest + first + end);
+                writer.text(&decode_entities(&html[rest..end]));
+                rest = end;
+            }
+        }
+    }
+    writer.finish()
+}
+
+/// Byte offset of the `>` ending the tag which starts `s`, skipping any in quoted attribute
+/// values.
+pub(crate) fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, '>') => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A start or end tag.
+struct Tag {
+    /// Whether this is an end tag.
+    closing: bool,
+    /// Element name, lower cased.
+    name: String,
+    /// Everything following the element name.
+    attributes: String,
+}
+
+impl Tag {
+    /// The tag written as `<{tag}>`.
+    fn parse(tag: &str) -> Tag {
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        Tag {
+            closing,
+            name: tag[..end].to_ascii_lowercase(),
+            attributes: tag[end..].to_owned(),
+        }
+    }
+
+    /// Value of the attribute `name`, with character references decoded.
+    fn attribute(&self, name: &str) -> Option<String> {
+        let mut rest = self.attributes.as_str();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+            if rest.is_empty() {
+                return None;
+            }
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+                .unwrap_or(rest.len());
+            let attribute = &rest[..end];
+            rest = rest[end..].trim_start();
+            let value = match rest.strip_prefix('=') {
+                Some(after) => {
+                    let after = after.trim_start();
+                    let (value, remaining) = match after.chars().next() {
+                        Some(quote @ ('"' | '\'')) => {
+                            let close = after[1..].find(quote).map_or(after.len(), |i| i + 1);
+                            (&after[1..close], after.get(close + 1..).unwrap_or(""))
+                        }
+                        _ => {
+                            let close = after.find(char::is_whitespace).unwrap_or(after.len());
+                            (&after[..close], &after[close..])
+                        }
+                    };
+                    rest = remaining;
+                    value
+                }
+                None => "",
+            };
+            if attribute.eq_ignore_ascii_case(name) {
+                return Some(decode_entities(value));
+            }
+        }
+    }
+}
+
+/// `text` with character references replaced by the characters they stand for.
+pub(crate) fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let c = reference.and_then(|reference| match reference.strip_prefix('#') {
+            Some(number) => match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => number.parse::<u32>().ok(),
+            }
+            .and_then(char::from_u32),
+            None => ENTITIES
+                .iter()
+                .find(|(name, _)| *name == reference)
+                .map(|(_, c)| *c),
+        });
+        match (reference, c) {
+            (Some(reference), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Text written so far along with the state of the elements it is inside of.
+#[derive(Default)]
+struct TextWriter {
+    /// Text written so far.
+    text: String,
+    /// Number of line breaks to write before the next text, 2 for a blank line.
+    breaks: usize,
+    /// Whether whitespace was collapsed since the last text written.
+    space: bool,
+    /// Depth of `pre` elements being written.
+    preformatted: usize,
+    /// Number of the next item of each open list, `None` for an unordered list.
+    lists: Vec<Option<usize>>,
+    /// URL of each open link along with where its text starts.
+    links: Vec<(Option<String>, usize)>,
+}
+
+impl TextWriter {
+    /// Write `text` as it would be displayed.
+    fn text(&mut self, text: &str) {
+        if self.preformatted > 0 {
+            self.write(&text.replace("\r\n", "\n"));
+            return;
+        }
+        if text.starts_with(char::is_whitespace) {
+            self.space = true;
+        }
+        for (index, word) in text.split_whitespace().enumerate() {
+            self.space |= index > 0;
+            self.write(word);
+        }
+        if text.ends_with(char::is_whitespace) {
+            self.space = true;
+        }
+    }
+
+    /// Append `s` after any pending line breaks or space.
+    fn write(&mut self, s: &str) {
+        if self.breaks > 0 && !self.text.is_empty() {
+            self.trim_end();
+            let written = self.text.len() - self.text.trim_end_matches('\n').len();
+            for _ in written..self.breaks {
+                self.text.push('\n');
+            }
+        } else if self.space && !self.text.is_empty() && !self.text.ends_with(char::is_whitespace) {
+            self.text.push(' ');
+        }
+        self.breaks = 0;
+        self.space = false;
+        self.text.push_str(s);
+    }
+
+    /// Request `breaks` line breaks before the next text.
+    fn line_break(&mut self, breaks: usize) {
+        self.breaks = self.breaks.max(breaks);
+        self.space = false;
+    }
+
+    /// Remove spaces from the end of the last line.
+    fn trim_end(&mut self) {
+        let trimmed = self.text.trim_end_matches([' ', '\t']).len();
+        self.text.truncate(trimmed);
+    }
+
+    /// Apply the formatting of `tag`.
+    fn tag(&mut self, tag: &Tag) {
+        let name = tag.name.as_str();
+        match (name, tag.closing) {
+            ("br", _) => {
+                self.write("");
+                self.trim_end();
+                self.text.push('\n');
+            }
+            ("hr", false) => {
+                self.line_break(2);
+                self.write("---");
+                self.line_break(2);
+            }
+            ("ul", false) | ("ol", false) => {
+                self.line_break(if self.lists.is_empty() { 2 } else { 1 });
+                let start = tag.attribute("start").and_then(|s| s.trim().parse().ok());
+                self.lists
+                    .push((name == "ol").then_some(start.unwrap_or(1)));
+            }
+            ("ul", true) | ("ol", true) => {
+                self.lists.pop();
+                self.line_break(if self.lists.is_empty() { 2 } else { 1 });
+            }
+            ("li", false) => {
+                self.line_break(1);
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "* ".to_owned(),
+                };
+                self.write(&format!("{}{}", indent, marker));
+            }
+            ("li", true) => self.line_break(1),
+            ("pre", false) => {
+                self.line_break(2);
+                self.preformatted += 1;
+            }
+            ("pre", true) => {
+                self.preformatted = self.preformatted.saturating_sub(1);
+                self.line_break(2);
+            }
+            ("a", false) => {
+                self.write("");
+                self.links.push((tag.attribute("href"), self.text.len()));
+            }
+            ("a", true) => {
+                if let Some((Some(href), start)) = self.links.pop() {
+                    let href = href.trim();
+                    let label = self.text.get(start..).unwrap_or("").trim();
+                    let shown = href.strip_prefix("mailto:").unwrap_or(href);
+                    let skipped = href.is_empty()
+                        || href.starts_with('#')
+                        || href.to_ascii_lowercase().starts_with("javascript:")
+                        || label == shown;
+                    if !skipped {
+                        let space = self.space;
+                        self.space = !label.is_empty();
+                        self.write(&format!("({})", shown));
+                        self.space = space;
+                    }
+                }
+            }
+            ("img", false) => {
+                if let Some(alt) = tag.attribute("alt") {
+                    self.text(&alt);
+                }
+            }
+            ("td", _) | ("th", _) => self.space = true,
+            (name, _) if PARAGRAPH_ELEMENTS.contains(&name) => self.line_break(2),
+            (name, _) if LINE_ELEMENTS.contains(&name) => self.line_break(1),
+            _ => {}
+        }
+    }
+
+    /// The text written with surrounding whitespace and trailing spaces on each line removed.
+    fn finish(self) -> String {
+        self.text
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim()
+            .to_owned()
+    }
+}
+
+#[cfg(test)]
+mod html_to_text {
+    use super::*;
+
+    #[test]
+    fn drops_hidden_elements_and_comments() {
+        let text = html_to_text(
+            "<html><head><title>Title</title><style>p { color: red; }</style></head>\
+             <body><!-- note --><script type=\"text/javascript\">alert('<p>');</script>\
+             <p>Body</p></body></html>",
+        );
+        assert_eq!(text, "Body");
+    }
+
+    #[test]
+    fn collapses_whitespace_outside_pre() {
+        let text = html_to_text("<p>  One\n   two  </p><pre>  a\n   b</pre><p>three<br>four</p>");
+        assert_eq!(text, "One two\n\n  a\n   b\n\nthree\nfour");
+    }
+
+    #[test]
+    fn numbers_ordered_lists() {
+        let text = html_to_text(
+            "<ol start=\"3\"><li>Three</li><li>Four<ul><li>Nested</li></ul></li></ol><p>After</p>",
+        );
+        assert_eq!(text, "3. Three\n4. Four\n  * Nested\n\nAfter");
+    }
+
+    #[test]
+    fn writes_links_and_images() {
+        let text = html_to_text(
+            "<a href=\"https://example.com/a?b=1&amp;c=2\">Link</a> \
+             <a href=\"https://example.com\">https://example.com</a> \
+             <a href=\"mailto:help@example.com\">help@example.com</a> \
+             <a href=\"#top\">Top</a> \
+             <img src=\"logo.png\" alt='Logo &amp; Co'>",
+        );
+        assert_eq!(
+            text,
+            "Link (https://example.com/a?b=1&c=2) https://example.com help@example.com Top Logo & Co"
+        );
+    }
+
+    #[test]
+    fn decodes_entities() {
+        let text = html_to_text("<p>&lt;tag&gt; &#169; &#x2014; &unknown; AT&T</p>");
+        assert_eq!(text, "<tag> © — &unknown; AT&T");
+    }
+}
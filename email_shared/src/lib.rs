@@ -1,9 +1,135 @@
+mod alerts;
+mod archive;
+mod assume_role;
+mod attachments;
 pub mod attribute_value_wrapper;
+mod audit;
+mod cache;
+mod capacity;
+mod circuit_breaker;
 mod client;
+mod config;
+mod dead_letter;
+mod domains;
+mod drop_folder;
 mod dynamo;
 mod email_message;
+mod email_message_builder;
+mod eml;
+mod endpoint;
 mod error;
+mod events;
+mod feedback;
+mod fifo;
+mod html_text;
+mod markdown;
+mod max_age;
+mod metrics;
+mod mime;
+mod mime_store;
+mod outbox;
+mod personalization;
+mod producer;
+mod quarantine;
 mod queue;
+mod queue_url;
+mod rate_limit;
+mod reconcile;
+mod retry;
+mod runner;
+mod sandbox;
+mod sanitize;
+pub mod schema;
+mod secrets;
+mod services;
+mod status_machine;
+mod stream;
+mod suppression;
+mod sweeper;
+mod telemetry;
+mod templates;
+mod tenants;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+mod timeouts;
+mod tracking;
+mod weighted_poll;
 
-pub use crate::client::Client;
-pub use crate::queue::get_sqs_email_messages;
+pub use crate::alerts::{Alert, Alerts, ALERT_MIN_ATTEMPTS};
+pub use crate::archive::{ArchiveBcc, ArchiveBccError};
+pub use crate::assume_role::{AssumeRole, DEFAULT_SESSION_NAME};
+pub use crate::attachments::{AttachmentContent, AttachmentError, AttachmentFetcher, HttpFetcher};
+pub use crate::audit::{check_audit, AuditEntry, AuditFinding, AuditRefusal, AuditSummary};
+pub use crate::cache::{TtlCache, DEFAULT_CACHE_MAX_ENTRIES};
+pub use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+pub use crate::client::{BatchOutcome, Client};
+pub use crate::config::{
+    is_region, redact_url, Config, ConfigError, ConfigSources, DEFAULT_ALERT_FAILURE_RATE,
+    DEFAULT_ALERT_WINDOW, DEFAULT_ATTACHMENT_MAX_BYTES, DEFAULT_ATTACHMENT_TIMEOUT,
+    DEFAULT_CIRCUIT_BREAKER_COOLDOWN, DEFAULT_MAX_ATTEMPTS, DEFAULT_RATE_LIMIT_MAX_DELAY,
+    DEFAULT_SENDING_LEASE, DEFAULT_TEMPLATE_TTL, REDACTED,
+};
+pub use crate::dead_letter::{DeadLetter, FailureQueue};
+pub use crate::domains::DomainPolicy;
+pub use crate::drop_folder::{parse_drop_notification, DropError, DropFolder, DroppedObject};
+pub use crate::dynamo::{query_by_status, StatusEntry, StatusPage, StatusTransition};
+pub use crate::email_message::{
+    EmailId, EmailIdError, EmailMessage, EmailMessageAttachment, EmailStatus, Recipient,
+    RecipientError, S3Object, StatusChange,
+};
+pub use crate::email_message_builder::{normalize_address, EmailMessageBuilder, ValidationError};
+pub use crate::eml::EmlError;
+pub use crate::endpoint::{dynamodb_config, sqs_config, Endpoint, EndpointError};
+pub use crate::error::{
+    CancelError, DirectSendError, EnqueueError, GetError, PutError, UpdateError,
+};
+pub use crate::events::{EmailEvent, EmailEventType, EventBus, EVENT_SOURCE};
+pub use crate::feedback::{
+    parse_notification, Feedback, FeedbackError, FeedbackNotification, FeedbackType, FeedbackWorker,
+};
+pub use crate::fifo::MessageGroup;
+pub use crate::html_text::html_to_text;
+pub use crate::markdown::markdown_to_html;
+pub use crate::max_age::{MaxAgeError, MaxMessageAge};
+pub use crate::metrics::Metrics;
+pub use crate::mime::{build_message, MimeAttachment, MimeMessage};
+pub use crate::mime_store::{
+    MimeStore, MimeStoreEncryption, MimeStoreError, MimeStoreLocation, S3MimeStore,
+};
+pub use crate::outbox::{enqueue_email_with_outbox, OutboxRelay, RelayReport};
+pub use crate::personalization::{expand, PersonalizedRecipient};
+pub use crate::producer::{
+    cancel_email, enqueue_email, enqueue_tenant_email, idempotent_email_id, requeue_email,
+    requeue_tenant_email, EmailMessageDraft,
+};
+pub use crate::quarantine::{QuarantineRecord, QuarantineRedaction, S3QuarantineStore};
+pub use crate::queue::{
+    forward_pointer, get_sqs_email_messages, send_email_pointer, send_tenant_pointer,
+    EmailPointerMessage, PointerError,
+};
+pub use crate::queue_url::{QueueUrl, QueueUrlError};
+pub use crate::rate_limit::{RateLimitError, RateLimiter, RateLimits};
+pub use crate::reconcile::{PendingReconciler, ReconcileReport};
+pub use crate::retry::{RetryPolicy, RetryPolicyError};
+pub use crate::runner::{
+    BatchReport, DeleteOutcome, EventBatch, IdleBackoff, LoopEvent, MessageSource, RunSummary,
+    Runner, SqsPoll,
+};
+pub use crate::sanitize::sanitize_html;
+pub use crate::secrets::{SecretError, SecretRef, Secrets};
+pub use crate::services::ClientServices;
+pub use crate::status_machine::StatusMachine;
+pub use crate::stream::{StreamChange, StreamEnqueuer, StreamImage, StreamRecord, StreamReport};
+pub use crate::suppression::{suppression_key, SuppressionReason, Suppressions};
+pub use crate::sweeper::{StuckEmailSweeper, SweepReport};
+pub use crate::telemetry::{Telemetry, TelemetryError};
+pub use crate::templates::{
+    Template, TemplateCache, TemplateData, TemplateError, TemplateId, TemplateSource, Templates,
+    USE_TEMPLATE_V2,
+};
+pub use crate::tenants::{Tenant, Tenants};
+pub use crate::timeouts::{CallTimeouts, DEFAULT_CONNECT_TIMEOUT, DEFAULT_OPERATION_TIMEOUT};
+pub use crate::tracking::{
+    TrackedLink, Tracking, TrackingKind, TrackingUrl, TrackingUrlError, TRACKING_PIXEL,
+};
+pub use crate::weighted_poll::{WeightedPoll, WeightedQueue, WeightedQueueError};
@@ -0,0 +1,531 @@
+use crate::email_message::EmailMessage;
+use crate::html_text::html_to_text;
+
+/// URL schemes links and images may use, any other link is written as its text alone.
+const SAFE_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+
+/// Fill in the HTML and TXT bodies of `email` from its `BodyMarkdown` when it has neither. The
+/// TXT body is derived from the rendered HTML so both read the same.
+pub(crate) fn render_bodies(email: &mut EmailMessage) {
+    if email.body_markdown.is_empty() || !email.body_html.is_empty() || !email.body_text.is_empty()
+    {
+        return;
+    }
+    email.body_html = markdown_to_html(&email.body_markdown);
+    email.body_text = html_to_text(&email.body_html);
+}
+
+/// Render `markdown` as HTML. Paragraphs, ATX headings, block quotes, bulleted and numbered
+/// lists, fenced code blocks, thematic breaks, emphasis, code spans, links, images, and hard line
+/// breaks are supported. The output is safe to send whatever the input, HTML in the input is
+/// escaped rather than passed through and links and images using a scheme other than `http`,
+/// `https`, or `mailto` are written as their text alone.
+///
+/// ```
+/// use email_shared::markdown_to_html;
+///
+/// let html = markdown_to_html("# Hello\n\nYour order *shipped*, [track it](https://example.com).");
+/// assert_eq!(
+///     html,
+///     "<h1>Hello</h1>\n<p>Your order <em>shipped</em>, \
+///      <a href=\"https://example.com\">track it</a>.</p>\n"
+/// );
+/// assert_eq!(
+///     markdown_to_html("<script>alert(1)</script> [x](javascript:alert(1))"),
+///     "<p>&lt;script&gt;alert(1)&lt;/script&gt; x</p>\n"
+/// );
+/// ```
+pub fn markdown_to_html(markdown: &str) -> String {
+    let markdown = markdown.replace("\r\n", "\n");
+    let lines = markdown.lines().collect::<Vec<_>>();
+    let mut html = String::new();
+    blocks(&lines, false, &mut html);
+    html
+}
+
+/// Write the blocks of `lines` to `html`. Paragraphs in a `tight` list item are written without
+/// `p` elements.
+fn blocks(lines: &[&str], tight: bool, html: &mut String) {
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() {
+            index += 1;
+        } else if let Some(fence) = fence(trimmed) {
+            let end = lines[index + 1..]
+                .iter()
+                .position(|line| line.trim_start().starts_with(fence))
+                .map_or(lines.len(), |end| index + 1 + end);
+            html.push_str("<pre><code>");
+            for line in &lines[index + 1..end] {
+                html.push_str(&escape(line));
+                html.push('\n');
+            }
+            html.push_str("</code></pre>\n");
+            index = end + 1;
+        } else if let Some((level, text)) = heading(trimmed) {
+            html.push_str(&format!("<h{}>{}</h{}>\n", level, inline(text), level));
+            index += 1;
+        } else if is_thematic_break(trimmed) {
+            html.push_str("<hr>\n");
+            index += 1;
+        } else if trimmed.starts_with('>') {
+            let end = lines[index..]
+                .iter()
+                .position(|line| !line.trim_start().starts_with('>'))
+                .map_or(lines.len(), |end| index + end);
+            let quoted = lines[index..end]
+                .iter()
+                .map(|line| {
+                    let line = &line.trim_start()[1..];
+                    line.strip_prefix(' ').unwrap_or(line)
+                })
+                .collect::<Vec<_>>();
+            html.push_str("<blockquote>\n");
+            blocks(&quoted, false, html);
+            html.push_str("</blockquote>\n");
+            index = end;
+        } else if list_marker(line).is_some() {
+            index = list(lines, index, html);
+        } else {
+            let end = lines[index..]
+                .iter()
+                .position(|line| ends_paragraph(line))
+                .map_or(lines.len(), |end| index + end.max(1));
+            let text = lines[index..end]
+                .iter()
+                .map(|line| match line.strip_suffix('\\') {
+                    Some(line) => format!("{}<br>", inline(line.trim())),
+                    None if line.ends_with("  ") => format!("{}<br>", inline(line.trim())),
+                    None => inline(line.trim()),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let text = text.strip_suffix("<br>").unwrap_or(&text);
+            if tight {
+                html.push_str(text);
+                html.push('\n');
+            } else {
+                html.push_str(&format!("<p>{}</p>\n", text));
+            }
+            index = end;
+        }
+    }
+}
+
+/// Whether `line` ends a paragraph by being blank or starting another block.
+fn ends_paragraph(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.is_empty()
+        || fence(trimmed).is_some()
+        || heading(trimmed).is_some()
+        || is_thematic_break(trimmed)
+        || trimmed.starts_with('>')
+        || list_marker(line).is_some()
+}
+
+/// The fence opening a code block written on `line`.
+fn fence(line: &str) -> Option<&'static str> {
+    if line.starts_with("```") {
+        Some("```")
+    } else if line.starts_with("~~~") {
+        Some("~~~")
+    } else {
+        None
+    }
+}
+
+/// Level and text of the ATX heading written on `line`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let text = &line[level..];
+    if !(1..=6).contains(&level) || !(text.is_empty() || text.starts_with(' ')) {
+        return None;
+    }
+    let text = text.trim();
+    let closed = text.trim_end_matches('#');
+    let text = if closed.is_empty() || closed.ends_with(' ') {
+        closed.trim_end()
+    } else {
+        text
+    };
+    Some((level, text))
+}
+
+/// Whether `line` is three or more `-`, `*`, or `_` and nothing else but spaces.
+fn is_thematic_break(line: &str) -> bool {
+    let marks = line.chars().filter(|c| *c != ' ').collect::<Vec<_>>();
+    marks.len() >= 3 && ['-', '*', '_'].contains(&marks[0]) && marks.iter().all(|c| *c == marks[0])
+}
+
+/// A list item marker starting `line`: the start number for a numbered item, or `None` for a
+/// bulleted one, along with the width of the indent and marker.
+fn list_marker(line: &str) -> Option<(Option<usize>, usize)> {
+    let indent = line.len() - line.trim_start().len();
+    let rest = &line[indent..];
+    if indent > 3 || is_thematic_break(rest) {
+        return None;
+    }
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    let (number, marker) = if digits == 0 {
+        (None, rest.chars().next().filter(|c| "-*+".contains(*c))?)
+    } else if digits <= 9 {
+        (rest[..digits].parse().ok(), rest[digits..].chars().next()?)
+    } else {
+        return None;
+    };
+    if number.is_some() && marker != '.' && marker != ')' {
+        return None;
+    }
+    let width = indent + digits + 1;
+    match line[width..].chars().next() {
+        Some(' ') => Some((number, width + 1)),
+        None => Some((number, width)),
+        _ => None,
+    }
+}
+
+/// Write the list starting at `lines[start]` to `html`, returning the index of the line after it.
+fn list(lines: &[&str], start: usize, html: &mut String) -> usize {
+    let (number, _) = list_marker(lines[start]).expect("list starts with a marker");
+    let mut items: Vec<Vec<&str>> = Vec::new();
+    let mut tight = true;
+    let mut index = start;
+    let mut width = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        match list_marker(line) {
+            Some((item_number, item_width))
+                if item_number.is_some() == number.is_some()
+                    && (items.is_empty() || indent_of(line) < width) =>
+            {
+                items.push(vec![&line[item_width..]]);
+                width = item_width;
+            }
+            _ if line.trim().is_empty() => {
+                let next = lines.get(index + 1);
+                let continues = next.is_some_and(|next| {
+                    indent_of(next) >= width
+                        || list_marker(next).is_some_and(|(n, _)| n.is_some() == number.is_some())
+                });
+                if !continues {
+                    break;
+                }
+                tight = false;
+                items.last_mut().expect("item started").push("");
+            }
+            _ if indent_of(line) >= width => {
+                items.last_mut().expect("item started").push(&line[width..]);
+            }
+            _ if list_marker(line).is_none() && !ends_paragraph(line) => {
+                // A lazy continuation of the paragraph of the last item
+                let last = items.last_mut().expect("item started");
+                if last.last().is_some_and(|line| !line.trim().is_empty()) {
+                    last.push(line.trim_start());
+                } else {
+                    break;
+                }
+            }
+            _ => break,
+        }
+        index += 1;
+    }
+    match number {
+        Some(1) => html.push_str("<ol>\n"),
+        Some(number) => html.push_str(&format!("<ol start=\"{}\">\n", number)),
+        None => html.push_str("<ul>\n"),
+    }
+    for item in items {
+        html.push_str("<li>");
+        let mut content = String::new();
+        blocks(&item, tight, &mut content);
+        if tight && !content.contains('<') || content.trim_end().lines().count() <= 1 {
+            html.push_str(content.trim_end());
+        } else {
+            html.push('\n');
+            html.push_str(&content);
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str(if number.is_some() {
+        "</ol>\n"
+    } else {
+        "</ul>\n"
+    });
+    index
+}
+
+/// Number of spaces `line` is indented by.
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Render the inline content of `text`: emphasis, code spans, links, images, and escapes.
+fn inline(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let consumed = match c {
+            '\\' => match rest[1..].chars().next() {
+                Some(next) if next.is_ascii_punctuation() => {
+                    html.push_str(&escape(&next.to_string()));
+                    2
+                }
+                _ => 0,
+            },
+            '`' => code_span(rest, &mut html),
+            '!' if rest[1..].starts_with('[') => match link(&rest[1..]) {
+                Some((label, url, length)) if is_safe_url(&url) => {
+                    html.push_str(&format!(
+                        "<img src=\"{}\" alt=\"{}\">",
+                        escape(&url),
+                        escape(&label)
+                    ));
+                    length + 1
+                }
+                Some((label, _, length)) => {
+                    html.push_str(&escape(&label));
+                    length + 1
+                }
+                None => 0,
+            },
+            '[' => match link(rest) {
+                Some((label, url, length)) if is_safe_url(&url) => {
+                    html.push_str(&format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape(&url),
+                        inline(&label)
+                    ));
+                    length
+                }
+                Some((label, _, length)) => {
+                    html.push_str(&inline(&label));
+                    length
+                }
+                None => 0,
+            },
+            '<' => match rest[1..].find('>').map(|end| &rest[1..end + 1]) {
+                Some(url) if is_safe_url(url) && !url.contains(char::is_whitespace) => {
+                    html.push_str(&format!("<a href=\"{0}\">{0}</a>", escape(url)));
+                    url.len() + 2
+                }
+                _ => 0,
+            },
+            '*' | '_' => emphasis(rest, text, &mut html),
+            _ => 0,
+        };
+        if consumed == 0 {
+            html.push_str(&escape(&c.to_string()));
+            rest = &rest[c.len_utf8()..];
+        } else {
+            rest = &rest[consumed..];
+        }
+    }
+    html
+}
+
+/// Write the code span starting `rest` to `html`, returning its length or 0 when the backticks
+/// are not closed.
+fn code_span(rest: &str, html: &mut String) -> usize {
+    let ticks = rest.chars().take_while(|c| *c == '`').count();
+    let delimiter = &rest[..ticks];
+    let mut search = ticks;
+    while let Some(found) = rest[search..].find(delimiter) {
+        let end = search + found;
+        let run = rest[end..].chars().take_while(|c| *c == '`').count();
+        if run == ticks {
+            let code = &rest[ticks..end];
+            let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+                Some(inner) if !inner.trim().is_empty() => inner,
+                _ => code,
+            };
+            html.push_str(&format!("<code>{}</code>", escape(code)));
+            return end + ticks;
+        }
+        search = end + run;
+    }
+    html.push_str(&escape(delimiter));
+    ticks
+}
+
+/// Label, URL, and length of the link written as `[label](url "title")` starting `rest`.
+fn link(rest: &str) -> Option<(String, String, usize)> {
+    let mut depth = 0;
+    let mut label_end = None;
+    let mut escaped = false;
+    for (index, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    label_end = Some(index);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let label_end = label_end?;
+    let destination = rest[label_end + 1..].strip_prefix('(')?;
+    let mut depth = 0;
+    let close = destination.char_indices().find_map(|(index, c)| {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(index),
+            ')' => depth -= 1,
+            _ => {}
+        }
+        None
+    })?;
+    let target = destination[..close].trim();
+    let url = match target.find(char::is_whitespace) {
+        Some(space) if target[space..].trim_start().starts_with(['"', '\'']) => &target[..space],
+        Some(_) => return None,
+        None => target,
+    };
+    let url = url
+        .strip_prefix('<')
+        .and_then(|url| url.strip_suffix('>'))
+        .unwrap_or(url);
+    Some((
+        rest[1..label_end].to_owned(),
+        url.to_owned(),
+        label_end + 2 + close + 1,
+    ))
+}
+
+/// Write the emphasis starting `rest`, a suffix of `text`, to `html`, returning its length or 0
+/// when it is not closed. `_` only delimits emphasis at the edges of words.
+fn emphasis(rest: &str, text: &str, html: &mut String) -> usize {
+    let marker = &rest[..1];
+    let strong = rest[1..].starts_with(marker);
+    let delimiter = if strong { &rest[..2] } else { marker };
+    let before = text[..text.len() - rest.len()].chars().next_back();
+    if marker == "_" && before.is_some_and(char::is_alphanumeric) {
+        return 0;
+    }
+    let inner = &rest[delimiter.len()..];
+    if inner.starts_with(char::is_whitespace) || inner.is_empty() {
+        return 0;
+    }
+    let mut search = 0;
+    while let Some(found) = inner[search..].find(delimiter) {
+        let end = search + found;
+        let after = inner[end + delimiter.len()..].chars().next();
+        let closes = end > 0
+            && !inner[..end].ends_with(char::is_whitespace)
+            && !(marker == "_" && after.is_some_and(char::is_alphanumeric))
+            && (strong || !inner[end + 1..].starts_with(marker));
+        if closes {
+            let tag = if strong { "strong" } else { "em" };
+            html.push_str(&format!("<{0}>{1}</{0}>", tag, inline(&inner[..end])));
+            return delimiter.len() * 2 + end;
+        }
+        search = end + delimiter.len();
+    }
+    0
+}
+
+/// Whether `url` uses one of the `SAFE_SCHEMES`.
+fn is_safe_url(url: &str) -> bool {
+    let lower = url.trim().to_ascii_lowercase();
+    SAFE_SCHEMES.iter().any(|scheme| lower.starts_with(scheme))
+}
+
+/// `text` with the characters HTML treats specially escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod markdown_to_html {
+    use super::*;
+
+    #[test]
+    fn writes_block_elements() {
+        let html = markdown_to_html(
+            "## Title ##\n\nFirst line  \nsecond line\n\n> Quoted\n> text\n\n---\n\n```\n<b>code</b>\n```",
+        );
+        assert_eq!(
+            html,
+            "<h2>Title</h2>\n<p>First line<br>\nsecond line</p>\n\
+             <blockquote>\n<p>Quoted\ntext</p>\n</blockquote>\n<hr>\n\
+             <pre><code>&lt;b&gt;code&lt;/b&gt;\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn writes_lists() {
+        let html = markdown_to_html("- One\n- Two\n  - Nested\n\n3. Three\n4. Four\n");
+        assert_eq!(
+            html,
+            "<ul>\n<li>One</li>\n<li>\nTwo\n<ul>\n<li>Nested</li>\n</ul>\n</li>\n</ul>\n\
+             <ol start=\"3\">\n<li>Three</li>\n<li>Four</li>\n</ol>\n"
+        );
+    }
+
+    #[test]
+    fn writes_inline_elements() {
+        let html = markdown_to_html(
+            "**Bold** and _em_ in snake_case_name with `a < b` and \\*literal\\* \
+             ![Logo](https://example.com/logo.png) <https://example.com>",
+        );
+        assert_eq!(
+            html,
+            "<p><strong>Bold</strong> and <em>em</em> in snake_case_name with <code>a &lt; b</code> \
+             and *literal* <img src=\"https://example.com/logo.png\" alt=\"Logo\"> \
+             <a href=\"https://example.com\">https://example.com</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn drops_unsafe_urls() {
+        let html = markdown_to_html(
+            "[a](javascript:alert(1)) ![b](data:image/png;base64,AAAA) <javascript:alert(1)> \
+             [c](https://example.com/\"onmouseover=\"x)",
+        );
+        assert_eq!(
+            html,
+            "<p>a b &lt;javascript:alert(1)&gt; \
+             <a href=\"https://example.com/&quot;onmouseover=&quot;x\">c</a></p>\n"
+        );
+    }
+}
+
+#[cfg(test)]
+mod render_bodies {
+    use super::*;
+
+    #[test]
+    fn fills_missing_bodies() {
+        let mut email = EmailMessage {
+            body_markdown: "Hello *there*".into(),
+            ..EmailMessage::default()
+        };
+        render_bodies(&mut email);
+        assert_eq!(email.body_html, "<p>Hello <em>there</em></p>\n");
+        assert_eq!(email.body_text, "Hello there");
+        let mut email = EmailMessage {
+            body_markdown: "Hello *there*".into(),
+            body_text: "Text".into(),
+            ..EmailMessage::default()
+        };
+        render_bodies(&mut email);
+        assert!(email.body_html.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Possible errors while parsing a `MaxMessageAge`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum MaxAgeError {
+    /// A duration was not a number followed by one of the units `s`, `m`, `h`, or `d`.
+    #[error("InvalidDuration({0})")]
+    InvalidDuration(String),
+    /// More than one rule without a category was given.
+    #[error("DuplicateDefault({0})")]
+    DuplicateDefault(String),
+}
+
+/// The oldest a pointer message may be before its email is marked `EmailStatus::Failed` instead
+/// of being sent. Emails with a `Category` use the age configured for that category, falling
+/// back to the default age. Without an applicable age emails are always sent.
+///
+/// Rules are separated by commas, a rule is either a duration or `<category>=<duration>`.
+///
+/// ```
+/// use email_shared::MaxMessageAge;
+/// use std::time::Duration;
+///
+/// let max_age: MaxMessageAge = "24h,otp=15m".parse().unwrap();
+/// assert_eq!(max_age.for_category(None), Some(Duration::from_secs(24 * 60 * 60)));
+/// assert_eq!(max_age.for_category(Some("otp")), Some(Duration::from_secs(15 * 60)));
+/// assert_eq!(max_age.for_category(Some("newsletter")), Some(Duration::from_secs(24 * 60 * 60)));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MaxMessageAge {
+    /// Age applied to emails without a category, or with a category without its own age.
+    default: Option<Duration>,
+    /// Age applied to emails of a category.
+    categories: HashMap<String, Duration>,
+}
+
+impl MaxMessageAge {
+    /// Maximum age of an email in `category`.
+    pub fn for_category(&self, category: Option<&str>) -> Option<Duration> {
+        category
+            .and_then(|category| self.categories.get(category))
+            .or(self.default.as_ref())
+            .copied()
+    }
+
+    /// Whether a message sent `age` ago for an email in `category` is too old to send.
+    pub fn is_expired(&self, category: Option<&str>, age: Duration) -> bool {
+        self.for_category(category)
+            .map(|max_age| age > max_age)
+            .unwrap_or(false)
+    }
+}
+
+impl FromStr for MaxMessageAge {
+    type Err = MaxAgeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut max_age = MaxMessageAge::default();
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule.find('=') {
+                Some(index) => {
+                    let duration = parse_duration(&rule[index + 1..])?;
+                    let category = rule[..index].trim().to_owned();
+                    max_age.categories.insert(category, duration);
+                }
+                None if max_age.default.is_some() => {
+                    return Err(MaxAgeError::DuplicateDefault(rule.into()));
+                }
+                None => max_age.default = Some(parse_duration(rule)?),
+            }
+        }
+        Ok(max_age)
+    }
+}
+
+/// Parse a whole number followed by a unit of `s`, `m`, `h`, or `d` as a `Duration`.
+fn parse_duration(s: &str) -> Result<Duration, MaxAgeError> {
+    let s = s.trim();
+    let invalid = || MaxAgeError::InvalidDuration(s.into());
+    let unit = s.chars().last().ok_or_else(invalid)?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let value: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    let seconds = value.checked_mul(seconds).ok_or_else(invalid)?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn parses_empty_as_unlimited() {
+        let max_age: MaxMessageAge = "".parse().unwrap();
+        assert_eq!(max_age, MaxMessageAge::default());
+        assert!(!max_age.is_expired(None, Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn parses_categories_without_default() {
+        let max_age: MaxMessageAge = "otp=90s, newsletter=3d".parse().unwrap();
+        assert_eq!(max_age.for_category(None), None);
+        assert_eq!(
+            max_age.for_category(Some("newsletter")),
+            Some(Duration::from_secs(3 * 24 * 60 * 60))
+        );
+        assert!(max_age.is_expired(Some("otp"), Duration::from_secs(91)));
+        assert!(!max_age.is_expired(Some("otp"), Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(
+            "24".parse::<MaxMessageAge>(),
+            Err(MaxAgeError::InvalidDuration("24".into()))
+        );
+        assert_eq!(
+            "otp=h".parse::<MaxMessageAge>(),
+            Err(MaxAgeError::InvalidDuration("h".into()))
+        );
+        assert_eq!(
+            "1h,2h".parse::<MaxMessageAge>(),
+            Err(MaxAgeError::DuplicateDefault("2h".into()))
+        );
+    }
+}
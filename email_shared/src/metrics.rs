@@ -0,0 +1,508 @@
+use crate::capacity::Capacity;
+use aws_sdk_cloudwatch::error::DisplayErrorContext;
+use aws_sdk_cloudwatch::types::{MetricDatum, StandardUnit, StatisticSet};
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::fmt::Write;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// Most metrics sent in a single `PutMetricData` call.
+const PUT_METRIC_DATA_LIMIT: usize = 1000;
+
+/// Counts published by `Metrics`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Counter {
+    /// Messages received from the queue.
+    Received,
+    /// Emails transmitted through the provider.
+    Sent,
+    /// Emails which did not need to be sent, for example because they were already sent.
+    Skipped,
+    /// Messages left on the queue to be delivered again.
+    Retried,
+    /// Emails given up on and messages which could never be processed.
+    Failed,
+    /// Receives which returned no messages.
+    EmptyReceives,
+    /// Template and suppression lookups answered from memory.
+    CacheHits,
+    /// Template and suppression lookups made to DynamoDB or S3.
+    CacheMisses,
+    /// Emails left `Sending` by a worker which stopped and enqueued again.
+    Recovered,
+    /// Emails left `Pending` without a pointer and enqueued again.
+    Reconciled,
+}
+
+impl Counter {
+    const ALL: [Counter; 10] = [
+        Counter::Received,
+        Counter::Sent,
+        Counter::Skipped,
+        Counter::Retried,
+        Counter::Failed,
+        Counter::EmptyReceives,
+        Counter::CacheHits,
+        Counter::CacheMisses,
+        Counter::Recovered,
+        Counter::Reconciled,
+    ];
+
+    /// Name of the metric in CloudWatch.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::Received => "MessagesReceived",
+            Counter::Sent => "EmailsSent",
+            Counter::Skipped => "EmailsSkipped",
+            Counter::Retried => "MessagesRetried",
+            Counter::Failed => "EmailsFailed",
+            Counter::EmptyReceives => "EmptyReceives",
+            Counter::CacheHits => "CacheHits",
+            Counter::CacheMisses => "CacheMisses",
+            Counter::Recovered => "EmailsRecovered",
+            Counter::Reconciled => "EmailsReconciled",
+        }
+    }
+
+    /// Name of the metric in the Prometheus exposition format.
+    pub fn prometheus_name(&self) -> &'static str {
+        match self {
+            Counter::Received => "messages_received_total",
+            Counter::Sent => "emails_sent_total",
+            Counter::Skipped => "emails_skipped_total",
+            Counter::Retried => "messages_retried_total",
+            Counter::Failed => "emails_failed_total",
+            Counter::EmptyReceives => "empty_receives_total",
+            Counter::CacheHits => "cache_hits_total",
+            Counter::CacheMisses => "cache_misses_total",
+            Counter::Recovered => "emails_recovered_total",
+            Counter::Reconciled => "emails_reconciled_total",
+        }
+    }
+
+    /// Description of the metric.
+    pub fn help(&self) -> &'static str {
+        match self {
+            Counter::Received => "Messages received from the queue.",
+            Counter::Sent => "Emails transmitted through the provider.",
+            Counter::Skipped => "Emails which did not need to be sent.",
+            Counter::Retried => "Messages left on the queue to be delivered again.",
+            Counter::Failed => "Emails given up on and messages which could never be processed.",
+            Counter::EmptyReceives => "Receives which returned no messages.",
+            Counter::CacheHits => "Template and suppression lookups answered from memory.",
+            Counter::CacheMisses => "Template and suppression lookups made to DynamoDB or S3.",
+            Counter::Recovered => "Emails left Sending by a stopped worker and enqueued again.",
+            Counter::Reconciled => "Emails left Pending without a pointer and enqueued again.",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Name of the metric holding the time taken to transmit an email through the provider.
+const SEND_LATENCY: &str = "SendLatency";
+/// Name of the send latency metric in the Prometheus exposition format.
+const SEND_LATENCY_SECONDS: &str = "send_latency_seconds";
+/// Names of the metrics holding the read and write capacity units DynamoDB consumed.
+const CONSUMED_CAPACITY: [&str; 2] = ["ConsumedReadCapacityUnits", "ConsumedWriteCapacityUnits"];
+/// Names of the consumed capacity metrics in the Prometheus exposition format.
+const CONSUMED_CAPACITY_TOTAL: [&str; 2] = [
+    "consumed_read_capacity_units_total",
+    "consumed_write_capacity_units_total",
+];
+
+/// Statistics of the send latencies recorded since the last publish, in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Latency {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Latency {
+    fn record(&mut self, millis: f64) {
+        if self.count == 0 || millis < self.min {
+            self.min = millis;
+        }
+        if self.count == 0 || millis > self.max {
+            self.max = millis;
+        }
+        self.count += 1;
+        self.sum += millis;
+    }
+}
+
+/// Counters and timers of processed messages. Values are kept in memory for as long as the
+/// process runs, to be rendered for Prometheus, and optionally published to CloudWatch where they
+/// are reset each time they are published. CloudWatch receives them either through
+/// `PutMetricData` or as a log line in the Embedded Metric Format, which CloudWatch Logs turns
+/// into metrics without an API call. The broker publishes after every loop iteration and the
+/// Lambda after every invocation.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::Metrics;
+///
+/// let metrics = Metrics::default();
+/// assert!(metrics
+///     .render_prometheus()
+///     .contains("emails_sent_total 0\n"));
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connection to CloudWatch and the namespace metrics are published under.
+    cloudwatch: Option<(CloudWatchClient, String)>,
+    /// Namespace metrics are written to standard output under in the Embedded Metric Format.
+    embedded: Option<String>,
+    /// Counts since the last publish.
+    counters: [AtomicU64; 10],
+    /// Counts since the process started.
+    totals: [AtomicU64; 10],
+    /// Send latencies since the last publish.
+    latency: Mutex<Latency>,
+    /// Send latencies since the process started.
+    latency_total: Mutex<Latency>,
+    /// DynamoDB capacity consumed since the last publish.
+    capacity: Mutex<Capacity>,
+    /// DynamoDB capacity consumed since the process started.
+    capacity_total: Mutex<Capacity>,
+}
+
+impl Metrics {
+    /// Publish metrics to CloudWatch under `namespace`.
+    pub fn with_cloudwatch(
+        self,
+        namespace: impl Into<String>,
+        cloudwatch: CloudWatchClient,
+    ) -> Self {
+        Metrics {
+            cloudwatch: Some((cloudwatch, namespace.into())),
+            ..self
+        }
+    }
+
+    /// Publish metrics to CloudWatch under `namespace` by writing them to standard output in the
+    /// Embedded Metric Format, for processes whose output is collected by CloudWatch Logs.
+    pub fn with_embedded_format(self, namespace: impl Into<String>) -> Self {
+        Metrics {
+            embedded: Some(namespace.into()),
+            ..self
+        }
+    }
+
+    /// Add `count` to `counter`.
+    pub(crate) fn count(&self, counter: Counter, count: u64) {
+        self.counters[counter.index()].fetch_add(count, Ordering::Relaxed);
+        self.totals[counter.index()].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Record the time one transmission through the provider took.
+    pub(crate) fn time_send(&self, elapsed: Duration) {
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        lock(&self.latency).record(millis);
+        lock(&self.latency_total).record(millis);
+    }
+
+    /// Record the DynamoDB capacity consumed by one batch.
+    pub(crate) fn consume(&self, capacity: Capacity) {
+        lock(&self.capacity).add(capacity);
+        lock(&self.capacity_total).add(capacity);
+    }
+
+    /// Every value recorded since the process started in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut text = String::new();
+        for counter in Counter::ALL.iter() {
+            let count = self.totals[counter.index()].load(Ordering::Relaxed);
+            let name = counter.prometheus_name();
+            let _ = writeln!(text, "# HELP {} {}", name, counter.help());
+            let _ = writeln!(text, "# TYPE {} counter", name);
+            let _ = writeln!(text, "{} {}", name, count);
+        }
+        let latency = *lock(&self.latency_total);
+        let name = SEND_LATENCY_SECONDS;
+        let _ = writeln!(
+            text,
+            "# HELP {} Time taken to transmit an email through the provider.",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} summary", name);
+        let _ = writeln!(text, "{}_sum {}", name, latency.sum / 1000.0);
+        let _ = writeln!(text, "{}_count {}", name, latency.count);
+        let capacity = *lock(&self.capacity_total);
+        let units = [capacity.read, capacity.write];
+        for ((name, units), kind) in CONSUMED_CAPACITY_TOTAL
+            .iter()
+            .zip(units)
+            .zip(["Read", "Write"])
+        {
+            let _ = writeln!(
+                text,
+                "# HELP {} {} capacity units DynamoDB consumed.",
+                name, kind
+            );
+            let _ = writeln!(text, "# TYPE {} counter", name);
+            let _ = writeln!(text, "{} {}", name, units);
+        }
+        text
+    }
+
+    /// Publish the values recorded since the last publish to CloudWatch, returning the number of
+    /// metrics sent. Nothing is sent when CloudWatch is not configured. Values which could not be
+    /// published are dropped rather than counted twice.
+    pub async fn publish(&self) -> Result<usize, String> {
+        if self.cloudwatch.is_none() && self.embedded.is_none() {
+            return Ok(0);
+        }
+        let counts = Counter::ALL.map(|counter| {
+            let count = self.counters[counter.index()].swap(0, Ordering::Relaxed);
+            (counter, count)
+        });
+        let latency = std::mem::take(&mut *lock(&self.latency));
+        let capacity = std::mem::take(&mut *lock(&self.capacity));
+        if let Some(namespace) = &self.embedded {
+            let document = embedded_document(namespace, &counts, &latency, &capacity, Utc::now());
+            // Written directly rather than through `tracing` which nests fields under the event
+            // where CloudWatch Logs does not look for them
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{}", document).map_err(|error| error.to_string())?;
+            event!(Level::DEBUG, %namespace, "embedded metrics written");
+        }
+        let (cloudwatch, namespace) = match &self.cloudwatch {
+            Some(cloudwatch) => cloudwatch,
+            None => {
+                let capacity = CONSUMED_CAPACITY.len() * usize::from(!capacity.is_empty());
+                return Ok(counts.len() + usize::from(latency.count > 0) + capacity);
+            }
+        };
+        let data = metric_data(&counts, &latency, &capacity);
+        let sent = data.len();
+        for chunk in data.chunks(PUT_METRIC_DATA_LIMIT) {
+            cloudwatch
+                .put_metric_data()
+                .namespace(namespace)
+                .set_metric_data(Some(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(|error| format!("{}", DisplayErrorContext(&error)))?;
+        }
+        event!(Level::DEBUG, %namespace, sent, "metrics published");
+        Ok(sent)
+    }
+}
+
+fn lock<T>(value: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    value.lock().expect("Metrics lock poisoned")
+}
+
+/// The metrics for `counts`, `latency`, and `capacity`. Every counter is included, even when
+/// zero, so alarms see a value each period, latency is only included when a send was timed and
+/// capacity when DynamoDB reported consuming some.
+fn metric_data(
+    counts: &[(Counter, u64)],
+    latency: &Latency,
+    capacity: &Capacity,
+) -> Vec<MetricDatum> {
+    let mut data = counts
+        .iter()
+        .map(|(counter, count)| {
+            MetricDatum::builder()
+                .metric_name(counter.name())
+                .unit(StandardUnit::Count)
+                .value(*count as f64)
+                .build()
+        })
+        .collect::<Vec<_>>();
+    if latency.count > 0 {
+        let statistics = StatisticSet::builder()
+            .sample_count(latency.count as f64)
+            .sum(latency.sum)
+            .minimum(latency.min)
+            .maximum(latency.max)
+            .build();
+        data.push(
+            MetricDatum::builder()
+                .metric_name(SEND_LATENCY)
+                .unit(StandardUnit::Milliseconds)
+                .statistic_values(statistics)
+                .build(),
+        );
+    }
+    if !capacity.is_empty() {
+        for (name, units) in CONSUMED_CAPACITY
+            .iter()
+            .zip([capacity.read, capacity.write])
+        {
+            data.push(
+                MetricDatum::builder()
+                    .metric_name(*name)
+                    .unit(StandardUnit::Count)
+                    .value(units)
+                    .build(),
+            );
+        }
+    }
+    data
+}
+
+/// A log line in the Embedded Metric Format holding `counts`, `latency`, and `capacity` under
+/// `namespace`. Values are included as `metric_data` includes them. The format has no statistic
+/// sets so latency is recorded as the mean of the sends timed since the last publish.
+fn embedded_document(
+    namespace: &str,
+    counts: &[(Counter, u64)],
+    latency: &Latency,
+    capacity: &Capacity,
+    timestamp: chrono::DateTime<Utc>,
+) -> Value {
+    let mut definitions = Vec::new();
+    let mut document = Map::new();
+    for (counter, count) in counts {
+        definitions.push(json!({ "Name": counter.name(), "Unit": "Count" }));
+        document.insert(counter.name().to_owned(), json!(count));
+    }
+    if latency.count > 0 {
+        definitions.push(json!({ "Name": SEND_LATENCY, "Unit": "Milliseconds" }));
+        let mean = latency.sum / latency.count as f64;
+        document.insert(SEND_LATENCY.to_owned(), json!(mean));
+    }
+    if !capacity.is_empty() {
+        for (name, units) in CONSUMED_CAPACITY
+            .iter()
+            .zip([capacity.read, capacity.write])
+        {
+            definitions.push(json!({ "Name": name, "Unit": "Count" }));
+            document.insert((*name).to_owned(), json!(units));
+        }
+    }
+    document.insert(
+        "_aws".to_owned(),
+        json!({
+            "Timestamp": timestamp.timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [[]],
+                "Metrics": definitions,
+            }],
+        }),
+    );
+    Value::Object(document)
+}
+
+#[cfg(test)]
+mod embedded_document {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn declares_every_value() {
+        let mut latency = Latency::default();
+        latency.record(10.0);
+        latency.record(30.0);
+        let timestamp = Utc.timestamp_millis_opt(1_600_000_000_000).unwrap();
+        let counts = [(Counter::Sent, 3), (Counter::Failed, 0)];
+        let capacity = Capacity {
+            read: 1.5,
+            write: 2.0,
+        };
+        let document = embedded_document("Test Namespace", &counts, &latency, &capacity, timestamp);
+        let directive = &document["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(document["_aws"]["Timestamp"], 1_600_000_000_000_i64);
+        assert_eq!(directive["Namespace"], "Test Namespace");
+        assert_eq!(directive["Dimensions"], json!([[]]));
+        assert_eq!(
+            directive["Metrics"],
+            json!([
+                { "Name": "EmailsSent", "Unit": "Count" },
+                { "Name": "EmailsFailed", "Unit": "Count" },
+                { "Name": "SendLatency", "Unit": "Milliseconds" },
+                { "Name": "ConsumedReadCapacityUnits", "Unit": "Count" },
+                { "Name": "ConsumedWriteCapacityUnits", "Unit": "Count" },
+            ])
+        );
+        assert_eq!(document["EmailsSent"], 3);
+        assert_eq!(document["EmailsFailed"], 0);
+        assert_eq!(document["SendLatency"], 20.0);
+        assert_eq!(document["ConsumedReadCapacityUnits"], 1.5);
+    }
+
+    #[test]
+    fn omits_latency_without_sends() {
+        let document = embedded_document(
+            "Test Namespace",
+            &[],
+            &Latency::default(),
+            &Capacity::default(),
+            Utc::now(),
+        );
+        assert_eq!(
+            document["_aws"]["CloudWatchMetrics"][0]["Metrics"],
+            json!([])
+        );
+        assert!(document.get("SendLatency").is_none());
+    }
+}
+
+#[cfg(test)]
+mod metric_data {
+    use super::*;
+
+    #[test]
+    fn includes_every_counter() {
+        let counts = [(Counter::Sent, 3), (Counter::EmptyReceives, 0)];
+        let data = metric_data(&counts, &Latency::default(), &Capacity::default());
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].metric_name(), Some("EmailsSent"));
+        assert_eq!(data[0].value(), Some(3.0));
+        assert_eq!(data[1].metric_name(), Some("EmptyReceives"));
+        assert_eq!(data[1].value(), Some(0.0));
+    }
+
+    #[test]
+    fn summarizes_latency() {
+        let mut latency = Latency::default();
+        latency.record(20.0);
+        latency.record(10.0);
+        latency.record(30.0);
+        let data = metric_data(&[], &latency, &Capacity::default());
+        let statistics = data[0].statistic_values().unwrap();
+        assert_eq!(data[0].metric_name(), Some(SEND_LATENCY));
+        assert_eq!(statistics.sample_count(), Some(3.0));
+        assert_eq!(statistics.sum(), Some(60.0));
+        assert_eq!(statistics.minimum(), Some(10.0));
+        assert_eq!(statistics.maximum(), Some(30.0));
+    }
+}
+
+#[cfg(test)]
+mod render_prometheus {
+    use super::*;
+
+    #[test]
+    fn keeps_totals_after_publish() {
+        let metrics = Metrics::default();
+        metrics.count(Counter::Sent, 2);
+        metrics.count(Counter::Sent, 1);
+        metrics.time_send(Duration::from_millis(250));
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE emails_sent_total counter\nemails_sent_total 3\n"));
+        assert!(text.contains("send_latency_seconds_sum 0.25\n"));
+        assert!(text.contains("send_latency_seconds_count 1\n"));
+        metrics.consume(Capacity {
+            read: 0.5,
+            write: 1.0,
+        });
+        let text = metrics.render_prometheus();
+        assert!(text.contains("consumed_read_capacity_units_total 0.5\n"));
+        assert!(text.contains("consumed_write_capacity_units_total 1\n"));
+    }
+}
@@ -0,0 +1,470 @@
+use crate::email_message::{EmailMessage, Recipient};
+use crate::html_text::html_to_text;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Longest line, not counting the line ending, written for encoded content.
+const LINE_LENGTH: usize = 76;
+/// Longest encoded-word allowed by RFC 2047.
+const ENCODED_WORD_LENGTH: usize = 75;
+/// Domain used in a Message-ID when the sender has none.
+const DEFAULT_DOMAIN: &str = "localhost";
+/// Header carrying the `EmailId` of the record a message was built from so delivery feedback,
+/// such as bounce notifications including the original headers, can be matched to the record.
+pub(crate) const EMAIL_ID_HEADER: &str = "X-Email-Id";
+/// Headers written from the fields of an `EmailMessage` which custom headers can not replace.
+const RESERVED_HEADERS: [&str; 10] = [
+    "bcc",
+    "cc",
+    "date",
+    "from",
+    "message-id",
+    "mime-version",
+    "reply-to",
+    "subject",
+    "to",
+    "x-email-id",
+];
+
+/// An attachment read into memory ready to be encoded into a message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MimeAttachment {
+    /// File name of the attachment.
+    pub name: String,
+    /// MIME type of the attachment.
+    pub content_type: String,
+    /// Raw, not base64 encoded, bytes of the attachment.
+    pub body: Vec<u8>,
+}
+
+/// An RFC 5322 message assembled from an `EmailMessage`, shared by every sender which transmits
+/// raw messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MimeMessage {
+    /// Message-ID header of the message, including the angle brackets.
+    pub message_id: String,
+    /// The message with CRLF line endings. BCC recipients are not included.
+    pub raw: Vec<u8>,
+}
+
+/// Whether `name` may be used for one of the custom `headers` of an `EmailMessage`. The name must
+/// be an RFC 5322 field name and not a header written from another field or describing content.
+pub(crate) fn is_custom_header_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| (b'!'..=b'~').contains(&b) && b != b':')
+        && !RESERVED_HEADERS.contains(&lower.as_str())
+        && !lower.starts_with("content-")
+}
+
+/// Assemble `email` with `attachments` into an RFC 5322 message dated `date`.
+///
+/// Bodies are `multipart/alternative` when the email has an HTML body, with a TXT body generated
+/// by `html_to_text` when the email has none, and the message is `multipart/mixed` when there are
+/// attachments. Non-ASCII subjects and custom header values are encoded as RFC 2047 encoded-words
+/// and bodies are quoted-printable. Custom headers with names which are not allowed are left out.
+///
+/// ```
+/// use email_shared::{build_message, EmailMessage};
+///
+/// let email = EmailMessage {
+///     body_text: "Hello".into(),
+///     recipients_to: vec!["to@example.com".into()],
+///     sender: "from@example.com".into(),
+///     subject: "Greetings".into(),
+///     ..EmailMessage::default()
+/// };
+/// let message = build_message(&email, &[], chrono::Utc::now());
+/// let raw = String::from_utf8(message.raw).unwrap();
+/// assert!(raw.contains("Subject: Greetings\r\n"));
+/// assert!(message.message_id.ends_with("@example.com>"));
+/// ```
+pub fn build_message(
+    email: &EmailMessage,
+    attachments: &[MimeAttachment],
+    date: DateTime<Utc>,
+) -> MimeMessage {
+    let domain = Some(email.sender.domain())
+        .filter(|domain| !domain.is_empty())
+        .unwrap_or(DEFAULT_DOMAIN);
+    let message_id = format!("<{}@{}>", Uuid::new_v4(), domain);
+    let mut raw = String::new();
+    header(&mut raw, "Date", &date.to_rfc2822());
+    header(&mut raw, "Message-ID", &message_id);
+    header(
+        &mut raw,
+        "From",
+        &mailboxes(std::slice::from_ref(&email.sender)),
+    );
+    if !email.recipients_to.is_empty() {
+        header(&mut raw, "To", &mailboxes(&email.recipients_to));
+    }
+    if !email.recipients_cc.is_empty() {
+        header(&mut raw, "Cc", &mailboxes(&email.recipients_cc));
+    }
+    if !email.reply_to.is_empty() {
+        header(&mut raw, "Reply-To", &mailboxes(&email.reply_to));
+    }
+    header(&mut raw, "Subject", &encode_header(&email.subject));
+    if !email.email_id.as_str().is_empty() {
+        header(
+            &mut raw,
+            EMAIL_ID_HEADER,
+            &sanitize(email.email_id.as_str()),
+        );
+    }
+    for (name, value) in email.headers.iter() {
+        if is_custom_header_name(name) {
+            header(&mut raw, name, &encode_header(value));
+        }
+    }
+    header(&mut raw, "MIME-Version", "1.0");
+    if attachments.is_empty() {
+        body_part(&mut raw, email);
+    } else {
+        let boundary = boundary();
+        header(
+            &mut raw,
+            "Content-Type",
+            &format!("multipart/mixed; boundary=\"{}\"", boundary),
+        );
+        raw.push_str("\r\n");
+        raw.push_str(&format!("--{}\r\n", boundary));
+        body_part(&mut raw, email);
+        for attachment in attachments {
+            raw.push_str(&format!("\r\n--{}\r\n", boundary));
+            attachment_part(&mut raw, attachment);
+        }
+        raw.push_str(&format!("\r\n--{}--\r\n", boundary));
+    }
+    MimeMessage {
+        message_id,
+        raw: raw.into_bytes(),
+    }
+}
+
+/// Write the headers and content of the bodies of `email`. An email with only an HTML body is
+/// given a TXT body generated from it, for text-only clients and spam filters which penalize a
+/// message without one.
+fn body_part(raw: &mut String, email: &EmailMessage) {
+    let generated;
+    let body_text = if email.body_text.is_empty() && !email.body_html.is_empty() {
+        generated = html_to_text(&email.body_html);
+        &generated
+    } else {
+        &email.body_text
+    };
+    match (email.body_html.is_empty(), body_text.is_empty()) {
+        (false, false) => {
+            let boundary = boundary();
+            header(
+                raw,
+                "Content-Type",
+                &format!("multipart/alternative; boundary=\"{}\"", boundary),
+            );
+            raw.push_str("\r\n");
+            raw.push_str(&format!("--{}\r\n", boundary));
+            text_part(raw, "text/plain", body_text);
+            raw.push_str(&format!("\r\n--{}\r\n", boundary));
+            text_part(raw, "text/html", &email.body_html);
+            raw.push_str(&format!("\r\n--{}--\r\n", boundary));
+        }
+        (false, true) => text_part(raw, "text/html", &email.body_html),
+        _ => text_part(raw, "text/plain", body_text),
+    }
+}
+
+/// Write a quoted-printable text part of `content_type`.
+fn text_part(raw: &mut String, content_type: &str, body: &str) {
+    header(
+        raw,
+        "Content-Type",
+        &format!("{}; charset=utf-8", content_type),
+    );
+    header(raw, "Content-Transfer-Encoding", "quoted-printable");
+    raw.push_str("\r\n");
+    raw.push_str(&encode_quoted_printable(body));
+    raw.push_str("\r\n");
+}
+
+/// Write a base64 part for `attachment`.
+fn attachment_part(raw: &mut String, attachment: &MimeAttachment) {
+    let content_type = if attachment.content_type.is_empty() {
+        "application/octet-stream"
+    } else {
+        attachment.content_type.as_str()
+    };
+    header(raw, "Content-Type", &sanitize(content_type));
+    header(raw, "Content-Transfer-Encoding", "base64");
+    header(
+        raw,
+        "Content-Disposition",
+        &format!("attachment; {}", filename_parameter(&attachment.name)),
+    );
+    raw.push_str("\r\n");
+    let encoded = STANDARD.encode(&attachment.body);
+    for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+        raw.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        raw.push_str("\r\n");
+    }
+}
+
+/// Write a header line. Values must already be free of line breaks other than folding.
+fn header(raw: &mut String, name: &str, value: &str) {
+    raw.push_str(name);
+    raw.push_str(": ");
+    raw.push_str(value);
+    raw.push_str("\r\n");
+}
+
+/// A new multipart boundary which can not occur in quoted-printable or base64 content.
+fn boundary() -> String {
+    format!("=_{}", Uuid::new_v4().simple())
+}
+
+/// `recipients` as the value of an address header, with display names which are not ASCII
+/// written as RFC 2047 encoded-words.
+fn mailboxes(recipients: &[Recipient]) -> String {
+    recipients
+        .iter()
+        .map(|recipient| match recipient.name() {
+            Some(name) if !name.is_ascii() => {
+                format!("{} <{}>", encode_header(name), recipient.address())
+            }
+            _ => sanitize(&recipient.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Replace line breaks in a header value with spaces.
+fn sanitize(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Encode `value` as RFC 2047 encoded-words when it is not ASCII, folding between words.
+fn encode_header(value: &str) -> String {
+    let value = sanitize(value);
+    if value.is_ascii() {
+        return value;
+    }
+    // "=?utf-8?B?" and "?=" surround at most this many base64 characters
+    let max_encoded = ENCODED_WORD_LENGTH - "=?utf-8?B??=".len();
+    let max_bytes = max_encoded / 4 * 3;
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        if chunk.len() + c.len_utf8() > max_bytes {
+            words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+    }
+    words.join("\r\n ")
+}
+
+/// A Content-Disposition filename parameter, RFC 2231 encoded when `name` is not plain ASCII.
+fn filename_parameter(name: &str) -> String {
+    let name = sanitize(name);
+    if name.is_ascii() && !name.contains(['"', '\\']) {
+        return format!("filename=\"{}\"", name);
+    }
+    let encoded: String = name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("filename*=utf-8''{}", encoded)
+}
+
+/// Encode `body` as quoted-printable with CRLF line endings.
+fn encode_quoted_printable(body: &str) -> String {
+    let normalized = body.replace("\r\n", "\n");
+    let mut lines = Vec::new();
+    for line in normalized.split('\n') {
+        let bytes = line.as_bytes();
+        let mut encoded = String::new();
+        let mut length = 0;
+        for (index, &b) in bytes.iter().enumerate() {
+            let last = index == bytes.len() - 1;
+            let token = match b {
+                b' ' | b'\t' if !last => (b as char).to_string(),
+                b'!'..=b'<' | b'>'..=b'~' => (b as char).to_string(),
+                _ => format!("={:02X}", b),
+            };
+            // Leave room for the "=" of a soft line break
+            if length + token.len() > LINE_LENGTH - 1 {
+                encoded.push_str("=\r\n");
+                length = 0;
+            }
+            length += token.len();
+            encoded.push_str(&token);
+        }
+        lines.push(encoded);
+    }
+    lines.join("\r\n")
+}
+
+#[cfg(test)]
+mod build_message {
+    use super::*;
+
+    fn email(body_html: &str, body_text: &str) -> EmailMessage {
+        EmailMessage {
+            body_html: body_html.into(),
+            body_text: body_text.into(),
+            recipients_bcc: vec!["bcc@example.com".into()],
+            recipients_cc: vec!["cc@example.com".into()],
+            recipients_to: vec!["a@example.com".into(), "b@example.com".into()],
+            sender: "from@example.com".into(),
+            subject: "Test Subject".into(),
+            ..EmailMessage::default()
+        }
+    }
+
+    fn raw(email: &EmailMessage, attachments: &[MimeAttachment]) -> String {
+        String::from_utf8(build_message(email, attachments, Utc::now()).raw).unwrap()
+    }
+
+    #[test]
+    fn writes_single_part() {
+        let raw = raw(&email("", "Text"), &[]);
+        assert!(raw.contains("To: a@example.com, b@example.com\r\n"));
+        assert!(raw.contains("Cc: cc@example.com\r\n"));
+        assert!(!raw.contains("bcc@example.com"));
+        assert!(raw.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(!raw.contains("multipart"));
+        assert!(!raw.contains("X-Email-Id"));
+    }
+
+    #[test]
+    fn writes_email_id() {
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            ..email("", "Text")
+        };
+        let raw = raw(&email, &[]);
+        assert!(raw.contains("X-Email-Id: Test EmailId\r\n"));
+    }
+
+    #[test]
+    fn writes_alternative_bodies() {
+        let raw = raw(&email("<p>Html</p>", "Text"), &[]);
+        assert!(raw.contains("Content-Type: multipart/alternative; boundary="));
+        let text = raw.find("text/plain").unwrap();
+        let html = raw.find("text/html").unwrap();
+        assert!(text < html);
+    }
+
+    #[test]
+    fn generates_text_from_html() {
+        let raw = raw(&email("<p>Hello</p><p>World</p>", ""), &[]);
+        assert!(raw.contains("Content-Type: multipart/alternative; boundary="));
+        let text = raw.find("text/plain").unwrap();
+        let html = raw.find("text/html").unwrap();
+        assert!(text < html);
+        assert!(raw[text..html].contains("Hello\r\n\r\nWorld"));
+    }
+
+    #[test]
+    fn writes_mixed_with_attachments() {
+        let attachment = MimeAttachment {
+            name: "résumé.pdf".into(),
+            content_type: "application/pdf".into(),
+            body: b"hello".to_vec(),
+        };
+        let raw = raw(&email("<p>Html</p>", "Text"), &[attachment]);
+        assert!(raw.contains("Content-Type: multipart/mixed; boundary="));
+        assert!(raw.contains("multipart/alternative"));
+        assert!(raw.contains("filename*=utf-8''r%C3%A9sum%C3%A9.pdf"));
+        assert!(raw.contains("\r\naGVsbG8=\r\n"));
+    }
+
+    #[test]
+    fn writes_reply_to_and_custom_headers() {
+        let mut email = email("", "Text");
+        email.reply_to = vec!["reply@example.com".into()];
+        email.headers.insert(
+            "List-Unsubscribe".into(),
+            "<https://example.com/unsubscribe/token>".into(),
+        );
+        email.headers.insert(
+            "List-Unsubscribe-Post".into(),
+            "List-Unsubscribe=One-Click".into(),
+        );
+        email
+            .headers
+            .insert("Content-Type".into(), "text/evil".into());
+        email
+            .headers
+            .insert("From".into(), "evil@example.com".into());
+        let raw = raw(&email, &[]);
+        assert!(raw.contains("Reply-To: reply@example.com\r\n"));
+        assert!(raw.contains("List-Unsubscribe: <https://example.com/unsubscribe/token>\r\n"));
+        assert!(raw.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"));
+        assert!(!raw.contains("text/evil"));
+        assert!(!raw.contains("evil@example.com"));
+    }
+
+    #[test]
+    fn encodes_display_names() {
+        let mut email = email("", "Text");
+        email.sender = "Support Team <support@example.com>".into();
+        email.recipients_to = vec!["Grüße <a@example.com>".into(), "b@example.com".into()];
+        let raw = raw(&email, &[]);
+        assert!(raw.contains("From: Support Team <support@example.com>\r\n"));
+        assert!(raw.contains("To: =?utf-8?B?R3LDvMOfZQ==?= <a@example.com>, b@example.com\r\n"));
+    }
+
+    #[test]
+    fn prevents_header_injection() {
+        let mut email = email("", "Text");
+        email.subject = "Hi\r\nBcc: victim@example.com".into();
+        let raw = raw(&email, &[]);
+        assert!(raw.contains("Subject: Hi  Bcc: victim@example.com\r\n"));
+    }
+}
+
+#[cfg(test)]
+mod encode_header {
+    use super::*;
+
+    #[test]
+    fn leaves_ascii() {
+        assert_eq!(encode_header("Hello"), "Hello");
+    }
+
+    #[test]
+    fn encodes_non_ascii_words() {
+        assert_eq!(encode_header("Grüße"), "=?utf-8?B?R3LDvMOfZQ==?=");
+        let long = "é".repeat(40);
+        let encoded = encode_header(&long);
+        assert!(encoded
+            .split("\r\n ")
+            .all(|word| word.len() <= ENCODED_WORD_LENGTH));
+        assert_eq!(encoded.split("\r\n ").count(), 2);
+    }
+}
+
+#[cfg(test)]
+mod encode_quoted_printable {
+    use super::*;
+
+    #[test]
+    fn escapes_and_wraps() {
+        assert_eq!(encode_quoted_printable("a=b \nc"), "a=3Db=20\r\nc");
+        assert_eq!(encode_quoted_printable("é"), "=C3=A9");
+        let encoded = encode_quoted_printable(&"x".repeat(100));
+        assert!(encoded.lines().all(|line| line.len() <= LINE_LENGTH));
+        assert!(encoded.contains("=\r\n"));
+    }
+}
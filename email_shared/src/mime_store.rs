@@ -0,0 +1,303 @@
+use crate::email_message::S3Object;
+use crate::mime::MimeMessage;
+use async_trait::async_trait;
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::ServerSideEncryption;
+use aws_sdk_s3::Client as S3Client;
+use chrono::{NaiveDate, Utc};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// MIME type of stored messages.
+const MESSAGE_CONTENT_TYPE: &str = "message/rfc822";
+
+/// Possible errors while storing or reading a rendered message.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum MimeStoreError {
+    /// The location is not of the form "s3://<bucket>/<prefix>".
+    #[error("InvalidLocation({0})")]
+    InvalidLocation(String),
+    /// The encryption is not one of "AES256", "aws:kms", or "aws:kms:<key id>".
+    #[error("InvalidEncryption({0})")]
+    InvalidEncryption(String),
+    /// No message is stored at the location.
+    #[error("NotFound({bucket}/{key})")]
+    NotFound { bucket: String, key: String },
+    /// The store could not be reached or rejected the request.
+    #[error("ServiceError({0})")]
+    ServiceError(String),
+}
+
+/// Storage for the exact bytes of sent messages so they can be transmitted again unchanged.
+#[async_trait]
+pub trait MimeStore: Send + Sync {
+    /// Store `message` as sent for the email identified by `email_id`, returning where it is.
+    async fn put(&self, email_id: &str, message: &MimeMessage) -> Result<S3Object, MimeStoreError>;
+
+    /// Read the message stored at `location`.
+    async fn get(&self, location: &S3Object) -> Result<Vec<u8>, MimeStoreError>;
+}
+
+/// Bucket and key prefix under which rendered messages are stored, parsed from
+/// "s3://<bucket>/<prefix>".
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::MimeStoreLocation;
+///
+/// let location = "s3://bucket/sent/".parse::<MimeStoreLocation>().unwrap();
+/// assert_eq!(location.bucket, "bucket");
+/// assert_eq!(location.prefix, "sent/");
+/// assert!("bucket/sent/".parse::<MimeStoreLocation>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MimeStoreLocation {
+    /// Bucket messages are stored in.
+    pub bucket: String,
+    /// Prefix of the key of each message.
+    pub prefix: String,
+}
+
+impl FromStr for MimeStoreLocation {
+    type Err = MimeStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(location) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = match location.find('/') {
+                Some(index) => (&location[..index], &location[index + 1..]),
+                None => (location, ""),
+            };
+            if !bucket.is_empty() {
+                return Ok(MimeStoreLocation {
+                    bucket: bucket.into(),
+                    prefix: prefix.into(),
+                });
+            }
+        }
+        Err(MimeStoreError::InvalidLocation(s.into()))
+    }
+}
+
+/// Server-side encryption of stored messages, parsed from "AES256" for keys managed by S3 or
+/// from "aws:kms" or "aws:kms:<key id>" for a KMS key, the AWS managed key when none is given.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::MimeStoreEncryption;
+///
+/// assert_eq!("AES256".parse(), Ok(MimeStoreEncryption::S3Managed));
+/// assert_eq!(
+///     "aws:kms:alias/archive".parse(),
+///     Ok(MimeStoreEncryption::Kms { key_id: Some("alias/archive".into()) })
+/// );
+/// assert!("none".parse::<MimeStoreEncryption>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MimeStoreEncryption {
+    /// Encrypt with keys managed by S3.
+    S3Managed,
+    /// Encrypt with a KMS key, the AWS managed key for S3 when `key_id` is `None`.
+    Kms { key_id: Option<String> },
+}
+
+impl FromStr for MimeStoreEncryption {
+    type Err = MimeStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "AES256" {
+            return Ok(MimeStoreEncryption::S3Managed);
+        }
+        match s.strip_prefix("aws:kms") {
+            Some("") => Ok(MimeStoreEncryption::Kms { key_id: None }),
+            Some(key_id) => match key_id.strip_prefix(':') {
+                Some(key_id) if !key_id.is_empty() => Ok(MimeStoreEncryption::Kms {
+                    key_id: Some(key_id.into()),
+                }),
+                _ => Err(MimeStoreError::InvalidEncryption(s.into())),
+            },
+            None => Err(MimeStoreError::InvalidEncryption(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for MimeStoreEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MimeStoreEncryption::S3Managed => write!(f, "AES256"),
+            MimeStoreEncryption::Kms { key_id: None } => write!(f, "aws:kms"),
+            MimeStoreEncryption::Kms {
+                key_id: Some(key_id),
+            } => write!(f, "aws:kms:{}", key_id),
+        }
+    }
+}
+
+/// Store rendered messages as S3 objects at `{prefix}{YYYY}/{MM}/{DD}/{EmailId}.eml`, by the
+/// date they were sent, encrypted at rest.
+#[derive(Clone, Debug)]
+pub struct S3MimeStore {
+    /// How stored messages are encrypted.
+    encryption: MimeStoreEncryption,
+    /// Where messages are stored.
+    location: MimeStoreLocation,
+    /// Connection to S3.
+    s3: S3Client,
+}
+
+impl S3MimeStore {
+    pub fn new(location: MimeStoreLocation, s3: S3Client) -> Self {
+        S3MimeStore {
+            encryption: MimeStoreEncryption::S3Managed,
+            location,
+            s3,
+        }
+    }
+
+    /// Encrypt stored messages with `encryption` rather than keys managed by S3.
+    pub fn with_encryption(self, encryption: MimeStoreEncryption) -> Self {
+        S3MimeStore { encryption, ..self }
+    }
+
+    /// Key of the object holding the message of the email identified by `email_id` sent on
+    /// `sent_on`.
+    fn key(&self, email_id: &str, sent_on: NaiveDate) -> String {
+        format!(
+            "{}{}/{}.eml",
+            self.location.prefix,
+            sent_on.format("%Y/%m/%d"),
+            email_id
+        )
+    }
+}
+
+#[async_trait]
+impl MimeStore for S3MimeStore {
+    async fn put(&self, email_id: &str, message: &MimeMessage) -> Result<S3Object, MimeStoreError> {
+        let object = S3Object {
+            bucket: self.location.bucket.clone(),
+            key: self.key(email_id, Utc::now().date_naive()),
+        };
+        let request = self
+            .s3
+            .put_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .content_type(MESSAGE_CONTENT_TYPE)
+            .body(ByteStream::from(message.raw.clone()));
+        let request = match &self.encryption {
+            MimeStoreEncryption::S3Managed => {
+                request.server_side_encryption(ServerSideEncryption::Aes256)
+            }
+            MimeStoreEncryption::Kms { key_id } => request
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .set_ssekms_key_id(key_id.clone()),
+        };
+        request
+            .send()
+            .await
+            .map_err(|e| MimeStoreError::ServiceError(format!("{}", DisplayErrorContext(&e))))?;
+        Ok(object)
+    }
+
+    async fn get(&self, location: &S3Object) -> Result<Vec<u8>, MimeStoreError> {
+        let output = match self
+            .s3
+            .get_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(SdkError::ServiceError(context))
+                if matches!(context.err(), GetObjectError::NoSuchKey(_)) =>
+            {
+                return Err(MimeStoreError::NotFound {
+                    bucket: location.bucket.clone(),
+                    key: location.key.clone(),
+                });
+            }
+            Err(error) => {
+                return Err(MimeStoreError::ServiceError(format!(
+                    "{}",
+                    DisplayErrorContext(&error)
+                )))
+            }
+        };
+        let body = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| MimeStoreError::ServiceError(e.to_string()))?;
+        Ok(body.into_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn reads_bucket_without_prefix() {
+        let location = "s3://bucket".parse::<MimeStoreLocation>().unwrap();
+        assert_eq!(location.bucket, "bucket");
+        assert_eq!(location.prefix, "");
+    }
+
+    #[test]
+    fn rejects_missing_bucket() {
+        assert_eq!(
+            "s3:///sent/".parse::<MimeStoreLocation>(),
+            Err(MimeStoreError::InvalidLocation("s3:///sent/".into()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod key {
+    use super::*;
+    use aws_sdk_s3::config::BehaviorVersion;
+
+    #[test]
+    fn partitions_by_date_sent() {
+        let s3 = S3Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let location = "s3://bucket/sent/".parse().unwrap();
+        let store = S3MimeStore::new(location, s3);
+        let sent_on = NaiveDate::from_ymd_opt(2021, 3, 7).unwrap();
+        assert_eq!(
+            store.key("Test EmailId", sent_on),
+            "sent/2021/03/07/Test EmailId.eml"
+        );
+    }
+}
+
+#[cfg(test)]
+mod encryption_from_str {
+    use super::*;
+
+    #[test]
+    fn reads_kms_without_key() {
+        let encryption = "aws:kms".parse::<MimeStoreEncryption>().unwrap();
+        assert_eq!(encryption, MimeStoreEncryption::Kms { key_id: None });
+        assert_eq!(encryption.to_string(), "aws:kms");
+    }
+
+    #[test]
+    fn rejects_empty_key() {
+        for encryption in ["aws:kms:", "aws:kmsx", "aes256"] {
+            assert_eq!(
+                encryption.parse::<MimeStoreEncryption>(),
+                Err(MimeStoreError::InvalidEncryption(encryption.into()))
+            );
+        }
+    }
+}
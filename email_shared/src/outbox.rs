@@ -0,0 +1,241 @@
+use crate::attribute_value_wrapper::AttributeValueMap;
+use crate::dynamo::to_hashmap;
+use crate::email_message::EmailId;
+use crate::error::{EnqueueError, PutError};
+use crate::fifo::MessageGroup;
+use crate::producer::EmailMessageDraft;
+use crate::queue::send_email_pointer;
+use crate::queue_url::QueueUrl;
+use crate::schema::{attribute, attribute_not_exists, email_key};
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, TransactWriteItem};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
+use std::collections::HashMap;
+use tracing::{event, Level};
+
+/// Most outbox markers relayed in one pass.
+const RELAY_BATCH_SIZE: i32 = 25;
+
+/// The outbox marker recording that a pointer for `email_id` still needs to be sent, in the FIFO
+/// message group `group_id`.
+fn outbox_marker(
+    email_id: &str,
+    created_at: String,
+    group_id: String,
+) -> HashMap<String, AttributeValue> {
+    AttributeValueMap::with_entries(vec![
+        (attribute::EMAIL_ID.into(), email_id.to_owned()),
+        (attribute::CREATED_AT.into(), created_at),
+        (attribute::MESSAGE_GROUP_ID.into(), group_id),
+    ])
+}
+
+/// Create an email record from `draft` along with an outbox marker in `outbox_table`, in a single
+/// transaction, so a pointer is sent for every email written even if the producer stops right
+/// after writing it. Pointers are sent by an `OutboxRelay` rather than the producer.
+///
+/// 1. Generate an `EmailId` for the email and validate it.
+/// 2. Write the email as `EmailStatus::Pending` and its outbox marker, or neither.
+///
+/// When the draft has an idempotency key and an email was already written for it the existing
+/// `EmailId` is returned. The marker keeps the FIFO message group `message_group` names for the
+/// email, used when the relay sends to a FIFO queue.
+#[tracing::instrument(skip(dynamodb, draft), level = Level::INFO)]
+pub async fn enqueue_email_with_outbox(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    outbox_table: &str,
+    message_group: MessageGroup,
+    draft: EmailMessageDraft,
+) -> Result<EmailId, EnqueueError> {
+    // 1. Generate an `EmailId` for the email and validate it.
+    let idempotent = draft.idempotency_key.is_some();
+    let email = draft.into_email().map_err(EnqueueError::Invalid)?;
+    let email_id = email.email_id.clone();
+    // 2. Write the email as `EmailStatus::Pending` and its outbox marker, or neither.
+    let item = to_hashmap(&email).map_err(|e| PutError::SerializeError(e.to_string()))?;
+    let email_put = Put::builder()
+        .condition_expression(attribute_not_exists(attribute::EMAIL_ID))
+        .set_item(Some(item))
+        .table_name(table_name)
+        .build()
+        .map_err(|e| PutError::SerializeError(e.to_string()))?;
+    let marker_put = Put::builder()
+        .set_item(Some(outbox_marker(
+            email_id.as_str(),
+            email.created_at.clone(),
+            message_group.group_id(&email),
+        )))
+        .table_name(outbox_table)
+        .build()
+        .map_err(|e| PutError::SerializeError(e.to_string()))?;
+    let result = dynamodb
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().put(email_put).build())
+        .transact_items(TransactWriteItem::builder().put(marker_put).build())
+        .send()
+        .await
+        .map_err(PutError::from);
+    match result {
+        Ok(_) => event!(Level::INFO, %email_id, "email written to outbox"),
+        // The marker was written along with the existing email so its pointer is already handled
+        Err(PutError::ConditionalCheckFailed(_)) if idempotent => {
+            event!(Level::INFO, %email_id, "duplicate request, email already in outbox");
+        }
+        Err(error) => return Err(error.into()),
+    }
+    Ok(email_id)
+}
+
+/// Summary of a single pass of an `OutboxRelay`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RelayReport {
+    /// Number of outbox markers read.
+    pub found: usize,
+    /// Number of markers whose pointer was sent and which were removed.
+    pub relayed: usize,
+    /// Number of markers left in the outbox to be relayed again.
+    pub failed: usize,
+}
+
+/// Send a pointer for each email with a marker in an outbox table, removing the marker once the
+/// pointer has been sent. A marker is only removed after its pointer is sent so a pointer may be
+/// sent twice but never not at all, receivers skip emails which are no longer pending.
+pub struct OutboxRelay<'a> {
+    /// Connection to DynamoDB.
+    dynamodb: &'a DynamoDbClient,
+    /// DynamoDB table holding outbox markers.
+    outbox_table: &'a str,
+    /// URL of the queue pointers are sent to.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+}
+
+impl OutboxRelay<'_> {
+    pub fn new<'a>(
+        dynamodb: &'a DynamoDbClient,
+        outbox_table: &'a str,
+        queue_url: &'a QueueUrl,
+        sqs: &'a SqsClient,
+    ) -> OutboxRelay<'a> {
+        OutboxRelay {
+            dynamodb,
+            outbox_table,
+            queue_url,
+            sqs,
+        }
+    }
+
+    /// Relay the next batch of outbox markers.
+    ///
+    /// 1. Read up to `RELAY_BATCH_SIZE` markers from the outbox table.
+    /// 2. Send a pointer for the email of each marker.
+    /// 3. Remove each marker whose pointer was sent.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn run_once(&self) -> Result<RelayReport, String> {
+        // 1. Read up to `RELAY_BATCH_SIZE` markers from the outbox table.
+        let items = self
+            .dynamodb
+            .scan()
+            .consistent_read(true)
+            .limit(RELAY_BATCH_SIZE)
+            .projection_expression(format!(
+                "{}, {}",
+                attribute::EMAIL_ID,
+                attribute::MESSAGE_GROUP_ID
+            ))
+            .table_name(self.outbox_table)
+            .send()
+            .await
+            .map_err(|error| format!("{}", DisplayErrorContext(&error)))?
+            .items
+            .unwrap_or_default();
+        let mut report = RelayReport {
+            found: items.len(),
+            ..RelayReport::default()
+        };
+        for item in items {
+            let email_id = match item.get(attribute::EMAIL_ID) {
+                Some(AttributeValue::S(email_id)) => email_id,
+                _ => {
+                    event!(Level::ERROR, ?item, "outbox marker without EmailId");
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            // Markers written before groups were kept are grouped by `EmailId`
+            let group_id = match item.get(attribute::MESSAGE_GROUP_ID) {
+                Some(AttributeValue::S(group_id)) => Some(group_id.as_str()),
+                _ => None,
+            };
+            if self
+                .relay(&EmailId::from(email_id.as_str()), group_id)
+                .await
+            {
+                report.relayed += 1;
+            } else {
+                report.failed += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Send a pointer for `email_id` in the FIFO message group `group_id` and remove its marker,
+    /// returning whether both succeeded.
+    async fn relay(&self, email_id: &EmailId, group_id: Option<&str>) -> bool {
+        // 2. Send a pointer for the email of each marker.
+        if let Err(error) = send_email_pointer(self.queue_url, self.sqs, email_id, group_id).await {
+            let error = format!("{}", DisplayErrorContext(&error));
+            event!(Level::ERROR, %email_id, %error, "email pointer not sent");
+            return false;
+        }
+        // 3. Remove each marker whose pointer was sent.
+        let result = self
+            .dynamodb
+            .delete_item()
+            .set_key(Some(email_key(email_id.as_str())))
+            .table_name(self.outbox_table)
+            .send()
+            .await;
+        match result {
+            Ok(_) => {
+                event!(Level::INFO, %email_id, "email enqueued from outbox");
+                true
+            }
+            Err(error) => {
+                // The marker is relayed again, sending a second pointer for the same email
+                let error = format!("{}", DisplayErrorContext(&error));
+                event!(Level::WARN, %email_id, %error, "outbox marker not removed");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod outbox_marker {
+    use super::*;
+
+    #[test]
+    fn keys_marker_by_email_id() {
+        let marker = outbox_marker(
+            "Test EmailId",
+            "2021-03-01T00:00:00+00:00".into(),
+            "example.com".into(),
+        );
+        assert_eq!(
+            marker.get("EmailId"),
+            Some(&AttributeValue::S("Test EmailId".into()))
+        );
+        assert_eq!(
+            marker.get("CreatedAt"),
+            Some(&AttributeValue::S("2021-03-01T00:00:00+00:00".into()))
+        );
+        assert_eq!(
+            marker.get("MessageGroupId"),
+            Some(&AttributeValue::S("example.com".into()))
+        );
+    }
+}
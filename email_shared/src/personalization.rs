@@ -0,0 +1,111 @@
+use crate::email_message::{EmailMessage, Recipient};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Name under which the merge fields of a recipient are available to templates.
+const RECIPIENT_KEY: &str = "recipient";
+
+/// A recipient of a personalized email along with the merge fields used to render their copy.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PersonalizedRecipient {
+    /// Address to which the copy is sent.
+    pub address: Recipient,
+    /// Name used to greet the recipient.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Token identifying the recipient to an unsubscribe link.
+    #[serde(default)]
+    pub unsubscribe_token: Option<String>,
+}
+
+impl PersonalizedRecipient {
+    /// Merge fields made available to templates as `recipient`.
+    fn merge_fields(&self) -> serde_json::Value {
+        json!({
+            "address": self.address,
+            "name": self.name,
+            "unsubscribe_token": self.unsubscribe_token,
+        })
+    }
+}
+
+/// Expand `email` into an individual copy for each of its personalized recipients. Each copy is
+/// addressed only to its recipient and makes the merge fields of that recipient available to its
+/// template as `recipient`, alongside the `TemplateData` of `email`.
+pub fn expand(email: &EmailMessage) -> Vec<(PersonalizedRecipient, EmailMessage)> {
+    email
+        .personalization
+        .iter()
+        .flatten()
+        .map(|recipient| {
+            let mut data = email.template_data.clone().unwrap_or_default();
+            data.insert(RECIPIENT_KEY.into(), recipient.merge_fields());
+            let copy = EmailMessage {
+                personalization: None,
+                recipients_bcc: Vec::new(),
+                recipients_cc: Vec::new(),
+                recipients_to: vec![recipient.address.clone()],
+                template_data: Some(data),
+                ..email.clone()
+            };
+            (recipient.clone(), copy)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod expand {
+    use super::*;
+    use crate::templates::Template;
+
+    fn recipient(address: &str, name: &str) -> PersonalizedRecipient {
+        PersonalizedRecipient {
+            address: address.into(),
+            name: Some(name.into()),
+            unsubscribe_token: Some(format!("token-{}", name)),
+        }
+    }
+
+    #[test]
+    fn ignores_plain_email() {
+        let email = EmailMessage {
+            recipients_to: vec!["to@example.com".into()],
+            ..EmailMessage::default()
+        };
+        assert!(expand(&email).is_empty());
+    }
+
+    #[test]
+    fn creates_copy_per_recipient() {
+        let email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            personalization: Some(vec![
+                recipient("a@example.com", "A"),
+                recipient("b@example.com", "B"),
+            ]),
+            recipients_bcc: vec!["bcc@example.com".into()],
+            template_data: json!({ "product": "Widget" }).as_object().cloned(),
+            template_id: Some("Test TemplateId".into()),
+            ..EmailMessage::default()
+        };
+        let copies = expand(&email);
+        assert_eq!(copies.len(), 2);
+        let (recipient, copy) = &copies[1];
+        assert_eq!(recipient.address, "b@example.com");
+        assert_eq!(copy.email_id, "Test EmailId");
+        assert_eq!(copy.recipients_to, vec!["b@example.com"]);
+        assert!(copy.recipients_bcc.is_empty());
+        assert!(copy.personalization.is_none());
+        let template = Template {
+            body_html: None,
+            body_text: Some(
+                "{{ recipient.name }}: {{ product }} {{ recipient.unsubscribe_token }}".into(),
+            ),
+        };
+        let (_, text) = template
+            .render(copy.template_data.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(text, "B: Widget token-B");
+    }
+}
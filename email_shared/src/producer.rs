@@ -0,0 +1,600 @@
+use crate::dynamo::{get_email_message_with, put_email_message, set_email_status, ReadOptions};
+use crate::email_message::{EmailId, EmailMessage, EmailMessageAttachment, EmailStatus};
+use crate::email_message_builder::{EmailMessageBuilder, ValidationError};
+use crate::error::{CancelError, EnqueueError, PutError};
+use crate::fifo::MessageGroup;
+use crate::personalization::PersonalizedRecipient;
+use crate::queue::{send_tenant_pointer, EmailPointerMessage};
+use crate::queue_url::QueueUrl;
+use crate::schema::attribute;
+use crate::status_machine::StatusMachine;
+use crate::templates::{TemplateData, TemplateId};
+use crate::tenants::Tenant;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::Client as SqsClient;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::time::Duration;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+/// Namespace of the version 5 UUIDs derived from idempotency keys.
+const IDEMPOTENCY_NAMESPACE: Uuid = Uuid::from_u128(0x6f1c_2a8e_93d4_4b57_a0e2_5c9d_7b31_f4a6);
+
+/// The `EmailId` of an email enqueued with `idempotency_key`, a version 5 UUID so the same key
+/// always identifies the same email.
+///
+/// ```
+/// use email_shared::idempotent_email_id;
+///
+/// let email_id = idempotent_email_id("order-1234-receipt");
+/// assert_eq!(email_id, idempotent_email_id("order-1234-receipt"));
+/// assert_ne!(email_id, idempotent_email_id("order-1235-receipt"));
+/// ```
+pub fn idempotent_email_id(idempotency_key: &str) -> EmailId {
+    let mut hasher = Sha1::new();
+    hasher.update(IDEMPOTENCY_NAMESPACE.as_bytes());
+    hasher.update(idempotency_key.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.finalize()[..16]);
+    uuid::Builder::from_sha1_bytes(bytes)
+        .into_uuid()
+        .to_string()
+        .into()
+}
+
+/// The caller provided content of an email to enqueue. Identity, status, and timestamps are
+/// assigned by `enqueue_email`. Deserialized from JSON with any missing field left empty.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EmailMessageDraft {
+    /// Attachments to include with the email message.
+    pub attachments: Vec<EmailMessageAttachment>,
+    /// The HTML email body.
+    pub body_html: String,
+    /// Markdown from which the HTML and TXT bodies are rendered when neither is set.
+    pub body_markdown: String,
+    /// The TXT email body.
+    pub body_text: String,
+    /// Names of experimental behaviors enabled for this email.
+    pub flags: Vec<String>,
+    /// Additional headers included in the message, for example `List-Unsubscribe`.
+    pub headers: Vec<(String, String)>,
+    /// Key identifying this request so a retried call enqueues the same email only once.
+    pub idempotency_key: Option<String>,
+    /// Recipients each sent an individual copy rendered with their own merge fields.
+    pub personalization: Vec<PersonalizedRecipient>,
+    /// List of recipients to BCC.
+    pub recipients_bcc: Vec<String>,
+    /// List of recipients to CC.
+    pub recipients_cc: Vec<String>,
+    /// List of recipients to send to directly.
+    pub recipients_to: Vec<String>,
+    /// Addresses replies should be sent to instead of the sender.
+    pub reply_to: Vec<String>,
+    /// Email address from which the message is sent.
+    pub sender: String,
+    /// Subject line of the email.
+    pub subject: String,
+    /// Values used to render the template identified by `template_id`.
+    pub template_data: TemplateData,
+    /// Template from which the bodies are rendered when neither body is set.
+    pub template_id: Option<TemplateId>,
+}
+
+impl EmailMessageDraft {
+    /// Validate this draft as a `Pending` `EmailMessage` identified by a new `EmailId`, or the
+    /// `EmailId` derived from its idempotency key.
+    pub fn into_email(self) -> Result<EmailMessage, Vec<ValidationError>> {
+        let email_id = match &self.idempotency_key {
+            Some(key) => idempotent_email_id(key),
+            None => Uuid::new_v4().to_string().into(),
+        };
+        self.into_email_message(email_id)
+    }
+
+    /// Validate this draft as a `Pending` `EmailMessage` identified by `email_id`.
+    fn into_email_message(self, email_id: EmailId) -> Result<EmailMessage, Vec<ValidationError>> {
+        let builder = EmailMessageBuilder::new(email_id)
+            .body_html(self.body_html)
+            .body_markdown(self.body_markdown)
+            .body_text(self.body_text)
+            .sender(self.sender)
+            .subject(self.subject);
+        let builder = self
+            .attachments
+            .into_iter()
+            .fold(builder, |b, a| b.attachment(a));
+        let builder = self.flags.into_iter().fold(builder, |b, f| b.flag(f));
+        let builder = self
+            .headers
+            .into_iter()
+            .fold(builder, |b, (name, value)| b.header(name, value));
+        let builder = self
+            .personalization
+            .into_iter()
+            .fold(builder, |b, r| b.personalized(r));
+        let builder = self
+            .recipients_bcc
+            .into_iter()
+            .fold(builder, |b, r| b.bcc(r));
+        let builder = self.recipients_cc.into_iter().fold(builder, |b, r| b.cc(r));
+        let builder = self.recipients_to.into_iter().fold(builder, |b, r| b.to(r));
+        let builder = self
+            .reply_to
+            .into_iter()
+            .fold(builder, |b, r| b.reply_to(r));
+        match self.template_id {
+            Some(template_id) => builder.template(template_id, self.template_data).build(),
+            None => builder.build(),
+        }
+    }
+}
+
+/// Create an email record from `draft` and notify receivers it is ready to be sent.
+///
+/// 1. Generate an `EmailId` for the email and validate it.
+/// 2. Write the email to DynamoDB as `EmailStatus::Pending`.
+/// 3. Send an `EmailPointer` for the new `EmailId` to SQS.
+///
+/// The record is always written before the pointer is sent so a receiver never gets a pointer to
+/// a missing record. If sending the pointer fails the `EmailId` of the written record is part of
+/// the returned `EnqueueError::SendMessageError` so a pointer can be sent again.
+///
+/// When the draft has an idempotency key and an email was already written for it the existing
+/// `EmailId` is returned without writing another record. While the existing record is still
+/// `EmailStatus::Pending` a pointer is sent again, so retrying a call which failed with
+/// `EnqueueError::SendMessageError` completes it. A duplicate pointer is skipped once the email
+/// has been claimed.
+///
+/// When `queue_url` is a FIFO queue the pointer is deduplicated by `EmailId` and grouped by the
+/// attribute `message_group` names, so duplicate calls within the deduplication interval of the
+/// queue send a single pointer.
+#[tracing::instrument(skip(dynamodb, sqs, draft), level = Level::INFO)]
+pub async fn enqueue_email(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    draft: EmailMessageDraft,
+) -> Result<EmailId, EnqueueError> {
+    enqueue(
+        dynamodb,
+        table_name,
+        sqs,
+        queue_url,
+        message_group,
+        draft,
+        None,
+    )
+    .await
+}
+
+/// Create an email record from `draft` in the table of `tenant` and send a pointer naming it as
+/// `name`, as `enqueue_email` does for the default table, so the email is sent from the sender
+/// and through the provider of the tenant.
+#[tracing::instrument(skip(dynamodb, sqs, tenant, draft), level = Level::INFO)]
+pub async fn enqueue_tenant_email(
+    dynamodb: &DynamoDbClient,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    name: &str,
+    tenant: &Tenant,
+    draft: EmailMessageDraft,
+) -> Result<EmailId, EnqueueError> {
+    let table_name = tenant.table_name.as_str();
+    enqueue(
+        dynamodb,
+        table_name,
+        sqs,
+        queue_url,
+        message_group,
+        draft,
+        Some(name),
+    )
+    .await
+}
+
+/// Write the record of `draft` to `table_name` and send a pointer naming `tenant`.
+async fn enqueue(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    draft: EmailMessageDraft,
+    tenant: Option<&str>,
+) -> Result<EmailId, EnqueueError> {
+    // 1. Generate an `EmailId` for the email and validate it.
+    let idempotent = draft.idempotency_key.is_some();
+    let email = draft.into_email().map_err(EnqueueError::Invalid)?;
+    let email_id = email.email_id.clone();
+    let group_id = message_group.group_id(&email);
+    // 2. Write the email to DynamoDB as `EmailStatus::Pending`.
+    match put_email_message(dynamodb, table_name, &email).await {
+        Ok(()) => event!(Level::DEBUG, %email_id, "email record written"),
+        Err(PutError::ConditionalCheckFailed(_)) if idempotent => {
+            // The pointer of the first request may never have been sent
+            let pointer = EmailPointerMessage::unqueued(email_id.as_str());
+            let options = ReadOptions::default()
+                .with_attributes(&[attribute::EMAIL_STATUS])
+                .with_consistent_read(true);
+            let existing = get_email_message_with(dynamodb, table_name, &pointer, &options).await?;
+            if existing.status != EmailStatus::Pending {
+                event!(Level::INFO, %email_id, status = %existing.status, "duplicate request, email already enqueued");
+                return Ok(email_id);
+            }
+            event!(Level::INFO, %email_id, "duplicate request, email still Pending so pointer sent again");
+        }
+        Err(error) => return Err(error.into()),
+    }
+    // 3. Send an `EmailPointer` for the new `EmailId` to SQS.
+    match send_tenant_pointer(queue_url, sqs, &email_id, Some(&group_id), tenant).await {
+        Ok(_) => {
+            event!(Level::INFO, %email_id, "email enqueued");
+            Ok(email_id)
+        }
+        Err(error) => {
+            let message = format!("{}", DisplayErrorContext(&error));
+            event!(Level::ERROR, %email_id, %message, "email pointer not sent");
+            Err(EnqueueError::SendMessageError { email_id, message })
+        }
+    }
+}
+
+/// Return the email identified by `email_id` to `EmailStatus::Pending` and send a pointer for it
+/// so it is attempted again, for example once the cause of its failure is fixed.
+///
+/// 1. Read the email and check the `StatusMachine` allows it to become `EmailStatus::Pending`.
+/// 2. Set the email `EmailStatus::Pending`.
+/// 3. Send an `EmailPointer` for the email to SQS.
+///
+/// An email which may not become `EmailStatus::Pending`, such as one already sent, fails with
+/// `UpdateError::IllegalTransition` and nothing is written or sent.
+#[tracing::instrument(skip(dynamodb, sqs), level = Level::INFO)]
+pub async fn requeue_email(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    email_id: &EmailId,
+) -> Result<(), EnqueueError> {
+    requeue(
+        dynamodb,
+        table_name,
+        sqs,
+        queue_url,
+        message_group,
+        email_id,
+        None,
+    )
+    .await
+}
+
+/// Return the email identified by `email_id` in the table of `tenant` to `EmailStatus::Pending`
+/// and send a pointer naming it as `name`, as `requeue_email` does for the default table.
+#[tracing::instrument(skip(dynamodb, sqs, tenant), level = Level::INFO)]
+pub async fn requeue_tenant_email(
+    dynamodb: &DynamoDbClient,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    name: &str,
+    tenant: &Tenant,
+    email_id: &EmailId,
+) -> Result<(), EnqueueError> {
+    requeue(
+        dynamodb,
+        tenant.table_name.as_str(),
+        sqs,
+        queue_url,
+        message_group,
+        email_id,
+        Some(name),
+    )
+    .await
+}
+
+/// Return the email identified by `email_id` in `table_name` to `EmailStatus::Pending` and send a
+/// pointer naming `tenant`.
+async fn requeue(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    message_group: MessageGroup,
+    email_id: &EmailId,
+    tenant: Option<&str>,
+) -> Result<(), EnqueueError> {
+    // 1. Read the email and check the `StatusMachine` allows it to become `EmailStatus::Pending`.
+    let pointer = EmailPointerMessage::unqueued(email_id.as_str());
+    let options = ReadOptions::default().with_consistent_read(true);
+    let email = get_email_message_with(dynamodb, table_name, &pointer, &options).await?;
+    let transition = StatusMachine::transition(email.status, EmailStatus::Pending)?;
+    // 2. Set the email `EmailStatus::Pending`.
+    set_email_status(
+        dynamodb,
+        table_name,
+        &pointer,
+        email.version,
+        transition,
+        None,
+    )
+    .await?;
+    event!(Level::DEBUG, %email_id, from = %email.status, "email returned to Pending");
+    // 3. Send an `EmailPointer` for the email to SQS.
+    let group_id = message_group.group_id(&email);
+    match send_tenant_pointer(queue_url, sqs, email_id, Some(&group_id), tenant).await {
+        Ok(_) => {
+            event!(Level::INFO, %email_id, "email requeued");
+            Ok(())
+        }
+        Err(error) => {
+            let message = format!("{}", DisplayErrorContext(&error));
+            event!(Level::ERROR, %email_id, %message, "email pointer not sent");
+            Err(EnqueueError::SendMessageError {
+                email_id: email_id.clone(),
+                message,
+            })
+        }
+    }
+}
+
+/// Retract the email identified by `email_id` so it is never sent. Its record is set
+/// `EmailStatus::Cancelled` and a pointer to it received later is deleted without sending. With a
+/// `retention` the record expires that long after it is cancelled.
+///
+/// 1. Read the email and check the `StatusMachine` allows it to become `EmailStatus::Cancelled`.
+/// 2. Set the email `EmailStatus::Cancelled` unless it changed since it was read.
+///
+/// Only an email still `EmailStatus::Pending` may be cancelled, any other fails with
+/// `UpdateError::IllegalTransition` and nothing is written. An email claimed by a delivery after
+/// it was read fails with `UpdateError::VersionConflict`.
+#[tracing::instrument(skip(dynamodb), level = Level::INFO)]
+pub async fn cancel_email(
+    dynamodb: &DynamoDbClient,
+    table_name: &str,
+    email_id: &EmailId,
+    retention: Option<Duration>,
+) -> Result<(), CancelError> {
+    // 1. Read the email and check the `StatusMachine` allows it to become `EmailStatus::Cancelled`.
+    let pointer = EmailPointerMessage::unqueued(email_id.as_str());
+    let options = ReadOptions::default()
+        .with_attributes(&[attribute::EMAIL_STATUS])
+        .with_consistent_read(true);
+    let email = get_email_message_with(dynamodb, table_name, &pointer, &options).await?;
+    let transition = StatusMachine::transition(email.status, EmailStatus::Cancelled)?;
+    // 2. Set the email `EmailStatus::Cancelled` unless it changed since it was read.
+    set_email_status(
+        dynamodb,
+        table_name,
+        &pointer,
+        email.version,
+        transition,
+        retention,
+    )
+    .await?;
+    event!(Level::INFO, %email_id, "email cancelled");
+    Ok(())
+}
+
+#[cfg(test)]
+mod into_email_message {
+    use super::*;
+    use crate::email_message::EmailStatus;
+
+    #[test]
+    fn assigns_identity_status_and_timestamps() {
+        let draft = EmailMessageDraft {
+            body_text: "Test Body".into(),
+            recipients_to: vec!["to@example.com".into()],
+            sender: "from@example.com".into(),
+            subject: "Test Subject".into(),
+            ..EmailMessageDraft::default()
+        };
+        let email = draft.into_email_message("Test EmailId".into()).unwrap();
+        assert_eq!(email.email_id, "Test EmailId");
+        assert_eq!(email.status, EmailStatus::Pending);
+        assert!(!email.created_at.is_empty());
+        assert_eq!(email.created_at, email.updated_at);
+        assert_eq!(email.body_text, "Test Body");
+        assert_eq!(email.recipients_to, vec!["to@example.com"]);
+        assert_eq!(email.sender, "from@example.com");
+        assert_eq!(email.subject, "Test Subject");
+        assert_eq!(email.provider_response, None);
+        assert_eq!(email.sent_at, None);
+    }
+
+    #[test]
+    fn derives_email_id_from_idempotency_key() {
+        let draft = EmailMessageDraft {
+            body_text: "Test Body".into(),
+            idempotency_key: Some("Test Key".into()),
+            recipients_to: vec!["to@example.com".into()],
+            sender: "from@example.com".into(),
+            subject: "Test Subject".into(),
+            ..EmailMessageDraft::default()
+        };
+        let first = draft.clone().into_email().unwrap();
+        let second = draft.into_email().unwrap();
+        assert_eq!(first.email_id, second.email_id);
+        assert_eq!(first.email_id, idempotent_email_id("Test Key"));
+        let uuid = Uuid::parse_str(first.email_id.as_str()).unwrap();
+        assert_eq!(uuid.get_version_num(), 5);
+    }
+
+    #[test]
+    fn rejects_invalid_draft() {
+        let draft = EmailMessageDraft {
+            recipients_to: vec!["to@example.com".into()],
+            sender: "from@example.com".into(),
+            ..EmailMessageDraft::default()
+        };
+        let errors = draft.into_email_message("Test EmailId".into()).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::MissingSubject,
+                ValidationError::MissingBody
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod enqueue_email {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+    use aws_sdk_sqs::config::BehaviorVersion;
+
+    fn draft() -> EmailMessageDraft {
+        EmailMessageDraft {
+            body_text: "Test Body".into(),
+            idempotency_key: Some("Test IdempotencyKey".into()),
+            recipients_to: vec!["to@example.com".into()],
+            sender: "from@example.com".into(),
+            subject: "Test Subject".into(),
+            ..EmailMessageDraft::default()
+        }
+    }
+
+    /// Enqueue `draft` when a record for its idempotency key already has `status`. No SQS
+    /// endpoint answers in tests so any pointer sent fails with `SendMessageError`.
+    async fn enqueue_duplicate(
+        status: EmailStatus,
+    ) -> (Result<EmailId, EnqueueError>, InMemoryDynamoDb) {
+        let email = draft().into_email().unwrap();
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage { status, ..email });
+        let sqs = SqsClient::from_conf(
+            aws_sdk_sqs::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let queue_url = "http://localhost:4566/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        let enqueued = enqueue_email(
+            &table.client(),
+            "Test Table",
+            &sqs,
+            &queue_url,
+            MessageGroup::EmailId,
+            draft(),
+        )
+        .await;
+        (enqueued, table)
+    }
+
+    #[tokio::test]
+    async fn resends_pointers_of_pending_duplicates() {
+        let (enqueued, table) = enqueue_duplicate(EmailStatus::Pending).await;
+        assert!(matches!(
+            enqueued,
+            Err(EnqueueError::SendMessageError { .. })
+        ));
+        assert_eq!(table.calls("GetItem"), 1);
+    }
+
+    #[tokio::test]
+    async fn returns_duplicates_already_claimed() {
+        let email_id = draft().into_email().unwrap().email_id;
+        let (enqueued, table) = enqueue_duplicate(EmailStatus::Sent).await;
+        assert_eq!(enqueued, Ok(email_id.clone()));
+        assert_eq!(table.status(email_id.as_str()).as_deref(), Some("Sent"));
+    }
+}
+
+#[cfg(test)]
+mod requeue_email {
+    use super::*;
+    use crate::error::UpdateError;
+    use crate::test_support::InMemoryDynamoDb;
+    use aws_sdk_sqs::config::BehaviorVersion;
+
+    #[tokio::test]
+    async fn refuses_sent_emails() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            email_id: "Test EmailId".into(),
+            status: EmailStatus::Sent,
+            ..EmailMessage::default()
+        });
+        let sqs = SqsClient::from_conf(
+            aws_sdk_sqs::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let queue_url = "http://localhost:4566/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        let requeued = requeue_email(
+            &table.client(),
+            "Test Table",
+            &sqs,
+            &queue_url,
+            MessageGroup::EmailId,
+            &EmailId::from("Test EmailId"),
+        )
+        .await;
+        assert!(matches!(
+            requeued,
+            Err(EnqueueError::UpdateError(UpdateError::IllegalTransition(_)))
+        ));
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sent"));
+    }
+}
+
+#[cfg(test)]
+mod cancel_email {
+    use super::*;
+    use crate::error::UpdateError;
+    use crate::test_support::InMemoryDynamoDb;
+
+    fn email(status: EmailStatus) -> EmailMessage {
+        EmailMessage {
+            email_id: "Test EmailId".into(),
+            status,
+            ..EmailMessage::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn cancels_pending_emails() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email(EmailStatus::Pending));
+        let cancelled = cancel_email(
+            &table.client(),
+            "Test Table",
+            &EmailId::from("Test EmailId"),
+            None,
+        )
+        .await;
+        assert_eq!(cancelled, Ok(()));
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Cancelled"));
+    }
+
+    #[tokio::test]
+    async fn refuses_emails_being_sent() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email(EmailStatus::Sending));
+        let cancelled = cancel_email(
+            &table.client(),
+            "Test Table",
+            &EmailId::from("Test EmailId"),
+            None,
+        )
+        .await;
+        assert!(matches!(
+            cancelled,
+            Err(CancelError::UpdateError(UpdateError::IllegalTransition(_)))
+        ));
+        assert_eq!(table.calls("UpdateItem"), 0);
+        assert_eq!(table.status("Test EmailId").as_deref(), Some("Sending"));
+    }
+}
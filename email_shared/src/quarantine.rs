@@ -0,0 +1,233 @@
+use crate::config::REDACTED;
+use crate::mime_store::{MimeStoreError, MimeStoreLocation};
+use crate::queue::PointerError;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_sqs::types::Message;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Fields kept when no allowlist is configured.
+const DEFAULT_ALLOWED_FIELDS: [&str; 1] = ["email_id"];
+
+/// Which fields of a quarantined message are kept as received. Every other string in the body
+/// and value of a message attribute is replaced with `REDACTED` so stored messages can be read
+/// without exposing recipients or other personal data.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::QuarantineRedaction;
+///
+/// let redaction = QuarantineRedaction::default();
+/// assert_eq!(
+///     redaction.redact_body(r#"{"email_id":"abc","to":"a@example.com","retries":2}"#),
+///     r#"{"email_id":"abc","retries":2,"to":"<redacted>"}"#,
+/// );
+/// assert_eq!(redaction.redact_body("a@example.com"), "<redacted>");
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuarantineRedaction {
+    /// Names of fields and message attributes kept as received.
+    allow_fields: BTreeSet<String>,
+}
+
+impl Default for QuarantineRedaction {
+    fn default() -> Self {
+        QuarantineRedaction::new(DEFAULT_ALLOWED_FIELDS.iter())
+    }
+}
+
+impl QuarantineRedaction {
+    pub fn new<I, S>(allow_fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        QuarantineRedaction {
+            allow_fields: allow_fields
+                .into_iter()
+                .map(|field| field.as_ref().trim().to_owned())
+                .filter(|field| !field.is_empty())
+                .collect(),
+        }
+    }
+
+    /// `body` with every string not held by an allowed field replaced. A body which is not JSON
+    /// is replaced entirely.
+    pub fn redact_body(&self, body: &str) -> String {
+        match serde_json::from_str::<Value>(body) {
+            Ok(value) => self.redact_value(value).to_string(),
+            Err(_) => REDACTED.to_owned(),
+        }
+    }
+
+    /// `value` with every string not held by an allowed field replaced.
+    fn redact_value(&self, value: Value) -> Value {
+        match value {
+            Value::String(_) => Value::String(REDACTED.into()),
+            Value::Array(values) => {
+                Value::Array(values.into_iter().map(|v| self.redact_value(v)).collect())
+            }
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = if self.allow_fields.contains(&name) {
+                            value
+                        } else {
+                            self.redact_value(value)
+                        };
+                        (name, value)
+                    })
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    /// The record stored for `message` which could not be processed because of `error`. The
+    /// receipt handle is never included.
+    pub fn record(&self, message: &Message, error: &PointerError) -> QuarantineRecord {
+        let attributes = message
+            .message_attributes()
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| {
+                let value = match value.string_value() {
+                    Some(value) if self.allow_fields.contains(name) => value.to_owned(),
+                    _ => REDACTED.to_owned(),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        QuarantineRecord {
+            message_id: message.message_id().map(String::from),
+            error: error.to_string(),
+            body: message.body().map(|body| self.redact_body(body)),
+            attributes,
+            quarantined_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// A message which could not be processed, as stored for later debugging.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct QuarantineRecord {
+    /// Id of the SQS message.
+    pub message_id: Option<String>,
+    /// Why the message could not be processed.
+    pub error: String,
+    /// Body of the message with fields not on the allowlist redacted.
+    pub body: Option<String>,
+    /// Message attributes with values not on the allowlist redacted.
+    pub attributes: BTreeMap<String, String>,
+    /// When the message was quarantined.
+    pub quarantined_at: String,
+}
+
+/// Store quarantined messages as S3 objects at `{prefix}{MessageId}.json`.
+#[derive(Clone, Debug)]
+pub struct S3QuarantineStore {
+    /// Where records are stored.
+    location: MimeStoreLocation,
+    /// Fields kept as received.
+    redaction: QuarantineRedaction,
+    /// Connection to S3.
+    s3: S3Client,
+}
+
+impl S3QuarantineStore {
+    pub fn new(location: MimeStoreLocation, redaction: QuarantineRedaction, s3: S3Client) -> Self {
+        S3QuarantineStore {
+            location,
+            redaction,
+            s3,
+        }
+    }
+
+    /// Store the redacted record of `message`, returning the key it was stored at.
+    pub async fn put(
+        &self,
+        message: &Message,
+        error: &PointerError,
+    ) -> Result<String, MimeStoreError> {
+        let record = self.redaction.record(message, error);
+        let name = record
+            .message_id
+            .clone()
+            .unwrap_or_else(|| format!("unknown-{}", record.quarantined_at));
+        let key = format!("{}{}.json", self.location.prefix, name);
+        let body =
+            serde_json::to_vec(&record).map_err(|e| MimeStoreError::ServiceError(e.to_string()))?;
+        self.s3
+            .put_object()
+            .bucket(&self.location.bucket)
+            .key(&key)
+            .content_type("application/json")
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(|e| MimeStoreError::ServiceError(format!("{}", DisplayErrorContext(&e))))?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod record {
+    use super::*;
+    use aws_sdk_sqs::types::MessageAttributeValue;
+
+    #[test]
+    fn omits_receipt_handle_and_redacts() {
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email":"a@example.com","nested":[{"email_id":"abc"}]}"#)
+            .message_attributes(
+                "Tenant",
+                MessageAttributeValue::builder()
+                    .data_type("String")
+                    .string_value("acme")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let redaction = QuarantineRedaction::new(vec!["email_id", "Tenant"]);
+        let record = redaction.record(&message, &PointerError::InvalidBody);
+        assert_eq!(record.message_id.as_deref(), Some("Test MessageId"));
+        assert_eq!(
+            record.body.as_deref(),
+            Some(r#"{"email":"<redacted>","nested":[{"email_id":"abc"}]}"#)
+        );
+        assert_eq!(
+            record.attributes.get("Tenant").map(String::as_str),
+            Some("acme")
+        );
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(!json.contains("Test ReceiptHandle"));
+    }
+
+    #[test]
+    fn redacts_attributes_not_allowed() {
+        let message = Message::builder()
+            .message_attributes(
+                "Recipient",
+                MessageAttributeValue::builder()
+                    .data_type("String")
+                    .string_value("a@example.com")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let record = QuarantineRedaction::default().record(&message, &PointerError::InvalidBody);
+        assert_eq!(
+            record.attributes.get("Recipient").map(String::as_str),
+            Some(REDACTED)
+        );
+        assert_eq!(record.body, None);
+    }
+}
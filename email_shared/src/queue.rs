@@ -1,56 +1,180 @@
-use rusoto_core::RusotoError;
-use rusoto_sqs::{
-    DeleteMessageBatchRequestEntry, Message, ReceiveMessageError, ReceiveMessageRequest, Sqs,
-    SqsClient,
+use crate::config::REDACTED;
+use crate::email_message::EmailId;
+use crate::fifo::deduplication_id;
+use crate::queue_url::QueueUrl;
+use crate::telemetry::{trace_context_attributes, TRACE_CONTEXT_ATTRIBUTES};
+use aws_sdk_sqs::error::SdkError;
+use aws_sdk_sqs::operation::receive_message::ReceiveMessageError;
+use aws_sdk_sqs::operation::send_message::{SendMessageError, SendMessageOutput};
+use aws_sdk_sqs::types::{
+    ChangeMessageVisibilityBatchRequestEntry, DeleteMessageBatchRequestEntry, Message,
+    MessageSystemAttributeName,
 };
-use serde::Deserialize;
-use serde_json;
+use aws_sdk_sqs::Client as SqsClient;
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::Span;
+use uuid::Uuid;
 
-#[derive(Deserialize, Debug)]
+/// Seconds a message stays hidden after it is received.
+pub(crate) const VISIBILITY_TIMEOUT: i32 = 30;
+/// Seconds a receive waits for a message to arrive when the queue is empty.
+pub(crate) const RECEIVE_WAIT_TIME_SECONDS: i32 = 20;
+/// Seconds a message to retry stays hidden after each receive, the last entry is used for every
+/// later receive.
+const RETRY_VISIBILITY_TIMEOUTS: [i32; 3] = [60, 5 * 60, 15 * 60];
+/// Longest SQS delays the delivery of a message sent to a queue, fifteen minutes.
+const MAX_DELAY_SECONDS: u64 = 15 * 60;
+/// Longest SQS keeps a received message hidden, twelve hours.
+const MAX_VISIBILITY_TIMEOUT: i32 = 12 * 60 * 60;
+
+#[derive(Deserialize, Debug, Serialize)]
 struct EmailPointer {
-    email_id: String,
+    email_id: EmailId,
+    /// Name of the `Tenant` the email belongs to, `None` for emails in the default table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+}
+
+/// SNS notification wrapping the pointer when a topic delivers to the queue without raw message
+/// delivery, or delivers to the Lambda directly.
+#[derive(Deserialize)]
+struct SnsEnvelope {
+    #[serde(rename = "Message")]
+    message: String,
 }
 
 impl EmailPointer {
+    /// The pointer in `json`, or in the SNS notification `json` holds, `None` when it is not a
+    /// pointer or its `email_id` is not valid.
     fn from_json(json: String) -> Option<EmailPointer> {
-        serde_json::from_str(&json).ok()
+        let json = match serde_json::from_str::<SnsEnvelope>(&json) {
+            Ok(envelope) => envelope.message,
+            Err(_) => json,
+        };
+        serde_json::from_str(&json)
+            .ok()
+            .filter(|pointer: &EmailPointer| pointer.email_id.validate().is_ok())
     }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("EmailPointer is always representable as JSON")
+    }
+}
+
+/// Reasons an SQS `Message` can not be read as an `EmailPointerMessage`.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum PointerError {
+    /// The message has no id.
+    #[error("No message id was found")]
+    MissingMessageId,
+    /// The message has no receipt handle.
+    #[error("No receipt handle for message")]
+    MissingReceiptHandle,
+    /// The body of the message is not an `EmailPointer`.
+    #[error("Unable to parse EmailPointer.")]
+    InvalidBody,
+    /// The pointer names a tenant which is not configured.
+    #[error("EmailPointer names an unknown tenant.")]
+    UnknownTenant,
 }
 
-#[derive(Clone, Debug)]
+/// An `EmailPointer` along with the SQS `Message` identifiers needed to delete it.
+#[derive(Clone)]
 pub struct EmailPointerMessage {
     message_id: String,
     handle: String,
-    pub email_id: String,
+    pub email_id: EmailId,
+    /// Name of the `Tenant` whose table holds the email, `None` for the default table.
+    pub tenant: Option<String>,
+    /// Number of times the message has been received, including this time.
+    pub receive_count: u32,
+    /// Milliseconds since the epoch when the message was sent to the queue.
+    pub sent_timestamp: Option<u64>,
 }
 
 impl EmailPointerMessage {
     pub fn from_message(message: Message) -> Option<EmailPointerMessage> {
         EmailPointerMessage::try_from(message).ok()
     }
+
+    /// Id of the SQS message.
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// Identifies this delivery of the message as the holder of the claim on its email. Copies
+    /// of a pointer sent more than once and later deliveries of the same message each differ.
+    pub fn claim_id(&self) -> String {
+        format!("{}/{}", self.message_id, self.receive_count)
+    }
 }
 
 impl TryFrom<Message> for EmailPointerMessage {
-    type Error = &'static str;
+    type Error = PointerError;
 
     fn try_from(message: Message) -> Result<Self, Self::Error> {
+        let attribute = |name: MessageSystemAttributeName| {
+            message
+                .attributes
+                .as_ref()
+                .and_then(|attributes| attributes.get(&name))
+                .cloned()
+        };
+        let receive_count = attribute(MessageSystemAttributeName::ApproximateReceiveCount)
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1);
+        let sent_timestamp = attribute(MessageSystemAttributeName::SentTimestamp)
+            .and_then(|timestamp| timestamp.parse().ok());
         let id = message.message_id;
         let handle = message.receipt_handle;
-        let body = message.body.map(EmailPointer::from_json).flatten();
+        let body = message.body.and_then(EmailPointer::from_json);
         match (id, handle, body) {
             (Some(id), Some(handle), Some(pointer)) => Ok(EmailPointerMessage {
                 message_id: id,
                 handle,
                 email_id: pointer.email_id,
+                tenant: pointer.tenant,
+                receive_count,
+                sent_timestamp,
             }),
-            (None, _, _) => Err("No message id was found"),
-            (Some(_), None, _) => Err("No receipt handle for message"),
-            (Some(_), Some(_), None) => Err("Unable to parse EmailPointer."),
+            (None, _, _) => Err(PointerError::MissingMessageId),
+            (Some(_), None, _) => Err(PointerError::MissingReceiptHandle),
+            (Some(_), Some(_), None) => Err(PointerError::InvalidBody),
+        }
+    }
+}
+
+impl EmailPointerMessage {
+    /// A pointer to `email_id` which was never sent to a queue, used to process an email directly.
+    pub(crate) fn unqueued(email_id: &str) -> Self {
+        EmailPointerMessage {
+            message_id: format!("unqueued-{}", email_id),
+            handle: String::new(),
+            email_id: email_id.into(),
+            tenant: None,
+            receive_count: 1,
+            sent_timestamp: None,
         }
     }
 }
 
+/// The receipt handle grants control of the message so it is never written to logs.
+impl std::fmt::Debug for EmailPointerMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("EmailPointerMessage")
+            .field("message_id", &self.message_id)
+            .field("handle", &REDACTED)
+            .field("email_id", &self.email_id)
+            .field("tenant", &self.tenant)
+            .field("receive_count", &self.receive_count)
+            .field("sent_timestamp", &self.sent_timestamp)
+            .finish()
+    }
+}
+
 impl std::fmt::Display for EmailPointerMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("EmailIdMessage")
@@ -62,27 +186,424 @@ impl std::fmt::Display for EmailPointerMessage {
 
 impl From<&EmailPointerMessage> for DeleteMessageBatchRequestEntry {
     fn from(message: &EmailPointerMessage) -> Self {
-        DeleteMessageBatchRequestEntry {
-            id: message.message_id.clone(),
-            receipt_handle: message.handle.clone(),
+        delete_entry(message.message_id.clone(), message.handle.clone())
+    }
+}
+
+impl EmailPointerMessage {
+    /// How long before `now` the message was sent to the queue, if known.
+    pub fn age(&self, now: SystemTime) -> Option<Duration> {
+        let sent_at = UNIX_EPOCH + Duration::from_millis(self.sent_timestamp?);
+        Some(now.duration_since(sent_at).unwrap_or_default())
+    }
+
+    /// Seconds until the message should be delivered again after a temporary failure. The delay
+    /// grows with the number of receives, one, five, then fifteen minutes, so a message which
+    /// keeps failing does not keep a receiver busy.
+    pub fn retry_visibility_timeout(&self) -> i32 {
+        let index = (self.receive_count.saturating_sub(1) as usize)
+            .min(RETRY_VISIBILITY_TIMEOUTS.len() - 1);
+        RETRY_VISIBILITY_TIMEOUTS[index]
+    }
+
+    /// Entry hiding the message until `delay` has passed, or for the longest SQS allows when
+    /// `delay` is longer, after which the message is received and deferred again.
+    pub(crate) fn defer_entry(&self, delay: Duration) -> ChangeMessageVisibilityBatchRequestEntry {
+        let seconds = i32::try_from(delay.as_secs()).unwrap_or(i32::MAX);
+        self.visibility_entry(seconds.clamp(1, MAX_VISIBILITY_TIMEOUT))
+    }
+
+    /// Entry hiding the message for `visibility_timeout` seconds.
+    fn visibility_entry(
+        &self,
+        visibility_timeout: i32,
+    ) -> ChangeMessageVisibilityBatchRequestEntry {
+        ChangeMessageVisibilityBatchRequestEntry::builder()
+            .id(self.message_id.clone())
+            .receipt_handle(self.handle.clone())
+            .visibility_timeout(visibility_timeout)
+            .build()
+            .expect("id and receipt_handle are always set")
+    }
+}
+
+impl From<&EmailPointerMessage> for ChangeMessageVisibilityBatchRequestEntry {
+    fn from(message: &EmailPointerMessage) -> Self {
+        message.visibility_entry(message.retry_visibility_timeout())
+    }
+}
+
+/// Create a `DeleteMessageBatchRequestEntry` for the message identified by `id` and
+/// `receipt_handle`. Both required fields are always provided so building the entry can not fail.
+pub(crate) fn delete_entry(id: String, receipt_handle: String) -> DeleteMessageBatchRequestEntry {
+    DeleteMessageBatchRequestEntry::builder()
+        .id(id)
+        .receipt_handle(receipt_handle)
+        .build()
+        .expect("id and receipt_handle are always set")
+}
+
+/// `ReceiveRequestAttemptId` of the receives from a FIFO queue. The id of a failed receive is
+/// used again by the next receive, so a batch SQS handed out but which never arrived is delivered
+/// again intact instead of waiting out its visibility timeout. A successful receive starts a new
+/// attempt.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReceiveAttempt {
+    /// Whether receives carry an attempt id, only FIFO queues accept one.
+    enabled: bool,
+    /// Id of the attempt not yet received successfully.
+    pending: Option<String>,
+}
+
+impl ReceiveAttempt {
+    pub(crate) fn new(enabled: bool) -> Self {
+        ReceiveAttempt {
+            enabled,
+            pending: None,
+        }
+    }
+
+    /// Id the next receive is made with, the id of the last receive when it failed.
+    pub(crate) fn id(&mut self) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let id = self
+            .pending
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+        Some(id.clone())
+    }
+
+    /// Record whether the receive made with the current id `succeeded`.
+    pub(crate) fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            self.pending = None;
         }
     }
 }
 
 /// Poll SQS at the given `queue_url` for new messages providing an iterator for `EmailIdMessage`.
 pub async fn get_sqs_email_messages(
-    queue_url: &str,
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+) -> Result<Vec<Message>, SdkError<ReceiveMessageError>> {
+    receive_messages(
+        queue_url,
+        sqs,
+        RECEIVE_WAIT_TIME_SECONDS,
+        VISIBILITY_TIMEOUT,
+        None,
+    )
+    .await
+}
+
+/// Receive messages from the SQS queue at `queue_url`, waiting up to `wait_time_seconds` for one
+/// to arrive, 0 returning right away when the queue is empty, and hiding each for
+/// `visibility_timeout` seconds. A FIFO queue given `attempt_id` returns the same batch as an
+/// earlier receive with that id.
+pub(crate) async fn receive_messages(
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+    wait_time_seconds: i32,
+    visibility_timeout: i32,
+    attempt_id: Option<String>,
+) -> Result<Vec<Message>, SdkError<ReceiveMessageError>> {
+    sqs.receive_message()
+        .message_system_attribute_names(MessageSystemAttributeName::ApproximateReceiveCount)
+        .message_system_attribute_names(MessageSystemAttributeName::MessageGroupId)
+        .message_system_attribute_names(MessageSystemAttributeName::SentTimestamp)
+        .set_message_attribute_names(Some(
+            TRACE_CONTEXT_ATTRIBUTES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        ))
+        .max_number_of_messages(1)
+        .queue_url(queue_url.as_str())
+        .set_receive_request_attempt_id(attempt_id)
+        .visibility_timeout(visibility_timeout)
+        .wait_time_seconds(wait_time_seconds)
+        .send()
+        .await
+        .map(|result| result.messages.unwrap_or_default())
+}
+
+/// Send an `EmailPointer` for `email_id` to the SQS queue at `queue_url` so the associated email
+/// will be picked up by a receiver and transmitted. The trace context of the current span is sent
+/// along in the message attributes so the delivery is traced as part of the same request.
+///
+/// On a FIFO queue the `MessageDeduplicationId` is derived from `email_id`, so pointers to the
+/// same email sent within the deduplication interval collapse into one, and the
+/// `MessageGroupId` is `group_id`, or `email_id` when not given. Both are ignored otherwise.
+pub async fn send_email_pointer(
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+    email_id: &EmailId,
+    group_id: Option<&str>,
+) -> Result<SendMessageOutput, SdkError<SendMessageError>> {
+    send_tenant_pointer(queue_url, sqs, email_id, group_id, None).await
+}
+
+/// Send an `EmailPointer` for `email_id` as `send_email_pointer` does, naming `tenant` so the
+/// email is read from the table of that `Tenant` rather than the default table.
+pub async fn send_tenant_pointer(
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+    email_id: &EmailId,
+    group_id: Option<&str>,
+    tenant: Option<&str>,
+) -> Result<SendMessageOutput, SdkError<SendMessageError>> {
+    let pointer = EmailPointer {
+        email_id: email_id.clone(),
+        tenant: tenant.map(String::from),
+    };
+    send_pointer(queue_url, sqs, &pointer, group_id, None).await
+}
+
+/// Send a pointer naming the same email and tenant as `pointer` to the queue at `queue_url`,
+/// for a delivery which can not be hidden and received again itself, such as an SNS
+/// notification. The new message is delayed by `delay`, up to the fifteen minutes SQS allows,
+/// so an email scheduled later is not received again right away. A FIFO queue does not take a
+/// delay for each message, so its pointer is received right away and deferred again.
+pub async fn forward_pointer(
+    queue_url: &QueueUrl,
     sqs: &SqsClient,
-) -> Result<Vec<Message>, RusotoError<ReceiveMessageError>> {
-    let request = ReceiveMessageRequest {
-        attribute_names: Some(vec![String::from("MessageGroupId")]),
-        max_number_of_messages: Some(1),
-        queue_url: queue_url.into(),
-        visibility_timeout: Some(30),
-        wait_time_seconds: Some(20),
-        ..ReceiveMessageRequest::default()
+    pointer: &EmailPointerMessage,
+    delay: Option<Duration>,
+) -> Result<SendMessageOutput, SdkError<SendMessageError>> {
+    let forwarded = EmailPointer {
+        email_id: pointer.email_id.clone(),
+        tenant: pointer.tenant.clone(),
     };
-    sqs.receive_message(request)
+    send_pointer(queue_url, sqs, &forwarded, None, delay).await
+}
+
+/// Send `pointer` to the queue at `queue_url`, delayed by `delay` on a queue which is not a FIFO
+/// queue.
+async fn send_pointer(
+    queue_url: &QueueUrl,
+    sqs: &SqsClient,
+    pointer: &EmailPointer,
+    group_id: Option<&str>,
+    delay: Option<Duration>,
+) -> Result<SendMessageOutput, SdkError<SendMessageError>> {
+    let email_id = &pointer.email_id;
+    let attributes = trace_context_attributes(&Span::current());
+    let (deduplication_id, group_id) = if queue_url.is_fifo() {
+        (
+            Some(deduplication_id(email_id.as_str())),
+            Some(group_id.map_or_else(|| deduplication_id(email_id.as_str()), String::from)),
+        )
+    } else {
+        (None, None)
+    };
+    let delay_seconds = delay.and_then(|delay| delay_seconds(queue_url, delay));
+    sqs.send_message()
+        .message_body(pointer.to_json())
+        .set_delay_seconds(delay_seconds)
+        .set_message_attributes(Some(attributes).filter(|attributes| !attributes.is_empty()))
+        .set_message_deduplication_id(deduplication_id)
+        .set_message_group_id(group_id)
+        .queue_url(queue_url.as_str())
+        .send()
         .await
-        .map(|result| result.messages.unwrap_or(Vec::new()))
+}
+
+/// `DelaySeconds` of a message sent to `queue_url` which should be delivered after `delay`, up
+/// to the most SQS allows, or `None` for a FIFO queue which only takes a delay for the queue.
+fn delay_seconds(queue_url: &QueueUrl, delay: Duration) -> Option<i32> {
+    if queue_url.is_fifo() {
+        return None;
+    }
+    Some(delay.as_secs().min(MAX_DELAY_SECONDS) as i32)
+}
+
+#[cfg(test)]
+mod delay_seconds {
+    use super::*;
+
+    #[test]
+    fn caps_delay_at_fifteen_minutes() {
+        let queue_url: QueueUrl = "https://sqs.us-east-1.amazonaws.com/000000000000/emails"
+            .parse()
+            .unwrap();
+        assert_eq!(delay_seconds(&queue_url, Duration::from_secs(90)), Some(90));
+        assert_eq!(
+            delay_seconds(&queue_url, Duration::from_secs(2 * 60 * 60)),
+            Some(900)
+        );
+        let fifo: QueueUrl = "https://sqs.us-east-1.amazonaws.com/000000000000/emails.fifo"
+            .parse()
+            .unwrap();
+        assert_eq!(delay_seconds(&fifo, Duration::from_secs(90)), None);
+    }
+}
+
+#[cfg(test)]
+mod email_pointer {
+    use super::*;
+
+    #[test]
+    fn round_trips_json() {
+        let pointer = EmailPointer {
+            email_id: "Test EmailId".into(),
+            tenant: None,
+        };
+        let json = pointer.to_json();
+        assert_eq!(json, r#"{"email_id":"Test EmailId"}"#);
+        let parsed = EmailPointer::from_json(json).unwrap();
+        assert_eq!(parsed.email_id, "Test EmailId");
+        assert_eq!(parsed.tenant, None);
+    }
+
+    #[test]
+    fn round_trips_tenant() {
+        let pointer = EmailPointer {
+            email_id: "Test EmailId".into(),
+            tenant: Some("acme".into()),
+        };
+        let json = pointer.to_json();
+        assert_eq!(json, r#"{"email_id":"Test EmailId","tenant":"acme"}"#);
+        let parsed = EmailPointer::from_json(json).unwrap();
+        assert_eq!(parsed.tenant.as_deref(), Some("acme"));
+    }
+
+    #[test]
+    fn unwraps_sns_notification() {
+        let notification = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "Test MessageId",
+            "TopicArn": "arn:aws:sns:us-east-1:000000000000:emails",
+            "Message": r#"{"email_id":"Test EmailId"}"#,
+            "Timestamp": "2021-03-24T00:00:00.000Z"
+        });
+        let parsed = EmailPointer::from_json(notification.to_string()).unwrap();
+        assert_eq!(parsed.email_id, "Test EmailId");
+        let not_pointer = serde_json::json!({ "Type": "Notification", "Message": "Hello" });
+        assert!(EmailPointer::from_json(not_pointer.to_string()).is_none());
+    }
+}
+
+#[cfg(test)]
+mod try_from {
+    use super::*;
+
+    #[test]
+    fn reads_pointer() {
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId"}"#)
+            .build();
+        let pointer = EmailPointerMessage::try_from(message).unwrap();
+        assert_eq!(pointer.email_id, "Test EmailId");
+        assert_eq!(pointer.receive_count, 1);
+    }
+
+    #[test]
+    fn reads_receive_count() {
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId"}"#)
+            .attributes(MessageSystemAttributeName::ApproximateReceiveCount, "3")
+            .attributes(MessageSystemAttributeName::SentTimestamp, "1616544000000")
+            .build();
+        let pointer = EmailPointerMessage::try_from(message).unwrap();
+        assert_eq!(pointer.receive_count, 3);
+        assert_eq!(pointer.sent_timestamp, Some(1_616_544_000_000));
+        let now = UNIX_EPOCH + Duration::from_secs(1_616_544_060);
+        assert_eq!(pointer.age(now), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn fails_on_missing_parts() {
+        let message = Message::builder().build();
+        assert_eq!(
+            EmailPointerMessage::try_from(message).unwrap_err(),
+            PointerError::MissingMessageId
+        );
+        let message = Message::builder().message_id("Test MessageId").build();
+        assert_eq!(
+            EmailPointerMessage::try_from(message).unwrap_err(),
+            PointerError::MissingReceiptHandle
+        );
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body("not json")
+            .build();
+        assert_eq!(
+            EmailPointerMessage::try_from(message).unwrap_err(),
+            PointerError::InvalidBody
+        );
+    }
+}
+
+#[cfg(test)]
+mod receive_attempt {
+    use super::*;
+
+    #[test]
+    fn reuses_id_until_receive_succeeds() {
+        let mut attempt = ReceiveAttempt::new(true);
+        let first = attempt.id();
+        assert!(first.is_some());
+        attempt.record(false);
+        assert_eq!(attempt.id(), first);
+        attempt.record(true);
+        assert_ne!(attempt.id(), first);
+    }
+
+    #[test]
+    fn has_no_id_when_disabled() {
+        let mut attempt = ReceiveAttempt::new(false);
+        assert_eq!(attempt.id(), None);
+        attempt.record(false);
+        assert_eq!(attempt.id(), None);
+    }
+}
+
+#[cfg(test)]
+mod retry_visibility_timeout {
+    use super::*;
+
+    fn pointer(receive_count: u32) -> EmailPointerMessage {
+        EmailPointerMessage {
+            message_id: "Test MessageId".into(),
+            handle: "Test ReceiptHandle".into(),
+            email_id: "Test EmailId".into(),
+            tenant: None,
+            receive_count,
+            sent_timestamp: None,
+        }
+    }
+
+    #[test]
+    fn backs_off_each_receive() {
+        assert_eq!(pointer(0).retry_visibility_timeout(), 60);
+        assert_eq!(pointer(1).retry_visibility_timeout(), 60);
+        assert_eq!(pointer(2).retry_visibility_timeout(), 300);
+        assert_eq!(pointer(3).retry_visibility_timeout(), 900);
+    }
+
+    #[test]
+    fn never_exceeds_fifteen_minutes() {
+        assert_eq!(pointer(4).retry_visibility_timeout(), 900);
+        assert_eq!(pointer(u32::MAX).retry_visibility_timeout(), 900);
+    }
+}
+
+#[cfg(test)]
+mod defer_entry {
+    use super::*;
+
+    #[test]
+    fn hides_until_due_up_to_twelve_hours() {
+        let pointer = EmailPointerMessage::unqueued("Test EmailId");
+        let timeout = |delay| pointer.defer_entry(delay).visibility_timeout();
+        assert_eq!(timeout(Duration::from_secs(90)), Some(90));
+        assert_eq!(timeout(Duration::from_millis(200)), Some(1));
+        assert_eq!(timeout(Duration::from_secs(2 * 24 * 60 * 60)), Some(43_200));
+    }
 }
@@ -0,0 +1,196 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Region of queues addressed by the legacy `queue.amazonaws.com` host.
+const LEGACY_REGION: &str = "us-east-1";
+
+/// Reasons a string can not be read as a `QueueUrl`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum QueueUrlError {
+    #[error("InvalidQueueUrl({0})")]
+    InvalidQueueUrl(String),
+}
+
+/// URL of an SQS queue along with the region, account, and name read from it, so a queue can be
+/// labelled in logs and metrics and recognised by account as well as by URL.
+///
+/// ```
+/// use email_shared::QueueUrl;
+///
+/// let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/emails"
+///     .parse::<QueueUrl>()
+///     .unwrap();
+/// assert_eq!(queue_url.region(), Some("us-east-1"));
+/// assert_eq!(queue_url.account_id(), "000000000000");
+/// assert_eq!(queue_url.name(), "emails");
+/// assert!(!queue_url.is_fifo());
+///
+/// let local = "http://localhost:4566/000000000000/emails_local".parse::<QueueUrl>().unwrap();
+/// assert_eq!(local.region(), None);
+/// assert!("emails".parse::<QueueUrl>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct QueueUrl {
+    /// The URL as given.
+    url: String,
+    /// Region read from the host, `None` for hosts other than AWS such as LocalStack.
+    region: Option<String>,
+    /// Id of the AWS account owning the queue.
+    account_id: String,
+    /// Name of the queue.
+    name: String,
+}
+
+impl QueueUrl {
+    /// The URL as given.
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+
+    /// Region the queue resides in, `None` when the host is not an AWS endpoint.
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Id of the AWS account owning the queue.
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    /// Name of the queue.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the queue is a FIFO queue, which SQS requires to be named with a ".fifo" suffix.
+    pub fn is_fifo(&self) -> bool {
+        self.name.ends_with(".fifo")
+    }
+}
+
+/// Region named by an SQS `host`, such as "sqs.eu-west-1.amazonaws.com" or
+/// "eu-west-1.queue.amazonaws.com".
+fn region_of(host: &str) -> Option<String> {
+    let labels = host.split('.').collect::<Vec<_>>();
+    match labels.as_slice() {
+        ["queue", "amazonaws", "com"] => Some(LEGACY_REGION.to_owned()),
+        ["sqs", region, "amazonaws", ..] | ["sqs", region, "api", "aws"] => {
+            Some((*region).to_owned())
+        }
+        [region, "queue", "amazonaws", ..] => Some((*region).to_owned()),
+        _ => None,
+    }
+}
+
+impl FromStr for QueueUrl {
+    type Err = QueueUrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || QueueUrlError::InvalidQueueUrl(s.to_owned());
+        let rest = s
+            .strip_prefix("https://")
+            .or_else(|| s.strip_prefix("http://"))
+            .ok_or_else(invalid)?;
+        let mut parts = rest.trim_end_matches('/').split('/');
+        let authority = parts.next().filter(|authority| !authority.is_empty());
+        let account_id = parts.next().filter(|account_id| !account_id.is_empty());
+        let name = parts.next().filter(|name| !name.is_empty());
+        match (authority, account_id, name, parts.next()) {
+            (Some(authority), Some(account_id), Some(name), None) => {
+                let host = authority.rsplit('@').next().unwrap_or(authority);
+                let host = host.split(':').next().unwrap_or(host);
+                Ok(QueueUrl {
+                    url: s.to_owned(),
+                    region: region_of(host),
+                    account_id: account_id.to_owned(),
+                    name: name.to_owned(),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for QueueUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl AsRef<str> for QueueUrl {
+    fn as_ref(&self) -> &str {
+        &self.url
+    }
+}
+
+impl<'de> Deserialize<'de> for QueueUrl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for QueueUrl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.url)
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn reads_regional_hosts() {
+        let queue_url = "https://sqs.eu-west-1.amazonaws.com/123456789012/emails.fifo"
+            .parse::<QueueUrl>()
+            .unwrap();
+        assert_eq!(queue_url.region(), Some("eu-west-1"));
+        assert_eq!(queue_url.account_id(), "123456789012");
+        assert_eq!(queue_url.name(), "emails.fifo");
+        assert!(queue_url.is_fifo());
+    }
+
+    #[test]
+    fn reads_legacy_hosts() {
+        let queue_url = "https://queue.amazonaws.com/123456789012/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        assert_eq!(queue_url.region(), Some(LEGACY_REGION));
+        let queue_url = "https://ap-south-1.queue.amazonaws.com/123456789012/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        assert_eq!(queue_url.region(), Some("ap-south-1"));
+    }
+
+    #[test]
+    fn reads_dual_stack_hosts() {
+        let queue_url = "https://sqs.us-west-2.api.aws/123456789012/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        assert_eq!(queue_url.region(), Some("us-west-2"));
+    }
+
+    #[test]
+    fn keeps_url_as_given() {
+        let url = "http://localhost:4566/000000000000/emails_local";
+        let queue_url = url.parse::<QueueUrl>().unwrap();
+        assert_eq!(queue_url.as_str(), url);
+        assert_eq!(queue_url.to_string(), url);
+    }
+
+    #[test]
+    fn rejects_missing_parts() {
+        for url in &[
+            "sqs.us-east-1.amazonaws.com/123456789012/emails",
+            "https://sqs.us-east-1.amazonaws.com/emails",
+            "https://sqs.us-east-1.amazonaws.com/123456789012/emails/extra",
+            "https:///123456789012/emails",
+        ] {
+            assert!(url.parse::<QueueUrl>().is_err(), "{} was parsed", url);
+        }
+    }
+}
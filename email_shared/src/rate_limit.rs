@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Possible errors while parsing `RateLimits`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RateLimitError {
+    /// A rate was not a positive number of messages per second.
+    #[error("InvalidRate({0})")]
+    InvalidRate(String),
+    /// More than one rule without a provider was given.
+    #[error("DuplicateDefault({0})")]
+    DuplicateDefault(String),
+}
+
+/// The most messages per second sent in total and through each provider.
+///
+/// Rules are separated by commas, a rule is either a rate or `<provider>=<rate>`. A rate is a
+/// positive number of messages per second and may be a fraction.
+///
+/// ```
+/// use email_shared::RateLimits;
+///
+/// let limits: RateLimits = "20,ses=14,sendgrid=0.5".parse().unwrap();
+/// assert_eq!(limits.total(), Some(20.0));
+/// assert_eq!(limits.for_provider("ses"), Some(14.0));
+/// assert_eq!(limits.for_provider("smtp"), None);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    /// Messages per second across every provider.
+    total: Option<f64>,
+    /// Messages per second through a provider.
+    providers: HashMap<String, f64>,
+}
+
+impl RateLimits {
+    /// Messages per second across every provider.
+    pub fn total(&self) -> Option<f64> {
+        self.total
+    }
+
+    /// Messages per second through `provider`.
+    pub fn for_provider(&self, provider: &str) -> Option<f64> {
+        self.providers.get(provider).copied()
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = RateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = RateLimits::default();
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule.find('=') {
+                Some(index) => {
+                    let rate = parse_rate(&rule[index + 1..])?;
+                    let provider = rule[..index].trim().to_owned();
+                    limits.providers.insert(provider, rate);
+                }
+                None if limits.total.is_some() => {
+                    return Err(RateLimitError::DuplicateDefault(rule.into()));
+                }
+                None => limits.total = Some(parse_rate(rule)?),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Parse a positive, finite number of messages per second.
+fn parse_rate(s: &str) -> Result<f64, RateLimitError> {
+    let s = s.trim();
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(RateLimitError::InvalidRate(s.into())),
+    }
+}
+
+/// Tokens available in a `TokenBucket` as of `updated`.
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Allows `rate` messages per second with bursts of up to one second of messages.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens added each second.
+    rate: f64,
+    /// Most tokens the bucket holds.
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// A full bucket refilled at `rate` tokens per second.
+    fn new(rate: f64, now: Instant) -> Self {
+        let capacity = rate.max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                updated: now,
+            }),
+        }
+    }
+
+    /// Tokens available at `now`.
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+        state.updated = now;
+    }
+
+    /// How long after `now` until a token is available.
+    fn wait(&self, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("TokenBucket lock poisoned");
+        self.refill(&mut state, now);
+        if state.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - state.tokens) / self.rate)
+        }
+    }
+
+    /// Remove a token at `now`. The bucket may go into debt when a token was taken concurrently,
+    /// later callers then wait longer.
+    fn take(&self, now: Instant) {
+        let mut state = self.state.lock().expect("TokenBucket lock poisoned");
+        self.refill(&mut state, now);
+        state.tokens -= 1.0;
+    }
+}
+
+/// Keep sends within `RateLimits` so a large backlog does not exceed provider quotas. A send
+/// waits for budget up to `max_delay`, beyond that the message should be retried later.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Budget across every provider.
+    total: Option<TokenBucket>,
+    /// Budget for each provider with a limit.
+    providers: HashMap<String, TokenBucket>,
+    /// Longest a send waits for budget.
+    max_delay: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits, max_delay: Duration) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            total: limits.total.map(|rate| TokenBucket::new(rate, now)),
+            providers: limits
+                .providers
+                .iter()
+                .map(|(provider, rate)| (provider.clone(), TokenBucket::new(*rate, now)))
+                .collect(),
+            max_delay,
+        }
+    }
+
+    /// Wait until one message may be sent through `provider`. When the budget will not be
+    /// available within the maximum delay nothing is taken and the time until it will be is
+    /// returned as the error.
+    pub async fn acquire(&self, provider: &str) -> Result<(), Duration> {
+        loop {
+            match self.try_acquire(provider, Instant::now()) {
+                Ok(()) => return Ok(()),
+                Err(wait) if wait > self.max_delay => return Err(wait),
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Take budget for one message through `provider` at `now`, or how long until there is
+    /// budget in every applicable bucket.
+    fn try_acquire(&self, provider: &str, now: Instant) -> Result<(), Duration> {
+        let buckets = self
+            .total
+            .iter()
+            .chain(self.providers.get(provider))
+            .collect::<Vec<_>>();
+        let wait = buckets
+            .iter()
+            .map(|bucket| bucket.wait(now))
+            .max()
+            .unwrap_or_default();
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        for bucket in buckets {
+            bucket.take(now);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn parses_providers_without_total() {
+        let limits: RateLimits = " ses=14 ".parse().unwrap();
+        assert_eq!(limits.total(), None);
+        assert_eq!(limits.for_provider("ses"), Some(14.0));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(
+            "0".parse::<RateLimits>(),
+            Err(RateLimitError::InvalidRate("0".into()))
+        );
+        assert_eq!(
+            "ses=fast".parse::<RateLimits>(),
+            Err(RateLimitError::InvalidRate("fast".into()))
+        );
+        assert_eq!(
+            "1,2".parse::<RateLimits>(),
+            Err(RateLimitError::DuplicateDefault("2".into()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod try_acquire {
+    use super::*;
+
+    #[test]
+    fn allows_burst_then_waits() {
+        let limiter = RateLimiter::new(&"2".parse().unwrap(), Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(limiter.try_acquire("", now), Ok(()));
+        assert_eq!(limiter.try_acquire("", now), Ok(()));
+        assert_eq!(
+            limiter.try_acquire("", now),
+            Err(Duration::from_millis(500))
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.try_acquire("", later), Ok(()));
+    }
+
+    #[test]
+    fn applies_provider_and_total_limits() {
+        let limiter = RateLimiter::new(&"10,ses=1".parse().unwrap(), Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(limiter.try_acquire("ses", now), Ok(()));
+        assert_eq!(limiter.try_acquire("ses", now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.try_acquire("sendgrid", now), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn defers_beyond_max_delay() {
+        let limiter = RateLimiter::new(&"1".parse().unwrap(), Duration::from_millis(100));
+        assert_eq!(limiter.acquire("").await, Ok(()));
+        assert!(matches!(
+            limiter.acquire("").await,
+            Err(wait) if wait > Duration::from_millis(100)
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::dynamo::query_by_status;
+use crate::email_message::{EmailId, EmailStatus};
+use crate::metrics::{Counter, Metrics};
+use crate::queue::send_email_pointer;
+use crate::queue_url::QueueUrl;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::Client as SqsClient;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// Emails read from the status index in each query.
+const STATUS_PAGE_SIZE: i32 = 100;
+
+/// Summary of a single pass of a `PendingReconciler`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReconcileReport {
+    /// Number of emails found `Pending` for longer than the minimum age.
+    pub found: usize,
+    /// Number of emails whose pointer was sent again.
+    pub enqueued: usize,
+    /// Number of emails left to be found again by a later pass.
+    pub failed: usize,
+}
+
+/// Enqueue again emails left `EmailStatus::Pending` without a pointer, such as when a producer
+/// wrote the record but failed to send its pointer. An email is taken to have no pointer once it
+/// has been `Pending` for longer than `min_age`, so `min_age` must be longer than a pointer may
+/// wait on the queue. A pointer sent for an email which still has one is harmless, whichever
+/// delivery claims the email first sends it and the other finds it no longer `Pending`.
+pub struct PendingReconciler<'a> {
+    /// Connection to DynamoDB.
+    dynamodb: &'a DynamoDbClient,
+    /// Counters published to CloudWatch.
+    metrics: Option<&'a Metrics>,
+    /// How long an email is `Pending` before its pointer is taken to be lost.
+    min_age: Duration,
+    /// URL of the queue pointers are sent to.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+    /// Index of the email table listing emails by `EmailStatus`.
+    status_index: &'a str,
+    /// DynamoDB table from which email data is read.
+    table_name: &'a str,
+}
+
+impl PendingReconciler<'_> {
+    pub fn new<'a>(
+        dynamodb: &'a DynamoDbClient,
+        table_name: &'a str,
+        status_index: &'a str,
+        queue_url: &'a QueueUrl,
+        sqs: &'a SqsClient,
+        min_age: Duration,
+    ) -> PendingReconciler<'a> {
+        PendingReconciler {
+            dynamodb,
+            metrics: None,
+            min_age,
+            queue_url,
+            sqs,
+            status_index,
+            table_name,
+        }
+    }
+
+    /// Reconcile the email table once.
+    ///
+    /// 1. Query the status index for emails `Pending` for longer than the minimum age.
+    /// 2. Send a pointer for each email found.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn run_once(&self) -> Result<ReconcileReport, String> {
+        // 1. Query the status index for emails `Pending` for longer than the minimum age.
+        let emails = self.stale_emails(Utc::now()).await?;
+        let mut report = ReconcileReport {
+            found: emails.len(),
+            ..ReconcileReport::default()
+        };
+        // 2. Send a pointer for each email found.
+        for email_id in emails {
+            match send_email_pointer(self.queue_url, self.sqs, &email_id, None).await {
+                Ok(_) => {
+                    event!(Level::INFO, %email_id, "pending email enqueued again");
+                    report.enqueued += 1;
+                }
+                Err(error) => {
+                    let error = format!("{}", DisplayErrorContext(&error));
+                    event!(Level::ERROR, %email_id, %error, "email pointer not sent");
+                    report.failed += 1;
+                }
+            }
+        }
+        if let Some(metrics) = self.metrics {
+            metrics.count(Counter::Reconciled, report.enqueued as u64);
+        }
+        Ok(report)
+    }
+
+    /// `EmailId` of every email which has been `Pending` since before `min_age` ago as of `now`.
+    /// Emails are listed least recently updated first so reading stops at the first one updated
+    /// since.
+    async fn stale_emails(&self, now: DateTime<Utc>) -> Result<Vec<EmailId>, String> {
+        let min_age = chrono::Duration::from_std(self.min_age).unwrap_or(chrono::Duration::MAX);
+        let cutoff = now
+            .checked_sub_signed(min_age)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let mut emails = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = query_by_status(
+                self.dynamodb,
+                self.table_name,
+                self.status_index,
+                EmailStatus::Pending,
+                STATUS_PAGE_SIZE,
+                cursor.as_deref(),
+            )
+            .await
+            .map_err(|error| error.to_string())?;
+            for email in page.emails {
+                match DateTime::parse_from_rfc3339(&email.updated_at) {
+                    Ok(updated_at) if updated_at < cutoff => emails.push(email.email_id),
+                    Ok(_) => return Ok(emails),
+                    Err(_) => {
+                        let (email_id, updated_at) = (email.email_id, email.updated_at);
+                        event!(Level::WARN, %email_id, %updated_at, "invalid UpdatedAt");
+                    }
+                }
+            }
+            cursor = page.cursor;
+            if cursor.is_none() {
+                return Ok(emails);
+            }
+        }
+    }
+}
+
+impl<'a> PendingReconciler<'a> {
+    /// Count enqueued emails in `metrics`.
+    pub fn with_metrics(self, metrics: &'a Metrics) -> Self {
+        PendingReconciler {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod stale_emails {
+    use super::*;
+    use crate::email_message::EmailMessage;
+    use crate::test_support::InMemoryDynamoDb;
+    use aws_sdk_sqs::config::BehaviorVersion;
+    use chrono::SecondsFormat;
+
+    fn email(email_id: &str, status: EmailStatus, updated_at: &str) -> EmailMessage {
+        EmailMessage {
+            email_id: email_id.into(),
+            status,
+            updated_at: updated_at.into(),
+            ..EmailMessage::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_emails_pending_longer_than_min_age() {
+        let now = Utc::now();
+        let minutes_ago = |minutes| {
+            (now - chrono::Duration::minutes(minutes)).to_rfc3339_opts(SecondsFormat::Secs, true)
+        };
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email(
+            "Stale EmailId",
+            EmailStatus::Pending,
+            &minutes_ago(90),
+        ));
+        table.insert(&email(
+            "Older EmailId",
+            EmailStatus::Pending,
+            &minutes_ago(120),
+        ));
+        table.insert(&email(
+            "Fresh EmailId",
+            EmailStatus::Pending,
+            &minutes_ago(5),
+        ));
+        table.insert(&email(
+            "Failed EmailId",
+            EmailStatus::Failed,
+            &minutes_ago(120),
+        ));
+        let dynamodb = table.client();
+        let sqs = SqsClient::from_conf(
+            aws_sdk_sqs::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let queue_url = "http://localhost:4566/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        let reconciler = PendingReconciler::new(
+            &dynamodb,
+            "Test Table",
+            "Status Index",
+            &queue_url,
+            &sqs,
+            Duration::from_secs(30 * 60),
+        );
+        let emails = reconciler.stale_emails(now).await.unwrap();
+        assert_eq!(emails, vec!["Older EmailId", "Stale EmailId"]);
+    }
+}
@@ -0,0 +1,339 @@
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+/// Default longest wait between two attempts.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(20);
+/// Wait after the first failed attempt of a parsed policy which does not give one.
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Possible errors while parsing a `RetryPolicy`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RetryPolicyError {
+    /// The number of attempts was not a positive whole number.
+    #[error("InvalidAttempts({0})")]
+    InvalidAttempts(String),
+    /// The backoff was not a whole number of `ms` or `s`.
+    #[error("InvalidBackoff({0})")]
+    InvalidBackoff(String),
+}
+
+/// How many times, and how far apart, a failed call is attempted. Policies are set separately for
+/// the DynamoDB, SQS, and provider layers of a `Client` so each can match the quotas and failure
+/// modes of the service it calls.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::exponential(Duration::from_millis(100), 4)
+///     .with_max_backoff(Duration::from_millis(300));
+/// assert_eq!(policy.max_attempts(), 4);
+/// assert_eq!(policy.backoff(1), Duration::from_millis(100));
+/// assert_eq!(policy.backoff(2), Duration::from_millis(200));
+/// assert_eq!(policy.backoff(3), Duration::from_millis(300));
+/// assert_eq!(RetryPolicy::none().max_attempts(), 1);
+/// assert!(policy.with_jitter().jittered(3) <= Duration::from_millis(300));
+/// ```
+///
+/// Policies are configured as the most attempts, optionally followed by the wait after the
+/// first failure in `ms` or `s`, 100ms when not given. Configured policies wait a random part of
+/// each backoff.
+///
+/// ```
+/// use email_shared::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy: RetryPolicy = "4,250ms".parse().unwrap();
+/// assert_eq!(policy.max_attempts(), 4);
+/// assert_eq!(policy.backoff(2), Duration::from_millis(500));
+/// let policy: RetryPolicy = "3".parse().unwrap();
+/// assert_eq!(policy.backoff(1), Duration::from_millis(100));
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Most attempts made, including the first.
+    max_attempts: u32,
+    /// Wait after the first failed attempt, doubled after each further failure.
+    initial_backoff: Duration,
+    /// Longest wait between two attempts.
+    max_backoff: Duration,
+    /// Whether each wait is a random part of the backoff rather than all of it.
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::none()
+    }
+}
+
+impl RetryPolicy {
+    /// Attempt a call once, never retrying.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            jitter: false,
+        }
+    }
+
+    /// Attempt a call up to `max_attempts` times, waiting `initial_backoff` after the first
+    /// failure and twice as long after each further failure.
+    pub fn exponential(initial_backoff: Duration, max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            max_backoff: DEFAULT_MAX_BACKOFF.max(initial_backoff),
+            jitter: false,
+        }
+    }
+
+    /// Wait at most `max_backoff` between two attempts.
+    pub fn with_max_backoff(self, max_backoff: Duration) -> Self {
+        RetryPolicy {
+            max_backoff,
+            ..self
+        }
+    }
+
+    /// Wait a random part of each backoff, up to all of it, so callers which failed together do
+    /// not all attempt again at the same moment.
+    pub fn with_jitter(self) -> Self {
+        RetryPolicy {
+            jitter: true,
+            ..self
+        }
+    }
+
+    /// Most attempts made, including the first.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Wait after `attempt` failed, counting the first attempt as 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Wait after `attempt` failed, a random part of `backoff` when the policy has jitter.
+    pub fn jittered(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if !self.jitter {
+            return backoff;
+        }
+        // The low 53 bits of a version 4 UUID are random and fit the mantissa of an f64
+        let random = Uuid::new_v4().as_u128() as u64 & ((1 << 53) - 1);
+        backoff.mul_f64(random as f64 / (1u64 << 53) as f64)
+    }
+
+    /// Configuration applying the policy to calls made by an AWS SDK client, which decides
+    /// itself which errors are transient.
+    pub fn retry_config(&self) -> RetryConfig {
+        if self.max_attempts <= 1 {
+            return RetryConfig::disabled();
+        }
+        RetryConfig::standard()
+            .with_max_attempts(self.max_attempts)
+            .with_initial_backoff(self.initial_backoff)
+            .with_max_backoff(self.max_backoff)
+    }
+
+    /// Call `operation` until it succeeds or the attempts are exhausted, returning the error of
+    /// the last attempt.
+    pub async fn retry<T, E, F, Fut>(&self, operation: F) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry_if(|_| true, operation).await
+    }
+
+    /// Call `operation` until it succeeds, fails with an error `transient` does not accept, or the
+    /// attempts are exhausted, returning the error of the last attempt.
+    pub async fn retry_if<T, E, P, F, Fut>(&self, transient: P, mut operation: F) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        P: Fn(&E) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(error) if attempt < self.max_attempts && transient(&error) => {
+                    let backoff = self.jittered(attempt);
+                    event!(Level::WARN, %error, attempt, ?backoff, "attempt failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = RetryPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (attempts, backoff) = match s.split_once(',') {
+            Some((attempts, backoff)) => (attempts.trim(), Some(backoff.trim())),
+            None => (s.trim(), None),
+        };
+        let max_attempts = match attempts.parse::<u32>() {
+            Ok(max_attempts) if max_attempts > 0 => max_attempts,
+            _ => return Err(RetryPolicyError::InvalidAttempts(attempts.into())),
+        };
+        let initial_backoff = match backoff {
+            Some(backoff) => parse_backoff(backoff)?,
+            None => DEFAULT_INITIAL_BACKOFF,
+        };
+        Ok(RetryPolicy::exponential(initial_backoff, max_attempts).with_jitter())
+    }
+}
+
+/// Parse a whole number followed by a unit of `ms` or `s` as a `Duration`.
+fn parse_backoff(s: &str) -> Result<Duration, RetryPolicyError> {
+    let invalid = || RetryPolicyError::InvalidBackoff(s.into());
+    if let Some(millis) = s.strip_suffix("ms") {
+        millis
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid())
+    } else if let Some(seconds) = s.strip_suffix('s') {
+        seconds
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| invalid())
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn parses_attempts_and_backoff() {
+        let policy: RetryPolicy = "5,2s".parse().unwrap();
+        assert_eq!(
+            policy,
+            RetryPolicy::exponential(Duration::from_secs(2), 5).with_jitter()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert_eq!(
+            "0".parse::<RetryPolicy>(),
+            Err(RetryPolicyError::InvalidAttempts("0".into()))
+        );
+        assert_eq!(
+            "many".parse::<RetryPolicy>(),
+            Err(RetryPolicyError::InvalidAttempts("many".into()))
+        );
+        assert_eq!(
+            "3,soon".parse::<RetryPolicy>(),
+            Err(RetryPolicyError::InvalidBackoff("soon".into()))
+        );
+        assert_eq!(
+            "3,100".parse::<RetryPolicy>(),
+            Err(RetryPolicyError::InvalidBackoff("100".into()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod retry_policy {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn stops_after_max_attempts() {
+        let policy = RetryPolicy::exponential(Duration::ZERO, 3);
+        let attempts = Cell::new(0);
+        let result: Result<(), String> = policy
+            .retry(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err("failed".to_owned()) }
+            })
+            .await;
+        assert_eq!(result, Err("failed".into()));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_after_success() {
+        let policy = RetryPolicy::exponential(Duration::ZERO, 3);
+        let attempts = Cell::new(0);
+        let result = policy
+            .retry(|| {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    if attempt < 2 {
+                        Err("failed".to_owned())
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn stops_at_permanent_errors() {
+        let policy = RetryPolicy::exponential(Duration::ZERO, 3);
+        let attempts = Cell::new(0);
+        let result: Result<(), String> = policy
+            .retry_if(
+                |error: &String| error == "throttled",
+                || {
+                    attempts.set(attempts.get() + 1);
+                    let attempt = attempts.get();
+                    async move {
+                        match attempt {
+                            1 => Err("throttled".to_owned()),
+                            _ => Err("failed".to_owned()),
+                        }
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result, Err("failed".into()));
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn jitters_within_backoff() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(100), 3);
+        assert_eq!(policy.jittered(2), Duration::from_millis(200));
+        let policy = policy.with_jitter();
+        for _ in 0..100 {
+            assert!(policy.jittered(2) <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn caps_backoff() {
+        let policy = RetryPolicy::exponential(Duration::from_secs(1), 100);
+        assert_eq!(policy.backoff(64), DEFAULT_MAX_BACKOFF);
+        assert_eq!(policy.retry_config().max_attempts(), 100);
+        assert_eq!(RetryPolicy::none().retry_config(), RetryConfig::disabled());
+    }
+}
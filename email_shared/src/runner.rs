@@ -0,0 +1,710 @@
+use crate::audit::AuditEntry;
+use crate::client::Client;
+use crate::metrics::Counter;
+use crate::quarantine::S3QuarantineStore;
+use crate::queue::{
+    delete_entry, receive_messages, ReceiveAttempt, RECEIVE_WAIT_TIME_SECONDS, VISIBILITY_TIMEOUT,
+};
+use crate::queue_url::QueueUrl;
+use async_trait::async_trait;
+use aws_sdk_sqs::error::{DisplayErrorContext, SdkError};
+use aws_sdk_sqs::operation::receive_message::ReceiveMessageError;
+use aws_sdk_sqs::types::{
+    ChangeMessageVisibilityBatchRequestEntry, DeleteMessageBatchRequestEntry, Message,
+};
+use aws_sdk_sqs::Client as SqsClient;
+use futures::channel::mpsc::UnboundedSender;
+use std::time::Duration;
+use tracing::{event, Instrument, Level};
+
+/// Supplies batches of SQS `Message`s to a `Runner`.
+#[async_trait]
+pub trait MessageSource {
+    /// Error produced when a batch can not be received.
+    type Error: std::fmt::Display + Send;
+
+    /// Receive the next batch of messages.
+    async fn receive(&mut self) -> Result<Vec<Message>, Self::Error>;
+
+    /// Whether processed messages need to be deleted when every message in the batch was
+    /// processed. Sources which delete a batch themselves on success should return `false`.
+    fn delete_complete_batch(&self) -> bool {
+        true
+    }
+
+    /// Queue the last batch was received from, for sources reading from more than one queue.
+    /// Messages are deleted from the queue given to the `Runner` when `None`.
+    fn queue_url(&self) -> Option<&QueueUrl> {
+        None
+    }
+}
+
+/// Poll an SQS queue for messages, used by the long running broker. Receives from a FIFO queue
+/// carry a `ReceiveRequestAttemptId` which is used again after a failed receive.
+pub struct SqsPoll<'a> {
+    /// Attempt id of receives from a FIFO queue.
+    attempt: ReceiveAttempt,
+    /// URL of the queue to poll.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+    /// Seconds each received message stays hidden.
+    visibility_timeout: i32,
+}
+
+impl SqsPoll<'_> {
+    pub fn new<'a>(queue_url: &'a QueueUrl, sqs: &'a SqsClient) -> SqsPoll<'a> {
+        SqsPoll {
+            attempt: ReceiveAttempt::new(queue_url.is_fifo()),
+            queue_url,
+            sqs,
+            visibility_timeout: VISIBILITY_TIMEOUT,
+        }
+    }
+
+    /// Hide each received message for `seconds` rather than the default visibility timeout, 0
+    /// leaving it visible to other receivers right away.
+    pub fn with_visibility_timeout(self, seconds: i32) -> Self {
+        SqsPoll {
+            visibility_timeout: seconds,
+            ..self
+        }
+    }
+
+    /// Whether receives carry a `ReceiveRequestAttemptId`, by default only when the queue is a
+    /// FIFO queue. Queues which are not FIFO queues ignore the id.
+    pub fn with_receive_attempt_ids(self, enabled: bool) -> Self {
+        SqsPoll {
+            attempt: ReceiveAttempt::new(enabled),
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSource for SqsPoll<'_> {
+    type Error = String;
+
+    async fn receive(&mut self) -> Result<Vec<Message>, Self::Error> {
+        let attempt_id = self.attempt.id();
+        let result = receive_messages(
+            self.queue_url,
+            self.sqs,
+            RECEIVE_WAIT_TIME_SECONDS,
+            self.visibility_timeout,
+            attempt_id,
+        )
+        .await;
+        self.attempt.record(result.is_ok());
+        result.map_err(|error: SdkError<ReceiveMessageError>| {
+            format!("{}", DisplayErrorContext(&error))
+        })
+    }
+}
+
+/// A batch of messages delivered by an event, used by the Lambda. The Lambda SQS trigger deletes
+/// every message in the batch when the handler succeeds so complete batches are not deleted.
+pub struct EventBatch {
+    /// Messages not yet handed to the `Runner`.
+    messages: Option<Vec<Message>>,
+}
+
+impl EventBatch {
+    pub fn new(messages: Vec<Message>) -> Self {
+        EventBatch {
+            messages: Some(messages),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSource for EventBatch {
+    type Error = std::convert::Infallible;
+
+    async fn receive(&mut self) -> Result<Vec<Message>, Self::Error> {
+        Ok(self.messages.take().unwrap_or_default())
+    }
+
+    fn delete_complete_batch(&self) -> bool {
+        false
+    }
+}
+
+/// What happened to processed messages after a batch was processed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeleteOutcome {
+    /// There was nothing to delete or the source deletes the batch itself.
+    NotNeeded,
+    /// The processed messages were deleted from the queue.
+    Deleted,
+    /// Deleting the processed messages failed with the given error.
+    Failed(String),
+}
+
+/// Summary of a single receive, process, and delete pass.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BatchReport {
+    /// Number of messages received from the source.
+    pub received: usize,
+    /// Number of messages which were processed and need no further delivery attempts, including
+    /// quarantined messages.
+    pub processed: usize,
+    /// Number of messages left to be delivered again.
+    pub retried: usize,
+    /// Number of messages which could never be processed.
+    pub quarantined: usize,
+    /// Result of deleting the processed messages.
+    pub delete: DeleteOutcome,
+    /// Whether receiving from the source failed, in which case nothing was received.
+    pub receive_failed: bool,
+}
+
+impl BatchReport {
+    /// Whether every received message was processed.
+    pub fn is_complete(&self) -> bool {
+        self.received == self.processed
+    }
+
+    /// Whether the source was read and had no messages, so the queue is drained for now.
+    pub fn is_empty(&self) -> bool {
+        self.received == 0 && !self.receive_failed
+    }
+}
+
+/// Step of a single receive, process, and delete pass, sent to the channel given to
+/// `Runner::with_events` so tests can follow the loop without reading its logs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LoopEvent {
+    /// A pass started.
+    IterationStarted,
+    /// A batch was received, with the id of each message in it.
+    BatchReceived(Vec<String>),
+    /// Every message of the batch was processed, by message id.
+    BatchProcessed {
+        /// Messages which need no further delivery attempts.
+        processed: Vec<String>,
+        /// Messages left to be delivered again.
+        retried: Vec<String>,
+        /// Messages which could never be processed.
+        quarantined: Vec<String>,
+    },
+    /// A pass finished.
+    IterationFinished(BatchReport),
+}
+
+/// Totals of every `BatchReport` produced while running, logged when the broker stops.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RunSummary {
+    /// Number of batches run.
+    pub batches: usize,
+    /// Number of messages received.
+    pub received: usize,
+    /// Number of messages processed, including quarantined messages.
+    pub processed: usize,
+    /// Number of messages left to be delivered again.
+    pub retried: usize,
+    /// Number of messages which could never be processed.
+    pub quarantined: usize,
+    /// Number of batches whose processed messages could not be deleted.
+    pub delete_failures: usize,
+}
+
+impl RunSummary {
+    /// Add the counts of `report` to the totals.
+    pub fn record(&mut self, report: &BatchReport) {
+        self.batches += 1;
+        self.received += report.received;
+        self.processed += report.processed;
+        self.retried += report.retried;
+        self.quarantined += report.quarantined;
+        if let DeleteOutcome::Failed(_) = report.delete {
+            self.delete_failures += 1;
+        }
+    }
+}
+
+/// Wait between polls of a queue which keeps returning no messages, so low volume queues are not
+/// received from continuously. Each empty receive doubles the wait, from `initial` up to `max`,
+/// and a receive with messages resets it. A failed receive keeps the current wait.
+///
+/// ```
+/// use email_shared::IdleBackoff;
+/// use std::time::Duration;
+///
+/// let mut backoff = IdleBackoff::new(Duration::from_secs(1), Duration::from_secs(3));
+/// assert_eq!(backoff.record_receive(Some(0)), Duration::from_secs(1));
+/// assert_eq!(backoff.record_receive(Some(0)), Duration::from_secs(2));
+/// assert_eq!(backoff.record_receive(None), Duration::from_secs(2));
+/// assert_eq!(backoff.record_receive(Some(0)), Duration::from_secs(3));
+/// assert_eq!(backoff.record_receive(Some(1)), Duration::ZERO);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IdleBackoff {
+    /// Receives in a row which returned no messages.
+    empty_receives: u32,
+    /// Wait after the first empty receive.
+    initial: Duration,
+    /// Longest wait between two receives.
+    max: Duration,
+}
+
+impl IdleBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        IdleBackoff {
+            empty_receives: 0,
+            initial: initial.min(max),
+            max,
+        }
+    }
+
+    /// Record a receive which returned `received` messages, `None` when it failed, returning the
+    /// wait before the next receive.
+    pub fn record_receive(&mut self, received: Option<usize>) -> Duration {
+        match received {
+            Some(0) => self.empty_receives = self.empty_receives.saturating_add(1),
+            Some(_) => self.empty_receives = 0,
+            None => {}
+        }
+        self.wait()
+    }
+
+    /// Receives in a row which returned no messages.
+    pub fn empty_receives(&self) -> u32 {
+        self.empty_receives
+    }
+
+    /// Wait before the next receive.
+    pub fn wait(&self) -> Duration {
+        match self.empty_receives {
+            0 => Duration::ZERO,
+            n => self
+                .initial
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(self.max),
+        }
+    }
+}
+
+/// Receive messages from a `MessageSource`, process them, and delete processed messages from
+/// the queue. Shared by the broker and the Lambda so both handle batches the same way.
+pub struct Runner<'a> {
+    /// Client used to process each message.
+    client: Client<'a>,
+    /// URL of the queue processed messages are deleted from, unless the source names another.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: SqsClient,
+    /// Storage for messages which can never be processed.
+    quarantine: Option<&'a S3QuarantineStore>,
+    /// Channel each step of a pass is sent to.
+    events: Option<UnboundedSender<LoopEvent>>,
+}
+
+impl Runner<'_> {
+    pub fn new<'a>(client: Client<'a>, queue_url: &'a QueueUrl, sqs: &SqsClient) -> Runner<'a> {
+        // Retries of deletes and visibility changes follow the policy set on the client
+        let sqs = match client.sqs_retry() {
+            Some(policy) => SqsClient::from_conf(
+                sqs.config()
+                    .to_builder()
+                    .retry_config(policy.retry_config())
+                    .build(),
+            ),
+            None => sqs.clone(),
+        };
+        Runner {
+            client,
+            queue_url,
+            sqs,
+            quarantine: None,
+            events: None,
+        }
+    }
+
+    /// Run a single pass over the next batch from `source`.
+    ///
+    /// 1. Receive a batch of messages from the source.
+    /// 2. Process each message.
+    /// 3. Delete processed and quarantined messages unless the source handles deletion itself.
+    /// 4. Shorten the visibility timeout of messages to retry so they are delivered again soon,
+    ///    and extend that of messages for emails scheduled later until they are due.
+    #[tracing::instrument(skip(self, source), level = Level::INFO)]
+    pub async fn run_once<S>(&self, source: &mut S) -> BatchReport
+    where
+        S: MessageSource + Send,
+    {
+        self.emit(LoopEvent::IterationStarted);
+        // 1. Receive a batch of messages from the source.
+        let (messages, receive_failed) = match source.receive().in_current_span().await {
+            Ok(messages) => (messages, false),
+            Err(error) => {
+                event!(Level::ERROR, %error, "ReceiveMessageError");
+                (Vec::new(), true)
+            }
+        };
+        let received = messages.len();
+        self.emit(LoopEvent::BatchReceived(
+            messages
+                .iter()
+                .filter_map(|message| message.message_id.clone())
+                .collect(),
+        ));
+        if let (0, Some(metrics)) = (received, self.client.metrics()) {
+            metrics.count(Counter::EmptyReceives, 1);
+        }
+        // 2. Process each message.
+        let outcome = self
+            .client
+            .process_messages(messages)
+            .in_current_span()
+            .await;
+        let retry_entries = outcome
+            .retry
+            .iter()
+            .map(ChangeMessageVisibilityBatchRequestEntry::from)
+            .chain(
+                outcome
+                    .defer
+                    .iter()
+                    .map(|(pointer, delay)| pointer.defer_entry(*delay)),
+            )
+            .collect::<Vec<_>>();
+        let retried = retry_entries.len();
+        let quarantined = outcome.quarantine.len();
+        self.emit(LoopEvent::BatchProcessed {
+            processed: outcome
+                .delete
+                .iter()
+                .map(|entry| entry.id().to_string())
+                .collect(),
+            retried: retry_entries
+                .iter()
+                .map(|entry| entry.id().to_string())
+                .collect(),
+            quarantined: outcome
+                .quarantine
+                .iter()
+                .filter_map(|(message, _)| message.message_id.clone())
+                .collect(),
+        });
+        // Messages which can never be processed are removed so they are not delivered forever.
+        let mut entries = outcome.delete;
+        for (message, error) in outcome.quarantine {
+            event!(Level::ERROR, message_id = ?message.message_id, %error, "quarantine message");
+            if let Some(store) = self.quarantine {
+                match store.put(&message, &error).in_current_span().await {
+                    Ok(key) => event!(Level::INFO, %key, "quarantined message stored"),
+                    Err(error) => event!(Level::WARN, %error, "store quarantined message failed"),
+                }
+            }
+            if let (Some(id), Some(handle)) = (message.message_id, message.receipt_handle) {
+                entries.push(delete_entry(id, handle));
+            }
+        }
+        let processed = received - retried;
+        let queue_url = source.queue_url().unwrap_or(self.queue_url).clone();
+        // 3. Delete processed and quarantined messages unless the source handles deletion itself.
+        let delete =
+            if entries.is_empty() || (processed == received && !source.delete_complete_batch()) {
+                event!(Level::INFO, received, processed, "no messages to delete");
+                DeleteOutcome::NotNeeded
+            } else {
+                delete_messages(&self.sqs, &queue_url, entries)
+                    .in_current_span()
+                    .await
+            };
+        // 4. Shorten the visibility timeout of messages to retry so they are delivered again soon,
+        //    and extend that of messages for emails scheduled later until they are due.
+        if !retry_entries.is_empty() {
+            self.retry_messages(&queue_url, retry_entries)
+                .in_current_span()
+                .await;
+        }
+        let report = BatchReport {
+            received,
+            processed,
+            retried,
+            quarantined,
+            delete,
+            receive_failed,
+        };
+        self.emit(LoopEvent::IterationFinished(report.clone()));
+        report
+    }
+
+    /// Receive the next batch from `source` and report what processing it would do without
+    /// processing it. Nothing is written to the queue, so `source` should receive with a
+    /// visibility timeout of 0 for other receivers to see each message right away. Every receive
+    /// still counts toward the `ApproximateReceiveCount` of a message, bringing it closer to the
+    /// redrive policy of the queue and the attempts allowed by a `FailureQueue`.
+    #[tracing::instrument(skip(self, source), level = Level::INFO)]
+    pub async fn audit_once<S>(&self, source: &mut S) -> Vec<AuditEntry>
+    where
+        S: MessageSource + Send,
+    {
+        let messages = match source.receive().in_current_span().await {
+            Ok(messages) => messages,
+            Err(error) => {
+                event!(Level::ERROR, %error, "ReceiveMessageError");
+                Vec::new()
+            }
+        };
+        self.client.audit_messages(messages).in_current_span().await
+    }
+
+    /// Send `event` to the channel given to `Runner::with_events`, if any. Events are dropped once
+    /// the receiver is gone.
+    fn emit(&self, event: LoopEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.unbounded_send(event);
+        }
+    }
+
+    /// Change the visibility timeout of the messages identified by `entries` in the queue at
+    /// `queue_url`. A failure only delays the retry until the original visibility timeout expires
+    /// so it is logged and ignored.
+    async fn retry_messages(
+        &self,
+        queue_url: &QueueUrl,
+        entries: Vec<ChangeMessageVisibilityBatchRequestEntry>,
+    ) {
+        match self
+            .sqs
+            .change_message_visibility_batch()
+            .queue_url(queue_url.as_str())
+            .set_entries(Some(entries))
+            .send()
+            .await
+        {
+            Ok(result) => event!(Level::TRACE, ?result, "changed message visibility"),
+            Err(error) => {
+                let error = format!("{}", DisplayErrorContext(&error));
+                event!(Level::WARN, %error, "Change message visibility Error");
+            }
+        }
+    }
+}
+
+impl<'a> Runner<'a> {
+    /// Store a redacted copy of each message which can never be processed in `store` before it
+    /// is deleted.
+    pub fn with_quarantine_store(self, store: &'a S3QuarantineStore) -> Self {
+        Runner {
+            quarantine: Some(store),
+            ..self
+        }
+    }
+
+    /// Send each step of every pass to `events`.
+    pub fn with_events(self, events: UnboundedSender<LoopEvent>) -> Self {
+        Runner {
+            events: Some(events),
+            ..self
+        }
+    }
+}
+
+/// Delete the messages identified by `entries` from the queue at `queue_url`.
+pub(crate) async fn delete_messages(
+    sqs: &SqsClient,
+    queue_url: &QueueUrl,
+    entries: Vec<DeleteMessageBatchRequestEntry>,
+) -> DeleteOutcome {
+    match sqs
+        .delete_message_batch()
+        .queue_url(queue_url.as_str())
+        .set_entries(Some(entries))
+        .send()
+        .await
+    {
+        Ok(result) => {
+            event!(Level::TRACE, ?result, "deleted messages");
+            DeleteOutcome::Deleted
+        }
+        Err(error) => {
+            let error = format!("{}", DisplayErrorContext(&error));
+            event!(Level::ERROR, %error, "Delete messages Error");
+            DeleteOutcome::Failed(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod event_batch {
+    use super::*;
+
+    #[tokio::test]
+    async fn yields_messages_once() {
+        let message = Message::builder().message_id("Test MessageId").build();
+        let mut source = EventBatch::new(vec![message]);
+        assert_eq!(source.receive().await.unwrap().len(), 1);
+        assert_eq!(source.receive().await.unwrap().len(), 0);
+        assert!(!source.delete_complete_batch());
+    }
+}
+
+#[cfg(test)]
+mod run_once {
+    use super::*;
+    use crate::email_message::{EmailMessage, EmailStatus};
+    use crate::email_message_builder::EmailMessageBuilder;
+    use crate::test_support::InMemoryDynamoDb;
+    use aws_sdk_sqs::config::BehaviorVersion;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn emits_loop_events() {
+        let email = EmailMessageBuilder::new("Test EmailId")
+            .sender("from@example.com")
+            .to("to@example.com")
+            .subject("Test Subject")
+            .body_text("Test Body")
+            .build()
+            .unwrap();
+        let table = InMemoryDynamoDb::default();
+        table.insert(&EmailMessage {
+            status: EmailStatus::Sent,
+            ..email
+        });
+        let dynamodb = table.client();
+        let sqs = SqsClient::from_conf(
+            aws_sdk_sqs::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let (sender, receiver) = mpsc::unbounded();
+        let queue_url = "http://localhost:4566/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        let runner =
+            Runner::new(Client::new(&dynamodb, "Test Table"), &queue_url, &sqs).with_events(sender);
+        let message = Message::builder()
+            .message_id("Test MessageId")
+            .receipt_handle("Test ReceiptHandle")
+            .body(r#"{"email_id":"Test EmailId"}"#)
+            .build();
+        let report = runner.run_once(&mut EventBatch::new(vec![message])).await;
+        drop(runner);
+        let events = receiver.collect::<Vec<_>>().await;
+        assert_eq!(
+            events,
+            vec![
+                LoopEvent::IterationStarted,
+                LoopEvent::BatchReceived(vec!["Test MessageId".into()]),
+                LoopEvent::BatchProcessed {
+                    processed: vec!["Test MessageId".into()],
+                    retried: Vec::new(),
+                    quarantined: Vec::new(),
+                },
+                LoopEvent::IterationFinished(report),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod is_complete {
+    use super::*;
+
+    #[test]
+    fn compares_received_and_processed() {
+        let report = BatchReport {
+            received: 2,
+            processed: 1,
+            retried: 1,
+            quarantined: 0,
+            delete: DeleteOutcome::Deleted,
+            receive_failed: false,
+        };
+        assert!(!report.is_complete());
+        let report = BatchReport {
+            processed: 2,
+            retried: 0,
+            ..report
+        };
+        assert!(report.is_complete());
+    }
+}
+
+#[cfg(test)]
+mod is_empty {
+    use super::*;
+
+    #[test]
+    fn ignores_failed_receives() {
+        let report = BatchReport {
+            received: 0,
+            processed: 0,
+            retried: 0,
+            quarantined: 0,
+            delete: DeleteOutcome::NotNeeded,
+            receive_failed: false,
+        };
+        assert!(report.is_empty());
+        let report = BatchReport {
+            receive_failed: true,
+            ..report
+        };
+        assert!(!report.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod record {
+    use super::*;
+
+    #[test]
+    fn adds_report_counts() {
+        let mut summary = RunSummary::default();
+        summary.record(&BatchReport {
+            received: 3,
+            processed: 2,
+            retried: 1,
+            quarantined: 1,
+            delete: DeleteOutcome::Deleted,
+            receive_failed: false,
+        });
+        summary.record(&BatchReport {
+            received: 1,
+            processed: 1,
+            retried: 0,
+            quarantined: 0,
+            delete: DeleteOutcome::Failed("Test Error".into()),
+            receive_failed: false,
+        });
+        assert_eq!(
+            summary,
+            RunSummary {
+                batches: 2,
+                received: 4,
+                processed: 3,
+                retried: 1,
+                quarantined: 1,
+                delete_failures: 1,
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod record_receive {
+    use super::*;
+
+    #[test]
+    fn never_waits_without_max() {
+        let mut backoff = IdleBackoff::new(Duration::from_secs(1), Duration::ZERO);
+        assert_eq!(backoff.record_receive(Some(0)), Duration::ZERO);
+        assert_eq!(backoff.record_receive(Some(0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn stays_at_max_after_many_empty_receives() {
+        let mut backoff = IdleBackoff::new(Duration::from_secs(1), Duration::from_secs(20));
+        for _ in 0..100 {
+            backoff.record_receive(Some(0));
+        }
+        assert_eq!(backoff.empty_receives(), 100);
+        assert_eq!(backoff.wait(), Duration::from_secs(20));
+    }
+}
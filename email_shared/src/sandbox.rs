@@ -0,0 +1,44 @@
+use crate::email_message::{EmailMessage, Recipient};
+
+/// Send `email` to `address` instead of its recipients, naming the original recipients at the
+/// start of the subject so a redirected email can still be told apart from others. Used by
+/// non-production deployments to exercise the full pipeline without mailing real users.
+pub(crate) fn redirect(email: &mut EmailMessage, address: &str) {
+    let original = email
+        .recipients_to
+        .drain(..)
+        .chain(email.recipients_cc.drain(..))
+        .chain(email.recipients_bcc.drain(..))
+        .collect::<Vec<Recipient>>();
+    let original = original
+        .iter()
+        .map(Recipient::address)
+        .collect::<Vec<_>>()
+        .join(", ");
+    email.subject = format!("[{}] {}", original, email.subject);
+    email.recipients_to = vec![address.into()];
+}
+
+#[cfg(test)]
+mod redirect {
+    use super::*;
+
+    #[test]
+    fn replaces_every_recipient() {
+        let mut email = EmailMessage {
+            recipients_bcc: vec!["bcc@example.com".into()],
+            recipients_cc: vec!["cc@example.com".into()],
+            recipients_to: vec!["a@example.com".into(), "b@example.com".into()],
+            subject: "Test Subject".into(),
+            ..EmailMessage::default()
+        };
+        redirect(&mut email, "safe@example.com");
+        assert_eq!(email.recipients_to, vec!["safe@example.com"]);
+        assert!(email.recipients_cc.is_empty());
+        assert!(email.recipients_bcc.is_empty());
+        assert_eq!(
+            email.subject,
+            "[a@example.com, b@example.com, cc@example.com, bcc@example.com] Test Subject"
+        );
+    }
+}
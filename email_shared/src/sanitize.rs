@@ -0,0 +1,97 @@
+use crate::email_message::EmailMessage;
+
+/// Attributes kept on any element in addition to those ammonia allows, so the table layouts and
+/// inline styles email HTML relies on survive sanitizing.
+const LAYOUT_ATTRIBUTES: [&str; 12] = [
+    "align",
+    "bgcolor",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "class",
+    "color",
+    "height",
+    "id",
+    "style",
+    "valign",
+    "width",
+];
+
+/// Sanitize the HTML body of `email` in place, leaving an empty body empty.
+pub(crate) fn sanitize_body(email: &mut EmailMessage) {
+    if !email.body_html.is_empty() {
+        email.body_html = sanitize_html(&email.body_html);
+    }
+}
+
+/// Remove anything from `html` which could run script or load a dangerous URL when the message is
+/// read. `script` and `style` elements are dropped along with their contents, event handler
+/// attributes such as `onclick` are dropped, and links or images using a scheme such as
+/// `javascript:` are left without their URL. Formatting, tables, and inline styles are kept and
+/// `cid:` images continue to refer to attachments.
+///
+/// ```
+/// use email_shared::sanitize_html;
+///
+/// assert_eq!(
+///     sanitize_html("<p onclick=\"steal()\">Hi<script>steal()</script></p>"),
+///     "<p>Hi</p>"
+/// );
+/// assert_eq!(
+///     sanitize_html("<a href=\"javascript:steal()\">Track</a>"),
+///     "<a rel=\"noopener noreferrer\">Track</a>"
+/// );
+/// ```
+pub fn sanitize_html(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_generic_attributes(LAYOUT_ATTRIBUTES)
+        .add_url_schemes(["cid"])
+        .clean(html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod sanitize_html {
+    use super::*;
+
+    #[test]
+    fn removes_dangerous_content() {
+        assert_eq!(
+            sanitize_html(
+                "<img src=\"x\" onerror=\"steal()\"><iframe src=\"https://example.com\"></iframe>"
+            ),
+            "<img src=\"x\">"
+        );
+        assert_eq!(
+            sanitize_html("<style>body { color: red }</style><p>Hi</p>"),
+            "<p>Hi</p>"
+        );
+        assert_eq!(
+            sanitize_html("<img src=\"data:text/html,steal()\">"),
+            "<img>"
+        );
+    }
+
+    #[test]
+    fn keeps_email_layout() {
+        let html = "<table width=\"600\" cellpadding=\"0\"><tbody><tr>\
+            <td style=\"color: #333\" align=\"center\"><img src=\"cid:logo\" alt=\"Logo\"></td>\
+            </tr></tbody></table>";
+        assert_eq!(sanitize_html(html), html);
+    }
+}
+
+#[cfg(test)]
+mod sanitize_body {
+    use super::*;
+
+    #[test]
+    fn leaves_empty_body() {
+        let mut email = EmailMessage::default();
+        sanitize_body(&mut email);
+        assert!(email.body_html.is_empty());
+        email.body_html = "<b>Hi</b><script>steal()</script>".into();
+        sanitize_body(&mut email);
+        assert_eq!(email.body_html, "<b>Hi</b>");
+    }
+}
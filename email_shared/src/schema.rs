@@ -0,0 +1,209 @@
+//! Names shared between the records written to DynamoDB, the expressions used to update them,
+//! and the environment the Lambda is configured from. Code reading or writing a record refers to
+//! these rather than repeating the literal so a name only changes in one place.
+
+use crate::attribute_value_wrapper::AttributeValueMap;
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::HashMap;
+
+/// Names of the attributes of items in the email, recipient, outbox, suppression, and tracking tables.
+pub mod attribute {
+    /// Suppressed address, key of the suppression table.
+    pub const ADDRESS: &str = "Address";
+    /// Delivery of a pointer holding the claim on an email while it is `Sending`.
+    pub const CLAIMED_BY: &str = "ClaimedBy";
+    /// When an outbox marker or suppression was created.
+    pub const CREATED_AT: &str = "CreatedAt";
+    /// Identifier of the email, key of the email and outbox tables.
+    pub const EMAIL_ID: &str = "EmailId";
+    /// Last known state of an email.
+    pub const EMAIL_STATUS: &str = "EmailStatus";
+    /// Epoch seconds after which DynamoDB TTL deletes an email which is no longer being sent.
+    pub const EXPIRES_AT: &str = "ExpiresAt";
+    /// Bounces and complaints reported for an email.
+    pub const FEEDBACK: &str = "Feedback";
+    /// Number of times a tracked open or click has been recorded.
+    pub const HITS: &str = "Hits";
+    /// Whether a tracking item records opens or clicks.
+    pub const KIND: &str = "Kind";
+    /// When a tracked open or click was last recorded.
+    pub const LAST_HIT_AT: &str = "LastHitAt";
+    /// FIFO message group the pointer of an outbox marker is sent in.
+    pub const MESSAGE_GROUP_ID: &str = "MessageGroupId";
+    /// Why an address was suppressed.
+    pub const REASON: &str = "Reason";
+    /// Response from the provider after sending an email successfully.
+    pub const PROVIDER_RESPONSE: &str = "ProviderResponse";
+    /// Address of a personalized recipient, sort key of the recipient table.
+    pub const RECIPIENT: &str = "Recipient";
+    /// Last known state of the copy sent to a personalized recipient.
+    pub const RECIPIENT_STATUS: &str = "RecipientStatus";
+    /// Where the exact message sent is stored.
+    pub const RENDERED_MIME: &str = "RenderedMime";
+    /// When an email was sent.
+    pub const SENT_AT: &str = "SentAt";
+    /// When the claim on an email which is `Sending` lapses and another delivery may take it.
+    pub const SENDING_LOCK_EXPIRES_AT: &str = "SendingLockExpiresAt";
+    /// Each status an email reached and when, oldest first.
+    pub const STATUS_HISTORY: &str = "StatusHistory";
+    /// Why an email reached its status when it was not sent.
+    pub const STATUS_REASON: &str = "StatusReason";
+    /// Subject line of an email.
+    pub const SUBJECT: &str = "Subject";
+    /// Identifier of a tracked open or click, key of the tracking table.
+    pub const TRACKING_ID: &str = "TrackingId";
+    /// When the status of an email last changed, sort key of the status index.
+    pub const UPDATED_AT: &str = "UpdatedAt";
+    /// Destination of a tracked link.
+    pub const URL: &str = "Url";
+    /// Number of times an email has been updated, absent until the first update.
+    pub const VERSION: &str = "Version";
+}
+
+/// Placeholders for values in condition, key condition, and update expressions.
+pub mod placeholder {
+    /// Delivery of a pointer claiming a record.
+    pub const CLAIM: &str = ":claim";
+    /// `EmailId` of the record queried.
+    pub const EMAIL_ID: &str = ":email_id";
+    /// An empty list.
+    pub const EMPTY: &str = ":empty";
+    /// Status a record must have for an update to apply.
+    pub const EXPECTED: &str = ":expected";
+    /// When a claim written by an update lapses.
+    pub const EXPIRES: &str = ":expires";
+    /// Epoch seconds after which a record is deleted.
+    pub const EXPIRES_AT: &str = ":expires_at";
+    /// Feedback appended to a record.
+    pub const FEEDBACK: &str = ":feedback";
+    /// Status changes appended to a record.
+    pub const HISTORY: &str = ":history";
+    /// Location of a stored message.
+    pub const LOCATION: &str = ":location";
+    /// Status a record is updated to.
+    pub const NEXT: &str = ":next";
+    /// Version a record is updated to.
+    pub const NEXT_VERSION: &str = ":next_version";
+    /// Time an update is made, compared against when a claim lapses.
+    pub const NOW: &str = ":now";
+    /// The number one, added to a counter.
+    pub const ONE: &str = ":one";
+    /// Why a record reached its status.
+    pub const REASON: &str = ":reason";
+    /// Response from the provider after sending.
+    pub const RESPONSE: &str = ":response";
+    /// Status of a record whose claim may have lapsed.
+    pub const SENDING: &str = ":sending";
+    /// When a record was sent.
+    pub const SENT_AT: &str = ":sent_at";
+    /// When a record was updated.
+    pub const UPDATED_AT: &str = ":updated_at";
+    /// Version a record must have for an update to apply.
+    pub const VERSION: &str = ":version";
+}
+
+/// Names of the environment variables `email_lambda` is configured from.
+pub mod env_var {
+    pub const ALERT_FAILURE_RATE: &str = "ALERT_FAILURE_RATE";
+    pub const ALERT_TOPIC_ARN: &str = "ALERT_TOPIC_ARN";
+    pub const ALERT_WINDOW: &str = "ALERT_WINDOW";
+    pub const ALLOW_DOMAINS: &str = "ALLOW_DOMAINS";
+    pub const ARCHIVE_BCC: &str = "ARCHIVE_BCC";
+    pub const ASSUME_ROLE_ARN: &str = "ASSUME_ROLE_ARN";
+    pub const ASSUME_ROLE_EXTERNAL_ID: &str = "ASSUME_ROLE_EXTERNAL_ID";
+    pub const ASSUME_ROLE_SESSION_NAME: &str = "ASSUME_ROLE_SESSION_NAME";
+    pub const ATTACHMENT_MAX_BYTES: &str = "ATTACHMENT_MAX_BYTES";
+    pub const ATTACHMENT_TIMEOUT: &str = "ATTACHMENT_TIMEOUT";
+    pub const CACHE_MAX_ENTRIES: &str = "CACHE_MAX_ENTRIES";
+    pub const CIRCUIT_BREAKER_COOLDOWN: &str = "CIRCUIT_BREAKER_COOLDOWN";
+    pub const CIRCUIT_BREAKER_THRESHOLD: &str = "CIRCUIT_BREAKER_THRESHOLD";
+    pub const CONFIG_FILE: &str = "CONFIG_FILE";
+    pub const CONNECT_TIMEOUT: &str = "CONNECT_TIMEOUT";
+    pub const DENY_DOMAINS: &str = "DENY_DOMAINS";
+    pub const DYNAMO_ENDPOINT: &str = "DYNAMO_ENDPOINT";
+    pub const DYNAMO_RETRY: &str = "DYNAMO_RETRY";
+    pub const DYNAMO_TABLE: &str = "DYNAMO_TABLE";
+    pub const EVENT_BUS: &str = "EVENT_BUS";
+    pub const FAILURE_QUEUE_URL: &str = "FAILURE_QUEUE_URL";
+    pub const MAX_ATTEMPTS: &str = "MAX_ATTEMPTS";
+    pub const MAX_MESSAGE_AGE: &str = "MAX_MESSAGE_AGE";
+    pub const MESSAGE_BUDGET: &str = "MESSAGE_BUDGET";
+    pub const METRICS_FORMAT: &str = "METRICS_FORMAT";
+    pub const METRICS_NAMESPACE: &str = "METRICS_NAMESPACE";
+    pub const MIME_STORE: &str = "MIME_STORE";
+    pub const MIME_STORE_ENCRYPTION: &str = "MIME_STORE_ENCRYPTION";
+    pub const OPERATION_TIMEOUT: &str = "OPERATION_TIMEOUT";
+    pub const PROVIDER_RETRY: &str = "PROVIDER_RETRY";
+    pub const QUARANTINE_ALLOW_FIELDS: &str = "QUARANTINE_ALLOW_FIELDS";
+    pub const QUARANTINE_STORE: &str = "QUARANTINE_STORE";
+    pub const QUEUE_URL: &str = "QUEUE_URL";
+    pub const RATE_LIMIT: &str = "RATE_LIMIT";
+    pub const RATE_LIMIT_MAX_DELAY: &str = "RATE_LIMIT_MAX_DELAY";
+    pub const RECIPIENT_TABLE: &str = "RECIPIENT_TABLE";
+    pub const REDIRECT_TO: &str = "REDIRECT_TO";
+    pub const RETENTION: &str = "RETENTION";
+    pub const SANITIZE_HTML: &str = "SANITIZE_HTML";
+    pub const SENDING_LEASE: &str = "SENDING_LEASE";
+    pub const SQS_ENDPOINT: &str = "SQS_ENDPOINT";
+    pub const SQS_RETRY: &str = "SQS_RETRY";
+    pub const STATUS_INDEX: &str = "STATUS_INDEX";
+    pub const SUPPRESSION_CACHE_TTL: &str = "SUPPRESSION_CACHE_TTL";
+    pub const SUPPRESSION_TABLE: &str = "SUPPRESSION_TABLE";
+    pub const TEMPLATE_SOURCE: &str = "TEMPLATE_SOURCE";
+    pub const TEMPLATE_TTL: &str = "TEMPLATE_TTL";
+    pub const TENANTS: &str = "TENANTS";
+    pub const TRACKING_TABLE: &str = "TRACKING_TABLE";
+    pub const TRACKING_URL: &str = "TRACKING_URL";
+    pub const WEB_IDENTITY_TOKEN_FILE: &str = "WEB_IDENTITY_TOKEN_FILE";
+}
+
+/// Key of the record identified by `email_id` in the email or outbox table.
+pub fn email_key(email_id: &str) -> HashMap<String, AttributeValue> {
+    AttributeValueMap::with_entry(attribute::EMAIL_ID, email_id.to_owned())
+}
+
+/// Condition that an item has `name`, typically its key so the item exists.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::schema::{attribute, attribute_exists};
+///
+/// assert_eq!(attribute_exists(attribute::EMAIL_ID), "attribute_exists(EmailId)");
+/// ```
+pub fn attribute_exists(name: &str) -> String {
+    format!("attribute_exists({})", name)
+}
+
+/// Condition that an item does not have `name`, typically its key so the item is new.
+pub fn attribute_not_exists(name: &str) -> String {
+    format!("attribute_not_exists({})", name)
+}
+
+/// Condition that `name` holds the value of `placeholder`.
+pub fn equals(name: &str, placeholder: &str) -> String {
+    format!("{} = {}", name, placeholder)
+}
+
+/// Update expression setting each attribute to the value of its placeholder.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::schema::{attribute, placeholder, set};
+///
+/// assert_eq!(
+///     set(&[
+///         (attribute::EMAIL_STATUS, placeholder::NEXT),
+///         (attribute::STATUS_REASON, placeholder::REASON),
+///     ]),
+///     "SET EmailStatus = :next, StatusReason = :reason",
+/// );
+/// ```
+pub fn set(assignments: &[(&str, &str)]) -> String {
+    let assignments: Vec<String> = assignments
+        .iter()
+        .map(|(name, placeholder)| equals(name, placeholder))
+        .collect();
+    format!("SET {}", assignments.join(", "))
+}
@@ -0,0 +1,268 @@
+use aws_sdk_secretsmanager::error::DisplayErrorContext;
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use aws_sdk_ssm::Client as SsmClient;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{event, Level};
+
+/// Prefix of references to SSM Parameter Store parameters.
+const SSM_PREFIX: &str = "ssm:";
+/// Prefix of references to Secrets Manager secrets.
+const SECRETS_MANAGER_PREFIX: &str = "secretsmanager:";
+
+/// Possible errors while reading a secret. Secret values are never included.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum SecretError {
+    /// The given string does not describe a `SecretRef`.
+    #[error("InvalidReference({0})")]
+    InvalidReference(String),
+    /// The secret is a JSON object without the requested key.
+    #[error("MissingKey({0})")]
+    MissingKey(String),
+    /// The parameter or secret exists but holds no text value.
+    #[error("MissingValue({0})")]
+    MissingValue(String),
+    /// The parameter or secret could not be read.
+    #[error("SourceError({0})")]
+    SourceError(String),
+}
+
+/// Where a setting is stored rather than its value, so credentials need not live in plain
+/// environment variables.
+///
+/// ```
+/// use email_shared::SecretRef;
+///
+/// let reference: SecretRef = "ssm:/emails/queue_url".parse().unwrap();
+/// assert_eq!(reference, SecretRef::Parameter { name: "/emails/queue_url".into() });
+/// let reference: SecretRef = "secretsmanager:emails/smtp#password".parse().unwrap();
+/// assert_eq!(
+///     reference,
+///     SecretRef::Secret { id: "emails/smtp".into(), key: Some("password".into()) },
+/// );
+/// assert!("emails".parse::<SecretRef>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum SecretRef {
+    /// An SSM Parameter Store parameter, `SecureString` parameters are decrypted.
+    Parameter { name: String },
+    /// A Secrets Manager secret identified by name or ARN. With a `key` the secret is a JSON
+    /// object and only the value of that key is used.
+    Secret { id: String, key: Option<String> },
+}
+
+impl FromStr for SecretRef {
+    type Err = SecretError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix(SSM_PREFIX) {
+            if !name.is_empty() {
+                return Ok(SecretRef::Parameter { name: name.into() });
+            }
+        } else if let Some(location) = s.strip_prefix(SECRETS_MANAGER_PREFIX) {
+            let (id, key) = match location.split_once('#') {
+                Some((id, key)) => (id, Some(key.to_owned())),
+                None => (location, None),
+            };
+            if !id.is_empty() && key.as_deref() != Some("") {
+                return Ok(SecretRef::Secret { id: id.into(), key });
+            }
+        }
+        Err(SecretError::InvalidReference(s.into()))
+    }
+}
+
+impl fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretRef::Parameter { name } => write!(f, "{}{}", SSM_PREFIX, name),
+            SecretRef::Secret { id, key: None } => write!(f, "{}{}", SECRETS_MANAGER_PREFIX, id),
+            SecretRef::Secret { id, key: Some(key) } => {
+                write!(f, "{}{}#{}", SECRETS_MANAGER_PREFIX, id, key)
+            }
+        }
+    }
+}
+
+/// Read parameters from SSM Parameter Store and secrets from Secrets Manager. Each value is
+/// cached once read, and read again after the refresh interval when one is set so rotated
+/// credentials are picked up without a restart.
+pub struct Secrets {
+    /// Values already read along with the time they were read.
+    cache: Mutex<HashMap<SecretRef, (Instant, String)>>,
+    /// How long a value is used before it is read again, forever when `None`.
+    refresh: Option<Duration>,
+    /// Connection to Secrets Manager.
+    secrets_manager: SecretsManagerClient,
+    /// Connection to SSM Parameter Store.
+    ssm: SsmClient,
+}
+
+impl Secrets {
+    pub fn new(ssm: SsmClient, secrets_manager: SecretsManagerClient) -> Self {
+        Secrets {
+            cache: Mutex::new(HashMap::new()),
+            refresh: None,
+            secrets_manager,
+            ssm,
+        }
+    }
+
+    /// Read each value again once it has been used for `refresh`.
+    pub fn with_refresh(self, refresh: Duration) -> Self {
+        Secrets {
+            refresh: Some(refresh),
+            ..self
+        }
+    }
+
+    /// Get the value stored at `reference` from the cache or its source.
+    pub async fn get(&self, reference: &SecretRef) -> Result<String, SecretError> {
+        if let Some(value) = self.cached(reference) {
+            return Ok(value);
+        }
+        event!(Level::DEBUG, %reference, "read secret");
+        let value = match reference {
+            SecretRef::Parameter { name } => self.load_parameter(name).await?,
+            SecretRef::Secret { id, key } => {
+                let secret = self.load_secret(id).await?;
+                match key {
+                    Some(key) => secret_field(&secret, key)?,
+                    None => secret,
+                }
+            }
+        };
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.insert(reference.clone(), (Instant::now(), value.clone()));
+        Ok(value)
+    }
+
+    /// The cached value of `reference` if it was read within the refresh interval.
+    fn cached(&self, reference: &SecretRef) -> Option<String> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache
+            .get(reference)
+            .filter(|(read_at, _)| {
+                self.refresh
+                    .is_none_or(|refresh| read_at.elapsed() < refresh)
+            })
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Read and decrypt the parameter called `name`.
+    async fn load_parameter(&self, name: &str) -> Result<String, SecretError> {
+        let output = self
+            .ssm
+            .get_parameter()
+            .name(name)
+            .with_decryption(true)
+            .send()
+            .await
+            .map_err(|error| {
+                SecretError::SourceError(format!("{}", DisplayErrorContext(&error)))
+            })?;
+        output
+            .parameter()
+            .and_then(|parameter| parameter.value())
+            .map(String::from)
+            .ok_or_else(|| SecretError::MissingValue(name.into()))
+    }
+
+    /// Read the text of the secret identified by `id`.
+    async fn load_secret(&self, id: &str) -> Result<String, SecretError> {
+        let output = self
+            .secrets_manager
+            .get_secret_value()
+            .secret_id(id)
+            .send()
+            .await
+            .map_err(|error| {
+                SecretError::SourceError(format!("{}", DisplayErrorContext(&error)))
+            })?;
+        output
+            .secret_string()
+            .map(String::from)
+            .ok_or_else(|| SecretError::MissingValue(id.into()))
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("refresh", &self.refresh)
+            .finish()
+    }
+}
+
+/// Value of `key` in `secret`, a JSON object as Secrets Manager stores key/value secrets.
+/// Values which are not strings are used as their JSON text.
+fn secret_field(secret: &str, key: &str) -> Result<String, SecretError> {
+    let fields: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(secret).map_err(|_| SecretError::MissingKey(key.into()))?;
+    match fields.get(key) {
+        Some(serde_json::Value::String(value)) => Ok(value.clone()),
+        Some(value) => Ok(value.to_string()),
+        None => Err(SecretError::MissingKey(key.into())),
+    }
+}
+
+#[cfg(test)]
+mod secret_field {
+    use super::*;
+
+    #[test]
+    fn reads_keys_of_json_secrets() {
+        let secret = r#"{"username":"mailer","password":"hunter2","port":587}"#;
+        assert_eq!(secret_field(secret, "password"), Ok("hunter2".into()));
+        assert_eq!(secret_field(secret, "port"), Ok("587".into()));
+        assert_eq!(
+            secret_field(secret, "host"),
+            Err(SecretError::MissingKey("host".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_plain_text_secrets() {
+        assert_eq!(
+            secret_field("hunter2", "password"),
+            Err(SecretError::MissingKey("password".into()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_display() {
+        for reference in &[
+            "ssm:/emails/table_name",
+            "secretsmanager:arn:aws:secretsmanager:us-east-1:000000000000:secret:emails",
+            "secretsmanager:emails/sendgrid#api_key",
+        ] {
+            let parsed = reference.parse::<SecretRef>().unwrap();
+            assert_eq!(parsed.to_string(), *reference);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_references() {
+        for reference in &[
+            "ssm:",
+            "secretsmanager:",
+            "secretsmanager:emails#",
+            "s3://bucket",
+        ] {
+            assert!(
+                reference.parse::<SecretRef>().is_err(),
+                "{} was parsed",
+                reference
+            );
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use crate::alerts::Alerts;
+use crate::archive::ArchiveBcc;
+use crate::attachments::AttachmentFetcher;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::client::Client;
+use crate::dead_letter::FailureQueue;
+use crate::domains::DomainPolicy;
+use crate::events::EventBus;
+use crate::max_age::MaxMessageAge;
+use crate::metrics::Metrics;
+use crate::mime_store::S3MimeStore;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::suppression::Suppressions;
+use crate::templates::Templates;
+use crate::tenants::Tenants;
+use crate::tracking::Tracking;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Everything a `Client` sends with, created once by a binary and lent to every `Client` made by
+/// `client`. `email_broker` and `email_lambda` each build this from their `Config` with every
+/// field named, so a subsystem added here fails to build until both provide it, and `client` is
+/// the one place it is handed to the `Client`.
+pub struct ClientServices {
+    /// Topic on-call is alerted through when emails fail.
+    pub alerts: Option<Alerts>,
+    /// Archival address blind copied on every message sent.
+    pub archive_bcc: Option<ArchiveBcc>,
+    /// Fetcher for attachment contents stored outside of the email record.
+    pub attachments: AttachmentFetcher,
+    /// Stops calls to the email provider while it is failing.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Recipient domains which may be sent mail.
+    pub domains: Option<DomainPolicy>,
+    /// Connection to DynamoDB.
+    pub dynamodb: DynamoDbClient,
+    /// Attempts of each call to DynamoDB in place of the SDK default.
+    pub dynamo_retry: Option<RetryPolicy>,
+    /// Bus the life of each email is announced on.
+    pub event_bus: Option<EventBus>,
+    /// Queue receiving emails which ran out of attempts.
+    pub failure_queue: Option<FailureQueue>,
+    /// Oldest a pointer message may be before its email is failed instead of sent.
+    pub max_age: Option<MaxMessageAge>,
+    /// Longest a message may take to reach its transmission before its email is released.
+    pub message_budget: Option<Duration>,
+    /// Counters and timers, shared with whatever publishes or serves them.
+    pub metrics: Option<Arc<Metrics>>,
+    /// Storage for the exact messages sent so they can be resent unchanged.
+    pub mime_store: Option<S3MimeStore>,
+    /// Attempts of each transmission through the email provider.
+    pub provider_retry: Option<RetryPolicy>,
+    /// Budget of sends per second.
+    pub rate_limiter: Option<RateLimiter>,
+    /// DynamoDB table tracking the status of each recipient of personalized emails.
+    pub recipient_table: Option<String>,
+    /// Address every email is sent to in place of its recipients.
+    pub redirect_to: Option<String>,
+    /// How long an email is kept once it is no longer being sent, forever when `None`.
+    pub retention: Option<Duration>,
+    /// Whether HTML bodies are sanitized before they are sent.
+    pub sanitize_html: bool,
+    /// Longest an email is claimed for sending before another delivery may take it over.
+    pub sending_lease: Duration,
+    /// Attempts of each call a `Runner` makes to SQS in place of the SDK default.
+    pub sqs_retry: Option<RetryPolicy>,
+    /// Addresses which are never sent mail.
+    pub suppressions: Option<Suppressions>,
+    /// DynamoDB table from which email data will be read.
+    pub table_name: String,
+    /// Templates used to render bodies of emails which have none.
+    pub templates: Option<Templates>,
+    /// Tables and senders of the products pointers may name as their tenant.
+    pub tenants: Tenants,
+    /// Link rewriting and open tracking.
+    pub tracking: Option<Tracking>,
+}
+
+impl ClientServices {
+    /// A `Client` reading `table_name` and sending with every configured service.
+    pub fn client(&self) -> Client<'_> {
+        let client = Client::new(&self.dynamodb, &self.table_name);
+        // The retry policy replaces the configuration of the connection so it is set before the
+        // capacity meter is installed on it
+        let client = match self.dynamo_retry {
+            Some(policy) => client.with_dynamo_retry(policy),
+            None => client,
+        };
+        // Asking for consumed capacity is free and makes the capacity of the table plannable
+        let client = client
+            .with_consumed_capacity()
+            .with_attachments(&self.attachments)
+            .with_sending_lease(self.sending_lease);
+        let client = match &self.alerts {
+            Some(alerts) => client.with_alerts(alerts),
+            None => client,
+        };
+        let client = match &self.archive_bcc {
+            Some(archive_bcc) => client.with_archive_bcc(archive_bcc),
+            None => client,
+        };
+        let client = match &self.circuit_breaker {
+            Some(breaker) => client.with_circuit_breaker(breaker),
+            None => client,
+        };
+        let client = match &self.domains {
+            Some(domains) => client.with_domain_policy(domains),
+            None => client,
+        };
+        let client = match &self.event_bus {
+            Some(event_bus) => client.with_event_bus(event_bus),
+            None => client,
+        };
+        let client = match &self.failure_queue {
+            Some(failure_queue) => client.with_failure_queue(failure_queue),
+            None => client,
+        };
+        let client = match &self.max_age {
+            Some(max_age) => client.with_max_age(max_age),
+            None => client,
+        };
+        let client = match self.message_budget {
+            Some(budget) => client.with_message_budget(budget),
+            None => client,
+        };
+        let client = match &self.metrics {
+            Some(metrics) => client.with_metrics(metrics),
+            None => client,
+        };
+        let client = match &self.mime_store {
+            Some(mime_store) => client.with_mime_store(mime_store),
+            None => client,
+        };
+        let client = match self.provider_retry {
+            Some(policy) => client.with_provider_retry(policy),
+            None => client,
+        };
+        let client = match &self.rate_limiter {
+            Some(rate_limiter) => client.with_rate_limiter(rate_limiter),
+            None => client,
+        };
+        let client = match &self.recipient_table {
+            Some(recipient_table) => client.with_recipient_table(recipient_table),
+            None => client,
+        };
+        let client = match &self.redirect_to {
+            Some(address) => client.with_redirect_to(address),
+            None => client,
+        };
+        let client = match self.retention {
+            Some(retention) => client.with_retention(retention),
+            None => client,
+        };
+        let client = if self.sanitize_html {
+            client.with_html_sanitizer()
+        } else {
+            client
+        };
+        let client = match self.sqs_retry {
+            Some(policy) => client.with_sqs_retry(policy),
+            None => client,
+        };
+        let client = match &self.suppressions {
+            Some(suppressions) => client.with_suppressions(suppressions),
+            None => client,
+        };
+        let client = match &self.templates {
+            Some(templates) => client.with_templates(templates),
+            None => client,
+        };
+        let client = if self.tenants.is_empty() {
+            client
+        } else {
+            client.with_tenants(&self.tenants)
+        };
+        match &self.tracking {
+            Some(tracking) => client.with_tracking(tracking),
+            None => client,
+        }
+    }
+}
+
+#[cfg(test)]
+mod client {
+    use super::*;
+    use crate::email_message::EmailStatus;
+    use crate::tenants::Tenant;
+    use crate::test_support::{email, InMemoryDynamoDb};
+    use aws_sdk_s3::Client as S3Client;
+
+    #[tokio::test]
+    async fn lends_configured_services() {
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email("Test EmailId", EmailStatus::Pending));
+        let s3 = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .build();
+        let services = ClientServices {
+            alerts: None,
+            archive_bcc: None,
+            attachments: AttachmentFetcher::new(S3Client::from_conf(s3)),
+            circuit_breaker: None,
+            domains: None,
+            dynamodb: table.client(),
+            dynamo_retry: None,
+            event_bus: None,
+            failure_queue: None,
+            max_age: None,
+            message_budget: None,
+            metrics: None,
+            mime_store: None,
+            provider_retry: None,
+            rate_limiter: None,
+            recipient_table: None,
+            redirect_to: Some("qa@example.com".into()),
+            retention: None,
+            sanitize_html: false,
+            sending_lease: Duration::from_secs(300),
+            sqs_retry: None,
+            suppressions: None,
+            table_name: "Test Table".into(),
+            templates: None,
+            tenants: Tenants::from([(
+                "acme".to_owned(),
+                Tenant {
+                    table_name: "Acme Table".into(),
+                    provider: None,
+                    sender: Some("Acme <mail@acme.com>".into()),
+                },
+            )]),
+            tracking: None,
+        };
+        let message = services
+            .client()
+            .preview("Test EmailId", Some("acme"))
+            .await
+            .unwrap();
+        let raw = String::from_utf8(message.raw).unwrap();
+        assert!(raw.contains("From: Acme <mail@acme.com>\r\n"));
+        assert!(raw.contains("To: qa@example.com\r\n"));
+    }
+}
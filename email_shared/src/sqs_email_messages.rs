@@ -1,4 +1,4 @@
-use rusoto_sqs::Message;
+use aws_sdk_sqs::types::Message;
 
 use crate::queue::EmailPointerMessage;
 
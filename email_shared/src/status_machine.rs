@@ -0,0 +1,110 @@
+use crate::dynamo::StatusTransition;
+use crate::email_message::EmailStatus;
+use crate::error::UpdateError;
+
+/// The changes of `EmailStatus` an email may go through. Every status update checks its
+/// transition here so an illegal one is rejected before DynamoDB is called.
+///
+/// ```
+/// use email_shared::{EmailStatus, StatusMachine};
+///
+/// assert!(StatusMachine::allows(EmailStatus::Pending, EmailStatus::Sending));
+/// assert!(StatusMachine::allows(EmailStatus::Failed, EmailStatus::Pending));
+/// assert!(!StatusMachine::allows(EmailStatus::Sent, EmailStatus::Pending));
+/// assert_eq!(
+///     StatusMachine::next(EmailStatus::Failed),
+///     vec![EmailStatus::Pending]
+/// );
+/// assert!(StatusMachine::transition(EmailStatus::Sent, EmailStatus::Sending).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StatusMachine;
+
+/// Every legal transition, grouped by the status it starts from.
+const TRANSITIONS: [(EmailStatus, EmailStatus); 13] = [
+    // Claimed by a delivery.
+    (EmailStatus::Pending, EmailStatus::Sending),
+    // Rejected before sending, for example because it expired.
+    (EmailStatus::Pending, EmailStatus::Failed),
+    // Every recipient suppressed.
+    (EmailStatus::Pending, EmailStatus::Skipped),
+    // Every recipient on a blocked domain.
+    (EmailStatus::Pending, EmailStatus::Suppressed),
+    // Retracted by its producer.
+    (EmailStatus::Pending, EmailStatus::Cancelled),
+    // A lapsed claim taken over by another delivery.
+    (EmailStatus::Sending, EmailStatus::Sending),
+    // Transmitted by the provider.
+    (EmailStatus::Sending, EmailStatus::Sent),
+    // Claim released or lapsed before the email was transmitted.
+    (EmailStatus::Sending, EmailStatus::Pending),
+    // Given up on while claimed, or rejected once a lapsed claim is taken over.
+    (EmailStatus::Sending, EmailStatus::Failed),
+    // Every recipient suppressed once a lapsed claim is taken over.
+    (EmailStatus::Sending, EmailStatus::Skipped),
+    // Every recipient on a blocked domain once a lapsed claim is taken over.
+    (EmailStatus::Sending, EmailStatus::Suppressed),
+    // Requeued to be attempted again.
+    (EmailStatus::Failed, EmailStatus::Pending),
+    // Requeued after being withheld by a domain policy which has since changed.
+    (EmailStatus::Suppressed, EmailStatus::Pending),
+];
+
+impl StatusMachine {
+    /// Whether an email may change from `from` to `to`.
+    pub fn allows(from: EmailStatus, to: EmailStatus) -> bool {
+        TRANSITIONS.contains(&(from, to))
+    }
+
+    /// Statuses an email may change to from `from`, none once it is `EmailStatus::Sent`,
+    /// `EmailStatus::Skipped`, or `EmailStatus::Cancelled`.
+    pub fn next(from: EmailStatus) -> Vec<EmailStatus> {
+        TRANSITIONS
+            .iter()
+            .filter(|(start, _)| *start == from)
+            .map(|(_, to)| *to)
+            .collect()
+    }
+
+    /// The transition from `from` to `to`, or `UpdateError::IllegalTransition` when an email may
+    /// not make it.
+    pub fn transition(from: EmailStatus, to: EmailStatus) -> Result<StatusTransition, UpdateError> {
+        if Self::allows(from, to) {
+            Ok(StatusTransition { from, to })
+        } else {
+            Err(UpdateError::IllegalTransition(format!(
+                "{} to {} is not a legal status transition",
+                from, to
+            )))
+        }
+    }
+
+    /// `transition` after checking an email may make it.
+    pub fn check(transition: StatusTransition) -> Result<StatusTransition, UpdateError> {
+        Self::transition(transition.from, transition.to)
+    }
+}
+
+#[cfg(test)]
+mod next {
+    use super::*;
+
+    #[test]
+    fn ends_at_terminal_statuses() {
+        assert!(StatusMachine::next(EmailStatus::Sent).is_empty());
+        assert!(StatusMachine::next(EmailStatus::Skipped).is_empty());
+        assert!(StatusMachine::next(EmailStatus::Cancelled).is_empty());
+        assert!(StatusMachine::next(EmailStatus::Unknown).is_empty());
+        assert_eq!(
+            StatusMachine::next(EmailStatus::Sending),
+            vec![
+                EmailStatus::Sending,
+                EmailStatus::Sent,
+                EmailStatus::Pending,
+                EmailStatus::Failed,
+                EmailStatus::Skipped,
+                EmailStatus::Suppressed
+            ]
+        );
+    }
+}
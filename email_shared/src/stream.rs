@@ -0,0 +1,194 @@
+use crate::email_message::{EmailId, EmailStatus};
+use crate::queue::send_email_pointer;
+use crate::queue_url::QueueUrl;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::Client as SqsClient;
+use serde::Deserialize;
+use tracing::{event, Level};
+
+/// `eventName` of a stream record for a newly written item.
+const INSERT: &str = "INSERT";
+
+/// A record of the DynamoDB Stream of the email table, in the shape Lambda delivers it. Only what
+/// is needed to find newly written `EmailStatus::Pending` emails is read, the stream must include
+/// new images.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StreamRecord {
+    /// Kind of change, "INSERT", "MODIFY", or "REMOVE".
+    #[serde(rename = "eventName")]
+    pub event_name: String,
+    /// The change itself.
+    pub dynamodb: StreamChange,
+}
+
+/// The change described by a `StreamRecord`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct StreamChange {
+    /// The item after the change, when the stream includes new images.
+    #[serde(default)]
+    pub new_image: Option<StreamImage>,
+    /// Position of the change in its shard.
+    #[serde(default)]
+    pub sequence_number: Option<String>,
+}
+
+/// The attributes of an email record read from a stream image.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct StreamImage {
+    /// The `attribute::EMAIL_ID` of the item.
+    #[serde(default, rename = "EmailId")]
+    email_id: Option<StringAttribute>,
+    /// The `attribute::EMAIL_STATUS` of the item.
+    #[serde(default, rename = "EmailStatus")]
+    email_status: Option<StringAttribute>,
+}
+
+/// A string attribute value in DynamoDB JSON.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct StringAttribute {
+    #[serde(rename = "S")]
+    s: String,
+}
+
+impl StreamRecord {
+    /// `EmailId` of the email this record inserted as `EmailStatus::Pending`, if it did. Changes
+    /// to existing items, such as an email returned to `Pending` by `requeue_email`, are `None`
+    /// as whatever changed them sends its own pointer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use email_shared::StreamRecord;
+    ///
+    /// let record: StreamRecord = serde_json::from_str(r#"{
+    ///     "eventName": "INSERT",
+    ///     "dynamodb": {
+    ///         "NewImage": {"EmailId": {"S": "Test EmailId"}, "EmailStatus": {"S": "Pending"}},
+    ///         "SequenceNumber": "111"
+    ///     }
+    /// }"#).unwrap();
+    /// assert_eq!(record.pending_insert().unwrap(), "Test EmailId");
+    /// ```
+    pub fn pending_insert(&self) -> Option<EmailId> {
+        if self.event_name != INSERT {
+            return None;
+        }
+        let image = self.dynamodb.new_image.as_ref()?;
+        let status = image.email_status.as_ref()?;
+        if EmailStatus::from(status.s.as_str()) != EmailStatus::Pending {
+            return None;
+        }
+        image
+            .email_id
+            .as_ref()
+            .and_then(|email_id| EmailId::new(email_id.s.as_str()).ok())
+    }
+}
+
+/// Summary of a batch of stream records handled by a `StreamEnqueuer`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StreamReport {
+    /// Number of records in the batch.
+    pub received: usize,
+    /// Number of records which inserted a `Pending` email.
+    pub found: usize,
+    /// Number of emails whose pointer was sent.
+    pub enqueued: usize,
+    /// Number of emails whose pointer could not be sent.
+    pub failed: usize,
+}
+
+impl StreamReport {
+    /// Whether a pointer was sent for every `Pending` email found.
+    pub fn is_complete(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Send a pointer for every email inserted as `EmailStatus::Pending` in the email table, read
+/// from the table's DynamoDB Stream, so producers only write records and never call SQS. A
+/// producer which also sends its own pointer is harmless, whichever delivery claims the email
+/// first sends it and the other finds it no longer `Pending`, and a FIFO queue drops the second
+/// pointer as a duplicate.
+pub struct StreamEnqueuer<'a> {
+    /// URL of the queue pointers are sent to.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+}
+
+impl StreamEnqueuer<'_> {
+    pub fn new<'a>(queue_url: &'a QueueUrl, sqs: &'a SqsClient) -> StreamEnqueuer<'a> {
+        StreamEnqueuer { queue_url, sqs }
+    }
+
+    /// Send a pointer for each `Pending` email inserted by `records`. The batch should be
+    /// delivered again when the report is not complete, pointers already sent are sent again.
+    #[tracing::instrument(skip(self, records), level = Level::INFO)]
+    pub async fn run_once(&self, records: &[StreamRecord]) -> StreamReport {
+        let mut report = StreamReport {
+            received: records.len(),
+            ..StreamReport::default()
+        };
+        for email_id in records.iter().filter_map(StreamRecord::pending_insert) {
+            report.found += 1;
+            match send_email_pointer(self.queue_url, self.sqs, &email_id, None).await {
+                Ok(_) => {
+                    event!(Level::INFO, %email_id, "inserted email enqueued");
+                    report.enqueued += 1;
+                }
+                Err(error) => {
+                    let error = format!("{}", DisplayErrorContext(&error));
+                    event!(Level::ERROR, %email_id, %error, "email pointer not sent");
+                    report.failed += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod pending_insert {
+    use super::*;
+    use serde_json::json;
+
+    fn record(event_name: &str, status: &str) -> StreamRecord {
+        serde_json::from_value(json!({
+            "eventID": "1",
+            "eventName": event_name,
+            "eventSource": "aws:dynamodb",
+            "dynamodb": {
+                "Keys": {"EmailId": {"S": "Test EmailId"}},
+                "NewImage": {
+                    "EmailId": {"S": "Test EmailId"},
+                    "EmailStatus": {"S": status},
+                    "Subject": {"S": "Hello"},
+                    "Version": {"N": "1"}
+                },
+                "SequenceNumber": "111",
+                "StreamViewType": "NEW_AND_OLD_IMAGES"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_inserted_pending_emails() {
+        let email_id = record("INSERT", "Pending").pending_insert();
+        assert_eq!(email_id.unwrap(), "Test EmailId");
+    }
+
+    #[test]
+    fn ignores_other_changes() {
+        assert_eq!(record("MODIFY", "Pending").pending_insert(), None);
+        assert_eq!(record("INSERT", "Sent").pending_insert(), None);
+        let keys_only: StreamRecord = serde_json::from_value(json!({
+            "eventName": "INSERT",
+            "dynamodb": {"Keys": {"EmailId": {"S": "Test EmailId"}}}
+        }))
+        .unwrap();
+        assert_eq!(keys_only.pending_insert(), None);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::attribute_value_wrapper::AttributeValueMap;
+use crate::cache::TtlCache;
+use crate::email_message::{EmailMessage, Recipient};
+use crate::error::{GetError, PutError};
+use crate::schema::attribute;
+use aws_sdk_dynamodb::types::{AttributeValue, KeysAndAttributes};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// Most keys DynamoDB accepts in a single `BatchGetItem` request.
+const BATCH_GET_LIMIT: usize = 100;
+/// Number of requests made for keys DynamoDB leaves unprocessed before giving up.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Why mail to an address is suppressed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum SuppressionReason {
+    /// Mail to the address bounced permanently.
+    Bounce,
+    /// The recipient marked mail as spam.
+    Complaint,
+    /// The address was blocked by an operator, or for a reason not known to the broker.
+    Manual,
+}
+
+impl From<&str> for SuppressionReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "Bounce" => SuppressionReason::Bounce,
+            "Complaint" => SuppressionReason::Complaint,
+            _ => SuppressionReason::Manual,
+        }
+    }
+}
+
+impl std::fmt::Display for SuppressionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Key of `address` in the suppression table. Addresses are compared without regard to case so
+/// a bounce for one spelling of an address suppresses every spelling.
+pub fn suppression_key(address: &str) -> String {
+    address.trim().to_ascii_lowercase()
+}
+
+/// Addresses which must not be sent mail, stored in a DynamoDB table keyed by `Address` with the
+/// `SuppressionReason` as `Reason`.
+#[derive(Clone, Debug)]
+pub struct Suppressions {
+    /// Whether each recently checked key is suppressed, shared by every clone.
+    cache: Option<Arc<TtlCache<String, Option<SuppressionReason>>>>,
+    /// Connection to DynamoDB.
+    dynamodb: DynamoDbClient,
+    /// DynamoDB table of suppressed addresses.
+    table_name: String,
+}
+
+impl Suppressions {
+    pub fn new(dynamodb: DynamoDbClient, table_name: impl Into<String>) -> Self {
+        Suppressions {
+            cache: None,
+            dynamodb,
+            table_name: table_name.into(),
+        }
+    }
+
+    /// Remember whether each of at most `max_entries` addresses is suppressed for `ttl`, so an
+    /// address suppressed by another process is only seen once `ttl` has passed.
+    pub fn with_cache(self, ttl: Duration, max_entries: usize) -> Self {
+        Suppressions {
+            cache: Some(Arc::new(TtlCache::new(ttl, max_entries))),
+            ..self
+        }
+    }
+
+    /// Suppression cache hits and misses since they were last taken.
+    pub(crate) fn take_cache_counts(&self) -> (u64, u64) {
+        self.cache
+            .as_ref()
+            .map_or((0, 0), |cache| cache.take_counts())
+    }
+
+    /// Name of the suppression table.
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    /// Suppress mail to `address` for `reason`, replacing the reason of an address which is
+    /// already suppressed.
+    pub async fn add(&self, address: &str, reason: SuppressionReason) -> Result<(), PutError> {
+        let item = AttributeValueMap::with_entries(vec![
+            (attribute::ADDRESS.into(), suppression_key(address)),
+            (attribute::REASON.into(), reason.to_string()),
+            (attribute::CREATED_AT.into(), Utc::now().to_rfc3339()),
+        ]);
+        self.dynamodb
+            .put_item()
+            .set_item(Some(item))
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(PutError::from)?;
+        if let Some(cache) = &self.cache {
+            cache.insert(suppression_key(address), Some(reason));
+        }
+        Ok(())
+    }
+
+    /// Find which of `addresses` are suppressed along with the reason for each.
+    pub async fn check<'a, I>(
+        &self,
+        addresses: I,
+    ) -> Result<HashMap<String, SuppressionReason>, GetError>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut by_key: HashMap<String, Vec<&str>> = HashMap::new();
+        for address in addresses {
+            by_key
+                .entry(suppression_key(address))
+                .or_default()
+                .push(address);
+        }
+        let mut reasons = HashMap::new();
+        let mut keys = Vec::new();
+        for key in by_key.keys() {
+            match self.cache.as_ref().and_then(|cache| cache.get(key)) {
+                Some(reason) => {
+                    reasons.insert(key.clone(), reason);
+                }
+                None => keys.push(key.clone()),
+            }
+        }
+        for chunk in keys.chunks(BATCH_GET_LIMIT) {
+            let found = self
+                .get_reasons(chunk)
+                .await?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            // Keys without an item are cached too, most addresses checked are not suppressed
+            for key in chunk {
+                let reason = found.get(key).copied();
+                if let Some(cache) = &self.cache {
+                    cache.insert(key.clone(), reason);
+                }
+                reasons.insert(key.clone(), reason);
+            }
+        }
+        let mut suppressed = HashMap::new();
+        for (key, reason) in reasons {
+            if let Some(reason) = reason {
+                for address in by_key.get(&key).into_iter().flatten() {
+                    suppressed.insert((*address).to_owned(), reason);
+                }
+            }
+        }
+        Ok(suppressed)
+    }
+
+    /// Read the `Reason` of each of `keys` which has an item.
+    async fn get_reasons(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<(String, SuppressionReason)>, GetError> {
+        let mut request = Some(
+            keys.iter()
+                .map(|key| AttributeValueMap::with_entry(attribute::ADDRESS, key.clone()))
+                .collect::<Vec<_>>(),
+        );
+        let mut reasons = Vec::new();
+        for _ in 0..MAX_ATTEMPTS {
+            let keys = match request.take() {
+                Some(keys) if !keys.is_empty() => keys,
+                _ => return Ok(reasons),
+            };
+            let keys_and_attributes = KeysAndAttributes::builder()
+                .set_keys(Some(keys))
+                .projection_expression(format!("{}, {}", attribute::ADDRESS, attribute::REASON))
+                .build()
+                .map_err(|e| GetError::ServiceError(e.to_string()))?;
+            let output = self
+                .dynamodb
+                .batch_get_item()
+                .request_items(&self.table_name, keys_and_attributes)
+                .send()
+                .await?;
+            let items = output
+                .responses
+                .and_then(|mut responses| responses.remove(&self.table_name))
+                .unwrap_or_default();
+            for item in items {
+                let text = |name: &str| match item.get(name) {
+                    Some(AttributeValue::S(value)) => Some(value.as_str()),
+                    _ => None,
+                };
+                if let Some(address) = text(attribute::ADDRESS) {
+                    let reason =
+                        SuppressionReason::from(text(attribute::REASON).unwrap_or_default());
+                    reasons.push((address.to_owned(), reason));
+                }
+            }
+            request = output
+                .unprocessed_keys
+                .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+                .map(|unprocessed| unprocessed.keys);
+        }
+        match request {
+            Some(keys) if !keys.is_empty() => {
+                event!(
+                    Level::WARN,
+                    unprocessed = keys.len(),
+                    "suppression keys unprocessed"
+                );
+                Err(GetError::ProvisionedThroughputExceeded(format!(
+                    "{} suppression keys unprocessed",
+                    keys.len()
+                )))
+            }
+            _ => Ok(reasons),
+        }
+    }
+}
+
+/// Every address `email` would be sent to.
+pub(crate) fn recipients(email: &EmailMessage) -> Vec<&str> {
+    match &email.personalization {
+        Some(personalization) => personalization
+            .iter()
+            .map(|recipient| recipient.address.address())
+            .collect(),
+        None => email
+            .recipients_to
+            .iter()
+            .chain(email.recipients_cc.iter())
+            .chain(email.recipients_bcc.iter())
+            .map(Recipient::address)
+            .collect(),
+    }
+}
+
+/// Remove the `suppressed` addresses from the recipients of `email`, returning whether any
+/// recipient remains.
+pub(crate) fn remove_suppressed(
+    email: &mut EmailMessage,
+    suppressed: &HashMap<String, SuppressionReason>,
+) -> bool {
+    retain_recipients(email, |address| !suppressed.contains_key(address))
+}
+
+/// Keep only the recipients of `email` for which `keep` is true, returning whether any recipient
+/// remains.
+pub(crate) fn retain_recipients<F>(email: &mut EmailMessage, keep: F) -> bool
+where
+    F: Fn(&str) -> bool,
+{
+    match &mut email.personalization {
+        Some(personalization) => {
+            personalization.retain(|recipient| keep(recipient.address.address()))
+        }
+        None => {
+            email
+                .recipients_to
+                .retain(|address| keep(address.address()));
+            email
+                .recipients_cc
+                .retain(|address| keep(address.address()));
+            email
+                .recipients_bcc
+                .retain(|address| keep(address.address()));
+        }
+    }
+    !recipients(email).is_empty()
+}
+
+#[cfg(test)]
+mod check {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    #[tokio::test]
+    async fn answers_cached_addresses_without_reading() {
+        let table = InMemoryDynamoDb::default();
+        let suppressions = Suppressions::new(table.client(), "Test Suppressions")
+            .with_cache(Duration::from_secs(60), 10);
+        suppressions
+            .add("bounced@example.com", SuppressionReason::Bounce)
+            .await
+            .unwrap();
+        let suppressed = suppressions
+            .check(vec!["Bounced@Example.com"])
+            .await
+            .unwrap();
+        assert_eq!(
+            suppressed.get("Bounced@Example.com"),
+            Some(&SuppressionReason::Bounce)
+        );
+        assert_eq!(table.calls("BatchGetItem"), 0);
+        assert_eq!(suppressions.take_cache_counts(), (1, 0));
+    }
+}
+
+#[cfg(test)]
+mod remove_suppressed {
+    use super::*;
+    use crate::personalization::PersonalizedRecipient;
+
+    #[test]
+    fn drops_suppressed_recipients() {
+        let mut email = EmailMessage {
+            recipients_bcc: vec!["bcc@example.com".into()],
+            recipients_cc: vec!["cc@example.com".into()],
+            recipients_to: vec!["to@example.com".into()],
+            ..EmailMessage::default()
+        };
+        let mut suppressed = HashMap::new();
+        suppressed.insert("cc@example.com".into(), SuppressionReason::Bounce);
+        suppressed.insert("bcc@example.com".into(), SuppressionReason::Complaint);
+        assert!(remove_suppressed(&mut email, &suppressed));
+        assert_eq!(recipients(&email), vec!["to@example.com"]);
+        suppressed.insert("to@example.com".into(), SuppressionReason::Manual);
+        assert!(!remove_suppressed(&mut email, &suppressed));
+    }
+
+    #[test]
+    fn drops_personalized_recipients() {
+        let recipient = |address: &str| PersonalizedRecipient {
+            address: address.into(),
+            ..PersonalizedRecipient::default()
+        };
+        let mut email = EmailMessage {
+            personalization: Some(vec![recipient("a@example.com"), recipient("b@example.com")]),
+            recipients_to: vec!["ignored@example.com".into()],
+            ..EmailMessage::default()
+        };
+        let mut suppressed = HashMap::new();
+        suppressed.insert("a@example.com".into(), SuppressionReason::Bounce);
+        assert!(remove_suppressed(&mut email, &suppressed));
+        assert_eq!(recipients(&email), vec!["b@example.com"]);
+    }
+
+    #[test]
+    fn ignores_case_of_keys() {
+        assert_eq!(
+            suppression_key(" First.Last@Example.com "),
+            "first.last@example.com"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use crate::attribute_value_wrapper::AttributeValueMap;
+use crate::dynamo::{lease_timestamp, query_by_status, release_lapsed_claim};
+use crate::email_message::{EmailId, EmailStatus};
+use crate::error::UpdateError;
+use crate::metrics::{Counter, Metrics};
+use crate::queue::send_email_pointer;
+use crate::queue_url::QueueUrl;
+use crate::schema::{attribute, equals, placeholder};
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
+use chrono::Utc;
+use tracing::{event, Level};
+
+/// Emails read from the status index in each query.
+const STATUS_PAGE_SIZE: i32 = 100;
+
+/// Summary of a single pass of a `StuckEmailSweeper`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SweepReport {
+    /// Number of emails found `Sending` past the lease of their claim.
+    pub found: usize,
+    /// Number of emails whose pointer was sent again.
+    pub recovered: usize,
+    /// Number of emails left to be found again by a later pass.
+    pub failed: usize,
+}
+
+/// Recover emails left `EmailStatus::Sending` by a worker which stopped between claiming and
+/// recording the send. Each email whose claim has lapsed has a pointer sent for it again and is
+/// returned to `EmailStatus::Pending`. The pointer is sent first so an email is never left
+/// `Pending` without one, a delivery which takes the email over before it is reset keeps it
+/// because its claim has not lapsed.
+pub struct StuckEmailSweeper<'a> {
+    /// Connection to DynamoDB.
+    dynamodb: &'a DynamoDbClient,
+    /// Counters published to CloudWatch.
+    metrics: Option<&'a Metrics>,
+    /// URL of the queue pointers are sent to.
+    queue_url: &'a QueueUrl,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+    /// Index of the email table listing emails by `EmailStatus`, the table is scanned without it.
+    status_index: Option<&'a str>,
+    /// DynamoDB table from which email data is read.
+    table_name: &'a str,
+}
+
+impl StuckEmailSweeper<'_> {
+    pub fn new<'a>(
+        dynamodb: &'a DynamoDbClient,
+        table_name: &'a str,
+        queue_url: &'a QueueUrl,
+        sqs: &'a SqsClient,
+    ) -> StuckEmailSweeper<'a> {
+        StuckEmailSweeper {
+            dynamodb,
+            metrics: None,
+            queue_url,
+            sqs,
+            status_index: None,
+            table_name,
+        }
+    }
+
+    /// Sweep the email table once.
+    ///
+    /// 1. Scan the email table, or its status index, for emails `Sending` past their lease.
+    /// 2. Send a pointer for each email found.
+    /// 3. Return each email whose pointer was sent to `Pending`.
+    #[tracing::instrument(skip(self), level = Level::INFO)]
+    pub async fn run_once(&self) -> Result<SweepReport, String> {
+        // 1. Scan the email table, or its status index, for emails `Sending` past their lease.
+        let emails = self.lapsed_emails().await?;
+        let mut report = SweepReport {
+            found: emails.len(),
+            ..SweepReport::default()
+        };
+        for (email_id, version) in emails {
+            if self.recover(&email_id, version).await {
+                report.recovered += 1;
+            } else {
+                report.failed += 1;
+            }
+        }
+        if let Some(metrics) = self.metrics {
+            metrics.count(Counter::Recovered, report.recovered as u64);
+        }
+        Ok(report)
+    }
+
+    /// `EmailId` and `Version` of every email whose claim lapsed while it was `Sending`, reading
+    /// every page of the scan, or of the status index when there is one.
+    async fn lapsed_emails(&self) -> Result<Vec<(EmailId, u64)>, String> {
+        if let Some(index_name) = self.status_index {
+            return self.lapsed_emails_indexed(index_name).await;
+        }
+        let mut emails = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = self
+                .dynamodb
+                .scan()
+                .consistent_read(true)
+                .filter_expression(format!(
+                    "{} AND {} < {}",
+                    equals(attribute::EMAIL_STATUS, placeholder::EXPECTED),
+                    attribute::SENDING_LOCK_EXPIRES_AT,
+                    placeholder::NOW
+                ))
+                .set_expression_attribute_values(Some(AttributeValueMap::with_entries(vec![
+                    (
+                        placeholder::EXPECTED.into(),
+                        EmailStatus::Sending.to_string(),
+                    ),
+                    (placeholder::NOW.into(), lease_timestamp(Utc::now())),
+                ])))
+                .set_exclusive_start_key(start_key)
+                .projection_expression(format!("{}, {}", attribute::EMAIL_ID, attribute::VERSION))
+                .table_name(self.table_name)
+                .send()
+                .await
+                .map_err(|error| format!("{}", DisplayErrorContext(&error)))?;
+            for item in output.items.unwrap_or_default() {
+                // An email never updated has no `Version`
+                let version = match item.get(attribute::VERSION) {
+                    Some(AttributeValue::N(version)) => version.parse().unwrap_or_default(),
+                    _ => 0,
+                };
+                match item.get(attribute::EMAIL_ID) {
+                    Some(AttributeValue::S(email_id)) => {
+                        emails.push((EmailId::from(email_id.as_str()), version))
+                    }
+                    _ => event!(Level::ERROR, ?item, "email without EmailId"),
+                }
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(emails);
+            }
+        }
+    }
+
+    /// `lapsed_emails` listing only the emails `Sending` from `index_name` rather than scanning
+    /// every email. An email whose claim the index does not project is left alone.
+    async fn lapsed_emails_indexed(&self, index_name: &str) -> Result<Vec<(EmailId, u64)>, String> {
+        let now = lease_timestamp(Utc::now());
+        let mut emails = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = query_by_status(
+                self.dynamodb,
+                self.table_name,
+                index_name,
+                EmailStatus::Sending,
+                STATUS_PAGE_SIZE,
+                cursor.as_deref(),
+            )
+            .await
+            .map_err(|error| error.to_string())?;
+            for email in page.emails {
+                if email
+                    .sending_lock_expires_at
+                    .is_some_and(|expires_at| expires_at < now)
+                {
+                    emails.push((email.email_id, email.version));
+                }
+            }
+            cursor = page.cursor;
+            if cursor.is_none() {
+                return Ok(emails);
+            }
+        }
+    }
+
+    /// Send a pointer for `email_id` and return it to `Pending` unless it changed from `version`,
+    /// returning whether the pointer was sent.
+    async fn recover(&self, email_id: &EmailId, version: u64) -> bool {
+        // 2. Send a pointer for each email found.
+        if let Err(error) = send_email_pointer(self.queue_url, self.sqs, email_id, None).await {
+            let error = format!("{}", DisplayErrorContext(&error));
+            event!(Level::ERROR, %email_id, %error, "email pointer not sent");
+            return false;
+        }
+        // 3. Return each email whose pointer was sent to `Pending`.
+        match release_lapsed_claim(self.dynamodb, self.table_name, email_id.as_str(), version).await
+        {
+            Ok(_) => event!(Level::INFO, %email_id, "stuck email enqueued again"),
+            // Claimed again since it was found, by the pointer just sent or another delivery
+            Err(UpdateError::ConditionalCheckFailed(_) | UpdateError::VersionConflict(_)) => {
+                event!(Level::INFO, %email_id, "stuck email claimed again")
+            }
+            // The pointer takes the email over once received as its claim has lapsed
+            Err(error) => event!(Level::WARN, %email_id, %error, "stuck email not reset"),
+        }
+        true
+    }
+}
+
+impl<'a> StuckEmailSweeper<'a> {
+    /// Count recovered emails in `metrics`.
+    pub fn with_metrics(self, metrics: &'a Metrics) -> Self {
+        StuckEmailSweeper {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Find emails through `index_name`, a status index of the email table, instead of scanning
+    /// the table. The index must project `SendingLockExpiresAt` and `Version`.
+    pub fn with_status_index(self, index_name: &'a str) -> Self {
+        StuckEmailSweeper {
+            status_index: Some(index_name),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod lapsed_emails {
+    use super::*;
+    use crate::email_message::EmailMessage;
+    use crate::test_support::InMemoryDynamoDb;
+    use aws_sdk_sqs::config::BehaviorVersion;
+
+    fn email(email_id: &str, status: EmailStatus, expires_at: Option<&str>) -> EmailMessage {
+        EmailMessage {
+            email_id: email_id.into(),
+            status,
+            sending_lock_expires_at: expires_at.map(String::from),
+            updated_at: "2021-03-23T23:55:00Z".into(),
+            ..EmailMessage::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_sending_emails_past_their_lease() {
+        let lapsed = EmailMessage {
+            version: 3,
+            ..email(
+                "Versioned EmailId",
+                EmailStatus::Sending,
+                Some("2021-03-24T00:00:00Z"),
+            )
+        };
+        let table = InMemoryDynamoDb::default();
+        table.insert(&email(
+            "Lapsed EmailId",
+            EmailStatus::Sending,
+            Some("2021-03-24T00:00:00Z"),
+        ));
+        table.insert(&email(
+            "Held EmailId",
+            EmailStatus::Sending,
+            Some("9999-12-31T23:59:59Z"),
+        ));
+        table.insert(&email(
+            "Sent EmailId",
+            EmailStatus::Sent,
+            Some("2021-03-24T00:00:00Z"),
+        ));
+        table.insert(&email("Pending EmailId", EmailStatus::Pending, None));
+        table.insert(&lapsed);
+        let dynamodb = table.client();
+        let sqs = SqsClient::from_conf(
+            aws_sdk_sqs::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        );
+        let queue_url = "http://localhost:4566/000000000000/emails"
+            .parse::<QueueUrl>()
+            .unwrap();
+        let sweeper = StuckEmailSweeper::new(&dynamodb, "Test Table", &queue_url, &sqs);
+        let mut emails = sweeper.lapsed_emails().await.unwrap();
+        emails.sort();
+        assert_eq!(
+            emails,
+            vec![
+                ("Lapsed EmailId".into(), 0),
+                ("Versioned EmailId".into(), 3)
+            ]
+        );
+        let sweeper = sweeper.with_status_index("Status Index");
+        let mut indexed = sweeper.lapsed_emails().await.unwrap();
+        indexed.sort();
+        assert_eq!(indexed, emails);
+        assert_eq!(table.requests("Query")[0]["IndexName"], "Status Index");
+    }
+}
@@ -0,0 +1,152 @@
+use aws_sdk_sqs::types::{Message, MessageAttributeValue};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::Context;
+use opentelemetry_otlp::{
+    ExporterBuildError, SpanExporter, OTEL_EXPORTER_OTLP_ENDPOINT,
+    OTEL_EXPORTER_OTLP_TRACES_ENDPOINT,
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::env;
+use thiserror::Error;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Environment variable naming the service spans are reported under.
+const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+/// Message attributes carrying the W3C trace context of the span which sent a message.
+pub(crate) const TRACE_CONTEXT_ATTRIBUTES: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Possible errors from setting up trace export.
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("ExporterBuildError({0})")]
+    ExporterBuildError(#[from] ExporterBuildError),
+}
+
+/// Exports the spans of a process over OTLP so they can be viewed in Jaeger, X-Ray, Tempo, or any
+/// other backend an OpenTelemetry collector forwards to. The exporter is configured by the
+/// standard `OTEL_EXPORTER_OTLP_*` environment variables and spans are reported under
+/// `OTEL_SERVICE_NAME` when it is set.
+#[derive(Debug)]
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Export spans when `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is
+    /// set, reported under `service_name` unless `OTEL_SERVICE_NAME` is also set. Nothing is
+    /// exported, and `None` returned, when neither endpoint is set.
+    pub fn from_env(service_name: &'static str) -> Result<Option<Self>, TelemetryError> {
+        let configured = [
+            OTEL_EXPORTER_OTLP_ENDPOINT,
+            OTEL_EXPORTER_OTLP_TRACES_ENDPOINT,
+        ]
+        .iter()
+        .any(|name| env::var_os(name).is_some());
+        if !configured {
+            return Ok(None);
+        }
+        let exporter = SpanExporter::builder().with_http().build()?;
+        let resource = match env::var_os(OTEL_SERVICE_NAME) {
+            Some(_) => Resource::builder().build(),
+            None => Resource::builder().with_service_name(service_name).build(),
+        };
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build();
+        Ok(Some(Telemetry { provider }))
+    }
+
+    /// Layer exporting the spans of a `tracing` subscriber.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(env!("CARGO_PKG_NAME")))
+    }
+
+    /// Export every finished span now rather than with the next batch, for processes which may
+    /// be suspended once they go idle.
+    pub fn flush(&self) -> Result<(), String> {
+        self.provider
+            .force_flush()
+            .map_err(|error| error.to_string())
+    }
+
+    /// Export every finished span and stop exporting.
+    pub fn shutdown(&self) -> Result<(), String> {
+        self.provider.shutdown().map_err(|error| error.to_string())
+    }
+}
+
+/// Message attributes carrying the trace context of `span` so the receiver of a message can
+/// continue the trace which sent it. Empty when `span` is not exported.
+pub(crate) fn trace_context_attributes(span: &Span) -> HashMap<String, MessageAttributeValue> {
+    let mut fields = HashMap::new();
+    TraceContextPropagator::new().inject_context(&span.context(), &mut fields);
+    fields
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| {
+            let value = MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()
+                .expect("data_type is always set");
+            (name, value)
+        })
+        .collect()
+}
+
+/// Trace context carried in the attributes of `message`, `None` when it was sent outside of an
+/// exported span.
+pub(crate) fn trace_context(message: &Message) -> Option<Context> {
+    let fields: HashMap<String, String> = message
+        .message_attributes()
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| Some((name.clone(), value.string_value()?.to_string())))
+        .collect();
+    Some(TraceContextPropagator::new().extract(&fields)).filter(|context| context.has_active_span())
+}
+
+#[cfg(test)]
+mod trace_context {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn round_trips_through_message_attributes() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("enqueue");
+            let attributes = trace_context_attributes(&span);
+            assert!(attributes.contains_key(TRACE_CONTEXT_ATTRIBUTES[0]));
+            let message = Message::builder()
+                .set_message_attributes(Some(attributes))
+                .build();
+            let context = trace_context(&message).expect("trace context is sent");
+            assert_eq!(
+                context.span().span_context().trace_id(),
+                span.context().span().span_context().trace_id()
+            );
+            assert!(context.span().span_context().is_remote());
+        });
+    }
+
+    #[test]
+    fn is_empty_without_exported_span() {
+        let attributes = trace_context_attributes(&Span::none());
+        assert!(attributes.is_empty());
+        let message = Message::builder().build();
+        assert!(trace_context(&message).is_none());
+    }
+}
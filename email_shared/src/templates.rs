@@ -0,0 +1,390 @@
+use crate::attribute_value_wrapper::AttributeValueMap;
+use crate::cache::{TtlCache, DEFAULT_CACHE_MAX_ENTRIES};
+use crate::dynamo::from_hashmap;
+use crate::email_message::EmailMessage;
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::Client as S3Client;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+use tera::{Context, Tera};
+use thiserror::Error;
+use tracing::{event, Level};
+
+/// A `TemplateId` identifies a template in the configured `TemplateSource`.
+pub type TemplateId = String;
+
+/// Values made available to a template while it is rendered.
+pub type TemplateData = serde_json::Map<String, serde_json::Value>;
+
+/// Name of the S3 object, under the template prefix, containing the HTML body template.
+const S3_HTML_OBJECT: &str = "body.html";
+/// Name of the S3 object, under the template prefix, containing the TXT body template.
+const S3_TEXT_OBJECT: &str = "body.txt";
+
+/// Possible errors while loading or rendering a template.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum TemplateError {
+    /// The given string does not describe a `TemplateSource`.
+    #[error("InvalidSource({0})")]
+    InvalidSource(String),
+    /// No template exists with the given `TemplateId`.
+    #[error("NotFound({0})")]
+    NotFound(TemplateId),
+    /// Rendering the template failed.
+    #[error("RenderError({0})")]
+    RenderError(String),
+    /// The template could not be read from its source.
+    #[error("SourceError({0})")]
+    SourceError(String),
+}
+
+/// The body templates of an email.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Template {
+    /// Template for the HTML email body, rendered with HTML escaping.
+    #[serde(default)]
+    pub body_html: Option<String>,
+    /// Template for the TXT email body, rendered without escaping.
+    #[serde(default)]
+    pub body_text: Option<String>,
+}
+
+impl Template {
+    /// Render both bodies with `data`, a missing body template renders as an empty body.
+    pub fn render(&self, data: &TemplateData) -> Result<(String, String), TemplateError> {
+        let context = Context::from_serialize(data).map_err(render_error)?;
+        let render = |input: &Option<String>, autoescape| match input {
+            Some(input) => Tera::one_off(input, &context, autoescape).map_err(render_error),
+            None => Ok(String::new()),
+        };
+        Ok((
+            render(&self.body_html, true)?,
+            render(&self.body_text, false)?,
+        ))
+    }
+}
+
+/// Describe a Tera error along with each of its causes.
+fn render_error(error: tera::Error) -> TemplateError {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    TemplateError::RenderError(message)
+}
+
+/// Where templates are stored.
+///
+/// ```
+/// use email_shared::TemplateSource;
+///
+/// let source: TemplateSource = "dynamodb:email_templates".parse().unwrap();
+/// assert_eq!(source, TemplateSource::DynamoDb { table_name: "email_templates".into() });
+/// let source: TemplateSource = "s3://bucket/templates/".parse().unwrap();
+/// assert_eq!(
+///     source,
+///     TemplateSource::S3 { bucket: "bucket".into(), prefix: "templates/".into() },
+/// );
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TemplateSource {
+    /// A DynamoDB table keyed by `TemplateId` with `BodyHtml` and `BodyText` attributes.
+    DynamoDb { table_name: String },
+    /// An S3 bucket where the bodies of a template are stored as `body.html` and `body.txt` under
+    /// `{prefix}{TemplateId}/`.
+    S3 { bucket: String, prefix: String },
+}
+
+impl FromStr for TemplateSource {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(table_name) = s.strip_prefix("dynamodb:") {
+            if !table_name.is_empty() {
+                return Ok(TemplateSource::DynamoDb {
+                    table_name: table_name.into(),
+                });
+            }
+        } else if let Some(location) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = match location.find('/') {
+                Some(index) => (&location[..index], &location[index + 1..]),
+                None => (location, ""),
+            };
+            if !bucket.is_empty() {
+                return Ok(TemplateSource::S3 {
+                    bucket: bucket.into(),
+                    prefix: prefix.into(),
+                });
+            }
+        }
+        Err(TemplateError::InvalidSource(s.into()))
+    }
+}
+
+/// Templates which have been loaded recently, entries older than the TTL are loaded again.
+pub type TemplateCache = TtlCache<TemplateId, Template>;
+
+/// Flag of emails rendered with the `v2` revision of their template, stored as
+/// `<template_id>/v2`, when it exists.
+pub const USE_TEMPLATE_V2: &str = "use-template-v2";
+
+/// Load templates from a `TemplateSource` and render them into `EmailMessage` bodies.
+pub struct Templates {
+    /// Recently loaded templates.
+    cache: TemplateCache,
+    /// Connection to DynamoDB.
+    dynamodb: DynamoDbClient,
+    /// Connection to S3.
+    s3: S3Client,
+    /// Where templates are loaded from.
+    source: TemplateSource,
+}
+
+impl Templates {
+    pub fn new(
+        source: TemplateSource,
+        ttl: Duration,
+        dynamodb: DynamoDbClient,
+        s3: S3Client,
+    ) -> Self {
+        Templates {
+            cache: TemplateCache::new(ttl, DEFAULT_CACHE_MAX_ENTRIES),
+            dynamodb,
+            s3,
+            source,
+        }
+    }
+
+    /// Keep at most `max_entries` loaded templates.
+    pub fn with_cache_size(self, max_entries: usize) -> Self {
+        Templates {
+            cache: TemplateCache::new(self.cache.ttl(), max_entries),
+            ..self
+        }
+    }
+
+    /// Template cache hits and misses since they were last taken.
+    pub(crate) fn take_cache_counts(&self) -> (u64, u64) {
+        self.cache.take_counts()
+    }
+
+    /// Fill in the bodies of `email` from its template. Emails without a `TemplateId`, or which
+    /// already have a body, Markdown included, are left unchanged.
+    pub async fn render(&self, email: &mut EmailMessage) -> Result<(), TemplateError> {
+        let template_id = match &email.template_id {
+            Some(template_id) if !email.has_body() => template_id,
+            _ => return Ok(()),
+        };
+        let template = if email.has_flag(USE_TEMPLATE_V2) {
+            let revision = format!("{}/v2", template_id);
+            match self.get(&revision).await {
+                Ok(template) => template,
+                Err(TemplateError::NotFound(_)) => {
+                    event!(Level::WARN, %template_id, "no v2 template, using base template");
+                    self.get(template_id).await?
+                }
+                Err(error) => return Err(error),
+            }
+        } else {
+            self.get(template_id).await?
+        };
+        let data = email.template_data.clone().unwrap_or_default();
+        let (body_html, body_text) = template.render(&data)?;
+        email.body_html = body_html;
+        email.body_text = body_text;
+        Ok(())
+    }
+
+    /// Get the template for `template_id` from the cache or the source.
+    async fn get(&self, template_id: &str) -> Result<Template, TemplateError> {
+        if let Some(template) = self.cache.get(template_id) {
+            return Ok(template);
+        }
+        event!(Level::DEBUG, %template_id, "load template");
+        let template = match &self.source {
+            TemplateSource::DynamoDb { table_name } => {
+                self.load_dynamodb(table_name, template_id).await?
+            }
+            TemplateSource::S3 { bucket, prefix } => {
+                self.load_s3(bucket, prefix, template_id).await?
+            }
+        };
+        if template.body_html.is_none() && template.body_text.is_none() {
+            return Err(TemplateError::NotFound(template_id.into()));
+        }
+        self.cache.insert(template_id.into(), template.clone());
+        Ok(template)
+    }
+
+    /// Read the template item identified by `template_id` from `table_name`.
+    async fn load_dynamodb(
+        &self,
+        table_name: &str,
+        template_id: &str,
+    ) -> Result<Template, TemplateError> {
+        let output = self
+            .dynamodb
+            .get_item()
+            .set_key(Some(AttributeValueMap::with_entry(
+                "TemplateId",
+                template_id.into(),
+            )))
+            .table_name(table_name)
+            .send()
+            .await
+            .map_err(|e| TemplateError::SourceError(format!("{}", DisplayErrorContext(&e))))?;
+        let item = output
+            .item
+            .ok_or_else(|| TemplateError::NotFound(template_id.into()))?;
+        from_hashmap(item).map_err(|e| TemplateError::SourceError(e.to_string()))
+    }
+
+    /// Read the body objects for `template_id` from `bucket`.
+    async fn load_s3(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        template_id: &str,
+    ) -> Result<Template, TemplateError> {
+        let key = |name| format!("{}{}/{}", prefix, template_id, name);
+        Ok(Template {
+            body_html: self.load_s3_object(bucket, &key(S3_HTML_OBJECT)).await?,
+            body_text: self.load_s3_object(bucket, &key(S3_TEXT_OBJECT)).await?,
+        })
+    }
+
+    /// Read the object at `key` as a string, a missing object is `None`.
+    async fn load_s3_object(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Option<String>, TemplateError> {
+        let source_error = |message: String| TemplateError::SourceError(message);
+        let output = match self.s3.get_object().bucket(bucket).key(key).send().await {
+            Ok(output) => output,
+            Err(SdkError::ServiceError(context))
+                if matches!(context.err(), GetObjectError::NoSuchKey(_)) =>
+            {
+                return Ok(None);
+            }
+            Err(error) => return Err(source_error(format!("{}", DisplayErrorContext(&error)))),
+        };
+        let bytes = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| source_error(e.to_string()))?
+            .into_bytes();
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|e| source_error(e.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Templates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Templates")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod render {
+    use super::*;
+    use serde_json::json;
+
+    fn data() -> TemplateData {
+        match json!({ "name": "<Test>", "count": 3 }) {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn escapes_html_only() {
+        let template = Template {
+            body_html: Some("<p>Hello {{ name }}, {{ count }}</p>".into()),
+            body_text: Some("Hello {{ name }}, {{ count }}".into()),
+        };
+        let (html, text) = template.render(&data()).unwrap();
+        assert_eq!(html, "<p>Hello &lt;Test&gt;, 3</p>");
+        assert_eq!(text, "Hello <Test>, 3");
+    }
+
+    #[test]
+    fn renders_missing_body_empty() {
+        let template = Template {
+            body_html: None,
+            body_text: Some("Hello".into()),
+        };
+        let (html, text) = template.render(&data()).unwrap();
+        assert_eq!(html, "");
+        assert_eq!(text, "Hello");
+    }
+
+    #[test]
+    fn fails_on_missing_variable() {
+        let template = Template {
+            body_html: None,
+            body_text: Some("Hello {{ missing }}".into()),
+        };
+        match template.render(&data()) {
+            Err(TemplateError::RenderError(message)) => assert!(message.contains("missing")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn parses_s3_without_prefix() {
+        assert_eq!(
+            "s3://bucket".parse::<TemplateSource>(),
+            Ok(TemplateSource::S3 {
+                bucket: "bucket".into(),
+                prefix: "".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_sources() {
+        for source in ["", "dynamodb:", "s3://", "file:///templates"].iter() {
+            assert_eq!(
+                source.parse::<TemplateSource>(),
+                Err(TemplateError::InvalidSource(source.to_string()))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod template_cache {
+    use super::*;
+
+    #[test]
+    fn returns_fresh_entries() {
+        let cache = TemplateCache::new(Duration::from_secs(60), 10);
+        assert_eq!(cache.get("Test TemplateId"), None);
+        cache.insert("Test TemplateId".into(), Template::default());
+        assert_eq!(cache.get("Test TemplateId"), Some(Template::default()));
+    }
+
+    #[test]
+    fn expires_entries() {
+        let cache = TemplateCache::new(Duration::from_secs(0), 10);
+        cache.insert("Test TemplateId".into(), Template::default());
+        assert_eq!(cache.get("Test TemplateId"), None);
+    }
+}
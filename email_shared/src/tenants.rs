@@ -0,0 +1,74 @@
+use crate::email_message::{EmailMessage, Recipient};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Every configured `Tenant` by the name pointers give in their `tenant` field.
+pub type Tenants = BTreeMap<String, Tenant>;
+
+/// Where the emails of one product served by a shared deployment are kept and who they are sent
+/// as. A pointer naming the tenant has its email read from, and its status written to, the
+/// tenant's table, so one product can never send or change the emails of another.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::{EmailMessage, Tenant};
+///
+/// let tenant: Tenant = serde_json::from_str(
+///     r#"{"table_name": "acme_emails", "provider": "ses-acme", "sender": "Acme <mail@acme.com>"}"#,
+/// )
+/// .unwrap();
+/// let mut email = EmailMessage {
+///     sender: "other@example.com".into(),
+///     ..EmailMessage::default()
+/// };
+/// tenant.apply(&mut email);
+/// assert_eq!(email.sender, "Acme <mail@acme.com>");
+/// assert_eq!(email.provider, "ses-acme");
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tenant {
+    /// DynamoDB table holding the emails of the tenant.
+    pub table_name: String,
+    /// Provider the emails of the tenant are sent through, in place of the provider of each
+    /// record.
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Identity the emails of the tenant are sent from, in place of the sender of each record.
+    #[serde(default)]
+    pub sender: Option<Recipient>,
+}
+
+impl Tenant {
+    /// Send `email` through the provider and from the sender of this tenant, where configured.
+    pub fn apply(&self, email: &mut EmailMessage) {
+        if let Some(provider) = &self.provider {
+            email.provider = provider.clone();
+        }
+        if let Some(sender) = &self.sender {
+            email.sender = sender.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod apply {
+    use super::*;
+
+    #[test]
+    fn keeps_record_values_not_configured() {
+        let tenant = Tenant {
+            table_name: "acme_emails".into(),
+            provider: None,
+            sender: None,
+        };
+        let mut email = EmailMessage {
+            provider: "ses".into(),
+            sender: "\"Other\" <other@example.com>".into(),
+            ..EmailMessage::default()
+        };
+        tenant.apply(&mut email);
+        assert_eq!(email.provider, "ses");
+        assert_eq!(email.sender.raw(), "\"Other\" <other@example.com>");
+    }
+}
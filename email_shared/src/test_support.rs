@@ -0,0 +1,778 @@
+//! In-memory stand-ins for SQS and DynamoDB used by tests which exercise the processing pipeline
+//! end to end with realistic at-least-once delivery.
+
+use crate::client::BatchOutcome;
+use crate::dynamo::to_hashmap;
+use crate::email_message::{EmailMessage, EmailStatus};
+use crate::email_message_builder::EmailMessageBuilder;
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::types::{Message, MessageSystemAttributeName};
+use aws_sdk_sqs::Client as SqsClient;
+use aws_smithy_runtime_api::client::http::{
+    HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector,
+};
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use aws_smithy_runtime_api::http::{Response, StatusCode};
+use aws_smithy_types::body::SdkBody;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A sendable email identified by `email_id` at `status`, from `from@example.com` to
+/// `to@example.com` with a plain text body.
+pub fn email(email_id: &str, status: EmailStatus) -> EmailMessage {
+    let email = EmailMessageBuilder::new(email_id)
+        .sender("from@example.com")
+        .to("to@example.com")
+        .subject("Test Subject")
+        .body_text("Test Body")
+        .build()
+        .unwrap();
+    EmailMessage { status, ..email }
+}
+
+/// A message held by an `InMemoryQueue`.
+#[derive(Clone, Debug)]
+struct QueuedMessage {
+    body: String,
+    /// Times the message has been received.
+    receive_count: u32,
+    /// Receipt handle of the latest receive, only that handle deletes the message.
+    handle: Option<String>,
+    /// Whether the message is received by the next `receive`.
+    visible: bool,
+}
+
+/// A queue which, like SQS, delivers a message again until it is deleted with the receipt handle
+/// of its latest receive. Every receive increments the `ApproximateReceiveCount` of a message.
+#[derive(Debug, Default)]
+pub struct InMemoryQueue {
+    messages: BTreeMap<String, QueuedMessage>,
+    next_id: usize,
+}
+
+impl InMemoryQueue {
+    /// Send a message with `body`, returning its message id.
+    pub fn send(&mut self, body: impl Into<String>) -> String {
+        self.next_id += 1;
+        let message_id = format!("Test MessageId {}", self.next_id);
+        self.messages.insert(
+            message_id.clone(),
+            QueuedMessage {
+                body: body.into(),
+                receive_count: 0,
+                handle: None,
+                visible: true,
+            },
+        );
+        message_id
+    }
+
+    /// Send a pointer to the email identified by `email_id`.
+    pub fn send_pointer(&mut self, email_id: &str) -> String {
+        self.send(json!({ "email_id": email_id }).to_string())
+    }
+
+    /// Receive every visible message, hiding each until the outcome of processing it is applied.
+    pub fn receive(&mut self) -> Vec<Message> {
+        self.messages
+            .iter_mut()
+            .filter(|(_, message)| message.visible)
+            .map(|(message_id, message)| {
+                message.receive_count += 1;
+                message.visible = false;
+                let handle = format!("{} receipt {}", message_id, message.receive_count);
+                message.handle = Some(handle.clone());
+                Message::builder()
+                    .message_id(message_id)
+                    .receipt_handle(handle)
+                    .body(&message.body)
+                    .attributes(
+                        MessageSystemAttributeName::ApproximateReceiveCount,
+                        message.receive_count.to_string(),
+                    )
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Delete and redeliver messages as a `Runner` would for `outcome`. Deleted and quarantined
+    /// messages are removed, any other received message becomes visible again as though its
+    /// visibility timeout expired.
+    pub fn apply(&mut self, outcome: &BatchOutcome) {
+        let deletes = outcome
+            .delete
+            .iter()
+            .map(|entry| (entry.id().to_owned(), entry.receipt_handle().to_owned()))
+            .chain(outcome.quarantine.iter().filter_map(|(message, _)| {
+                Some((
+                    message.message_id()?.to_owned(),
+                    message.receipt_handle()?.to_owned(),
+                ))
+            }));
+        for (message_id, handle) in deletes {
+            let current = self
+                .messages
+                .get(&message_id)
+                .and_then(|message| message.handle.as_deref());
+            if current == Some(handle.as_str()) {
+                self.messages.remove(&message_id);
+            }
+        }
+        for message in self.messages.values_mut() {
+            message.visible = true;
+        }
+    }
+
+    /// Number of messages not yet deleted.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Whether every message has been deleted.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Times the message identified by `message_id` has been received.
+    pub fn receive_count(&self, message_id: &str) -> Option<u32> {
+        self.messages
+            .get(message_id)
+            .map(|message| message.receive_count)
+    }
+}
+
+/// Answers SQS `SendMessage` calls made by a client from `client` by sending the message body to
+/// an `InMemoryQueue`, so code sending pointers can be checked against what was queued.
+/// `GetQueueAttributes` is answered with the attributes given to `set_attribute`.
+#[derive(Clone, Debug, Default)]
+pub struct InMemorySqs {
+    queue: Arc<Mutex<InMemoryQueue>>,
+    /// Attributes of the queue by name, such as "RedrivePolicy".
+    attributes: Arc<Mutex<HashMap<String, String>>>,
+    /// Operations called with their requests, in order.
+    calls: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl InMemorySqs {
+    /// The queue messages are sent to.
+    pub fn queue(&self) -> MutexGuard<'_, InMemoryQueue> {
+        self.queue.lock().unwrap()
+    }
+
+    /// Set the attribute of the queue called `name` to `value`.
+    pub fn set_attribute(&self, name: &str, value: impl Into<String>) {
+        self.attributes
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), value.into());
+    }
+
+    /// Requests of the calls made to `operation`, for example "SendMessage", in order.
+    pub fn requests(&self, operation: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(call, _)| call == operation)
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
+    /// A client whose calls are answered by this queue.
+    pub fn client(&self) -> SqsClient {
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(aws_sdk_sqs::config::BehaviorVersion::latest())
+            .credentials_provider(Credentials::new("AKID", "SECRET", None, None, "test"))
+            .http_client(self.clone())
+            .region(Region::new("us-east-1"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        SqsClient::from_conf(config)
+    }
+
+    /// Answer the call of `operation` with `request`, returning the status and response body.
+    fn answer(&self, operation: &str, request: &Value) -> (u16, Value) {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((operation.to_owned(), request.clone()));
+        match operation {
+            "SendMessage" => {
+                let body = request["MessageBody"].as_str().unwrap_or_default();
+                let message_id = self.queue().send(body);
+                (200, json!({ "MessageId": message_id }))
+            }
+            "GetQueueAttributes" => {
+                let attributes = self.attributes.lock().unwrap().clone();
+                (200, json!({ "Attributes": attributes }))
+            }
+            _ => (
+                400,
+                json!({
+                    "__type": "com.amazonaws.sqs#UnsupportedOperation",
+                    "message": format!("{} is not supported", operation),
+                }),
+            ),
+        }
+    }
+}
+
+impl HttpConnector for InMemorySqs {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let (status, body) = self.answer(&operation(&request), &json_body(&request));
+        let response = Response::new(
+            StatusCode::try_from(status).unwrap(),
+            SdkBody::from(body.to_string()),
+        );
+        HttpConnectorFuture::ready(Ok(response))
+    }
+}
+
+impl HttpClient for InMemorySqs {
+    fn http_connector(
+        &self,
+        _settings: &HttpConnectorSettings,
+        _components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(self.clone())
+    }
+}
+
+/// Name of the operation `request` calls, from its `X-Amz-Target` header.
+fn operation(request: &HttpRequest) -> String {
+    let target = request.headers().get("x-amz-target").unwrap_or_default();
+    target.rsplit('.').next().unwrap_or_default().to_owned()
+}
+
+/// Body of `request` parsed as JSON, `Value::Null` when it is not.
+fn json_body(request: &HttpRequest) -> Value {
+    request
+        .body()
+        .bytes()
+        .and_then(|bytes| serde_json::from_slice(bytes).ok())
+        .unwrap_or(Value::Null)
+}
+
+/// Items of an `InMemoryDynamoDb` in the JSON wire format, keyed by `EmailId`.
+type Items = HashMap<String, Map<String, Value>>;
+
+/// Answers DynamoDB calls made against a single table from memory. `GetItem`, `BatchGetItem`,
+/// `PutItem`, `UpdateItem`, `Scan`, and `TransactWriteItems` of updates are supported along with
+/// the condition, update, and projection expressions the crate uses.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryDynamoDb {
+    items: Arc<Mutex<Items>>,
+    /// Operations called with their requests, in order.
+    calls: Arc<Mutex<Vec<(String, Value)>>>,
+    /// Keys the next `BatchGetItem` leaves unprocessed.
+    unprocessed: Arc<Mutex<usize>>,
+    /// Calls still to be refused for exceeding the provisioned throughput.
+    throttled: Arc<Mutex<usize>>,
+}
+
+impl InMemoryDynamoDb {
+    /// Store `email` as though it had been written by a producer.
+    pub fn insert(&self, email: &EmailMessage) {
+        let item = match wire_item(&to_hashmap(email).unwrap()) {
+            Value::Object(item) => item,
+            _ => unreachable!("items are objects"),
+        };
+        self.items
+            .lock()
+            .unwrap()
+            .insert(email.email_id.to_string(), item);
+    }
+
+    /// Current `EmailStatus` of the email identified by `email_id`.
+    pub fn status(&self, email_id: &str) -> Option<String> {
+        self.string(email_id, "EmailStatus")
+    }
+
+    /// Current value of the string attribute `name` of the email identified by `email_id`.
+    pub fn string(&self, email_id: &str, name: &str) -> Option<String> {
+        self.items
+            .lock()
+            .unwrap()
+            .get(email_id)
+            .and_then(|item| item.get(name))
+            .and_then(|value| value["S"].as_str())
+            .map(String::from)
+    }
+
+    /// Set the string attribute `name` of the item keyed by `email_id` to `value`, as though
+    /// written by another worker.
+    pub fn set_string(&self, email_id: &str, name: &str, value: &str) {
+        if let Some(item) = self.items.lock().unwrap().get_mut(email_id) {
+            item.insert(name.to_owned(), json!({ "S": value }));
+        }
+    }
+
+    /// Leave the last `count` keys of the next `BatchGetItem` unprocessed, as DynamoDB does when
+    /// the table is throttled.
+    pub fn leave_unprocessed(&self, count: usize) {
+        *self.unprocessed.lock().unwrap() = count;
+    }
+
+    /// Refuse the next `count` calls with `ProvisionedThroughputExceededException`, as DynamoDB
+    /// does when the table is throttled.
+    pub fn throttle(&self, count: usize) {
+        *self.throttled.lock().unwrap() = count;
+    }
+
+    /// Number of calls made to `operation`, for example "UpdateItem".
+    pub fn calls(&self, operation: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(call, _)| call == operation)
+            .count()
+    }
+
+    /// Requests of the calls made to `operation`, in order.
+    pub fn requests(&self, operation: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(call, _)| call == operation)
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
+    /// A client whose calls are answered by this table.
+    pub fn client(&self) -> DynamoDbClient {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .credentials_provider(Credentials::new("AKID", "SECRET", None, None, "test"))
+            .http_client(self.clone())
+            .region(Region::new("us-east-1"))
+            // Only the retries made by the crate itself are counted in `calls`
+            .retry_config(RetryConfig::disabled())
+            .build();
+        DynamoDbClient::from_conf(config)
+    }
+
+    /// Answer the call of `operation` with `request`, returning the status and response body.
+    fn answer(&self, operation: &str, request: &Value) -> (u16, Value) {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((operation.to_owned(), request.clone()));
+        {
+            let mut throttled = self.throttled.lock().unwrap();
+            if *throttled > 0 {
+                *throttled -= 1;
+                return (
+                    400,
+                    json!({
+                        "__type": "com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException",
+                        "message": "The level of configured provisioned throughput for the table was exceeded",
+                    }),
+                );
+            }
+        }
+        let mut items = self.items.lock().unwrap();
+        if operation == "TransactWriteItems" {
+            return transact(&mut items, request);
+        }
+        if operation == "BatchGetItem" {
+            let unprocessed = std::mem::take(&mut *self.unprocessed.lock().unwrap());
+            return batch_get(&items, request, unprocessed);
+        }
+        let email_id = request["Key"]["EmailId"]["S"]
+            .as_str()
+            .or_else(|| request["Item"]["EmailId"]["S"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let values = request["ExpressionAttributeValues"].clone();
+        let condition = request["ConditionExpression"].as_str().unwrap_or_default();
+        if !matches(items.get(&email_id), condition, &values) {
+            let mut error = json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed",
+            });
+            if let Some(item) = returned_on_failure(request, items.get(&email_id)) {
+                error["Item"] = item;
+            }
+            return (400, error);
+        }
+        match operation {
+            "GetItem" => {
+                let mut response = match items.get(&email_id) {
+                    Some(item) => json!({ "Item": project(item, request) }),
+                    None => json!({}),
+                };
+                consume(&mut response, request, read_units(request));
+                (200, response)
+            }
+            "PutItem" => {
+                if let Value::Object(item) = &request["Item"] {
+                    items.insert(email_id, item.clone());
+                }
+                let mut response = json!({});
+                consume(&mut response, request, 1.0);
+                (200, response)
+            }
+            "UpdateItem" => {
+                update(&mut items, &email_id, request);
+                let mut response = json!({});
+                consume(&mut response, request, 1.0);
+                (200, response)
+            }
+            "Scan" => {
+                let filter = request["FilterExpression"].as_str().unwrap_or_default();
+                let found = items
+                    .values()
+                    .filter(|item| matches(Some(item), filter, &values))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (200, json!({ "Count": found.len(), "Items": found }))
+            }
+            "Query" => (200, query(&items, request)),
+            _ => (400, json!({ "__type": "UnknownOperationException" })),
+        }
+    }
+}
+
+/// Answer a `Query` of `request` against the status index when it names one, keyed by
+/// `EmailStatus` and sorted by `UpdatedAt`, or the table otherwise. Only items with the key
+/// attributes of the index are in it. A page is read after `ExclusiveStartKey` and holds at most
+/// `Limit` items, with a `LastEvaluatedKey` whenever it is full.
+fn query(items: &Items, request: &Value) -> Value {
+    let values = &request["ExpressionAttributeValues"];
+    let condition = request["KeyConditionExpression"]
+        .as_str()
+        .unwrap_or_default();
+    let indexed = request["IndexName"].is_string();
+    let position = |item: &Map<String, Value>| {
+        let sort_key = if indexed {
+            item.get("UpdatedAt").and_then(|value| value["S"].as_str())
+        } else {
+            Some("")
+        };
+        Some((
+            sort_key?.to_owned(),
+            item.get("EmailId")?["S"].as_str()?.to_owned(),
+        ))
+    };
+    let start = position(
+        request["ExclusiveStartKey"]
+            .as_object()
+            .unwrap_or(&Map::new()),
+    );
+    let mut found = items
+        .values()
+        .filter(|item| matches(Some(item), condition, values))
+        .filter_map(|item| {
+            Some((
+                position(item).filter(|(key, _)| !indexed || !key.is_empty())?,
+                item,
+            ))
+        })
+        .filter(|(position, _)| start.as_ref().is_none_or(|start| position > start))
+        .collect::<Vec<_>>();
+    found.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let limit = request["Limit"].as_u64().map(|limit| limit as usize);
+    if let Some(limit) = limit {
+        found.truncate(limit);
+    }
+    let page = found.iter().map(|(_, item)| *item).collect::<Vec<_>>();
+    let mut response = json!({ "Count": page.len(), "Items": page });
+    match page.last() {
+        Some(last) if Some(page.len()) == limit => {
+            let key = ["EmailId", "EmailStatus", "UpdatedAt"]
+                .iter()
+                .filter(|name| indexed || **name == "EmailId")
+                .filter_map(|name| Some((name.to_string(), last.get(*name)?.clone())))
+                .collect::<Map<_, _>>();
+            response["LastEvaluatedKey"] = Value::Object(key);
+        }
+        _ => {}
+    }
+    response
+}
+
+/// Capacity units a read of one small item made by `request` consumes, half as many when it is
+/// eventually consistent.
+fn read_units(request: &Value) -> f64 {
+    if request["ConsistentRead"] == true {
+        1.0
+    } else {
+        0.5
+    }
+}
+
+/// Add the `ConsumedCapacity` of `units` to `response` when `request` asked for it.
+fn consume(response: &mut Value, request: &Value, units: f64) {
+    if request["ReturnConsumedCapacity"] == "TOTAL" {
+        response["ConsumedCapacity"] =
+            json!({ "TableName": request["TableName"], "CapacityUnits": units });
+    }
+}
+
+/// Answer a `BatchGetItem` of `request`, leaving the last `unprocessed` keys of each table
+/// unprocessed.
+fn batch_get(items: &Items, request: &Value, unprocessed: usize) -> (u16, Value) {
+    let mut responses = Map::new();
+    let mut unprocessed_keys = Map::new();
+    if let Value::Object(tables) = &request["RequestItems"] {
+        for (table_name, keys_and_attributes) in tables {
+            let keys = keys_and_attributes["Keys"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            let (processed, left) = keys.split_at(keys.len().saturating_sub(unprocessed));
+            let found = processed
+                .iter()
+                .filter_map(|key| items.get(key["EmailId"]["S"].as_str()?))
+                .map(|item| project(item, keys_and_attributes))
+                .collect::<Vec<_>>();
+            responses.insert(table_name.clone(), json!(found));
+            if !left.is_empty() {
+                unprocessed_keys.insert(table_name.clone(), json!({ "Keys": left }));
+            }
+        }
+    }
+    (
+        200,
+        json!({ "Responses": responses, "UnprocessedKeys": unprocessed_keys }),
+    )
+}
+
+/// The attributes of `item` named by the `ProjectionExpression` of `request`, every attribute when
+/// it has none.
+fn project(item: &Map<String, Value>, request: &Value) -> Map<String, Value> {
+    let projection = match request["ProjectionExpression"].as_str() {
+        Some(projection) => projection,
+        None => return item.clone(),
+    };
+    projection
+        .split(", ")
+        .map(|name| {
+            request["ExpressionAttributeNames"][name]
+                .as_str()
+                .unwrap_or(name)
+        })
+        .filter_map(|name| Some((name.to_owned(), item.get(name)?.clone())))
+        .collect()
+}
+
+/// Apply the update expression of `request` to the item identified by `email_id`, creating the
+/// item when there is none.
+fn update(items: &mut Items, email_id: &str, request: &Value) {
+    let values = &request["ExpressionAttributeValues"];
+    let item = items.entry(email_id.to_owned()).or_insert_with(|| {
+        let mut key = Map::new();
+        key.insert("EmailId".into(), json!({ "S": email_id }));
+        key
+    });
+    let update = request["UpdateExpression"].as_str().unwrap_or_default();
+    let (update, additions) = update.split_once(" ADD ").unwrap_or((update, ""));
+    let (assignments, removals) = update.split_once(" REMOVE ").unwrap_or((update, ""));
+    for assignment in split_assignments(assignments.trim_start_matches("SET ")) {
+        if let Some((name, operand)) = assignment.split_once(" = ") {
+            let value = match operand.strip_prefix("list_append(if_not_exists(") {
+                // list_append(if_not_exists(name, :empty), :placeholder)
+                Some(rest) => {
+                    let placeholder = rest.rsplit(", ").next().unwrap_or_default();
+                    let placeholder = placeholder.trim_end_matches(')');
+                    let mut list = item
+                        .get(name)
+                        .and_then(|value| value["L"].as_array())
+                        .cloned()
+                        .unwrap_or_default();
+                    list.extend(
+                        values[placeholder]["L"]
+                            .as_array()
+                            .cloned()
+                            .unwrap_or_default(),
+                    );
+                    json!({ "L": list })
+                }
+                None => values[operand].clone(),
+            };
+            item.insert(name.to_owned(), value);
+        }
+    }
+    for name in removals.split(", ").filter(|name| !name.is_empty()) {
+        item.remove(name);
+    }
+    for addition in additions
+        .split(", ")
+        .filter(|addition| !addition.is_empty())
+    {
+        if let Some((name, placeholder)) = addition.split_once(' ') {
+            let number = |value: Option<&Value>| {
+                value
+                    .and_then(|value| value["N"].as_str())
+                    .and_then(|n| n.parse::<u64>().ok())
+                    .unwrap_or_default()
+            };
+            let sum = number(item.get(name)) + number(Some(&values[placeholder]));
+            item.insert(name.to_owned(), json!({ "N": sum.to_string() }));
+        }
+    }
+}
+
+/// The item returned with a failed condition, when `request` asks for it.
+fn returned_on_failure(request: &Value, item: Option<&Map<String, Value>>) -> Option<Value> {
+    match (
+        request["ReturnValuesOnConditionCheckFailure"].as_str(),
+        item,
+    ) {
+        (Some("ALL_OLD"), Some(item)) => Some(Value::Object(item.clone())),
+        _ => None,
+    }
+}
+
+/// Split the `SET` clause of an update expression on the commas between assignments, leaving
+/// the arguments of functions such as `list_append` together.
+fn split_assignments(assignments: &str) -> Vec<&str> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut split = Vec::new();
+    for (i, c) in assignments.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(assignments[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(assignments[start..].trim());
+    split
+}
+
+/// Apply every update of the `TransactWriteItems` `request` when all of their conditions hold,
+/// otherwise cancel the transaction without applying any.
+fn transact(items: &mut Items, request: &Value) -> (u16, Value) {
+    let updates = request["TransactItems"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| item["Update"].clone())
+        .collect::<Vec<_>>();
+    let reasons = updates
+        .iter()
+        .map(|update| {
+            let email_id = update["Key"]["EmailId"]["S"].as_str().unwrap_or_default();
+            let condition = update["ConditionExpression"].as_str().unwrap_or_default();
+            let values = &update["ExpressionAttributeValues"];
+            if matches(items.get(email_id), condition, values) {
+                return json!({ "Code": "None" });
+            }
+            let mut reason = json!({ "Code": "ConditionalCheckFailed" });
+            if let Some(item) = returned_on_failure(update, items.get(email_id)) {
+                reason["Item"] = item;
+            }
+            reason
+        })
+        .collect::<Vec<_>>();
+    if reasons.iter().any(|reason| reason["Code"] != "None") {
+        let error = json!({
+            "__type": "com.amazonaws.dynamodb.v20120810#TransactionCanceledException",
+            "message": "Transaction cancelled",
+            "CancellationReasons": reasons,
+        });
+        return (400, error);
+    }
+    for update_request in &updates {
+        let email_id = update_request["Key"]["EmailId"]["S"]
+            .as_str()
+            .unwrap_or_default();
+        update(items, email_id, update_request);
+    }
+    (200, json!({}))
+}
+
+/// Whether `item` satisfies `condition`, supporting the forms built by `crate::schema`.
+fn matches(item: Option<&Map<String, Value>>, condition: &str, values: &Value) -> bool {
+    if condition.is_empty() {
+        return true;
+    }
+    condition.split(" OR ").any(|clauses| {
+        clauses
+            .split(" AND ")
+            .all(|clause| satisfies(item, clause, values))
+    })
+}
+
+/// Whether `item` satisfies a single comparison or function of a condition.
+fn satisfies(item: Option<&Map<String, Value>>, clause: &str, values: &Value) -> bool {
+    if let Some(name) = clause
+        .strip_prefix("attribute_not_exists(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        !item.is_some_and(|item| item.contains_key(name))
+    } else if let Some(name) = clause
+        .strip_prefix("attribute_exists(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        item.is_some_and(|item| item.contains_key(name))
+    } else if let Some((name, placeholder)) = clause.split_once(" = ") {
+        item.and_then(|item| item.get(name)) == Some(&values[placeholder])
+    } else if let Some((name, placeholder)) = clause.split_once(" < ") {
+        let value = item
+            .and_then(|item| item.get(name))
+            .and_then(|value| value["S"].as_str());
+        match (value, values[placeholder]["S"].as_str()) {
+            (Some(value), Some(bound)) => value < bound,
+            _ => false,
+        }
+    } else {
+        false
+    }
+}
+
+impl HttpConnector for InMemoryDynamoDb {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let (status, body) = self.answer(&operation(&request), &json_body(&request));
+        let response = Response::new(
+            StatusCode::try_from(status).unwrap(),
+            SdkBody::from(body.to_string()),
+        );
+        HttpConnectorFuture::ready(Ok(response))
+    }
+}
+
+impl HttpClient for InMemoryDynamoDb {
+    fn http_connector(
+        &self,
+        _settings: &HttpConnectorSettings,
+        _components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(self.clone())
+    }
+}
+
+/// `value` in the JSON wire format of DynamoDB.
+fn wire_value(value: &AttributeValue) -> Value {
+    match value {
+        AttributeValue::S(s) => json!({ "S": s }),
+        AttributeValue::N(n) => json!({ "N": n }),
+        AttributeValue::Bool(b) => json!({ "BOOL": b }),
+        AttributeValue::Ss(ss) => json!({ "SS": ss }),
+        AttributeValue::L(l) => json!({ "L": l.iter().map(wire_value).collect::<Vec<_>>() }),
+        AttributeValue::M(m) => json!({ "M": wire_item(m) }),
+        _ => json!({ "NULL": true }),
+    }
+}
+
+fn wire_item(item: &HashMap<String, AttributeValue>) -> Value {
+    Value::Object(
+        item.iter()
+            .map(|(name, value)| (name.clone(), wire_value(value)))
+            .collect::<Map<_, _>>(),
+    )
+}
@@ -0,0 +1,87 @@
+use crate::queue::{RECEIVE_WAIT_TIME_SECONDS, VISIBILITY_TIMEOUT};
+use aws_sdk_dynamodb::config::timeout::TimeoutConfig;
+use std::time::Duration;
+
+/// Default seconds allowed to establish a connection to an AWS service.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 3;
+/// Default seconds allowed for a call to an AWS service, including its retries.
+pub const DEFAULT_OPERATION_TIMEOUT: u64 = 10;
+
+/// Limits on how long a call to an AWS service may take. Without them a call can hang far longer
+/// than the visibility timeout of the message being processed, which is then delivered again and
+/// processed twice.
+///
+/// # Examples
+///
+/// ```
+/// use email_shared::CallTimeouts;
+/// use std::time::Duration;
+///
+/// let timeouts = CallTimeouts::new(Duration::from_secs(3), Duration::from_secs(10));
+/// assert_eq!(timeouts.receive_operation(), Duration::from_secs(30));
+/// assert!(!timeouts.exceeds_visibility_timeout());
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CallTimeouts {
+    /// Time allowed to establish a connection.
+    pub connect: Duration,
+    /// Time allowed for a call, including its retries.
+    pub operation: Duration,
+}
+
+impl Default for CallTimeouts {
+    fn default() -> Self {
+        CallTimeouts::new(
+            Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            Duration::from_secs(DEFAULT_OPERATION_TIMEOUT),
+        )
+    }
+}
+
+impl CallTimeouts {
+    pub fn new(connect: Duration, operation: Duration) -> Self {
+        CallTimeouts { connect, operation }
+    }
+
+    /// Time allowed for a call to SQS, which includes waiting for messages to arrive when
+    /// receiving.
+    pub fn receive_operation(&self) -> Duration {
+        self.operation + Duration::from_secs(RECEIVE_WAIT_TIME_SECONDS as u64)
+    }
+
+    /// Whether a single call may take longer than a received message stays hidden.
+    pub fn exceeds_visibility_timeout(&self) -> bool {
+        self.operation >= Duration::from_secs(VISIBILITY_TIMEOUT as u64)
+    }
+
+    /// Timeouts for calls to DynamoDB, S3, and other services which answer right away.
+    pub fn timeout_config(&self) -> TimeoutConfig {
+        timeout_config(self.connect, self.operation)
+    }
+
+    /// Timeouts for calls to SQS, allowing receives to wait for messages to arrive.
+    pub fn receive_timeout_config(&self) -> TimeoutConfig {
+        timeout_config(self.connect, self.receive_operation())
+    }
+}
+
+/// A `TimeoutConfig` bounding each attempt, and all attempts together, by `operation`.
+fn timeout_config(connect: Duration, operation: Duration) -> TimeoutConfig {
+    TimeoutConfig::builder()
+        .connect_timeout(connect)
+        .operation_attempt_timeout(operation)
+        .operation_timeout(operation)
+        .build()
+}
+
+#[cfg(test)]
+mod exceeds_visibility_timeout {
+    use super::*;
+
+    #[test]
+    fn compares_operation_with_visibility_timeout() {
+        let connect = Duration::from_secs(1);
+        assert!(!CallTimeouts::new(connect, Duration::from_secs(29)).exceeds_visibility_timeout());
+        assert!(CallTimeouts::new(connect, Duration::from_secs(30)).exceeds_visibility_timeout());
+    }
+}
@@ -0,0 +1,561 @@
+use crate::attribute_value_wrapper::AttributeValueMap;
+use crate::dynamo::{from_hashmap, to_hashmap};
+use crate::email_message::{EmailId, EmailMessage};
+use crate::error::{PutError, UpdateError};
+use crate::html_text::{decode_entities, tag_end};
+use crate::schema::{attribute, attribute_exists, attribute_not_exists, placeholder, set};
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Namespace of the version 5 UUIDs identifying tracked opens and clicks.
+const TRACKING_NAMESPACE: Uuid = Uuid::from_u128(0x2b7e_5d91_c4a3_4f08_9e6b_1d3c_8a52_f7e4);
+
+/// A transparent 1x1 GIF, the body an endpoint answers a tracked open with.
+pub const TRACKING_PIXEL: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Reasons a string can not be read as a `TrackingUrl`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum TrackingUrlError {
+    #[error("InvalidTrackingUrl({0})")]
+    InvalidTrackingUrl(String),
+}
+
+/// Base URL of the endpoint recording opens and clicks. Opens are requested from
+/// `{url}/open/{TrackingId}` and clicks from `{url}/click/{TrackingId}`, so the URL may have a
+/// path but not a query or fragment, and it must not hold characters which would need escaping
+/// in an HTML attribute.
+///
+/// ```
+/// use email_shared::TrackingUrl;
+///
+/// let url = "https://t.example.com/email/".parse::<TrackingUrl>().unwrap();
+/// assert_eq!(url.as_str(), "https://t.example.com/email");
+/// assert!("t.example.com".parse::<TrackingUrl>().is_err());
+/// assert!("https://t.example.com/?source=email".parse::<TrackingUrl>().is_err());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TrackingUrl {
+    /// The URL as given, without a trailing slash.
+    url: String,
+}
+
+impl TrackingUrl {
+    /// The URL as given, without a trailing slash.
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+}
+
+impl FromStr for TrackingUrl {
+    type Err = TrackingUrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = s.trim_end_matches('/');
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"));
+        match rest {
+            Some(rest)
+                if !rest.is_empty()
+                    && !rest.starts_with('/')
+                    && !rest.contains(|c: char| {
+                        c.is_whitespace() || c.is_control() || "?#\"'<>&".contains(c)
+                    }) =>
+            {
+                Ok(TrackingUrl {
+                    url: url.to_owned(),
+                })
+            }
+            _ => Err(TrackingUrlError::InvalidTrackingUrl(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for TrackingUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+/// Whether a tracking item records the opens of an email or the clicks of one of its links.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum TrackingKind {
+    Click,
+    Open,
+}
+
+impl TrackingKind {
+    /// Segment of the path this kind is requested from.
+    fn path(&self) -> &'static str {
+        match self {
+            TrackingKind::Click => "click",
+            TrackingKind::Open => "open",
+        }
+    }
+}
+
+impl fmt::Display for TrackingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// An item of the tracking table, mapping a `TrackingId` in a rewritten link or tracking pixel
+/// back to the email, recipient, and destination it was written for.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TrackedLink {
+    /// Key of the tracking table, found in the path of the tracked URL.
+    pub tracking_id: String,
+    /// Email the link or pixel was written into.
+    pub email_id: EmailId,
+    /// Address the email was sent to when it had a single `To` recipient.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Whether this records opens or clicks.
+    pub kind: TrackingKind,
+    /// Where a click is redirected to, absent for opens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Number of opens or clicks recorded.
+    #[serde(default)]
+    pub hits: u64,
+    /// When the link was written.
+    pub created_at: String,
+    /// When an open or click was last recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_hit_at: Option<String>,
+}
+
+/// Rewrites HTML bodies so opens and clicks are recorded by an endpoint at a `TrackingUrl`. Each
+/// `http` or `https` link is replaced by a link to the endpoint and a tracking pixel is added,
+/// with the destination of each stored in a DynamoDB table keyed by `TrackingId` so the
+/// endpoint can find where to redirect a click and count it with `Tracking::record`.
+#[derive(Clone, Debug)]
+pub struct Tracking {
+    /// Connection to DynamoDB.
+    dynamodb: DynamoDbClient,
+    /// DynamoDB table mapping each `TrackingId` to its email and destination.
+    table_name: String,
+    /// Base URL of the endpoint recording opens and clicks.
+    url: TrackingUrl,
+}
+
+impl Tracking {
+    pub fn new(dynamodb: DynamoDbClient, table_name: impl Into<String>, url: TrackingUrl) -> Self {
+        Tracking {
+            dynamodb,
+            table_name: table_name.into(),
+            url,
+        }
+    }
+
+    /// Name of the tracking table.
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    /// Rewrite the links of the HTML body of `email` to the tracking endpoint, add a tracking
+    /// pixel, and store where each leads. Identifiers are derived from the email, recipient, and
+    /// destination so a retried send writes the same links, and items already stored are left
+    /// with the hits they have recorded. Emails without an HTML body are left unchanged.
+    pub(crate) async fn instrument(&self, email: &mut EmailMessage) -> Result<(), PutError> {
+        if email.body_html.is_empty() {
+            return Ok(());
+        }
+        let recipient = match email.recipients_to.as_slice() {
+            [recipient] => Some(recipient.address().to_owned()),
+            _ => None,
+        };
+        let created_at = Utc::now().to_rfc3339();
+        let mut links = BTreeMap::new();
+        let mut track = |kind: TrackingKind, url: Option<&str>| {
+            let tracking_id = tracking_id(&email.email_id, recipient.as_deref(), kind, url);
+            let tracked = format!("{}/{}/{}", self.url, kind.path(), tracking_id);
+            links
+                .entry(tracking_id.clone())
+                .or_insert_with(|| TrackedLink {
+                    tracking_id,
+                    email_id: email.email_id.clone(),
+                    recipient: recipient.clone(),
+                    kind,
+                    url: url.map(String::from),
+                    hits: 0,
+                    created_at: created_at.clone(),
+                    last_hit_at: None,
+                });
+            tracked
+        };
+        let html = rewrite_links(&email.body_html, |url| {
+            track(TrackingKind::Click, Some(url))
+        });
+        let pixel = track(TrackingKind::Open, None);
+        email.body_html = insert_pixel(&html, &pixel);
+        for link in links.values() {
+            self.put(link).await?;
+        }
+        Ok(())
+    }
+
+    /// Store `link` unless it is already stored.
+    async fn put(&self, link: &TrackedLink) -> Result<(), PutError> {
+        let item = to_hashmap(link).map_err(|e| PutError::SerializeError(e.to_string()))?;
+        let result = self
+            .dynamodb
+            .put_item()
+            .condition_expression(attribute_not_exists(attribute::TRACKING_ID))
+            .set_item(Some(item))
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(PutError::from);
+        match result {
+            Ok(_) | Err(PutError::ConditionalCheckFailed(_)) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Record an open or click of the link identified by `tracking_id`, returning the link with
+    /// its hits counted so a click can be redirected to its `url`, or `None` when no link has the
+    /// identifier.
+    pub async fn record(&self, tracking_id: &str) -> Result<Option<TrackedLink>, UpdateError> {
+        let mut values = HashMap::new();
+        values.insert(
+            placeholder::NOW.to_owned(),
+            AttributeValue::S(Utc::now().to_rfc3339()),
+        );
+        values.insert(placeholder::ONE.to_owned(), AttributeValue::N("1".into()));
+        let result = self
+            .dynamodb
+            .update_item()
+            .condition_expression(attribute_exists(attribute::TRACKING_ID))
+            .set_expression_attribute_values(Some(values))
+            .set_key(Some(AttributeValueMap::with_entry(
+                attribute::TRACKING_ID,
+                tracking_id.to_owned(),
+            )))
+            .table_name(&self.table_name)
+            .update_expression(format!(
+                "{} ADD {} {}",
+                set(&[(attribute::LAST_HIT_AT, placeholder::NOW)]),
+                attribute::HITS,
+                placeholder::ONE
+            ))
+            .return_values(ReturnValue::AllNew)
+            .send()
+            .await
+            .map_err(UpdateError::from);
+        match result {
+            Ok(output) => output
+                .attributes
+                .map(from_hashmap)
+                .transpose()
+                .map_err(|e| UpdateError::SerializeError(e.to_string())),
+            Err(UpdateError::ConditionalCheckFailed(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Identifier of the open, or click of `url`, of `email_id` sent to `recipient`, a version 5
+/// UUID so the same link always has the same identifier.
+fn tracking_id(
+    email_id: &EmailId,
+    recipient: Option<&str>,
+    kind: TrackingKind,
+    url: Option<&str>,
+) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(TRACKING_NAMESPACE.as_bytes());
+    for part in [
+        email_id.as_str(),
+        recipient.unwrap_or_default(),
+        kind.path(),
+        url.unwrap_or_default(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.finalize()[..16]);
+    uuid::Builder::from_sha1_bytes(bytes)
+        .into_uuid()
+        .to_string()
+}
+
+/// `html` with the `href` of each `a` element linking to an `http` or `https` URL replaced by
+/// what `replace` returns for the URL, given with character references decoded. Other links,
+/// such as `mailto:` links, are left alone.
+fn rewrite_links(html: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    // Lower casing ASCII leaves every byte offset in place
+    let lower = html.to_ascii_lowercase();
+    let mut rewritten = String::with_capacity(html.len());
+    let mut written = 0;
+    let mut search = 0;
+    while let Some(found) = lower[search..].find("<a") {
+        let start = search + found;
+        search = start + 2;
+        if !lower[search..].starts_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let end = match tag_end(&html[start..]) {
+            Some(end) => start + end,
+            None => break,
+        };
+        search = end;
+        if let Some((from, to)) = href_span(&html[start..end]) {
+            let url = decode_entities(&html[start + from..start + to]);
+            let scheme = url.trim_start().to_ascii_lowercase();
+            if scheme.starts_with("http://") || scheme.starts_with("https://") {
+                rewritten.push_str(&html[written..start + from]);
+                rewritten.push_str(&replace(url.trim()));
+                written = start + to;
+            }
+        }
+    }
+    rewritten.push_str(&html[written..]);
+    rewritten
+}
+
+/// Byte range of the value of the `href` attribute of `tag`, an `a` start tag without its
+/// closing `>`.
+fn href_span(tag: &str) -> Option<(usize, usize)> {
+    let bytes = tag.as_bytes();
+    let skip_whitespace = |mut index: usize| {
+        while bytes.get(index).is_some_and(u8::is_ascii_whitespace) {
+            index += 1;
+        }
+        index
+    };
+    let mut index = 2;
+    loop {
+        while bytes
+            .get(index)
+            .is_some_and(|b| b.is_ascii_whitespace() || *b == b'/')
+        {
+            index += 1;
+        }
+        if index >= bytes.len() {
+            return None;
+        }
+        let name_start = index;
+        while bytes
+            .get(index)
+            .is_some_and(|b| !b.is_ascii_whitespace() && *b != b'=' && *b != b'/')
+        {
+            index += 1;
+        }
+        let name = &tag[name_start..index];
+        index = skip_whitespace(index);
+        if bytes.get(index) != Some(&b'=') {
+            continue;
+        }
+        index = skip_whitespace(index + 1);
+        let span = match bytes.get(index) {
+            Some(&quote @ (b'"' | b'\'')) => {
+                let from = index + 1;
+                let to = tag[from..]
+                    .find(quote as char)
+                    .map_or(tag.len(), |end| from + end);
+                index = (to + 1).min(tag.len());
+                (from, to)
+            }
+            _ => {
+                let from = index;
+                while bytes.get(index).is_some_and(|b| !b.is_ascii_whitespace()) {
+                    index += 1;
+                }
+                (from, index)
+            }
+        };
+        if name.eq_ignore_ascii_case("href") {
+            return Some(span);
+        }
+    }
+}
+
+/// `html` with an invisible image loading `src` added at the end of its body.
+fn insert_pixel(html: &str, src: &str) -> String {
+    let pixel = format!(
+        "<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\" style=\"display:block;border:0;width:1px;height:1px\">",
+        src
+    );
+    match html.to_ascii_lowercase().rfind("</body") {
+        Some(index) => format!("{}{}{}", &html[..index], pixel, &html[index..]),
+        None => format!("{}{}", html, pixel),
+    }
+}
+
+#[cfg(test)]
+mod rewrite_links {
+    use super::*;
+
+    #[test]
+    fn replaces_web_links() {
+        let html = "<p><a class=\"button\" href=\"https://example.com/?a=1&amp;b=2\">Go</a> \
+            <A HREF=http://example.com>Home</A> <a href=\"mailto:help@example.com\">Help</a> \
+            <abbr title=\"x\">X</abbr> <a name=\"top\">Top</a></p>";
+        let mut urls = Vec::new();
+        let rewritten = rewrite_links(html, |url| {
+            urls.push(url.to_owned());
+            format!("https://t.example.com/click/{}", urls.len())
+        });
+        assert_eq!(
+            urls,
+            vec!["https://example.com/?a=1&b=2", "http://example.com"]
+        );
+        assert_eq!(
+            rewritten,
+            "<p><a class=\"button\" href=\"https://t.example.com/click/1\">Go</a> \
+            <A HREF=https://t.example.com/click/2>Home</A> <a href=\"mailto:help@example.com\">Help</a> \
+            <abbr title=\"x\">X</abbr> <a name=\"top\">Top</a></p>"
+        );
+    }
+
+    #[test]
+    fn ignores_quoted_markup() {
+        let html = "<a title=\"a > b\" href='https://example.com/é'>Café</a>";
+        let rewritten = rewrite_links(html, |url| format!("[{}]", url));
+        assert_eq!(
+            rewritten,
+            "<a title=\"a > b\" href='[https://example.com/é]'>Café</a>"
+        );
+    }
+}
+
+#[cfg(test)]
+mod insert_pixel {
+    use super::*;
+
+    #[test]
+    fn adds_pixel_to_end_of_body() {
+        let pixel = "<img src=\"https://t.example.com/open/1\" width=\"1\" height=\"1\" alt=\"\" \
+            style=\"display:block;border:0;width:1px;height:1px\">";
+        assert_eq!(
+            insert_pixel(
+                "<html><BODY><p>Hi</p></BODY></html>",
+                "https://t.example.com/open/1"
+            ),
+            format!("<html><BODY><p>Hi</p>{}</BODY></html>", pixel)
+        );
+        assert_eq!(
+            insert_pixel("<p>Hi</p>", "https://t.example.com/open/1"),
+            format!("<p>Hi</p>{}", pixel)
+        );
+    }
+}
+
+#[cfg(test)]
+mod instrument {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    #[tokio::test]
+    async fn stores_each_link() {
+        let table = InMemoryDynamoDb::default();
+        let tracking = Tracking::new(
+            table.client(),
+            "Tracking Table",
+            "https://t.example.com".parse().unwrap(),
+        );
+        let mut email = EmailMessage {
+            email_id: "Test EmailId".into(),
+            recipients_to: vec!["Test <to@example.com>".into()],
+            body_html:
+                "<a href=\"https://example.com\">One</a><a href=\"https://example.com\">Two</a>"
+                    .into(),
+            ..EmailMessage::default()
+        };
+        tracking.instrument(&mut email).await.unwrap();
+        let click = tracking_id(
+            &email.email_id,
+            Some("to@example.com"),
+            TrackingKind::Click,
+            Some("https://example.com"),
+        );
+        let open = tracking_id(
+            &email.email_id,
+            Some("to@example.com"),
+            TrackingKind::Open,
+            None,
+        );
+        assert!(email.body_html.starts_with(&format!(
+            "<a href=\"https://t.example.com/click/{}\">One</a>",
+            click
+        )));
+        assert!(email
+            .body_html
+            .contains(&format!("src=\"https://t.example.com/open/{}\"", open)));
+        let requests = table.requests("PutItem");
+        assert_eq!(requests.len(), 2);
+        let stored = requests
+            .iter()
+            .map(|request| {
+                let item = &request["Item"];
+                (
+                    item["TrackingId"]["S"].as_str().unwrap(),
+                    (item["Url"]["S"].as_str(), item["Recipient"]["S"].as_str()),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            stored.get(click.as_str()),
+            Some(&(Some("https://example.com"), Some("to@example.com")))
+        );
+        assert_eq!(
+            stored.get(open.as_str()),
+            Some(&(None, Some("to@example.com")))
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_emails_without_html() {
+        let table = InMemoryDynamoDb::default();
+        let tracking = Tracking::new(
+            table.client(),
+            "Tracking Table",
+            "https://t.example.com".parse().unwrap(),
+        );
+        let mut email = EmailMessage {
+            body_text: "https://example.com".into(),
+            ..EmailMessage::default()
+        };
+        tracking.instrument(&mut email).await.unwrap();
+        assert!(email.body_html.is_empty());
+        assert_eq!(table.calls("PutItem"), 0);
+    }
+}
+
+#[cfg(test)]
+mod record {
+    use super::*;
+    use crate::test_support::InMemoryDynamoDb;
+
+    #[tokio::test]
+    async fn finds_nothing_for_unknown_ids() {
+        let table = InMemoryDynamoDb::default();
+        let tracking = Tracking::new(
+            table.client(),
+            "Tracking Table",
+            "https://t.example.com".parse().unwrap(),
+        );
+        assert_eq!(tracking.record("Unknown TrackingId").await, Ok(None));
+        let request = &table.requests("UpdateItem")[0];
+        assert_eq!(
+            request["UpdateExpression"],
+            "SET LastHitAt = :now ADD Hits :one"
+        );
+    }
+}
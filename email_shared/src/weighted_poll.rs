@@ -0,0 +1,291 @@
+use crate::queue::{
+    receive_messages, ReceiveAttempt, RECEIVE_WAIT_TIME_SECONDS, VISIBILITY_TIMEOUT,
+};
+use crate::queue_url::QueueUrl;
+use crate::runner::MessageSource;
+use async_trait::async_trait;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::types::Message;
+use aws_sdk_sqs::Client as SqsClient;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{event, Level};
+
+/// Possible errors while parsing a `WeightedQueue`.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum WeightedQueueError {
+    /// The weight is not a whole number greater than zero.
+    #[error("InvalidWeight({0})")]
+    InvalidWeight(String),
+    /// The queue is not a valid SQS queue URL.
+    #[error("InvalidQueueUrl({0})")]
+    InvalidQueueUrl(String),
+}
+
+/// Queue polled along with others, given as `<weight>=<queue url>`. Queues with a greater weight
+/// are received from first and receive that many batches in a row while they have messages.
+///
+/// ```
+/// use email_shared::WeightedQueue;
+///
+/// let queue = "5=https://sqs.us-east-1.amazonaws.com/123456789012/high"
+///     .parse::<WeightedQueue>()
+///     .unwrap();
+/// assert_eq!(queue.weight, 5);
+/// assert_eq!(queue.queue_url.name(), "high");
+/// assert!("0=https://sqs.us-east-1.amazonaws.com/123456789012/high"
+///     .parse::<WeightedQueue>()
+///     .is_err());
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WeightedQueue {
+    /// URL of the queue.
+    pub queue_url: QueueUrl,
+    /// Batches received in a row while the queue has messages, also its priority.
+    pub weight: u32,
+}
+
+impl FromStr for WeightedQueue {
+    type Err = WeightedQueueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (weight, queue_url) = s
+            .split_once('=')
+            .ok_or_else(|| WeightedQueueError::InvalidWeight(s.to_owned()))?;
+        let weight = match weight.trim().parse::<u32>() {
+            Ok(weight) if weight > 0 => weight,
+            _ => return Err(WeightedQueueError::InvalidWeight(weight.trim().to_owned())),
+        };
+        let queue_url = queue_url
+            .trim()
+            .parse::<QueueUrl>()
+            .map_err(|_| WeightedQueueError::InvalidQueueUrl(queue_url.trim().to_owned()))?;
+        Ok(WeightedQueue { queue_url, weight })
+    }
+}
+
+impl fmt::Display for WeightedQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.weight, self.queue_url)
+    }
+}
+
+/// Turns of weighted queues within a round. Each round every queue may be received from as many
+/// times in a row as its weight, in order of priority, and loses the rest of its turns once it is
+/// found empty. A new round starts once no queue has turns left.
+#[derive(Clone, Debug)]
+struct Rotation {
+    /// Turns left to each queue in the current round.
+    turns: Vec<u32>,
+    /// Turns given to each queue at the start of a round.
+    weights: Vec<u32>,
+}
+
+impl Rotation {
+    fn new(weights: Vec<u32>) -> Self {
+        Rotation {
+            turns: weights.clone(),
+            weights,
+        }
+    }
+
+    /// Index of the queue to receive from next, and whether it is the last queue with turns left
+    /// in the round. `None` once every turn of the round has been used.
+    fn next(&self) -> Option<(usize, bool)> {
+        let index = self.turns.iter().position(|turns| *turns > 0)?;
+        let last = self.turns[index + 1..].iter().all(|turns| *turns == 0);
+        Some((index, last))
+    }
+
+    /// Record a receive from the queue at `index` which did or did not return messages.
+    fn record(&mut self, index: usize, received: bool) {
+        if received {
+            self.turns[index] -= 1;
+        } else {
+            self.turns[index] = 0;
+        }
+    }
+
+    /// Start a new round, giving every queue its turns again.
+    fn restart(&mut self) {
+        self.turns.clone_from(&self.weights);
+    }
+}
+
+/// Poll several SQS queues in weighted round-robin, used by the broker to work through queues of
+/// different priority, such as high, normal, and bulk, with a single `Runner`. Queues with a
+/// greater weight are drained first, a queue is only received from for as many batches in a row
+/// as its weight so lower priority queues are never starved. Queues other than the last left in
+/// a round are checked without waiting so an empty high priority queue does not delay the rest.
+/// Receives from FIFO queues carry a `ReceiveRequestAttemptId` as `SqsPoll` does.
+pub struct WeightedPoll<'a> {
+    /// Attempt id of receives from each queue.
+    attempts: Vec<ReceiveAttempt>,
+    /// Queues ordered by priority, highest first.
+    queues: Vec<WeightedQueue>,
+    /// Queue the last batch was received from.
+    current: usize,
+    /// Turns of each queue within the current round.
+    rotation: Rotation,
+    /// Connection to SQS.
+    sqs: &'a SqsClient,
+    /// Seconds each received message stays hidden.
+    visibility_timeout: i32,
+}
+
+impl WeightedPoll<'_> {
+    /// Poll `queues`, ordered by weight, keeping the given order of queues of equal weight.
+    ///
+    /// # Panics
+    ///
+    /// If `queues` is empty.
+    pub fn new(mut queues: Vec<WeightedQueue>, sqs: &SqsClient) -> WeightedPoll<'_> {
+        assert!(!queues.is_empty(), "at least one queue is polled");
+        queues.sort_by_key(|queue| std::cmp::Reverse(queue.weight));
+        let rotation = Rotation::new(queues.iter().map(|queue| queue.weight).collect());
+        let attempts = queues
+            .iter()
+            .map(|queue| ReceiveAttempt::new(queue.queue_url.is_fifo()))
+            .collect();
+        WeightedPoll {
+            attempts,
+            queues,
+            current: 0,
+            rotation,
+            sqs,
+            visibility_timeout: VISIBILITY_TIMEOUT,
+        }
+    }
+
+    /// Hide each received message for `seconds` rather than the default visibility timeout, 0
+    /// leaving it visible to other receivers right away.
+    pub fn with_visibility_timeout(self, seconds: i32) -> Self {
+        WeightedPoll {
+            visibility_timeout: seconds,
+            ..self
+        }
+    }
+
+    /// Queues polled, highest priority first.
+    pub fn queues(&self) -> &[WeightedQueue] {
+        &self.queues
+    }
+}
+
+#[async_trait]
+impl MessageSource for WeightedPoll<'_> {
+    type Error = String;
+
+    async fn receive(&mut self) -> Result<Vec<Message>, Self::Error> {
+        loop {
+            let (index, last) = match self.rotation.next() {
+                Some(next) => next,
+                None => {
+                    // Every queue was found empty this round
+                    self.rotation.restart();
+                    return Ok(Vec::new());
+                }
+            };
+            self.current = index;
+            let queue_url = &self.queues[index].queue_url;
+            let wait_time_seconds = if last { RECEIVE_WAIT_TIME_SECONDS } else { 0 };
+            let attempt_id = self.attempts[index].id();
+            let messages = receive_messages(
+                queue_url,
+                self.sqs,
+                wait_time_seconds,
+                self.visibility_timeout,
+                attempt_id,
+            )
+            .await
+            .map_err(|error| format!("{}", DisplayErrorContext(&error)));
+            self.attempts[index].record(messages.is_ok());
+            event!(Level::DEBUG, queue_name = %queue_url.name(), received = ?messages.as_ref().map(Vec::len), "weighted receive");
+            match messages {
+                Ok(messages) if !messages.is_empty() => {
+                    self.rotation.record(index, true);
+                    return Ok(messages);
+                }
+                Ok(_) => self.rotation.record(index, false),
+                Err(error) => {
+                    // Move on to the next queue so one failing queue does not stop the others
+                    self.rotation.record(index, false);
+                    if self.rotation.next().is_none() {
+                        self.rotation.restart();
+                    }
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    fn queue_url(&self) -> Option<&QueueUrl> {
+        Some(&self.queues[self.current].queue_url)
+    }
+}
+
+#[cfg(test)]
+mod rotation {
+    use super::*;
+
+    /// Queues received from while each of them has messages for `receives` batches.
+    fn order(rotation: &mut Rotation, receives: usize) -> Vec<usize> {
+        (0..receives)
+            .map(|_| {
+                let (index, _) = rotation.next().unwrap_or_else(|| {
+                    rotation.restart();
+                    rotation.next().unwrap()
+                });
+                rotation.record(index, true);
+                index
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gives_each_queue_its_weight() {
+        let mut rotation = Rotation::new(vec![3, 2, 1]);
+        assert_eq!(
+            order(&mut rotation, 12),
+            vec![0, 0, 0, 1, 1, 2, 0, 0, 0, 1, 1, 2]
+        );
+    }
+
+    #[test]
+    fn moves_on_from_empty_queues() {
+        let mut rotation = Rotation::new(vec![3, 2, 1]);
+        assert_eq!(rotation.next(), Some((0, false)));
+        rotation.record(0, false);
+        assert_eq!(rotation.next(), Some((1, false)));
+        rotation.record(1, false);
+        assert_eq!(rotation.next(), Some((2, true)));
+        rotation.record(2, false);
+        assert_eq!(rotation.next(), None);
+        rotation.restart();
+        assert_eq!(rotation.next(), Some((0, false)));
+    }
+}
+
+#[cfg(test)]
+mod from_str {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_weights_and_urls() {
+        assert_eq!(
+            "https://sqs.us-east-1.amazonaws.com/123456789012/high".parse::<WeightedQueue>(),
+            Err(WeightedQueueError::InvalidWeight(
+                "https://sqs.us-east-1.amazonaws.com/123456789012/high".into()
+            ))
+        );
+        assert_eq!(
+            "high=https://sqs.us-east-1.amazonaws.com/123456789012/high".parse::<WeightedQueue>(),
+            Err(WeightedQueueError::InvalidWeight("high".into()))
+        );
+        assert_eq!(
+            "1=high".parse::<WeightedQueue>(),
+            Err(WeightedQueueError::InvalidQueueUrl("high".into()))
+        );
+    }
+}